@@ -128,6 +128,7 @@ pub fn init_gui(
                     } else {
                         Some(password)
                     },
+                    None,
                 )
                 .await
             {
@@ -227,6 +228,7 @@ pub fn init_gui(
                     } else {
                         Some(password)
                     },
+                    None,
                 )
                 .await
             {
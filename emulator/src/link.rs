@@ -38,12 +38,20 @@ pub enum RtcMessage {
     Write(u8, u32),
 }
 
+#[derive(Debug)]
+pub enum DisplaySettingMessage {
+    Brightness(u8),
+    Rotation(u8),
+}
+
 pub struct EmulatorStreams {
     pub display: mpsc::UnboundedReceiver<Vec<u16>>,
     pub flash: mpsc::UnboundedReceiver<FlashMessage>,
     pub rtc: mpsc::UnboundedReceiver<RtcMessage>,
     pub tick: mpsc::UnboundedReceiver<()>,
     pub finish_boot: mpsc::UnboundedReceiver<()>,
+    pub snapshot: mpsc::UnboundedReceiver<Vec<u8>>,
+    pub display_settings: mpsc::UnboundedReceiver<DisplaySettingMessage>,
 }
 
 pub fn stream_incoming_messages(
@@ -55,6 +63,8 @@ pub fn stream_incoming_messages(
     let (rtc_s, rtc) = mpsc::unbounded_channel();
     let (tick_s, tick) = mpsc::unbounded_channel();
     let (finish_boot_s, finish_boot) = mpsc::unbounded_channel();
+    let (snapshot_s, snapshot) = mpsc::unbounded_channel();
+    let (display_settings_s, display_settings) = mpsc::unbounded_channel();
 
     tokio::spawn(async move {
         let mut buffer_display = vec![];
@@ -74,6 +84,13 @@ pub fn stream_incoming_messages(
                 CardMessage::WriteRtcRegister(reg, value) => {
                     log::trace!("< WriteRtcRegister({:02X}, {:08X?})", reg, value)
                 }
+                CardMessage::SnapshotData(data) => log::trace!("< SnapshotData({})", data.len()),
+                CardMessage::DisplayBrightness(level) => {
+                    log::trace!("< DisplayBrightness({})", level)
+                }
+                CardMessage::DisplayRotation(rotation) => {
+                    log::trace!("< DisplayRotation({})", rotation)
+                }
             }
             let result = match card_message {
                 CardMessage::Display(data) => {
@@ -104,6 +121,13 @@ pub fn stream_incoming_messages(
                 CardMessage::WriteRtcRegister(reg, value) => rtc_s
                     .send(RtcMessage::Write(reg, value))
                     .map_err(|e| e.to_string()),
+                CardMessage::SnapshotData(data) => snapshot_s.send(data).map_err(|e| e.to_string()),
+                CardMessage::DisplayBrightness(level) => display_settings_s
+                    .send(DisplaySettingMessage::Brightness(level))
+                    .map_err(|e| e.to_string()),
+                CardMessage::DisplayRotation(rotation) => display_settings_s
+                    .send(DisplaySettingMessage::Rotation(rotation))
+                    .map_err(|e| e.to_string()),
             };
 
             if let Err(e) = result {
@@ -120,6 +144,8 @@ pub fn stream_incoming_messages(
             rtc,
             tick,
             finish_boot,
+            snapshot,
+            display_settings,
         },
         nfc,
     )
@@ -235,6 +261,18 @@ where
             }
         }
     }
+    while let Some(setting_msg) = try_pull_msg(&mut emulator.msgs.display_settings)? {
+        match setting_msg {
+            DisplaySettingMessage::Brightness(level) => {
+                append_to_console("< ", &format!("SetBrightness({})", level), arg);
+                emulator.brightness = Some(level);
+            }
+            DisplaySettingMessage::Rotation(rotation) => {
+                append_to_console("< ", &format!("SetRotation({})", rotation), arg);
+                emulator.rotation = Some(rotation);
+            }
+        }
+    }
 
     if pull_ticks {
         while let Some(_) = try_pull_msg::<()>(&mut emulator.msgs.tick)? {}
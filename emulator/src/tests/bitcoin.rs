@@ -44,6 +44,34 @@ async fn test_display_address(mut tester: Tester) -> Result<(), crate::Error> {
     Ok(())
 }
 
+// mnemonic = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about"
+#[functional_test_wrapper::functional_test(flash_file = "./test-vector/initialized.bin")]
+async fn test_display_change_address(mut tester: Tester) -> Result<(), crate::Error> {
+    tester.display_assertion(super::PORTAL_READY, None).await?;
+
+    tester
+        .nfc(NfcAction::DisplayKeychainAddress(
+            model::KeychainKind::Internal,
+            42,
+        ))
+        .await?;
+    tester.wait_ticks(4).await?;
+    tester.tsc(true).await?;
+
+    tester.wait_ticks(4).await?;
+    tester.tsc(true).await?;
+
+    tester.display_assertion(super::PORTAL_READY, None).await?;
+
+    tester
+        .nfc_assertion(model::Reply::Address(
+            "tb1qfdflx45k5v0q452s9l75gurm6ej6y5zkfq5xtv".to_string(),
+        ))
+        .await?;
+
+    Ok(())
+}
+
 // mnemonic = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about"
 #[functional_test_wrapper::functional_test(flash_file = "./test-vector/initialized.bin")]
 async fn test_public_descriptors(mut tester: Tester) -> Result<(), crate::Error> {
@@ -66,6 +94,89 @@ async fn test_public_descriptors(mut tester: Tester) -> Result<(), crate::Error>
     Ok(())
 }
 
+// mnemonic = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about"
+#[functional_test_wrapper::functional_test(flash_file = "./test-vector/initialized.bin")]
+async fn test_public_descriptors_long_press(mut tester: Tester) -> Result<(), crate::Error> {
+    tester.display_assertion(super::PORTAL_READY, None).await?;
+
+    tester.nfc(NfcAction::RequestDescriptors).await?;
+    tester.display_assertion("iVBORw0KGgoAAAANSUhEUgAAAIAAAABACAAAAAD3vSCjAAABrElEQVR4nO2Yi66DMAiG4f0f+j+ZWm6lPatztwyzmKZS+AoIdUxvvgqgAAqgAAqgAD4RAMckKA4eupAr4URQDfN07ZrBewFucofsWwB2MXv3QbmNwlMvj10rVLvMcBbLFYDmnBkAhBA2kM1674YFALnLz66yMxYgVTIF0PkzAJDlpwEe8oBLhqsBTA7EtTLT8pBDxni2+wCgvtSYkM+2/uWFYBhpojR1qxcUwHcA4GpGBKv8/wK+1j57nR8GoP0W/lSSVRGQrzRhIBO+mMVNcXYYsZ1zCABbb/uB69t+OUY5kMqPPUBWOG07+ZHKazkPANt/AkDTnYUg7OI0wGSb6AM7zudBDnRGJTVCCgxzwG1kEsfRWxB78marCwHbc2gyWAzB8kn7hb2gqx8vb0Z4VueqdswoAK2okE9IfXXNOZ+h0rZK7G/+9jTUplAK2BY0ZADt1niU7KBj+wViRYIChi5z6q1cANBdBIDuY6MH0H3PAVo9tYFf8QCYxh7wLQDKHTxgdV8KkDt5IQSahFswQhJ6iyR/N2Th02BQzNpJEv5wHaheUAAFUAA/D/AHrZmlRhwRFIgAAAAASUVORK5CYII=", None).await?;
+
+    tester
+        .gesture(model::emulator::Gesture::LongPress { ms: 1000 })
+        .await?;
+
+    tester.display_assertion(super::PORTAL_READY, None).await?;
+
+    tester
+        .nfc_assertion(model::Reply::Descriptor {
+            external: super::WPKH_EXTERNAL_DESC.to_string(),
+            internal: Some(super::WPKH_INTERNAL_DESC.to_string()),
+        })
+        .await?;
+
+    Ok(())
+}
+
+// mnemonic = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about"
+#[functional_test_wrapper::functional_test(flash_file = "./test-vector/initialized.bin")]
+async fn test_export_descriptor_external(mut tester: Tester) -> Result<(), crate::Error> {
+    tester.display_assertion(super::PORTAL_READY, None).await?;
+
+    tester
+        .nfc(NfcAction::ExportDescriptor(model::KeychainKind::External))
+        .await?;
+    tester.display_assertion("iVBORw0KGgoAAAANSUhEUgAAAIAAAABACAAAAAD3vSCjAAABrElEQVR4nO2Yi66DMAiG4f0f+j+ZWm6lPatztwyzmKZS+AoIdUxvvgqgAAqgAAqgAD4RAMckKA4eupAr4URQDfN07ZrBewFucofsWwB2MXv3QbmNwlMvj10rVLvMcBbLFYDmnBkAhBA2kM1674YFALnLz66yMxYgVTIF0PkzAJDlpwEe8oBLhqsBTA7EtTLT8pBDxni2+wCgvtSYkM+2/uWFYBhpojR1qxcUwHcA4GpGBKv8/wK+1j57nR8GoP0W/lSSVRGQrzRhIBO+mMVNcXYYsZ1zCABbb/uB69t+OUY5kMqPPUBWOG07+ZHKazkPANt/AkDTnYUg7OI0wGSb6AM7zudBDnRGJTVCCgxzwG1kEsfRWxB78marCwHbc2gyWAzB8kn7hb2gqx8vb0Z4VueqdswoAK2okE9IfXXNOZ+h0rZK7G/+9jTUplAK2BY0ZADt1niU7KBj+wViRYIChi5z6q1cANBdBIDuY6MH0H3PAVo9tYFf8QCYxh7wLQDKHTxgdV8KkDt5IQSahFswQhJ6iyR/N2Th02BQzNpJEv5wHaheUAAFUAA/D/AHrZmlRhwRFIgAAAAASUVORK5CYII=", None).await?;
+
+    tester.tsc(true).await?;
+
+    tester.display_assertion(super::PORTAL_READY, None).await?;
+
+    tester
+        .nfc_assertion(model::Reply::ExportedDescriptor(
+            super::WPKH_EXTERNAL_DESC.to_string(),
+        ))
+        .await?;
+
+    Ok(())
+}
+
+// mnemonic = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about"
+#[functional_test_wrapper::functional_test(flash_file = "./test-vector/initialized.bin")]
+async fn test_export_descriptor_internal(mut tester: Tester) -> Result<(), crate::Error> {
+    tester.display_assertion(super::PORTAL_READY, None).await?;
+
+    tester
+        .nfc(NfcAction::ExportDescriptor(model::KeychainKind::Internal))
+        .await?;
+    tester.display_assertion("iVBORw0KGgoAAAANSUhEUgAAAIAAAABACAAAAAD3vSCjAAABrElEQVR4nO2Yi66DMAiG4f0f+j+ZWm6lPatztwyzmKZS+AoIdUxvvgqgAAqgAAqgAD4RAMckKA4eupAr4URQDfN07ZrBewFucofsWwB2MXv3QbmNwlMvj10rVLvMcBbLFYDmnBkAhBA2kM1674YFALnLz66yMxYgVTIF0PkzAJDlpwEe8oBLhqsBTA7EtTLT8pBDxni2+wCgvtSYkM+2/uWFYBhpojR1qxcUwHcA4GpGBKv8/wK+1j57nR8GoP0W/lSSVRGQrzRhIBO+mMVNcXYYsZ1zCABbb/uB69t+OUY5kMqPPUBWOG07+ZHKazkPANt/AkDTnYUg7OI0wGSb6AM7zudBDnRGJTVCCgxzwG1kEsfRWxB78marCwHbc2gyWAzB8kn7hb2gqx8vb0Z4VueqdswoAK2okE9IfXXNOZ+h0rZK7G/+9jTUplAK2BY0ZADt1niU7KBj+wViRYIChi5z6q1cANBdBIDuY6MH0H3PAVo9tYFf8QCYxh7wLQDKHTxgdV8KkDt5IQSahFswQhJ6iyR/N2Th02BQzNpJEv5wHaheUAAFUAA/D/AHrZmlRhwRFIgAAAAASUVORK5CYII=", None).await?;
+
+    tester.tsc(true).await?;
+
+    tester.display_assertion(super::PORTAL_READY, None).await?;
+
+    tester
+        .nfc_assertion(model::Reply::ExportedDescriptor(
+            super::WPKH_INTERNAL_DESC.to_string(),
+        ))
+        .await?;
+
+    Ok(())
+}
+
+// mnemonic = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about"
+#[functional_test_wrapper::functional_test(flash_file = "./test-vector/initialized.bin")]
+async fn test_get_fingerprint(mut tester: Tester) -> Result<(), crate::Error> {
+    tester.display_assertion(super::PORTAL_READY, None).await?;
+
+    tester.nfc(NfcAction::GetFingerprint).await?;
+    tester
+        .nfc_assertion(model::Reply::Fingerprint([0x73, 0xc5, 0xda, 0x0a]))
+        .await?;
+
+    Ok(())
+}
+
 // mnemonic = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about"
 #[functional_test_wrapper::functional_test(flash_file = "./test-vector/initialized.bin")]
 async fn test_sign_psbt(mut tester: Tester) -> Result<(), crate::Error> {
@@ -76,6 +187,10 @@ async fn test_sign_psbt(mut tester: Tester) -> Result<(), crate::Error> {
 
     // LOADING
     tester.display_assertion(super::LOADING, None).await?;
+    // Timelock
+    tester.display_assertion("iVBORw0KGgoAAAANSUhEUgAAAIAAAABACAAAAAD3vSCjAAAB00lEQVR4nO2YDXPDIAiG4f//6HdrI/KhjbrE5XpHt2UnBXyCBDRMD38SIAFmAFAUwc0XQYLB+M8R+OQI/wrwvt/fC97/3pZFTiLBESeR8M0RONwfPzJSabmCVQLaBmCyQgFMMIgq5LYIqKQXgbIUN+dAu8rhui0HshImwIMAIMnocy1WfT77fhXgZSl/UwB9zWsAAw97AGqtDQBF/qox1taMD021F/1PEDwOv/iToczmbruOUbmN3vG7HAFimVhBxDG3Ie4CsE3leYDqJSwBSMAeA+jn5BhgcQmOuQOAeOkuwTAHFpMQFKzQZHUAlk2a03P6fYLsBd8CENZUk42cQMeY9T25J7TZD7sR9MUwVtB9AE2XNnd88tBfywEXAbsETdGhLQDo50AsNB7sRgBX4cImqAcwGwZemf8EwLdNTGzjVh9DphOAgHg/gOuCnabTAty8BDXEriCZcQCw8uwFCTACQAI0Y647UPI9v7RgbkofyDym7iQCtWB5ZYMxgFyER8kKXbViRJUyD4qyWqjTcwAyxA6g9Hxz9usACAXJxlotWN9xXo0AeAhQXpUZoukIXAOottcA9JgTk9DPSPXs001COa0gOM06kL0gARIgARLAfX4Ae3i8RlHK6UEAAAAASUVORK5CYII=", None).await?;
+    tester.tsc(true).await?;
+
     // Output
     tester.display_assertion("iVBORw0KGgoAAAANSUhEUgAAAIAAAABACAAAAAD3vSCjAAACOUlEQVR4nO2Z0baEIAhF4f8/mnsnFTiIWVNNL7bW5GiKW0Q0Ynr5WgAVQMofMSDxbPIc7QhACAH4xxoQeg2gDb2UfHKlcKu7FW75kpa5YW2XlLd8a1PlaT/s5pjJpIAslR4BLMXnpKPR51sHUV4+BQEgExg7c1WcBgBUOAH/HoBspkKVgYZuAuiEW7H732tqBIYAYEnRBg4IJG+0Zk+xPsgjv+4dA1ils2pQebR2v2ZdL7E+pxrYu044AZiyY/LuBTBN3QmwtuNfAgiXH2kSt6VjlsBwSx/PAGxln9fRv9NjdzuhAdENqbKQ+gWuAmeLYlvsrWq9NQHqZKcasKSJKTLnq7zWF7axNEGi/moXwAbaNDBun0y1AoAGSCdDJhrY+iK322m3qpBwavOzZr6QsSsbkE7G0WUo/L2xjzwpVl6OaAEsgAWwADjuxkk+T13MQAbleqKJR333GM/zkuUHaRdL6Mr9EcG/7EAlxj0jhkgmEDpEDvV3AaCfKwDaXhCg12wHkNnAFwDpyCS+q0kCegeA2+vzN+mnNfAkgHRGdTdAZoRmwWbW02XoBCfl55ahc0Sy54DcukcHE8ujI3KRt9wRrb1gASyAlwBkAXR5trgtHrE0WGLxdx8bJ4zPOsdvwWr1hPsA7QZfK+pWX2MwyCuxbamiImrQhKVXeAdAjhg/l5CKs1FEgEZBGmDSFu2B0GUNCE8BhAGgquSQBq4BaNtrAOQiQ2iE2KN9/UmN0MX4QOjyA2svgOsP93mLVbWaUNIAAAAASUVORK5CYII=", None).await?;
     tester.display_assertion("iVBORw0KGgoAAAANSUhEUgAAAIAAAABACAAAAAD3vSCjAAACSElEQVR4nO2Y0XLEIAhF4f8/mraJwAUxms3ubB/sdONoEI6oaGD68t8GSACS2mQVUpqYNHlZHRwnNdwedf3CPpss/iZ93gYQjLtyWgY4BqB9zsrhHlB8yGp91N4BSK2nmwIh7xx0YAcw7OXZbwQQZgPlfNV0Hkv2kbwCiMJNjLyMAGFqpASIQygNXgDMPOC7ZQRQKMgG0e0zANiVLLUtruxS71ozbJYRJs8vzLONJHpiCKBzjZPZPJdXJ2OgsnqhMLyPu+ZuKF4OTBP5lwHWQ/NE3tv3afjPAITPH1mxfq4mNfAoX88APFzc95Hwbyd/3PCAxRplIT0l/zQdCme78Ag3KtoeqsAC/NQDXqiaU+c8CjR5YR+LKhI/b68AfKDqgXH/YqoNIHiAbDJk4oHDFmGoZXdb+4fTDE+IEOM4mvIB2WSsbkPh1xd7vmHUOncg2gAbYANsAM6ncVGvS3EFMmi3Gw28yB+oIRnBUtUHZTqVina8IuCXXhBK34pFbmD2XaiXtq59CBDsPAGw/hIBes92ANUaeAGgHFlyMX6EvBcAzvretTkP8AkPfBJAukX1boBqEfoK9mU93YaguMwB3NmGEIjkKgB1uQGMNCFnkAKRgw4C0T4LNsAG+BKAbICu7rnYdMWyZAmkUiGXTjGRDIH/qJ4PC42XAPpQHidrdNaLJYs0O9KEvYcrvQYgIA4A7ZCCeF4AKAVZgsl66Auhxx4QngIIB4DmkiUPPAOwvs8ACDJDcRFGi2Q3wHIRQo4vKN1xYJ8F4e8Hs7F9VYaGGLkAAAAASUVORK5CYII=", None).await?;
@@ -93,13 +208,130 @@ async fn test_sign_psbt(mut tester: Tester) -> Result<(), crate::Error> {
             vec![
                 112, 115, 98, 116, 255, 1, 0, 51, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
                 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255, 255, 255, 255,
-                0, 255, 255, 255, 255, 0, 0, 0, 0, 0, 0, 34, 2, 3, 25, 203, 85, 92, 129, 231, 96,
-                208, 212, 175, 150, 144, 150, 200, 177, 216, 58, 32, 33, 245, 34, 15, 218, 119,
-                188, 92, 163, 24, 47, 59, 245, 195, 71, 48, 68, 2, 32, 30, 100, 57, 213, 243, 230,
-                91, 21, 255, 193, 91, 238, 114, 20, 94, 98, 79, 94, 251, 44, 151, 93, 76, 209, 1,
-                102, 49, 254, 33, 44, 40, 176, 2, 32, 71, 2, 0, 250, 190, 215, 228, 69, 5, 87, 221,
-                49, 166, 221, 182, 20, 78, 200, 211, 248, 105, 17, 169, 173, 214, 100, 163, 133,
-                86, 74, 144, 6, 1, 0,
+                0, 255, 255, 255, 255, 0, 0, 0, 0, 0, 0, 1, 8, 107, 2, 71, 48, 68, 2, 32, 30, 100,
+                57, 213, 243, 230, 91, 21, 255, 193, 91, 238, 114, 20, 94, 98, 79, 94, 251, 44,
+                151, 93, 76, 209, 1, 102, 49, 254, 33, 44, 40, 176, 2, 32, 71, 2, 0, 250, 190, 215,
+                228, 69, 5, 87, 221, 49, 166, 221, 182, 20, 78, 200, 211, 248, 105, 17, 169, 173,
+                214, 100, 163, 133, 86, 74, 144, 6, 1, 33, 3, 25, 203, 85, 92, 129, 231, 96, 208,
+                212, 175, 150, 144, 150, 200, 177, 216, 58, 32, 33, 245, 34, 15, 218, 119, 188, 92,
+                163, 24, 47, 59, 245, 195, 0,
+            ]
+            .into(),
+        ))
+        .await?;
+
+    Ok(())
+}
+
+// mnemonic = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about"
+#[functional_test_wrapper::functional_test(flash_file = "./test-vector/initialized.bin")]
+async fn test_sign_psbt_rejected(mut tester: Tester) -> Result<(), crate::Error> {
+    tester.display_assertion(super::PORTAL_READY, None).await?;
+
+    // Same PSBT as `test_sign_psbt` without the non_witness_utxo
+    tester.nfc(NfcAction::SignPsbt("cHNidP8BAFICAAAAAaBa/zzN4DufvU55XxA5Atv6Ce8IBjwQDorNb9ozNj0jAAAAAAD9////AfETAAAAAAAAFgAUow0Bk6zYJpM8neIOWSVDUI/SMw/09SoAAAEBHxAnAAAAAAAAFgAUjZMlxw1pfsKhfCwghXBAZbPAh6AiBgMZy1Vcgedg0NSvlpCWyLHYOiAh9SIP2ne8XKMYLzv1wxhzxdoKVAAAgAEAAIAAAACAAAAAACoAAAAAAA==".into())).await?;
+    tester.nfc_assertion(model::Reply::Ok).await?;
+
+    // LOADING
+    tester.display_assertion(super::LOADING, None).await?;
+
+    // The app is told why and the device goes back to idle
+    tester
+        .nfc_assertion(model::Reply::Error(
+            "Missing non_witness_utxo for input 0".into(),
+        ))
+        .await?;
+    tester.display_assertion(super::PORTAL_READY, None).await?;
+
+    tester.nfc(NfcAction::GetFingerprint).await?;
+    tester
+        .nfc_assertion(model::Reply::Fingerprint([0x73, 0xc5, 0xda, 0x0a]))
+        .await?;
+
+    Ok(())
+}
+
+// mnemonic = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about"
+#[functional_test_wrapper::functional_test(flash_file = "./test-vector/initialized.bin")]
+async fn test_sign_psbt_negative_fee(mut tester: Tester) -> Result<(), crate::Error> {
+    tester.display_assertion(super::PORTAL_READY, None).await?;
+
+    // Same PSBT as `test_sign_finalize_extract_tx` with an output of 20000 sat, spending an
+    // input of 10000 sat
+    tester.nfc(NfcAction::SignPsbt("cHNidP8BAFICAAAAAaBa/zzN4DufvU55XxA5Atv6Ce8IBjwQDorNb9ozNj0jAAAAAAD9////ASBOAAAAAAAAFgAUow0Bk6zYJpM8neIOWSVDUI/SMw/09SoAAAEBHxAnAAAAAAAAFgAUjZMlxw1pfsKhfCwghXBAZbPAh6ABAN4CAAAAAAEB5wbexMJPm5cAOIzEZEfaBja+X6j4PCEZMdH1FqlJET8AAAAAAP3///8CECcAAAAAAAAWABSNkyXHDWl+wqF8LCCFcEBls8CHoAAyAAAAAAAAFgAUDE+Hi6xSRoQyv20NbKaqOwhiuGECRzBEAiBsNI/BcueDMnAh1tFofo3HQlABy65FIIoTOqf2d0cMygIgIvZ4UESL+JcmUUOMtACOY578cYERCc1rsz/vHY+g4z8BIQOL3i/ypht9oqUxUQ6pDwd62GxnTuslqeZGeNFnMNxo6fT1KgAiBgMZy1Vcgedg0NSvlpCWyLHYOiAh9SIP2ne8XKMYLzv1wxhzxdoKVAAAgAEAAIAAAACAAAAAACoAAAAAAA==".into())).await?;
+    tester.nfc_assertion(model::Reply::Ok).await?;
+
+    // LOADING
+    tester.display_assertion(super::LOADING, None).await?;
+
+    tester
+        .nfc_assertion(model::Reply::Error(
+            "Outputs spend more than the inputs".into(),
+        ))
+        .await?;
+    tester.display_assertion(super::PORTAL_READY, None).await?;
+
+    Ok(())
+}
+
+// mnemonic = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about"
+#[functional_test_wrapper::functional_test(flash_file = "./test-vector/initialized.bin")]
+async fn test_sign_finalize_extract_tx(mut tester: Tester) -> Result<(), crate::Error> {
+    tester.display_assertion(super::PORTAL_READY, None).await?;
+
+    tester.nfc(NfcAction::SignPsbt("cHNidP8BAFICAAAAAaBa/zzN4DufvU55XxA5Atv6Ce8IBjwQDorNb9ozNj0jAAAAAAD9////AfETAAAAAAAAFgAUow0Bk6zYJpM8neIOWSVDUI/SMw/09SoAAAEBHxAnAAAAAAAAFgAUjZMlxw1pfsKhfCwghXBAZbPAh6ABAN4CAAAAAAEB5wbexMJPm5cAOIzEZEfaBja+X6j4PCEZMdH1FqlJET8AAAAAAP3///8CECcAAAAAAAAWABSNkyXHDWl+wqF8LCCFcEBls8CHoAAyAAAAAAAAFgAUDE+Hi6xSRoQyv20NbKaqOwhiuGECRzBEAiBsNI/BcueDMnAh1tFofo3HQlABy65FIIoTOqf2d0cMygIgIvZ4UESL+JcmUUOMtACOY578cYERCc1rsz/vHY+g4z8BIQOL3i/ypht9oqUxUQ6pDwd62GxnTuslqeZGeNFnMNxo6fT1KgAiBgMZy1Vcgedg0NSvlpCWyLHYOiAh9SIP2ne8XKMYLzv1wxhzxdoKVAAAgAEAAIAAAACAAAAAACoAAAAAAA==".into())).await?;
+    tester.nfc_assertion(model::Reply::Ok).await?;
+
+    // LOADING
+    tester.display_assertion(super::LOADING, None).await?;
+    // Timelock
+    tester.display_assertion("iVBORw0KGgoAAAANSUhEUgAAAIAAAABACAAAAAD3vSCjAAAB00lEQVR4nO2YDXPDIAiG4f//6HdrI/KhjbrE5XpHt2UnBXyCBDRMD38SIAFmAFAUwc0XQYLB+M8R+OQI/wrwvt/fC97/3pZFTiLBESeR8M0RONwfPzJSabmCVQLaBmCyQgFMMIgq5LYIqKQXgbIUN+dAu8rhui0HshImwIMAIMnocy1WfT77fhXgZSl/UwB9zWsAAw97AGqtDQBF/qox1taMD021F/1PEDwOv/iToczmbruOUbmN3vG7HAFimVhBxDG3Ie4CsE3leYDqJSwBSMAeA+jn5BhgcQmOuQOAeOkuwTAHFpMQFKzQZHUAlk2a03P6fYLsBd8CENZUk42cQMeY9T25J7TZD7sR9MUwVtB9AE2XNnd88tBfywEXAbsETdGhLQDo50AsNB7sRgBX4cImqAcwGwZemf8EwLdNTGzjVh9DphOAgHg/gOuCnabTAty8BDXEriCZcQCw8uwFCTACQAI0Y647UPI9v7RgbkofyDym7iQCtWB5ZYMxgFyER8kKXbViRJUyD4qyWqjTcwAyxA6g9Hxz9usACAXJxlotWN9xXo0AeAhQXpUZoukIXAOottcA9JgTk9DPSPXs001COa0gOM06kL0gARIgARLAfX4Ae3i8RlHK6UEAAAAASUVORK5CYII=", None).await?;
+    tester.tsc(true).await?;
+
+    // Output
+    tester.display_assertion("iVBORw0KGgoAAAANSUhEUgAAAIAAAABACAAAAAD3vSCjAAACOUlEQVR4nO2Z0baEIAhF4f8/mnsnFTiIWVNNL7bW5GiKW0Q0Ynr5WgAVQMofMSDxbPIc7QhACAH4xxoQeg2gDb2UfHKlcKu7FW75kpa5YW2XlLd8a1PlaT/s5pjJpIAslR4BLMXnpKPR51sHUV4+BQEgExg7c1WcBgBUOAH/HoBspkKVgYZuAuiEW7H732tqBIYAYEnRBg4IJG+0Zk+xPsgjv+4dA1ils2pQebR2v2ZdL7E+pxrYu044AZiyY/LuBTBN3QmwtuNfAgiXH2kSt6VjlsBwSx/PAGxln9fRv9NjdzuhAdENqbKQ+gWuAmeLYlvsrWq9NQHqZKcasKSJKTLnq7zWF7axNEGi/moXwAbaNDBun0y1AoAGSCdDJhrY+iK322m3qpBwavOzZr6QsSsbkE7G0WUo/L2xjzwpVl6OaAEsgAWwADjuxkk+T13MQAbleqKJR333GM/zkuUHaRdL6Mr9EcG/7EAlxj0jhkgmEDpEDvV3AaCfKwDaXhCg12wHkNnAFwDpyCS+q0kCegeA2+vzN+mnNfAkgHRGdTdAZoRmwWbW02XoBCfl55ahc0Sy54DcukcHE8ujI3KRt9wRrb1gASyAlwBkAXR5trgtHrE0WGLxdx8bJ4zPOsdvwWr1hPsA7QZfK+pWX2MwyCuxbamiImrQhKVXeAdAjhg/l5CKs1FEgEZBGmDSFu2B0GUNCE8BhAGgquSQBq4BaNtrAOQiQ2iE2KN9/UmN0MX4QOjyA2svgOsP93mLVbWaUNIAAAAASUVORK5CYII=", None).await?;
+    tester.display_assertion("iVBORw0KGgoAAAANSUhEUgAAAIAAAABACAAAAAD3vSCjAAACSElEQVR4nO2Y0XLEIAhF4f8/mraJwAUxms3ubB/sdONoEI6oaGD68t8GSACS2mQVUpqYNHlZHRwnNdwedf3CPpss/iZ93gYQjLtyWgY4BqB9zsrhHlB8yGp91N4BSK2nmwIh7xx0YAcw7OXZbwQQZgPlfNV0Hkv2kbwCiMJNjLyMAGFqpASIQygNXgDMPOC7ZQRQKMgG0e0zANiVLLUtruxS71ozbJYRJs8vzLONJHpiCKBzjZPZPJdXJ2OgsnqhMLyPu+ZuKF4OTBP5lwHWQ/NE3tv3afjPAITPH1mxfq4mNfAoX88APFzc95Hwbyd/3PCAxRplIT0l/zQdCme78Ag3KtoeqsAC/NQDXqiaU+c8CjR5YR+LKhI/b68AfKDqgXH/YqoNIHiAbDJk4oHDFmGoZXdb+4fTDE+IEOM4mvIB2WSsbkPh1xd7vmHUOncg2gAbYANsAM6ncVGvS3EFMmi3Gw28yB+oIRnBUtUHZTqVina8IuCXXhBK34pFbmD2XaiXtq59CBDsPAGw/hIBes92ANUaeAGgHFlyMX6EvBcAzvretTkP8AkPfBJAukX1boBqEfoK9mU93YaguMwB3NmGEIjkKgB1uQGMNCFnkAKRgw4C0T4LNsAG+BKAbICu7rnYdMWyZAmkUiGXTjGRDIH/qJ4PC42XAPpQHidrdNaLJYs0O9KEvYcrvQYgIA4A7ZCCeF4AKAVZgsl66Auhxx4QngIIB4DmkiUPPAOwvs8ACDJDcRFGi2Q3wHIRQo4vKN1xYJ8F4e8Hs7F9VYaGGLkAAAAASUVORK5CYII=", None).await?;
+    tester.display_assertion("iVBORw0KGgoAAAANSUhEUgAAAIAAAABACAAAAAD3vSCjAAACOUlEQVR4nO2Z0baEIAhF4f8/mnsnFTiIWVNNL7bW5GiKW0Q0Ynr5WgAVQMofMSDxbPIc7QhACAH4xxoQeg2gDb2UfHKlcKu7FW75kpa5YW2XlLd8a1PlaT/s5pjJpIAslR4BLMXnpKPR51sHUV4+BQEgExg7c1WcBgBUOAH/HoBspkKVgYZuAuiEW7H732tqBIYAYEnRBg4IJG+0Zk+xPsgjv+4dA1ils2pQebR2v2ZdL7E+pxrYu044AZiyY/LuBTBN3QmwtuNfAgiXH2kSt6VjlsBwSx/PAGxln9fRv9NjdzuhAdENqbKQ+gWuAmeLYlvsrWq9NQHqZKcasKSJKTLnq7zWF7axNEGi/moXwAbaNDBun0y1AoAGSCdDJhrY+iK322m3qpBwavOzZr6QsSsbkE7G0WUo/L2xjzwpVl6OaAEsgAWwADjuxkk+T13MQAbleqKJR333GM/zkuUHaRdL6Mr9EcG/7EAlxj0jhkgmEDpEDvV3AaCfKwDaXhCg12wHkNnAFwDpyCS+q0kCegeA2+vzN+mnNfAkgHRGdTdAZoRmwWbW02XoBCfl55ahc0Sy54DcukcHE8ujI3KRt9wRrb1gASyAlwBkAXR5trgtHrE0WGLxdx8bJ4zPOsdvwWr1hPsA7QZfK+pWX2MwyCuxbamiImrQhKVXeAdAjhg/l5CKs1FEgEZBGmDSFu2B0GUNCE8BhAGgquSQBq4BaNtrAOQiQ2iE2KN9/UmN0MX4QOjyA2svgOsP93mLVbWaUNIAAAAASUVORK5CYII=", None).await?;
+    tester.tsc(true).await?;
+
+    // Fee
+    tester.display_assertion("iVBORw0KGgoAAAANSUhEUgAAAIAAAABACAAAAAD3vSCjAAABlUlEQVR4nO2Y0dqDIAiG4f4vmv9ZinyiUrZ/62DsYGkSvgIixfTwLwESIAESIAGmAHIdTRgvN/RwqPcywH0tAYCwvIblJYRt/BeqbaZesjMPPLUFcKgrvdK2FZW2MLrAoFWu4NX7PLdIaAHTotPpWoh75wMATFTxj3lW8XAK4NVq2yxgky8AOIqHLQs0j1f3F8e7qBgAbsVAZsIEeAbAx6n2V1fbAgRXcYqFcFwFeAIgBFkU+qtry8Z2T8jpUEpxW9aEuJODAQonF8wHU7gAoJvnDYC2aj8+WnYAmMXALgBM3ixRXNCZGKuCzwCARUyhOMWfsQDVYw6ek/8B6IJlHYTUW2AHYBaEFsFW5pxswwFgANrZhpBgJE5AQSLisRSF0JDJeJ4FCZAACZAAzwNIAkzucCv5oJ6Gl1CGegSrIXtntZoR5KB7CqB/BEpqSz9W9LyDyKFgJRcDkMJ6AH1Vt1N9UMwCHF4OLPWuBYQXAGTP1G863wXwLliDXgtC0q9V3gVuyReCcAPg5/JAngUJkAAJ8PMAfzAVrEYGEamYAAAAAElFTkSuQmCC", Some(3)).await?;
+    tester.tsc(true).await?;
+
+    tester.display_assertion(super::PORTAL_READY, None).await?;
+
+    tester
+        .nfc_assertion(model::Reply::SignedPsbt(
+            vec![
+                112, 115, 98, 116, 255, 1, 0, 51, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255, 255, 255, 255,
+                0, 255, 255, 255, 255, 0, 0, 0, 0, 0, 0, 1, 8, 107, 2, 71, 48, 68, 2, 32, 30, 100,
+                57, 213, 243, 230, 91, 21, 255, 193, 91, 238, 114, 20, 94, 98, 79, 94, 251, 44,
+                151, 93, 76, 209, 1, 102, 49, 254, 33, 44, 40, 176, 2, 32, 71, 2, 0, 250, 190, 215,
+                228, 69, 5, 87, 221, 49, 166, 221, 182, 20, 78, 200, 211, 248, 105, 17, 169, 173,
+                214, 100, 163, 133, 86, 74, 144, 6, 1, 33, 3, 25, 203, 85, 92, 129, 231, 96, 208,
+                212, 175, 150, 144, 150, 200, 177, 216, 58, 32, 33, 245, 34, 15, 218, 119, 188, 92,
+                163, 24, 47, 59, 245, 195, 0,
+            ]
+            .into(),
+        ))
+        .await?;
+
+    // The signed PSBT combined with the reply above, with the input finalized
+    tester.nfc(NfcAction::ExtractTx("cHNidP8BAFICAAAAAaBa/zzN4DufvU55XxA5Atv6Ce8IBjwQDorNb9ozNj0jAAAAAAD9////AfETAAAAAAAAFgAUow0Bk6zYJpM8neIOWSVDUI/SMw/09SoAAAEA3gIAAAAAAQHnBt7Ewk+blwA4jMRkR9oGNr5fqPg8IRkx0fUWqUkRPwAAAAAA/f///wIQJwAAAAAAABYAFI2TJccNaX7CoXwsIIVwQGWzwIegADIAAAAAAAAWABQMT4eLrFJGhDK/bQ1spqo7CGK4YQJHMEQCIGw0j8Fy54MycCHW0Wh+jcdCUAHLrkUgihM6p/Z3RwzKAiAi9nhQRIv4lyZRQ4y0AI5jnvxxgREJzWuzP+8dj6DjPwEhA4veL/KmG32ipTFRDqkPB3rYbGdO6yWp5kZ40Wcw3Gjp9PUqAAEBHxAnAAAAAAAAFgAUjZMlxw1pfsKhfCwghXBAZbPAh6AiBgMZy1Vcgedg0NSvlpCWyLHYOiAh9SIP2ne8XKMYLzv1wxhzxdoKVAAAgAEAAIAAAACAAAAAACoAAAABCGsCRzBEAiAeZDnV8+ZbFf/BW+5yFF5iT177LJddTNEBZjH+ISwosAIgRwIA+r7X5EUFV90xpt22FE7I0/hpEamt1mSjhVZKkAYBIQMZy1Vcgedg0NSvlpCWyLHYOiAh9SIP2ne8XKMYLzv1wwAA".into())).await?;
+    tester
+        .nfc_assertion(model::Reply::RawTx(
+            vec![
+                2, 0, 0, 0, 0, 1, 1, 160, 90, 255, 60, 205, 224, 59, 159, 189, 78, 121, 95, 16, 57,
+                2, 219, 250, 9, 239, 8, 6, 60, 16, 14, 138, 205, 111, 218, 51, 54, 61, 35, 0, 0, 0,
+                0, 0, 253, 255, 255, 255, 1, 241, 19, 0, 0, 0, 0, 0, 0, 22, 0, 20, 163, 13, 1, 147,
+                172, 216, 38, 147, 60, 157, 226, 14, 89, 37, 67, 80, 143, 210, 51, 15, 2, 71, 48,
+                68, 2, 32, 30, 100, 57, 213, 243, 230, 91, 21, 255, 193, 91, 238, 114, 20, 94, 98,
+                79, 94, 251, 44, 151, 93, 76, 209, 1, 102, 49, 254, 33, 44, 40, 176, 2, 32, 71, 2,
+                0, 250, 190, 215, 228, 69, 5, 87, 221, 49, 166, 221, 182, 20, 78, 200, 211, 248,
+                105, 17, 169, 173, 214, 100, 163, 133, 86, 74, 144, 6, 1, 33, 3, 25, 203, 85, 92,
+                129, 231, 96, 208, 212, 175, 150, 144, 150, 200, 177, 216, 58, 32, 33, 245, 34, 15,
+                218, 119, 188, 92, 163, 24, 47, 59, 245, 195, 244, 245, 42, 0,
             ]
             .into(),
         ))
@@ -108,6 +340,78 @@ async fn test_sign_psbt(mut tester: Tester) -> Result<(), crate::Error> {
     Ok(())
 }
 
+// mnemonic = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about"
+#[functional_test_wrapper::functional_test(flash_file = "./test-vector/initialized.bin")]
+async fn test_sign_batch(mut tester: Tester) -> Result<(), crate::Error> {
+    tester.display_assertion(super::PORTAL_READY, None).await?;
+
+    let psbt = "cHNidP8BAFICAAAAAaBa/zzN4DufvU55XxA5Atv6Ce8IBjwQDorNb9ozNj0jAAAAAAD9////AfETAAAAAAAAFgAUow0Bk6zYJpM8neIOWSVDUI/SMw/09SoAAAEBHxAnAAAAAAAAFgAUjZMlxw1pfsKhfCwghXBAZbPAh6ABAN4CAAAAAAEB5wbexMJPm5cAOIzEZEfaBja+X6j4PCEZMdH1FqlJET8AAAAAAP3///8CECcAAAAAAAAWABSNkyXHDWl+wqF8LCCFcEBls8CHoAAyAAAAAAAAFgAUDE+Hi6xSRoQyv20NbKaqOwhiuGECRzBEAiBsNI/BcueDMnAh1tFofo3HQlABy65FIIoTOqf2d0cMygIgIvZ4UESL+JcmUUOMtACOY578cYERCc1rsz/vHY+g4z8BIQOL3i/ypht9oqUxUQ6pDwd62GxnTuslqeZGeNFnMNxo6fT1KgAiBgMZy1Vcgedg0NSvlpCWyLHYOiAh9SIP2ne8XKMYLzv1wxhzxdoKVAAAgAEAAIAAAACAAAAAACoAAAAAAA==";
+    tester
+        .nfc(NfcAction::SignBatch(vec![psbt.into(), psbt.into()]))
+        .await?;
+    tester.nfc_assertion(model::Reply::Ok).await?;
+
+    // LOADING
+    tester.display_assertion(super::LOADING, None).await?;
+    // Timelock of each transaction
+    tester.display_assertion("iVBORw0KGgoAAAANSUhEUgAAAIAAAABACAAAAAD3vSCjAAAB+UlEQVR4nO2YDY+DIAyG6f//0e/tlNLPCU7NsqTmjgxs6WOpLUjty1cBFEAEwDaM1w2Mm6BMJo6/bnjJST/1ABsfwn1arYw3rsNdAK9G7O8/oaX7CG3NRrvfVZJb2x+ER2gdIHnefOQfD6S9xqO9BclIWKIlD2QODwAqKgRAOSPE1ZkYWPSA1fUe6EvxCUB/uiwG4iq79qMYeHfhiaRRmbAAZgBoHL/HUpK1M8kDdZrZp/G/BJBLXgOYzPAMwMisDmDkJLJpSfq7pOiz/DsImruf5+MuWzOPPfoY3Epu/zvtgUZsWEB4YoouTgFIh/I6wJjFLQEag30NII/JOcDJJQAXP22RZ0mXYBoDJ4MQzWkhRLUD5i2ZkTPyOUHVgl8BcGsqwdbMgPSxOjct2lfRD73ts8nQZ9DnAEKVVk988NJfiwHjAb0EIem0RwCQx4BPNBbsRgCT4dwmKANYdQOdsX8AYMsmFrZxZ19DagcADvF+AFMFk6ITAW5eguFik5BU3wHo8aoFBTADQAGEPo0daLM1v5dgCqkPTb2m5iQC0SD+QIM5ADfMI2SdbmgRvEi3gy4sGjLpMUBTxAag13x19ksAmKLxxlo0SL5oXvUAaArQP4wpomUPXAMYutcA5Jjjg9BabOPskwYhn1bgJq08ULWgAAqgAArAXH+6xt5G97CGfgAAAABJRU5ErkJggg==", Some(100)).await?;
+    tester.tsc(true).await?;
+    tester.display_assertion("iVBORw0KGgoAAAANSUhEUgAAAIAAAABACAAAAAD3vSCjAAAB+UlEQVR4nO2Yi66DMAiG4f0f+j87s5RL0dapWZZgtiZFSj8pQivTl68CKIARAG8xXjfAKuJEx2jojSDBpJ96QCYX5dhPDS1OuAzwapzuq+P8sOm8vdDctd1tchJJAxcJrwP451Xjg84/Kdh6SaStBatkWKI1DyT+HgBMVCiAcUaMq7MxQAse8GOjB9pSfASwKWYxMK5yaD+Kgb0LTySNyoQFMAMASfwea+2WjbxELAOAuP+XAHLNawATC88A9MwaAHpOYp+WtN/KRh8v+nsQPHe/2JOuzOYeu/fRuY3e9jvtAWKZWEHEMI8uTgHYhvI6QLcSlgAkYF8DyGNyDnByCSDFz84oVtIlmMbAySAEhVEYojoAy5bM6Tn9nKBqwa8AhDXVYCMn0D5WbfPi/Cb6Ybd9PhnGDPocwFClzRMfvPTXYsB5wC7BkHToEQDkMRATjQe7EcBluLAJygBW3cBn5j8A8GUTC9u4s68h0wFAQLwfwFXBpOiMADcvQXexS0imHwCsvGpBAcwAUABDn/sOlHzNbyWYh9QHMq+pO4lAR7B8oMEcQBrhUbJG10cxokqbB01ZR6jRYwAyxA6g1Xxz9ksAhIJkY60jWL9oXvUAeArQPowZomUPXAPoY68B6DEnBqGfkfrZJw1COa0gGK08ULWgAAqgAArAXX86095G8eXsMAAAAABJRU5ErkJggg==", None).await?;
+    tester.tsc(true).await?;
+
+    // Total of both transactions, fees included
+    tester.display_assertion("iVBORw0KGgoAAAANSUhEUgAAAIAAAABACAAAAAD3vSCjAAABlUlEQVR4nO2Yi3KEMAhF4f8/+rY1II88tjo225llZ9ZEg3AChKhMb/4VQAEUQAEUgACg9TDmgQPFr5FF8oVOE5prDqOXAV7oDJRobvi5DEYID8xV39dlFLnvj03SdCZJVdmHoP1x3GD+iiGwUZV0sixaEL2bJaXlzikiPHM72Hvs8FUzp3MnjsHvdMZ84854UNsHEzaLjKp988BsUk5jCAEMKAAEV7ngONQz4hJ+DVTICi/Zh2CY828pRNhanqoU/2uAnAxnCYstUj1RvdrB0g6v7MdyrOephRVct07TOsTUEK/sxxo8MO7bOUAafxogbxZDgDs5cAUghYrIXd4BkHbu7R5AXiyPAwRbHUC28FQS2jK0Z5vpMlwC3FyGfnceFiD3DBK365iE9wtR7QUFUAAFUACbAFAAixHdbt2bJ/nvEIzz/vPZQE/hrjm5qwB6IKdEevq9IfJ2IocCJ3cBgHSOGUDfy223zwDSsnmP/sYD4AkA2T3yQWMvQA7BfQB7vMtJOJrys0n4MXWg9oICKIAC+HiAL/Ghg0YKhL1BAAAAAElFTkSuQmCC", None).await?;
+    tester.tsc(true).await?;
+
+    tester.display_assertion(super::PORTAL_READY, None).await?;
+
+    // The same PSBT signed twice
+    let signed: Vec<u8> = vec![
+        112, 115, 98, 116, 255, 1, 0, 51, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255, 255, 255, 255, 0, 255, 255, 255,
+        255, 0, 0, 0, 0, 0, 0, 1, 8, 107, 2, 71, 48, 68, 2, 32, 30, 100, 57, 213, 243, 230, 91, 21,
+        255, 193, 91, 238, 114, 20, 94, 98, 79, 94, 251, 44, 151, 93, 76, 209, 1, 102, 49, 254, 33,
+        44, 40, 176, 2, 32, 71, 2, 0, 250, 190, 215, 228, 69, 5, 87, 221, 49, 166, 221, 182, 20,
+        78, 200, 211, 248, 105, 17, 169, 173, 214, 100, 163, 133, 86, 74, 144, 6, 1, 33, 3, 25,
+        203, 85, 92, 129, 231, 96, 208, 212, 175, 150, 144, 150, 200, 177, 216, 58, 32, 33, 245,
+        34, 15, 218, 119, 188, 92, 163, 24, 47, 59, 245, 195, 0,
+    ];
+    tester
+        .nfc_assertion(model::Reply::SignedBatch(vec![
+            signed.clone().into(),
+            signed.into(),
+        ]))
+        .await?;
+
+    Ok(())
+}
+
+// mnemonic = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about"
+#[functional_test_wrapper::functional_test(flash_file = "./test-vector/initialized.bin")]
+async fn test_cancel_sign_batch(mut tester: Tester) -> Result<(), crate::Error> {
+    tester.display_assertion(super::PORTAL_READY, None).await?;
+
+    let psbt = "cHNidP8BAFICAAAAAaBa/zzN4DufvU55XxA5Atv6Ce8IBjwQDorNb9ozNj0jAAAAAAD9////AfETAAAAAAAAFgAUow0Bk6zYJpM8neIOWSVDUI/SMw/09SoAAAEBHxAnAAAAAAAAFgAUjZMlxw1pfsKhfCwghXBAZbPAh6ABAN4CAAAAAAEB5wbexMJPm5cAOIzEZEfaBja+X6j4PCEZMdH1FqlJET8AAAAAAP3///8CECcAAAAAAAAWABSNkyXHDWl+wqF8LCCFcEBls8CHoAAyAAAAAAAAFgAUDE+Hi6xSRoQyv20NbKaqOwhiuGECRzBEAiBsNI/BcueDMnAh1tFofo3HQlABy65FIIoTOqf2d0cMygIgIvZ4UESL+JcmUUOMtACOY578cYERCc1rsz/vHY+g4z8BIQOL3i/ypht9oqUxUQ6pDwd62GxnTuslqeZGeNFnMNxo6fT1KgAiBgMZy1Vcgedg0NSvlpCWyLHYOiAh9SIP2ne8XKMYLzv1wxhzxdoKVAAAgAEAAIAAAACAAAAAACoAAAAAAA==";
+    tester
+        .nfc(NfcAction::SignBatch(vec![
+            psbt.into();
+            model::MAX_BATCH_PSBTS
+        ]))
+        .await?;
+    tester.nfc_assertion(model::Reply::Ok).await?;
+
+    // LOADING
+    tester.display_assertion(super::LOADING, None).await?;
+
+    // Cancel while the batch is being signed, no signature is sent back
+    tester.nfc(NfcAction::Cancel).await?;
+    tester.nfc_assertion(model::Reply::Canceled).await?;
+
+    tester.display_assertion(super::PORTAL_READY, None).await?;
+
+    Ok(())
+}
+
 // mnemonic = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about"
 #[functional_test_wrapper::functional_test(flash_file = "./test-vector/initialized.bin")]
 async fn test_sign_psbt_ignore_change(mut tester: Tester) -> Result<(), crate::Error> {
@@ -118,6 +422,10 @@ async fn test_sign_psbt_ignore_change(mut tester: Tester) -> Result<(), crate::E
 
     // LOADING
     tester.display_assertion(super::LOADING, None).await?;
+    // Timelock
+    tester.display_assertion("iVBORw0KGgoAAAANSUhEUgAAAIAAAABACAAAAAD3vSCjAAAB00lEQVR4nO2Yi5LDIAhF4f8/+u42ijw0MWniZnaGPtLRAp4ABS3Ty48ESIAzAKiC4O6LMIPJ+GsP7BnCnwJs9/t7wfaxadZ5khkUP8kMP+yBYr48ZaSz9QrWGdAyAJMVCmCcQdQgl3lAZ0YeqKF4OAf6KIfrshzISpgALwKAJKOPpVjl+ej7qwAfTXmfAhhL3gOYWFgD0GptAKjznxpjdc24SKq+yO9B8Nz9Yk+Gspq77TZG4zZy5XXZA8SysIKIYe5dPARgm8rnAZqVEAKQgL0GMM7JOcDFEJS1A4BYGYZgmgMXkxAUtNBldQCWTZqTc/JjguwF/wUgxFSTjQYTvNuUvgZw2Q+7EeyLoc38dQBdl0Yo1fMWfj0HvGETAl10KQDGOeAKjYRgBYCrcGETZO9ee8WzALHORgBjbEkIQjuOAK5BrQBwXXDQdEYyD/8M25bIFCQztklqzsnZCxLgDAASoBtz24GS7/m1BbMrfdIb28/SnUSgGix/2WAOIBfhUbJK17QYUaSugyqsGmr0GIAMsQOoVcac/QYAQkGysVYN1v8473oAPAUAO4DqklMeuAfQdO8B6DEnJqFfkdrZZ5iEclpBMJp1IHtBAiRAAiSAe/wA06/ERm4Nn1MAAAAASUVORK5CYII=", None).await?;
+    tester.tsc(true).await?;
+
     // Output
     tester.display_assertion("iVBORw0KGgoAAAANSUhEUgAAAIAAAABACAAAAAD3vSCjAAACSUlEQVR4nO1Z27KDIAxM/v+jc84guSyES9UZX+hMtVbYLEtYBJk+/hwCbxGQBkt2ofmt+Fy+4Vr2sF8k0F7uMWBX8GqEkDbmahU111x/lx9aXoNpufac4GhwJqwIQRjkvO6XihXD2a4IdDhWhUFCaQgp01BROAlgDYrgnQIzAgRKDlsyIuD9HXDy+iMCiOMSY99P+hhkxkCDnOCQhFBAGxIAVsBtDiTJauVr7mikbhS0YzsgYW6wTAhEI+qj26gY+sDWON4Y5yOc8D+PnXWFv2M0I5yVAmc2/IyA8PUlO3Xz7NbUxHBIb68IuCP8rtG/WXI4/KBA9RxS7jbfFKQCuEr+Msi1aD0ogNZdK+Anhbkw1+5Qywt7WxRI3K5mBLyhqsC4ftLVRgAUIOsMWShQYlGwUgtrgghOoLHX3OMYQ3mDrDN2h6Hw/WQfOSYWPkZ0CBwCh8AhMFvT6/XgbI/yCtSW8ycave0VOCHQrul3lla4mOHuP3tEIH8ywAUILs3ixLGzvovB0hVxSgDu3yfgrVZlfyCQ5cAdAu3acUCAqF25vUQgPpzwBwpg4TcJyM4mQ7or9iQJPaOFaH+HI9sTuDcMg4HI1ID8sbUxloURub4DIzpzwSFwCHxEQA6B7prNV9HxyDZLfN8+7oUTzs3B+H2T25xwTkAPyseZVXZWy99aYN2riEHUTROWXvCOAAXGQKBOUsHPEwLKgoiAgIG2AtxSQHhJQBgIVEm2FHhGwOo+I0BhZwiTECP6W6M0CcMeH4AeHzhzAXz+AAjVl1XQqPTVAAAAAElFTkSuQmCC", None).await?;
     tester.display_assertion("iVBORw0KGgoAAAANSUhEUgAAAIAAAABACAAAAAD3vSCjAAACQklEQVR4nO2Z25LDIAhA4f8/mt1VuSlEk3Sm+0CnTZoU4UgQlSJ8+VUAbwFo0kFnKkls41v72D7mmg50Npku+AGAWPE9gAbOnem9gukax/f2heXZGMvN50BPuxEAOCPo3NmtScPOMLTuABY9AuNjgOw5IP17Y2CAOJC9crzQA1YuBQjOIYA+b/NE4vbnAATeQNSzBSCQI0hiAiEHMEHm4iJTPMdAEKwiP2KnNcgBAjc4YqM4BLCJaLUuoyJNREfj+N44393HLEW+Akj1rPdrNvxnAIT9A3Ja5tujKQrdIfx5B6CZ4b6PfpMmmsMND4zcA8wu807T1BTuBkEb7Cw6DqyA2+49oCdW03Xus8SQJ9S+sCLStHUFoB1lD+Ttg0ctAM4DIA+DNh5otsCkVDErDiGTanXGcPlXQ8kEtDyBKS7wIJ4fBnuWOakyYQEUQAEUQApA0eycn2XjyYpmOV3R6FLcLM0XgHlvf7DFcm1mObdEsPsRV1NAP2eYlif7PGss3BmHAO735wDaa/bsDYAoBp4AzHvIBABg3sF9CMAuTvALHvDCnwSgk2JDWB17E4Qa0VpF2A5DCGsDz4ahSSB0mYB02Tollk0iUv8miajmggIogC8BUAEs1yh51Wc8kGKJ1u9tTRz83GwSvxa7JRNeA/CBeZRs0Ekr/ffCt+0iomIUTZBWhy8AYIgdwJikTD4PAJgCAByAKJ0d8MgDhFsAQgcwXHLkgXcA0vYdAJjKkA9Cb1H/PQqD0NT4nNLKAzUXuNcPpFufVcuBISIAAAAASUVORK5CYII=", None).await?;
@@ -135,13 +443,13 @@ async fn test_sign_psbt_ignore_change(mut tester: Tester) -> Result<(), crate::E
             vec![
                 112, 115, 98, 116, 255, 1, 0, 51, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
                 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255, 255, 255, 255,
-                0, 255, 255, 255, 255, 0, 0, 0, 0, 0, 0, 34, 2, 3, 25, 203, 85, 92, 129, 231, 96,
+                0, 255, 255, 255, 255, 0, 0, 0, 0, 0, 0, 1, 8, 107, 2, 71, 48, 68, 2, 32, 75, 2,
+                71, 97, 21, 183, 106, 66, 96, 75, 211, 61, 65, 110, 213, 142, 250, 189, 50, 148,
+                215, 8, 185, 135, 168, 201, 15, 68, 99, 67, 170, 39, 2, 32, 88, 115, 248, 127, 199,
+                9, 80, 54, 205, 23, 126, 76, 218, 62, 146, 34, 129, 127, 4, 191, 106, 167, 198,
+                238, 167, 52, 248, 83, 5, 40, 144, 241, 1, 33, 3, 25, 203, 85, 92, 129, 231, 96,
                 208, 212, 175, 150, 144, 150, 200, 177, 216, 58, 32, 33, 245, 34, 15, 218, 119,
-                188, 92, 163, 24, 47, 59, 245, 195, 71, 48, 68, 2, 32, 75, 2, 71, 97, 21, 183, 106,
-                66, 96, 75, 211, 61, 65, 110, 213, 142, 250, 189, 50, 148, 215, 8, 185, 135, 168,
-                201, 15, 68, 99, 67, 170, 39, 2, 32, 88, 115, 248, 127, 199, 9, 80, 54, 205, 23,
-                126, 76, 218, 62, 146, 34, 129, 127, 4, 191, 106, 167, 198, 238, 167, 52, 248, 83,
-                5, 40, 144, 241, 1, 0,
+                188, 92, 163, 24, 47, 59, 245, 195, 0,
             ]
             .into(),
         ))
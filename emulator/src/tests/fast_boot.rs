@@ -83,6 +83,10 @@ async fn test_resume_locked_sign_psbt(mut tester: Tester) -> Result<(), crate::E
 
     // LOADING
     tester.display_assertion(super::LOADING, None).await?;
+    // Timelock
+    tester.display_assertion("iVBORw0KGgoAAAANSUhEUgAAAIAAAABACAAAAAD3vSCjAAAB00lEQVR4nO2YDXPDIAiG4f//6HdrI/KhjbrE5XpHt2UnBXyCBDRMD38SIAFmAFAUwc0XQYLB+M8R+OQI/wrwvt/fC97/3pZFTiLBESeR8M0RONwfPzJSabmCVQLaBmCyQgFMMIgq5LYIqKQXgbIUN+dAu8rhui0HshImwIMAIMnocy1WfT77fhXgZSl/UwB9zWsAAw97AGqtDQBF/qox1taMD021F/1PEDwOv/iToczmbruOUbmN3vG7HAFimVhBxDG3Ie4CsE3leYDqJSwBSMAeA+jn5BhgcQmOuQOAeOkuwTAHFpMQFKzQZHUAlk2a03P6fYLsBd8CENZUk42cQMeY9T25J7TZD7sR9MUwVtB9AE2XNnd88tBfywEXAbsETdGhLQDo50AsNB7sRgBX4cImqAcwGwZemf8EwLdNTGzjVh9DphOAgHg/gOuCnabTAty8BDXEriCZcQCw8uwFCTACQAI0Y647UPI9v7RgbkofyDym7iQCtWB5ZYMxgFyER8kKXbViRJUyD4qyWqjTcwAyxA6g9Hxz9usACAXJxlotWN9xXo0AeAhQXpUZoukIXAOottcA9JgTk9DPSPXs001COa0gOM06kL0gARIgARLAfX4Ae3i8RlHK6UEAAAAASUVORK5CYII=", None).await?;
+    tester.tsc(true).await?;
+
     // Output
     tester.display_assertion("iVBORw0KGgoAAAANSUhEUgAAAIAAAABACAAAAAD3vSCjAAACOUlEQVR4nO2Z0baEIAhF4f8/mnsnFTiIWVNNL7bW5GiKW0Q0Ynr5WgAVQMofMSDxbPIc7QhACAH4xxoQeg2gDb2UfHKlcKu7FW75kpa5YW2XlLd8a1PlaT/s5pjJpIAslR4BLMXnpKPR51sHUV4+BQEgExg7c1WcBgBUOAH/HoBspkKVgYZuAuiEW7H732tqBIYAYEnRBg4IJG+0Zk+xPsgjv+4dA1ils2pQebR2v2ZdL7E+pxrYu044AZiyY/LuBTBN3QmwtuNfAgiXH2kSt6VjlsBwSx/PAGxln9fRv9NjdzuhAdENqbKQ+gWuAmeLYlvsrWq9NQHqZKcasKSJKTLnq7zWF7axNEGi/moXwAbaNDBun0y1AoAGSCdDJhrY+iK322m3qpBwavOzZr6QsSsbkE7G0WUo/L2xjzwpVl6OaAEsgAWwADjuxkk+T13MQAbleqKJR333GM/zkuUHaRdL6Mr9EcG/7EAlxj0jhkgmEDpEDvV3AaCfKwDaXhCg12wHkNnAFwDpyCS+q0kCegeA2+vzN+mnNfAkgHRGdTdAZoRmwWbW02XoBCfl55ahc0Sy54DcukcHE8ujI3KRt9wRrb1gASyAlwBkAXR5trgtHrE0WGLxdx8bJ4zPOsdvwWr1hPsA7QZfK+pWX2MwyCuxbamiImrQhKVXeAdAjhg/l5CKs1FEgEZBGmDSFu2B0GUNCE8BhAGgquSQBq4BaNtrAOQiQ2iE2KN9/UmN0MX4QOjyA2svgOsP93mLVbWaUNIAAAAASUVORK5CYII=", None).await?;
     tester.display_assertion("iVBORw0KGgoAAAANSUhEUgAAAIAAAABACAAAAAD3vSCjAAACSElEQVR4nO2Y0XLEIAhF4f8/mraJwAUxms3ubB/sdONoEI6oaGD68t8GSACS2mQVUpqYNHlZHRwnNdwedf3CPpss/iZ93gYQjLtyWgY4BqB9zsrhHlB8yGp91N4BSK2nmwIh7xx0YAcw7OXZbwQQZgPlfNV0Hkv2kbwCiMJNjLyMAGFqpASIQygNXgDMPOC7ZQRQKMgG0e0zANiVLLUtruxS71ozbJYRJs8vzLONJHpiCKBzjZPZPJdXJ2OgsnqhMLyPu+ZuKF4OTBP5lwHWQ/NE3tv3afjPAITPH1mxfq4mNfAoX88APFzc95Hwbyd/3PCAxRplIT0l/zQdCme78Ag3KtoeqsAC/NQDXqiaU+c8CjR5YR+LKhI/b68AfKDqgXH/YqoNIHiAbDJk4oHDFmGoZXdb+4fTDE+IEOM4mvIB2WSsbkPh1xd7vmHUOncg2gAbYANsAM6ncVGvS3EFMmi3Gw28yB+oIRnBUtUHZTqVina8IuCXXhBK34pFbmD2XaiXtq59CBDsPAGw/hIBes92ANUaeAGgHFlyMX6EvBcAzvretTkP8AkPfBJAukX1boBqEfoK9mU93YaguMwB3NmGEIjkKgB1uQGMNCFnkAKRgw4C0T4LNsAG+BKAbICu7rnYdMWyZAmkUiGXTjGRDIH/qJ4PC42XAPpQHidrdNaLJYs0O9KEvYcrvQYgIA4A7ZCCeF4AKAVZgsl66Auhxx4QngIIB4DmkiUPPAOwvs8ACDJDcRFGi2Q3wHIRQo4vKN1xYJ8F4e8Hs7F9VYaGGLkAAAAASUVORK5CYII=", None).await?;
@@ -110,13 +114,13 @@ async fn test_resume_locked_sign_psbt(mut tester: Tester) -> Result<(), crate::E
             vec![
                 112, 115, 98, 116, 255, 1, 0, 51, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
                 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255, 255, 255, 255,
-                0, 255, 255, 255, 255, 0, 0, 0, 0, 0, 0, 34, 2, 3, 25, 203, 85, 92, 129, 231, 96,
-                208, 212, 175, 150, 144, 150, 200, 177, 216, 58, 32, 33, 245, 34, 15, 218, 119,
-                188, 92, 163, 24, 47, 59, 245, 195, 71, 48, 68, 2, 32, 30, 100, 57, 213, 243, 230,
-                91, 21, 255, 193, 91, 238, 114, 20, 94, 98, 79, 94, 251, 44, 151, 93, 76, 209, 1,
-                102, 49, 254, 33, 44, 40, 176, 2, 32, 71, 2, 0, 250, 190, 215, 228, 69, 5, 87, 221,
-                49, 166, 221, 182, 20, 78, 200, 211, 248, 105, 17, 169, 173, 214, 100, 163, 133,
-                86, 74, 144, 6, 1, 0,
+                0, 255, 255, 255, 255, 0, 0, 0, 0, 0, 0, 1, 8, 107, 2, 71, 48, 68, 2, 32, 30, 100,
+                57, 213, 243, 230, 91, 21, 255, 193, 91, 238, 114, 20, 94, 98, 79, 94, 251, 44,
+                151, 93, 76, 209, 1, 102, 49, 254, 33, 44, 40, 176, 2, 32, 71, 2, 0, 250, 190, 215,
+                228, 69, 5, 87, 221, 49, 166, 221, 182, 20, 78, 200, 211, 248, 105, 17, 169, 173,
+                214, 100, 163, 133, 86, 74, 144, 6, 1, 33, 3, 25, 203, 85, 92, 129, 231, 96, 208,
+                212, 175, 150, 144, 150, 200, 177, 216, 58, 32, 33, 245, 34, 15, 218, 119, 188, 92,
+                163, 24, 47, 59, 245, 195, 0,
             ]
             .into(),
         ))
@@ -160,3 +164,109 @@ async fn test_resume_ticks_display_address(mut tester: Tester) -> Result<(), cra
 
     Ok(())
 }
+
+// mnemonic = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about"
+#[functional_test_wrapper::functional_test(flash_file = "./test-vector/initialized.bin")]
+async fn test_brightness_persists_fast_boot(mut tester: Tester) -> Result<(), crate::Error> {
+    tester.display_assertion(super::PORTAL_READY, None).await?;
+
+    tester.nfc(NfcAction::SetBrightness(5)).await?;
+    tester
+        .nfc_assertion(model::Reply::Error("Invalid brightness".into()))
+        .await?;
+
+    tester.nfc(NfcAction::SetBrightness(4)).await?;
+    tester.nfc_assertion(model::Reply::Ok).await?;
+    tester.display_settings_assertion(Some(4), None).await?;
+
+    tester.fast_boot_reset().await?;
+    tester.display_assertion(super::PORTAL_READY, None).await?;
+    tester.display_settings_assertion(Some(4), None).await?;
+
+    Ok(())
+}
+
+// mnemonic = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about"
+#[functional_test_wrapper::functional_test(flash_file = "./test-vector/initialized.bin")]
+async fn test_rotation_persists_fast_boot(mut tester: Tester) -> Result<(), crate::Error> {
+    tester.display_assertion(super::PORTAL_READY, None).await?;
+
+    tester
+        .nfc(NfcAction::SetRotation(model::Rotation::Flipped))
+        .await?;
+    tester.nfc_assertion(model::Reply::Ok).await?;
+
+    tester.fast_boot_reset().await?;
+    tester.display_assertion(super::PORTAL_READY, None).await?;
+    // The panel is mounted upside down, so `Flipped` maps to the unrotated controller setting
+    tester.display_settings_assertion(None, Some(0)).await?;
+
+    tester
+        .nfc(NfcAction::SetRotation(model::Rotation::Normal))
+        .await?;
+    tester.nfc_assertion(model::Reply::Ok).await?;
+    tester.display_settings_assertion(None, Some(2)).await?;
+
+    Ok(())
+}
+
+// Snapshots only hold the persistent state, so the fork point has to be a committed checkpoint:
+// there is no way to restore a half-completed Noise handshake
+//
+// mnemonic = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about"
+#[functional_test_wrapper::functional_test(flash_file = "./test-vector/initialized.bin")]
+async fn test_snapshot_restore_sign_psbt(mut tester: Tester) -> Result<(), crate::Error> {
+    tester.display_assertion(super::PORTAL_READY, None).await?;
+
+    tester.nfc(NfcAction::SignPsbt("cHNidP8BAFICAAAAAaBa/zzN4DufvU55XxA5Atv6Ce8IBjwQDorNb9ozNj0jAAAAAAD9////AfETAAAAAAAAFgAUow0Bk6zYJpM8neIOWSVDUI/SMw/09SoAAAEBHxAnAAAAAAAAFgAUjZMlxw1pfsKhfCwghXBAZbPAh6ABAN4CAAAAAAEB5wbexMJPm5cAOIzEZEfaBja+X6j4PCEZMdH1FqlJET8AAAAAAP3///8CECcAAAAAAAAWABSNkyXHDWl+wqF8LCCFcEBls8CHoAAyAAAAAAAAFgAUDE+Hi6xSRoQyv20NbKaqOwhiuGECRzBEAiBsNI/BcueDMnAh1tFofo3HQlABy65FIIoTOqf2d0cMygIgIvZ4UESL+JcmUUOMtACOY578cYERCc1rsz/vHY+g4z8BIQOL3i/ypht9oqUxUQ6pDwd62GxnTuslqeZGeNFnMNxo6fT1KgAiBgMZy1Vcgedg0NSvlpCWyLHYOiAh9SIP2ne8XKMYLzv1wxhzxdoKVAAAgAEAAIAAAACAAAAAACoAAAAAAA==".into())).await?;
+    tester.nfc_assertion(model::Reply::Ok).await?;
+
+    // LOADING
+    tester.display_assertion(super::LOADING, None).await?;
+    // Timelock
+    tester.display_assertion("iVBORw0KGgoAAAANSUhEUgAAAIAAAABACAAAAAD3vSCjAAAB00lEQVR4nO2YDXPDIAiG4f//6HdrI/KhjbrE5XpHt2UnBXyCBDRMD38SIAFmAFAUwc0XQYLB+M8R+OQI/wrwvt/fC97/3pZFTiLBESeR8M0RONwfPzJSabmCVQLaBmCyQgFMMIgq5LYIqKQXgbIUN+dAu8rhui0HshImwIMAIMnocy1WfT77fhXgZSl/UwB9zWsAAw97AGqtDQBF/qox1taMD021F/1PEDwOv/iToczmbruOUbmN3vG7HAFimVhBxDG3Ie4CsE3leYDqJSwBSMAeA+jn5BhgcQmOuQOAeOkuwTAHFpMQFKzQZHUAlk2a03P6fYLsBd8CENZUk42cQMeY9T25J7TZD7sR9MUwVtB9AE2XNnd88tBfywEXAbsETdGhLQDo50AsNB7sRgBX4cImqAcwGwZemf8EwLdNTGzjVh9DphOAgHg/gOuCnabTAty8BDXEriCZcQCw8uwFCTACQAI0Y647UPI9v7RgbkofyDym7iQCtWB5ZYMxgFyER8kKXbViRJUyD4qyWqjTcwAyxA6g9Hxz9usACAXJxlotWN9xXo0AeAhQXpUZoukIXAOottcA9JgTk9DPSPXs001COa0gOM06kL0gARIgARLAfX4Ae3i8RlHK6UEAAAAASUVORK5CYII=", None).await?;
+    tester.tsc(true).await?;
+
+    // Output
+    tester.display_assertion("iVBORw0KGgoAAAANSUhEUgAAAIAAAABACAAAAAD3vSCjAAACOUlEQVR4nO2Z0baEIAhF4f8/mnsnFTiIWVNNL7bW5GiKW0Q0Ynr5WgAVQMofMSDxbPIc7QhACAH4xxoQeg2gDb2UfHKlcKu7FW75kpa5YW2XlLd8a1PlaT/s5pjJpIAslR4BLMXnpKPR51sHUV4+BQEgExg7c1WcBgBUOAH/HoBspkKVgYZuAuiEW7H732tqBIYAYEnRBg4IJG+0Zk+xPsgjv+4dA1ils2pQebR2v2ZdL7E+pxrYu044AZiyY/LuBTBN3QmwtuNfAgiXH2kSt6VjlsBwSx/PAGxln9fRv9NjdzuhAdENqbKQ+gWuAmeLYlvsrWq9NQHqZKcasKSJKTLnq7zWF7axNEGi/moXwAbaNDBun0y1AoAGSCdDJhrY+iK322m3qpBwavOzZr6QsSsbkE7G0WUo/L2xjzwpVl6OaAEsgAWwADjuxkk+T13MQAbleqKJR333GM/zkuUHaRdL6Mr9EcG/7EAlxj0jhkgmEDpEDvV3AaCfKwDaXhCg12wHkNnAFwDpyCS+q0kCegeA2+vzN+mnNfAkgHRGdTdAZoRmwWbW02XoBCfl55ahc0Sy54DcukcHE8ujI3KRt9wRrb1gASyAlwBkAXR5trgtHrE0WGLxdx8bJ4zPOsdvwWr1hPsA7QZfK+pWX2MwyCuxbamiImrQhKVXeAdAjhg/l5CKs1FEgEZBGmDSFu2B0GUNCE8BhAGgquSQBq4BaNtrAOQiQ2iE2KN9/UmN0MX4QOjyA2svgOsP93mLVbWaUNIAAAAASUVORK5CYII=", None).await?;
+    tester.display_assertion("iVBORw0KGgoAAAANSUhEUgAAAIAAAABACAAAAAD3vSCjAAACSElEQVR4nO2Y0XLEIAhF4f8/mraJwAUxms3ubB/sdONoEI6oaGD68t8GSACS2mQVUpqYNHlZHRwnNdwedf3CPpss/iZ93gYQjLtyWgY4BqB9zsrhHlB8yGp91N4BSK2nmwIh7xx0YAcw7OXZbwQQZgPlfNV0Hkv2kbwCiMJNjLyMAGFqpASIQygNXgDMPOC7ZQRQKMgG0e0zANiVLLUtruxS71ozbJYRJs8vzLONJHpiCKBzjZPZPJdXJ2OgsnqhMLyPu+ZuKF4OTBP5lwHWQ/NE3tv3afjPAITPH1mxfq4mNfAoX88APFzc95Hwbyd/3PCAxRplIT0l/zQdCme78Ag3KtoeqsAC/NQDXqiaU+c8CjR5YR+LKhI/b68AfKDqgXH/YqoNIHiAbDJk4oHDFmGoZXdb+4fTDE+IEOM4mvIB2WSsbkPh1xd7vmHUOncg2gAbYANsAM6ncVGvS3EFMmi3Gw28yB+oIRnBUtUHZTqVina8IuCXXhBK34pFbmD2XaiXtq59CBDsPAGw/hIBes92ANUaeAGgHFlyMX6EvBcAzvretTkP8AkPfBJAukX1boBqEfoK9mU93YaguMwB3NmGEIjkKgB1uQGMNCFnkAKRgw4C0T4LNsAG+BKAbICu7rnYdMWyZAmkUiGXTjGRDIH/qJ4PC42XAPpQHidrdNaLJYs0O9KEvYcrvQYgIA4A7ZCCeF4AKAVZgsl66Auhxx4QngIIB4DmkiUPPAOwvs8ACDJDcRFGi2Q3wHIRQo4vKN1xYJ8F4e8Hs7F9VYaGGLkAAAAASUVORK5CYII=", None).await?;
+    tester.display_assertion("iVBORw0KGgoAAAANSUhEUgAAAIAAAABACAAAAAD3vSCjAAACOUlEQVR4nO2Z0baEIAhF4f8/mnsnFTiIWVNNL7bW5GiKW0Q0Ynr5WgAVQMofMSDxbPIc7QhACAH4xxoQeg2gDb2UfHKlcKu7FW75kpa5YW2XlLd8a1PlaT/s5pjJpIAslR4BLMXnpKPR51sHUV4+BQEgExg7c1WcBgBUOAH/HoBspkKVgYZuAuiEW7H732tqBIYAYEnRBg4IJG+0Zk+xPsgjv+4dA1ils2pQebR2v2ZdL7E+pxrYu044AZiyY/LuBTBN3QmwtuNfAgiXH2kSt6VjlsBwSx/PAGxln9fRv9NjdzuhAdENqbKQ+gWuAmeLYlvsrWq9NQHqZKcasKSJKTLnq7zWF7axNEGi/moXwAbaNDBun0y1AoAGSCdDJhrY+iK322m3qpBwavOzZr6QsSsbkE7G0WUo/L2xjzwpVl6OaAEsgAWwADjuxkk+T13MQAbleqKJR333GM/zkuUHaRdL6Mr9EcG/7EAlxj0jhkgmEDpEDvV3AaCfKwDaXhCg12wHkNnAFwDpyCS+q0kCegeA2+vzN+mnNfAkgHRGdTdAZoRmwWbW02XoBCfl55ahc0Sy54DcukcHE8ujI3KRt9wRrb1gASyAlwBkAXR5trgtHrE0WGLxdx8bJ4zPOsdvwWr1hPsA7QZfK+pWX2MwyCuxbamiImrQhKVXeAdAjhg/l5CKs1FEgEZBGmDSFu2B0GUNCE8BhAGgquSQBq4BaNtrAOQiQ2iE2KN9/UmN0MX4QOjyA2svgOsP93mLVbWaUNIAAAAASUVORK5CYII=", None).await?;
+    tester.tsc(true).await?;
+
+    // The output is confirmed and checkpointed, fork from here
+    tester.wait_ticks(1).await?;
+    tester.snapshot().await?;
+
+    // A full reset clears the checkpoint, so the card boots to the idle screen
+    tester.reset().await?;
+    tester.display_assertion(super::PORTAL_READY, None).await?;
+
+    // Restoring the snapshot brings the signing session back
+    tester.restore().await?;
+    // Fee
+    tester.display_assertion("iVBORw0KGgoAAAANSUhEUgAAAIAAAABACAAAAAD3vSCjAAABlUlEQVR4nO2Y0dqDIAiG4f4vmv9ZinyiUrZ/62DsYGkSvgIixfTwLwESIAESIAGmAHIdTRgvN/RwqPcywH0tAYCwvIblJYRt/BeqbaZesjMPPLUFcKgrvdK2FZW2MLrAoFWu4NX7PLdIaAHTotPpWoh75wMATFTxj3lW8XAK4NVq2yxgky8AOIqHLQs0j1f3F8e7qBgAbsVAZsIEeAbAx6n2V1fbAgRXcYqFcFwFeAIgBFkU+qtry8Z2T8jpUEpxW9aEuJODAQonF8wHU7gAoJvnDYC2aj8+WnYAmMXALgBM3ixRXNCZGKuCzwCARUyhOMWfsQDVYw6ek/8B6IJlHYTUW2AHYBaEFsFW5pxswwFgANrZhpBgJE5AQSLisRSF0JDJeJ4FCZAACZAAzwNIAkzucCv5oJ6Gl1CGegSrIXtntZoR5KB7CqB/BEpqSz9W9LyDyKFgJRcDkMJ6AH1Vt1N9UMwCHF4OLPWuBYQXAGTP1G863wXwLliDXgtC0q9V3gVuyReCcAPg5/JAngUJkAAJ8PMAfzAVrEYGEamYAAAAAElFTkSuQmCC", Some(3)).await?;
+    tester.tsc(true).await?;
+
+    tester
+        .display_assertion(super::PORTAL_READY, Some(32))
+        .await?;
+
+    tester
+        .nfc_assertion(model::Reply::SignedPsbt(
+            vec![
+                112, 115, 98, 116, 255, 1, 0, 51, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255, 255, 255, 255,
+                0, 255, 255, 255, 255, 0, 0, 0, 0, 0, 0, 1, 8, 107, 2, 71, 48, 68, 2, 32, 30, 100,
+                57, 213, 243, 230, 91, 21, 255, 193, 91, 238, 114, 20, 94, 98, 79, 94, 251, 44,
+                151, 93, 76, 209, 1, 102, 49, 254, 33, 44, 40, 176, 2, 32, 71, 2, 0, 250, 190, 215,
+                228, 69, 5, 87, 221, 49, 166, 221, 182, 20, 78, 200, 211, 248, 105, 17, 169, 173,
+                214, 100, 163, 133, 86, 74, 144, 6, 1, 33, 3, 25, 203, 85, 92, 129, 231, 96, 208,
+                212, 175, 150, 144, 150, 200, 177, 216, 58, 32, 33, 245, 34, 15, 218, 119, 188, 92,
+                163, 24, 47, 59, 245, 195, 0,
+            ]
+            .into(),
+        ))
+        .await?;
+
+    Ok(())
+}
@@ -328,7 +328,53 @@ async fn test_reset_during_generate_mnemonic(mut tester: Tester) -> Result<(), c
     entropy = "0000000000000000000000000000000000000000000000000000000000000000"
 )]
 async fn test_send_raw_getinfo_msg(mut tester: Tester) -> Result<(), crate::Error> {
-    tester.nfc(NfcAction::Raw(vec![130, 0, 128])).await?;
+    tester
+        .nfc(NfcAction::Raw(vec![130, 1, 130, 0, 128]))
+        .await?;
+    tester
+        .nfc_assertion(model::Reply::Info(model::DeviceInfo {
+            initialized: model::InitializationStatus::Uninitialized,
+            firmware_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+        }))
+        .await?;
+
+    Ok(())
+}
+
+#[functional_test_wrapper::functional_test(
+    entropy = "0000000000000000000000000000000000000000000000000000000000000000"
+)]
+async fn test_get_protocol_version(mut tester: Tester) -> Result<(), crate::Error> {
+    tester.nfc(NfcAction::GetProtocolVersion).await?;
+    tester
+        .nfc_assertion(model::Reply::ProtocolVersion(model::PROTOCOL_VERSION))
+        .await?;
+
+    tester.nfc(NfcAction::GetStatus).await?;
+    tester
+        .nfc_assertion(model::Reply::Info(model::DeviceInfo {
+            initialized: model::InitializationStatus::Uninitialized,
+            firmware_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+        }))
+        .await?;
+
+    Ok(())
+}
+
+#[functional_test_wrapper::functional_test(
+    entropy = "0000000000000000000000000000000000000000000000000000000000000000"
+)]
+async fn test_unsupported_protocol_keeps_session(mut tester: Tester) -> Result<(), crate::Error> {
+    // GetInfo from protocol version 0
+    tester
+        .nfc(NfcAction::Raw(vec![130, 0, 130, 0, 128]))
+        .await?;
+    tester
+        .nfc_assertion(model::Reply::UnsupportedProtocol(model::PROTOCOL_VERSION))
+        .await?;
+
+    // Same session, no new handshake
+    tester.nfc(NfcAction::GetStatus).await?;
     tester
         .nfc_assertion(model::Reply::Info(model::DeviceInfo {
             initialized: model::InitializationStatus::Uninitialized,
@@ -338,3 +384,19 @@ async fn test_send_raw_getinfo_msg(mut tester: Tester) -> Result<(), crate::Erro
 
     Ok(())
 }
+
+#[functional_test_wrapper::functional_test(
+    entropy = "0000000000000000000000000000000000000000000000000000000000000000"
+)]
+async fn test_get_device_info(mut tester: Tester) -> Result<(), crate::Error> {
+    tester.nfc(NfcAction::GetDeviceInfo).await?;
+    tester
+        .nfc_assertion(model::Reply::DeviceInfo {
+            fw_version: env!("CARGO_PKG_VERSION").to_string(),
+            hw_rev: 1,
+            serial: model::emulator::EMULATOR_SERIAL,
+        })
+        .await?;
+
+    Ok(())
+}
@@ -64,6 +64,7 @@ async fn run_script(
     let mut log = vec![];
 
     let mut result = true;
+    let mut snapshot = None;
 
     let sdk = Arc::clone(&emulator.sdk);
     sdk.new_tag().await?;
@@ -86,6 +87,10 @@ async fn run_script(
                 emulator.card.send(EmulatorMessage::Tsc(*value))?;
                 None
             }
+            TestOp::Action(TestAction::Gesture(gesture)) => {
+                emulator.card.send(EmulatorMessage::TscGesture(*gesture))?;
+                None
+            }
             TestOp::Action(TestAction::Nfc(req)) => {
                 let cloned_sdk = Arc::clone(&sdk);
                 let req = req.to_owned();
@@ -102,6 +107,15 @@ async fn run_script(
                     NfcAction::DisplayAddress(addr) => tokio::spawn(async move {
                         let _ = cloned_sdk.display_address(addr).await;
                     }),
+                    NfcAction::DisplayKeychainAddress(keychain, index) => {
+                        tokio::spawn(async move {
+                            let keychain = match keychain {
+                                model::KeychainKind::External => portal::KeychainKind::External,
+                                model::KeychainKind::Internal => portal::KeychainKind::Internal,
+                            };
+                            let _ = cloned_sdk.display_keychain_address(keychain, index).await;
+                        })
+                    }
                     NfcAction::GenerateMnemonic(num_words, network, pair_code) => {
                         tokio::spawn(async move {
                             let num_words = match num_words {
@@ -113,19 +127,31 @@ async fn run_script(
                                 }
                             };
                             let _ = cloned_sdk
-                                .generate_mnemonic(num_words, network, pair_code)
+                                .generate_mnemonic(num_words, network, pair_code, None)
                                 .await;
                         })
                     }
                     NfcAction::RestoreMnemonic(words, network, pair_code) => {
                         tokio::spawn(async move {
-                            let _ = cloned_sdk.restore_mnemonic(words, network, pair_code).await;
+                            let _ = cloned_sdk
+                                .restore_mnemonic(words, network, pair_code, None)
+                                .await;
                         })
                     }
                     NfcAction::SignPsbt(psbt) => tokio::spawn(async move {
                         let signed_psbt = cloned_sdk.sign_psbt(psbt).await;
                         log::debug!("Full psbt: {:?}", signed_psbt);
                     }),
+                    NfcAction::SignBatch(psbts) => tokio::spawn(async move {
+                        let signed_psbts = cloned_sdk.sign_psbt_batch(psbts).await;
+                        log::debug!("Full psbts: {:?}", signed_psbts);
+                    }),
+                    NfcAction::ExtractTx(psbt) => tokio::spawn(async move {
+                        let _ = cloned_sdk.extract_tx(psbt).await;
+                    }),
+                    NfcAction::Cancel => tokio::spawn(async move {
+                        let _ = cloned_sdk.cancel().await;
+                    }),
                     NfcAction::RequestDescriptors => tokio::spawn(async move {
                         let _ = cloned_sdk.public_descriptors().await;
                     }),
@@ -134,6 +160,29 @@ async fn run_script(
                             .get_xpub(path.parse().expect("Valid derivation path"))
                             .await;
                     }),
+                    NfcAction::GetFingerprint => tokio::spawn(async move {
+                        let _ = cloned_sdk.get_fingerprint().await;
+                    }),
+                    NfcAction::ExportDescriptor(keychain) => tokio::spawn(async move {
+                        let keychain = match keychain {
+                            model::KeychainKind::External => portal::KeychainKind::External,
+                            model::KeychainKind::Internal => portal::KeychainKind::Internal,
+                        };
+                        let _ = cloned_sdk.export_descriptor(keychain).await;
+                    }),
+                    NfcAction::SetBrightness(level) => tokio::spawn(async move {
+                        let _ = cloned_sdk.set_brightness(level).await;
+                    }),
+                    NfcAction::SetAntiPhishingPhrase(phrase) => tokio::spawn(async move {
+                        let _ = cloned_sdk.set_anti_phishing_phrase(phrase).await;
+                    }),
+                    NfcAction::SetRotation(rotation) => tokio::spawn(async move {
+                        let rotation = match rotation {
+                            model::Rotation::Normal => portal::Rotation::Normal,
+                            model::Rotation::Flipped => portal::Rotation::Flipped,
+                        };
+                        let _ = cloned_sdk.set_rotation(rotation).await;
+                    }),
                     NfcAction::SetDescriptor(desc, bsms) => tokio::spawn(async move {
                         let bsms = bsms.map(|data| portal::SetDescriptorBsmsData {
                             first_address: data.first_address,
@@ -143,6 +192,12 @@ async fn run_script(
                         let _ = cloned_sdk.set_descriptor(desc, bsms).await;
                     }),
 
+                    NfcAction::GetProtocolVersion => tokio::spawn(async move {
+                        let _ = cloned_sdk.get_protocol_version().await;
+                    }),
+                    NfcAction::GetDeviceInfo => tokio::spawn(async move {
+                        let _ = cloned_sdk.get_device_info().await;
+                    }),
                     NfcAction::Raw(data) => tokio::spawn(async move {
                         let _ = cloned_sdk.debug_send_raw(data).await;
                     }),
@@ -157,9 +212,23 @@ async fn run_script(
                 if *wipe_registers {
                     emulator.rtc = [0; 32];
                 }
+                // Forget the settings applied before the reset, so that assertions only match
+                // the ones applied again while booting
+                while try_pull_msg(&mut emulator.msgs.display_settings)?.is_some() {}
+                emulator.brightness = None;
+                emulator.rotation = None;
                 emulator.card.send(EmulatorMessage::Reset)?;
                 None
             }
+            TestOp::Action(TestAction::Snapshot) => {
+                snapshot = Some(emulator.snapshot().await?);
+                None
+            }
+            TestOp::Action(TestAction::Restore) => {
+                let data = snapshot.as_ref().ok_or("No snapshot to restore")?;
+                emulator.restore(data).await?;
+                None
+            }
 
             TestOp::Assertion(TestAssertion::Display {
                 content,
@@ -197,6 +266,32 @@ async fn run_script(
                     }
                 }
             }
+            TestOp::Assertion(TestAssertion::DisplaySettings {
+                brightness,
+                rotation,
+            }) => {
+                let start = std::time::Instant::now();
+                let mut tick_counter = 0;
+
+                loop {
+                    manage_hw(emulator, |_, _, _| {}, &mut (), false, false).await?;
+
+                    if emulator.brightness == *brightness && emulator.rotation == *rotation {
+                        break None;
+                    }
+
+                    while let Some(_) = try_pull_msg::<()>(&mut emulator.msgs.tick)? {
+                        tick_counter += 1;
+                    }
+
+                    if tick_counter > 16 || start.elapsed().as_secs() > 5 {
+                        break Some(AssertionResult::WrongDisplaySettings(format!(
+                            "brightness = {:?}, rotation = {:?}",
+                            emulator.brightness, emulator.rotation
+                        )));
+                    }
+                }
+            }
             TestOp::Assertion(TestAssertion::NfcResponse(expected, send_ping)) => {
                 'outer: loop {
                     use ::model::Reply;
@@ -216,8 +311,7 @@ async fn run_script(
                                 if matches!(r, Reply::Pong | Reply::DelayedReply) =>
                             {
                                 if *send_ping {
-                                    let ping =
-                                        model::minicbor::to_vec(&model::Request::Ping).unwrap();
+                                    let ping = model::serialize_versioned(&model::Request::Ping);
                                     sdk.debug_send_raw(ping).await?;
                                 }
 
@@ -352,6 +446,25 @@ impl Tester {
         Ok(())
     }
 
+    pub async fn display_settings_assertion(
+        &mut self,
+        brightness: Option<u8>,
+        rotation: Option<u8>,
+    ) -> Result<(), crate::Error> {
+        self.op_sender
+            .send(
+                TestAssertion::DisplaySettings {
+                    brightness,
+                    rotation,
+                }
+                .into(),
+            )
+            .await?;
+        self.expect_reply().await?;
+
+        Ok(())
+    }
+
     pub async fn tsc(&mut self, value: bool) -> Result<(), crate::Error> {
         self.op_sender.send(TestAction::Input(value).into()).await?;
         self.expect_reply().await?;
@@ -359,6 +472,15 @@ impl Tester {
         Ok(())
     }
 
+    pub async fn gesture(&mut self, gesture: model::emulator::Gesture) -> Result<(), crate::Error> {
+        self.op_sender
+            .send(TestAction::Gesture(gesture).into())
+            .await?;
+        self.expect_reply().await?;
+
+        Ok(())
+    }
+
     pub async fn reset(&mut self) -> Result<(), crate::Error> {
         self.op_sender.send(TestAction::Reset(true).into()).await?;
         self.expect_reply().await?;
@@ -367,6 +489,21 @@ impl Tester {
         Ok(())
     }
 
+    pub async fn snapshot(&mut self) -> Result<(), crate::Error> {
+        self.op_sender.send(TestAction::Snapshot.into()).await?;
+        self.expect_reply().await?;
+
+        Ok(())
+    }
+
+    pub async fn restore(&mut self) -> Result<(), crate::Error> {
+        self.op_sender.send(TestAction::Restore.into()).await?;
+        self.expect_reply().await?;
+        self.wait_ticks(1).await?; // Force call to manage_hw
+
+        Ok(())
+    }
+
     pub async fn fast_boot_reset(&mut self) -> Result<(), crate::Error> {
         self.op_sender.send(TestAction::Reset(false).into()).await?;
         self.expect_reply().await?;
@@ -38,6 +38,9 @@ async fn test_get_xpub(mut tester: Tester) -> Result<(), crate::Error> {
     tester.display_assertion("iVBORw0KGgoAAAANSUhEUgAAAIAAAABACAAAAAD3vSCjAAAByklEQVR4nO2XiY4CIQxA6f9/dFdtoTfDbOK6idU4GUuPB7QcMD78aYAGaIAGaAANgHsihJ0YydIrJUZogsB1hDOAojlKnxIljQCk8Pg+21609I5KmyVak6z5VWu+fuxnakE9BexzUAAOQcbKLGk1+GZcUFBD//MpEEV5Wm7fGgHshFN2cb8uAeIIgOqMHYHHy+kIkFnIiVgFWQ4MnegrB8ih1jTPkAPZCJVVt68I/NXyEYL/NYBbAXop/ucAXFRSxLYM9LoX5JLyRp76qQDUkjoLztbhyAPRmg3oA02Z97MHoKW5BJDN4TrQEYCQr6BYALgeGvlGfw+AIlg+XA7YCbKONLdviSAjBZBVFVRvosPKH1r6o/gZwBpfUggOXSp6ea2fxfd6Kg+SOQ21lfWz1E/jvwEg81HHzwAgqcYBcSNC6wAh33NkM0/rvveCBmiABmiAzwNgAyQSWIdbuVzruyfgsgM5oNLfeZKe++q6kLPsEGA+hrLiN76Po+VFb8s3bK0CsilvAcbsowfgg/q6M6QAQwVTFncADkYA4RIAZZhAHbbeD7BsLcBquJWEahjtFChPwFmXJuE8zeEdgK9bB3ovaIAGaICvB/gBcI2wRnoFKhcAAAAASUVORK5CYII=", None).await?;
     tester.tsc(true).await?;
 
+    tester.display_assertion("iVBORw0KGgoAAAANSUhEUgAAAIAAAABACAAAAAD3vSCjAAACHElEQVR42u1Z63aCYAzL+790NoU2KaLuFtzZ+PZDQDy9p00HnOc8Hz38zPmjCujiIoLrxfvj5cHlc/PqTyuwGHeVvAhbb+1meSOmwNVKwvx81WX1g94IK8BSor8q8w9SACWf0gfHKEBJW3OuHq6eCOdAi7n7l62CLnJ2ISzV1wkZxwEJrmpY79AeWBMy44EyunxQhQf5od2TCQEksCM+IoNsCMzoLj3DIiIegqp1iWznU4Cc7IaVAqtgdBcqMB64EMkBgaKyTcVHZIGINBxiJwaoAgx3wy3kTJu7W+dC0E1/1iLVqJPNSGCvWLjfrQ5zONDmqyHKF1UV4W7YLb/MtgHFe1OoCmhtWe3ZwxTtBRjQt61Deo6GkJDmhR7K2uoOR3QmBE0SBcheFcGZ0AaQAmJ16nQvULXNKaRU8nLNhcBcDhGy0R2zI9lgYnZho/EBvcDRGEpLZHPAbG2C5tOIaRn0AAY1gAHCDlgGktCmsp6AKRUOYkbg6P4aFMmsB0b9Oy/izaYiyo7ZHB3O2LGzOUgtKERH9ibkAxYUcAygsSGGRzLtPzjo0C5JS25IbCmmsdDH0/SGRLsxYa/l5p/dlL5YgfOcJ3X44vOLFTDaJ0o+yLlwkDaViypQe5z7nn6igFHvflckFPrnhS1PyfELPBXzAMz2FdjQ4z0FsNnbgRkPEA888HIFvh0C3z3gJgmnRHqPvk3CLynwX3DgDU0y833H1L0oAAAAAElFTkSuQmCC", None).await?;
+    tester.tsc(true).await?;
+
     tester.display_assertion(super::PORTAL_READY, None).await?;
 
     tester
@@ -175,7 +178,7 @@ async fn test_set_descriptor_non_sorted_multisig(mut tester: Tester) -> Result<(
         script_type: ScriptType::NativeSegwit,
         bsms: None,
     };
-    let msg = model::minicbor::to_vec(&msg).unwrap();
+    let msg = model::serialize_versioned(&msg);
 
     tester.nfc(NfcAction::Raw(msg)).await?;
 
@@ -218,7 +221,7 @@ async fn test_set_descriptor_multisig_invalid_threshold(
         script_type: ScriptType::NativeSegwit,
         bsms: None,
     };
-    let msg = model::minicbor::to_vec(&msg).unwrap();
+    let msg = model::serialize_versioned(&msg);
 
     tester.nfc(NfcAction::Raw(msg)).await?;
 
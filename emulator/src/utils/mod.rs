@@ -16,7 +16,7 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use std::fs::File;
-use std::io::{Cursor, Read, Seek, Write};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 use std::process::Stdio;
 
 use std::path::{Path, PathBuf};
@@ -35,7 +35,7 @@ use embedded_graphics::geometry::Size;
 use embedded_graphics::pixelcolor::BinaryColor;
 use embedded_graphics_simulator::SimulatorDisplay;
 
-use ::model::emulator::{CardMessage, EmulatorMessage};
+use ::model::emulator::{CardFrame, CardMessage, EmulatorMessage, Snapshot};
 
 pub mod model;
 pub mod report;
@@ -106,49 +106,17 @@ pub fn get_qemu_instance(
 async fn decode_card_message<R: AsyncBufReadExt + Unpin>(
     reader: &mut R,
 ) -> Result<CardMessage, crate::Error> {
-    let ty = reader.read_u8().await?;
-    let has_len = match ty {
-        0x00 | 0x01 | 0x03 | 0x04 | 0x07 | 0x08 => true,
-        0x02 | 0x05 | 0x06 => false,
-        v => return Err(format!("Invalid CardMessage type {}", v).into()),
-    };
-
-    let data = if has_len {
-        let len = reader.read_u16().await?;
-        let mut buf = vec![0; len as usize];
-        reader.read_exact(&mut buf).await?;
-
-        buf
-    } else {
-        vec![]
-    };
-
-    match ty {
-        0x00 => Ok(CardMessage::Display(
-            data.chunks_exact(2)
-                .map(|arr| u16::from_be_bytes(arr.try_into().unwrap()))
-                .collect(),
-        )),
-        0x01 => Ok(CardMessage::Nfc(data)),
-        0x02 => Ok(CardMessage::Tick),
-        0x03 => {
-            let page = u16::from_be_bytes(data[..2].try_into().unwrap());
-            let data = data[2..].into_iter().copied().collect();
-            Ok(CardMessage::WriteFlash(page, data))
-        }
-        0x04 => {
-            let page = u16::from_be_bytes(data[..2].try_into().unwrap());
-            Ok(CardMessage::ReadFlash(page))
+    let mut frame = vec![];
+    loop {
+        match CardFrame::read(&frame) {
+            CardFrame::Complete(msg, _) => break Ok(msg),
+            CardFrame::Invalid => break Err(format!("Invalid CardMessage {:02X?}", frame).into()),
+            CardFrame::Incomplete { needed } => {
+                let start = frame.len();
+                frame.resize(start + needed, 0x00);
+                reader.read_exact(&mut frame[start..]).await?;
+            }
         }
-        0x05 => Ok(CardMessage::FinishBoot),
-        0x06 => Ok(CardMessage::FlushDisplay),
-        0x07 => Ok(CardMessage::ReadRtcRegister(data[0])),
-        0x08 => Ok(CardMessage::WriteRtcRegister(
-            data[0],
-            u32::from_be_bytes(data[1..5].try_into().unwrap()),
-        )),
-
-        _ => unreachable!(),
     }
 }
 
@@ -243,6 +211,8 @@ pub async fn get_emulator_instance(
             entropy,
 
             rtc: [0; 32],
+            brightness: None,
+            rotation: None,
 
             _qemu_handle: None,
         })
@@ -298,6 +268,10 @@ pub struct EmulatorInstance {
     pub sdk: Arc<PortalSdk>,
     pub entropy: [u8; 32],
     pub rtc: [u32; 32],
+    /// Last brightness level applied by the card, from 0 (dimmest) to 4 (brightest)
+    pub brightness: Option<u8>,
+    /// Last display rotation applied by the card, in quarter turns
+    pub rotation: Option<u8>,
 
     pub(super) _qemu_handle: Option<Child>,
 }
@@ -336,6 +310,8 @@ impl EmulatorInstance {
             sdk,
             entropy,
             rtc: [0; 32],
+            brightness: None,
+            rotation: None,
             _qemu_handle: Some(_qemu_handle),
         })
     }
@@ -400,6 +376,8 @@ impl EmulatorInstance {
                         EmulatorMessage::Reset => log::trace!("> Reset"),
                         EmulatorMessage::Entropy(data) => log::trace!("> Entropy({:02X?})", data),
                         EmulatorMessage::Rtc(_) => log::trace!("> Rtc"),
+                        EmulatorMessage::TscGesture(g) => log::trace!("> TscGesture({:?})", g),
+                        EmulatorMessage::Snapshot => log::trace!("> Snapshot"),
                     }
 
                     let encoded = msg.encode();
@@ -415,4 +393,56 @@ impl EmulatorInstance {
             }
         });
     }
+
+    /// Take a snapshot of the state of the card that survives a reset
+    ///
+    /// The snapshot can later be passed to [`EmulatorInstance::restore`] to fork execution from
+    /// the last checkpoint committed by the firmware. The card's RAM, including the Noise session,
+    /// isn't part of it.
+    pub async fn snapshot(&mut self) -> Result<Vec<u8>, crate::Error> {
+        self.card.send(EmulatorMessage::Snapshot)?;
+
+        let data =
+            tokio::time::timeout(std::time::Duration::from_secs(5), self.msgs.snapshot.recv())
+                .await?
+                .ok_or("Closed snapshot receiver")?;
+        if data.as_slice() != [::model::emulator::SNAPSHOT_VERSION] {
+            return Err(format!("Unsupported snapshot version {:02X?}", data).into());
+        }
+
+        // Apply the flash and RTC writes the card sent before replying
+        crate::link::manage_hw(self, |_, _, _| {}, &mut (), false, false).await?;
+
+        let mut flash = vec![];
+        self.flash.seek(SeekFrom::Start(0))?;
+        self.flash.read_to_end(&mut flash)?;
+        flash.resize(flash.len().div_ceil(2048) * 2048, 0x00);
+
+        Ok(Snapshot {
+            rtc: self.rtc,
+            entropy: self.entropy,
+            flash,
+        }
+        .encode())
+    }
+
+    /// Validate and restore a snapshot, then reset the card so that it boots from it
+    pub async fn restore(&mut self, data: &[u8]) -> Result<(), crate::Error> {
+        let mut snapshot = Snapshot::decode(data).ok_or("Invalid snapshot")?;
+
+        // Pages written after the snapshot was taken have to go back to being empty
+        let current_len = self.flash.seek(SeekFrom::End(0))? as usize;
+        if current_len > snapshot.flash.len() {
+            snapshot.flash.resize(current_len, 0x00);
+        }
+
+        self.flash.seek(SeekFrom::Start(0))?;
+        self.flash.write_all(&snapshot.flash)?;
+        self.rtc = snapshot.rtc;
+        self.entropy = snapshot.entropy;
+
+        self.card.send(EmulatorMessage::Reset)?;
+
+        Ok(())
+    }
 }
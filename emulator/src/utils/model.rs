@@ -71,6 +71,9 @@ impl From<TestAssertion> for TestOp {
 pub enum NfcAction {
     GetStatus,
     SignPsbt(String),
+    SignBatch(Vec<String>),
+    ExtractTx(String),
+    Cancel,
     GenerateMnemonic(
         model::NumWordsMnemonic,
         model::bitcoin::Network,
@@ -79,10 +82,18 @@ pub enum NfcAction {
     RestoreMnemonic(String, model::bitcoin::Network, Option<String>),
     RequestDescriptors,
     DisplayAddress(u32),
+    DisplayKeychainAddress(model::KeychainKind, u32),
     Unlock(String),
     Resume,
     GetXpub(String),
+    GetFingerprint,
+    ExportDescriptor(model::KeychainKind),
+    SetBrightness(u8),
+    SetRotation(model::Rotation),
+    SetAntiPhishingPhrase(String),
     SetDescriptor(String, Option<model::BsmsRound2>),
+    GetProtocolVersion,
+    GetDeviceInfo,
 
     Raw(Vec<u8>),
 }
@@ -91,9 +102,14 @@ pub enum NfcAction {
 pub enum TestAction {
     Nfc(NfcAction),
     Input(bool),
+    Gesture(model::emulator::Gesture),
     WaitTicks(usize),
     WipeFlash,
     Reset(bool),
+    /// Take a snapshot of the card, replacing the previous one
+    Snapshot,
+    /// Restore the last snapshot and reset the card
+    Restore,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -103,12 +119,18 @@ pub enum TestAssertion {
         content: String,
         timeout_ticks: Option<usize>,
     },
+    /// Display settings last applied by the card, `None` if the card hasn't applied any
+    DisplaySettings {
+        brightness: Option<u8>,
+        rotation: Option<u8>,
+    },
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum AssertionResult {
     WrongDisplay(String),
     WrongReply(String),
+    WrongDisplaySettings(String),
     NoReply,
 }
 impl fmt::Display for AssertionResult {
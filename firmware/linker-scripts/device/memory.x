@@ -1,7 +1,8 @@
 /* Linker script for the STM32L476 */
 MEMORY
 {
-    FLASH (rx) : ORIGIN = 0x08000000, LENGTH = 508K
+    /* Page 250 is reserved for the settings, 251 to 254 for the checkpoint, 255 for the config */
+    FLASH (rx) : ORIGIN = 0x08000000, LENGTH = 500K
     /* FLASH (rx) : ORIGIN = 0x08000000, LENGTH = 768K */
     DATA (r) : ORIGIN = 0x0807F800, LENGTH = 2K
     /* Use the largest section of memory for the HEAP */
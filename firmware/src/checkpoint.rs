@@ -26,13 +26,19 @@ use model::{ByteArray, EncryptionKey, SerializedDerivationPath};
 use crate::hw_common::PAGE_SIZE;
 use crate::{
     config::read_config,
-    hw::{read_flash, write_flash, FlashError},
+    hw::FlashError,
+    storage::{
+        compact_flash, erase_flash_ring, read_flash_wear_leveled, write_flash_wear_leveled,
+        FlashRing,
+    },
     CurrentState,
 };
 
-const CHECKPOINT_PAGE: usize = 254;
+/// The aux data is rewritten on every checkpoint, so it's spread over a few pages right
+/// below the config page. These pages are excluded from the firmware area in `memory.x`.
+pub const CHECKPOINT_RING: FlashRing = FlashRing::new(251, 4);
 
-pub const MAGIC: u32 = 0xFA57B007;
+pub use model::checkpoint::{validate, CheckpointStatus, MAGIC, WIPE_MAGIC};
 
 pub const MAGIC_REGISTER: usize = 0;
 const FIRST_KEY_REGISTER: usize = 1;
@@ -60,6 +66,14 @@ pub enum CheckpointVariant {
 
     #[cbor(n(7))]
     Removed,
+
+    #[cbor(n(8))]
+    ExportDescriptor(#[cbor(n(0))] model::KeychainKind),
+    #[cbor(n(9))]
+    DisplayInternalAddress(#[cbor(n(0))] u32),
+    /// Some inputs of a PSBT are signed, the aux data is a [`model::sign_session::SavedSignSession`]
+    #[cbor(n(10))]
+    SignPsbtProgress,
 }
 
 impl CheckpointVariant {
@@ -73,6 +87,9 @@ impl CheckpointVariant {
             CheckpointVariant::GetXpub => true,
             CheckpointVariant::PublicDescriptor => false,
             CheckpointVariant::Removed => false,
+            CheckpointVariant::ExportDescriptor(_) => false,
+            CheckpointVariant::DisplayInternalAddress(_) => false,
+            CheckpointVariant::SignPsbtProgress => true,
         }
     }
 }
@@ -185,9 +202,9 @@ impl Checkpoint {
         Self::new_with_key(variant, aux, resumable, Self::gen_key(rng))
     }
 
-    pub fn commit(
+    pub fn commit<T>(
         &self,
-        peripherals: &mut crate::handlers::HandlerPeripherals,
+        peripherals: &mut crate::handlers::HandlerPeripherals<T>,
     ) -> Result<(), FlashError> {
         if self.variant.has_aux() && self.aux.is_none() {
             return Err(FlashError::CorruptedData);
@@ -197,7 +214,7 @@ impl Checkpoint {
         if let Some(aux) = &self.aux {
             let mut key = EncryptionKey::new_stretch_key(self.encryption_key.deref().as_slice(), 0);
             let (aux, _) = key.encrypt(&aux).expect("Encryption workds");
-            write_flash(&mut peripherals.flash, CHECKPOINT_PAGE, &aux)?;
+            write_flash_wear_leveled(&mut peripherals.flash, &CHECKPOINT_RING, &aux)?;
         }
 
         Ok(())
@@ -209,7 +226,9 @@ impl Checkpoint {
         }
     }
 
-    pub fn load(peripherals: &mut crate::handlers::HandlerPeripherals) -> Result<Self, FlashError> {
+    pub fn load<T>(
+        peripherals: &mut crate::handlers::HandlerPeripherals<T>,
+    ) -> Result<Self, FlashError> {
         let registers = (FIRST_DATA_REGISTER..31)
             .filter_map(|v| {
                 let value = peripherals.rtc.read_backup_register(v);
@@ -226,7 +245,7 @@ impl Checkpoint {
         let mut checkpoint: Self = minicbor::decode(&bytes)?;
         if checkpoint.variant.has_aux() {
             let mut buf = [0u8; PAGE_SIZE];
-            let aux = read_flash(&mut peripherals.flash, CHECKPOINT_PAGE, &mut buf)?;
+            let aux = read_flash_wear_leveled(&mut peripherals.flash, &CHECKPOINT_RING, &mut buf)?;
 
             let key =
                 EncryptionKey::new_stretch_key(checkpoint.encryption_key.deref().as_slice(), 1);
@@ -263,14 +282,35 @@ impl Checkpoint {
         removed.commit_registers(rtc);
     }
 
-    pub fn into_current_state(
+    /// Like [`Checkpoint::remove`], but also zeroes all the data registers and erases the aux
+    /// data from flash
+    ///
+    /// Used once an operation that persisted sensitive progress (like a signing session) is over.
+    pub fn wipe<T>(
+        self,
+        peripherals: &mut crate::handlers::HandlerPeripherals<T>,
+    ) -> Result<(), FlashError> {
+        for reg in FIRST_DATA_REGISTER..31 {
+            peripherals.rtc.write_backup_register(reg, 0);
+        }
+        let has_aux = self.variant.has_aux();
+        self.remove(&peripherals.rtc);
+
+        if has_aux {
+            erase_flash_ring(&mut peripherals.flash, &CHECKPOINT_RING)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn into_current_state<T>(
         self,
-        peripherals: &mut crate::handlers::HandlerPeripherals,
+        peripherals: &mut crate::handlers::HandlerPeripherals<T>,
     ) -> Result<CurrentState, FlashError> {
         use crate::handlers::init::TryIntoCurrentState;
 
-        fn get_config(
-            peripherals: &mut crate::HandlerPeripherals,
+        fn get_config<T>(
+            peripherals: &mut crate::HandlerPeripherals<T>,
         ) -> Result<Option<CurrentState>, FlashError> {
             let config = read_config(&mut peripherals.flash)?;
             Ok(config.try_into_current_state(&peripherals.rtc).ok())
@@ -303,11 +343,18 @@ impl Checkpoint {
                         encryption_key: (*self.encryption_key).into(),
                         fees: aux.fees,
                         outputs: aux.outputs,
+                        warnings: aux.warnings,
                     })
                 } else {
                     Err(FlashError::CorruptedData)
                 }
             }
+            // Signing restarts when the PSBT is sent again, the progress is picked up from the
+            // checkpoint at that point
+            (CheckpointVariant::SignPsbtProgress, Some(_), _) => match get_config(peripherals)? {
+                Some(state @ CurrentState::Idle { .. }) => Ok(state),
+                _ => Err(FlashError::CorruptedData),
+            },
             (CheckpointVariant::SetDescriptor, Some(aux), Some(resumable)) => {
                 if let Some(CurrentState::Idle { wallet }) = get_config(peripherals)? {
                     let aux: SetDescriptorState = minicbor::decode(&aux)?;
@@ -328,6 +375,20 @@ impl Checkpoint {
                 if let Some(CurrentState::Idle { wallet }) = get_config(peripherals)? {
                     Ok(CurrentState::DisplayAddress {
                         wallet,
+                        keychain: model::KeychainKind::External,
+                        index,
+                        resumable,
+                        is_fast_boot: true,
+                    })
+                } else {
+                    Err(FlashError::CorruptedData)
+                }
+            }
+            (CheckpointVariant::DisplayInternalAddress(index), _, Some(resumable)) => {
+                if let Some(CurrentState::Idle { wallet }) = get_config(peripherals)? {
+                    Ok(CurrentState::DisplayAddress {
+                        wallet,
+                        keychain: model::KeychainKind::Internal,
                         index,
                         resumable,
                         is_fast_boot: true,
@@ -354,6 +415,19 @@ impl Checkpoint {
                 if let Some(CurrentState::Idle { wallet }) = get_config(peripherals)? {
                     Ok(CurrentState::PublicDescriptor {
                         wallet,
+                        keychain: None,
+                        resumable,
+                        is_fast_boot: true,
+                    })
+                } else {
+                    Err(FlashError::CorruptedData)
+                }
+            }
+            (CheckpointVariant::ExportDescriptor(keychain), _, Some(resumable)) => {
+                if let Some(CurrentState::Idle { wallet }) = get_config(peripherals)? {
+                    Ok(CurrentState::PublicDescriptor {
+                        wallet,
+                        keychain: Some(keychain),
                         resumable,
                         is_fast_boot: true,
                     })
@@ -376,6 +450,42 @@ pub fn write_fastboot_key(key: &[u8; 32], rtc: &crate::hw::Rtc) {
     }
 }
 
+/// Zero the fast boot key and all the checkpoint data registers
+pub fn wipe_registers(rtc: &crate::hw::Rtc) {
+    for reg in FIRST_KEY_REGISTER..31 {
+        rtc.write_backup_register(reg, 0);
+    }
+}
+
+/// Drop the checkpoint of the current operation, whatever it is, zeroing its data registers and
+/// erasing the aux data
+///
+/// Used when an operation is canceled, so that it's not resumed at the next boot. The fast boot
+/// key is left untouched.
+pub fn discard<T>(
+    peripherals: &mut crate::handlers::HandlerPeripherals<T>,
+) -> Result<(), FlashError> {
+    for reg in FIRST_DATA_REGISTER..31 {
+        peripherals.rtc.write_backup_register(reg, 0);
+    }
+    Checkpoint::new_with_key(CheckpointVariant::Removed, None, None, [0; 24])
+        .commit_registers(&peripherals.rtc);
+
+    erase_aux(&mut peripherals.flash)
+}
+
+/// Erase the checkpoint aux data from flash
+pub fn erase_aux(flash: &mut crate::hw::Flash) -> Result<(), FlashError> {
+    erase_flash_ring(flash, &CHECKPOINT_RING)
+}
+
+/// Erase the old versions of the aux data, only the newest one is kept
+///
+/// Called once per boot, returns the number of pages freed.
+pub fn compact_aux(flash: &mut crate::hw::Flash) -> Result<usize, FlashError> {
+    compact_flash(flash, CHECKPOINT_RING.pages())
+}
+
 pub fn get_fastboot_key(rtc: &crate::hw::Rtc) -> [u8; 32] {
     (FIRST_KEY_REGISTER..)
         .take(8)
@@ -441,6 +551,44 @@ pub struct SignPsbtState {
     pub fees: u64,
     #[cbor(n(2))]
     pub sig_bytes: model::ByteVec,
+    #[cbor(n(3))]
+    pub warnings: SignWarnings,
+}
+
+/// What the user is warned about before confirming the outputs of a PSBT
+#[derive(Debug, Clone, Default, minicbor::Encode, minicbor::Decode)]
+pub struct SignWarnings {
+    /// Inputs without a key from our wallet, out of `num_inputs`
+    #[cbor(n(0))]
+    pub foreign_inputs: alloc::vec::Vec<usize>,
+    #[cbor(n(1))]
+    pub num_inputs: usize,
+    /// Description of each timelock of the transaction
+    #[cbor(n(2))]
+    pub timelocks: alloc::vec::Vec<alloc::string::String>,
+}
+
+impl SignWarnings {
+    /// The message and details of each warning, one per page
+    pub fn pages(&self) -> alloc::vec::Vec<(&'static str, alloc::string::String)> {
+        let mut pages = alloc::vec![];
+        if !self.foreign_inputs.is_empty() {
+            pages.push((
+                "Foreign inputs",
+                alloc::format!("{} of {}", self.foreign_inputs.len(), self.num_inputs),
+            ));
+        }
+        for timelock in &self.timelocks {
+            // Like "after block 800000", which doesn't fit on a single line
+            let details = match timelock.rsplit_once(' ') {
+                Some((condition, value)) => alloc::format!("{}\n{}", condition, value),
+                None => timelock.clone(),
+            };
+            pages.push(("Timelocked", details));
+        }
+
+        pages
+    }
 }
 
 mod cbor_bitcoin_address {
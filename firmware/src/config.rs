@@ -15,12 +15,108 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use minicbor::{Decode, Encode};
+
 use model::Config;
 
-use crate::hw::{Flash, FlashError};
+use crate::checkpoint;
+use crate::hw::{Flash, FlashError, Rtc};
 use crate::hw_common::PAGE_SIZE;
 
 pub const CONFIG_PAGE: usize = 255;
+/// Device settings are kept separate from the config so that they also apply to
+/// uninitialized devices and survive a wipe of the wallet
+pub const SETTINGS_PAGE: usize = 250;
+
+/// Speed of the display bus. A full frame is 1 KiB, so it runs in fast mode to flush it quicker
+pub const DISPLAY_I2C_HZ: u32 = model::peripheral_config::I2C_FAST_MODE_HZ;
+/// Speed of the NFC bus
+pub const NFC_I2C_HZ: u32 = model::peripheral_config::I2C_STANDARD_MODE_HZ;
+
+/// Settings that are not tied to the wallet
+///
+/// Every field is optional so that settings added later can be decoded from older pages.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct DeviceSettings {
+    #[cbor(n(0))]
+    pub brightness: Option<u8>,
+    #[cbor(n(1))]
+    pub rotation: Option<model::Rotation>,
+    #[cbor(n(2))]
+    pub tsc_calibration: Option<model::tsc::Calibration>,
+    #[cbor(n(3))]
+    pub anti_phishing_phrase: Option<EncryptedPhrase>,
+}
+
+/// Anti-phishing phrase, encrypted with a key derived from the wallet
+///
+/// The key also depends on a random salt chosen at every write, so the same key and nonce are
+/// never reused even if the same wallet is imported again.
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct EncryptedPhrase {
+    #[cbor(n(0))]
+    salt: [u8; 8],
+    #[cbor(n(1))]
+    nonce: u32,
+    #[cbor(n(2))]
+    data: model::ByteVec,
+}
+
+const ANTI_PHISHING_KEY_TAG: &[u8] = b"portal-anti-phishing";
+
+fn anti_phishing_key(
+    xprv: &model::bitcoin::util::bip32::ExtendedPrivKey,
+    salt: &[u8; 8],
+    nonce: u32,
+) -> model::EncryptionKey {
+    use model::bitcoin::hashes::{sha256, Hash, HashEngine};
+
+    let mut engine = sha256::HashEngine::default();
+    engine.input(ANTI_PHISHING_KEY_TAG);
+    engine.input(&xprv.private_key.secret_bytes());
+    engine.input(salt);
+    let key = sha256::Hash::from_engine(engine).into_inner();
+    model::EncryptionKey::new_raw_key(key, nonce)
+}
+
+/// Decrypt the anti-phishing phrase, if one was set with this wallet
+pub fn read_anti_phishing_phrase(
+    flash: &mut Flash,
+    xprv: &model::bitcoin::util::bip32::ExtendedPrivKey,
+) -> Option<alloc::string::String> {
+    let phrase = read_settings(flash).anti_phishing_phrase?;
+    let data = anti_phishing_key(xprv, &phrase.salt, phrase.nonce)
+        .decrypt_raw(&phrase.data)
+        .ok()?;
+    alloc::string::String::from_utf8(data).ok()
+}
+
+/// Store `phrase` encrypted, or remove the stored one if `phrase` is empty
+pub fn write_anti_phishing_phrase(
+    flash: &mut Flash,
+    xprv: &model::bitcoin::util::bip32::ExtendedPrivKey,
+    phrase: &str,
+    rng: &mut impl rand::RngCore,
+) -> Result<(), FlashError> {
+    let mut settings = read_settings(flash);
+    settings.anti_phishing_phrase = match phrase.is_empty() {
+        true => None,
+        false => {
+            let mut salt = [0; 8];
+            rng.fill_bytes(&mut salt);
+            let (data, nonce) = anti_phishing_key(xprv, &salt, 0)
+                .encrypt(phrase.as_bytes())
+                .expect("Always ok");
+
+            Some(EncryptedPhrase {
+                salt,
+                nonce,
+                data: data.into(),
+            })
+        }
+    };
+    write_settings(flash, &settings)
+}
 
 pub fn read_config(flash: &mut Flash) -> Result<Config, FlashError> {
     let mut buf = [0u8; PAGE_SIZE];
@@ -31,5 +127,105 @@ pub fn read_config(flash: &mut Flash) -> Result<Config, FlashError> {
 
 pub fn write_config(flash: &mut Flash, config: &Config) -> Result<(), FlashError> {
     let serialized = minicbor::to_vec(config).expect("always succeed");
-    crate::hw::write_flash(flash, CONFIG_PAGE, &serialized)
+    crate::storage::write_flash_transaction(flash, &[(CONFIG_PAGE, serialized.as_slice())])
+}
+
+/// Read the device settings, falling back to the defaults if they were never written
+pub fn read_settings(flash: &mut Flash) -> DeviceSettings {
+    let mut buf = [0u8; PAGE_SIZE];
+    crate::hw::read_flash(flash, SETTINGS_PAGE, &mut buf)
+        .ok()
+        .and_then(|buf| minicbor::decode(buf).ok())
+        .unwrap_or_default()
+}
+
+pub fn write_settings(flash: &mut Flash, settings: &DeviceSettings) -> Result<(), FlashError> {
+    let serialized = minicbor::to_vec(settings).expect("always succeed");
+    crate::storage::update_flash_if_changed(flash, SETTINGS_PAGE, &serialized)?;
+    Ok(())
+}
+
+/// Wipe all the key material, returning the device to the uninitialized state
+///
+/// The magic register is set to [`checkpoint::WIPE_MAGIC`] first, so that an interrupted wipe
+/// is completed at the next boot before anything reads the config: a partial wipe never leaves
+/// the device usable with the old keys. The order of the following steps matters too:
+///
+/// 1. the fast boot key and checkpoint registers are zeroed
+/// 2. any staged transaction is discarded, since it may hold a copy of the config that would
+///    otherwise be restored at boot
+/// 3. the config page is overwritten with random bytes and erased, in both banks since a
+///    firmware update copies it to the spare bank
+/// 4. the checkpoint aux data is erased
+/// 5. the anti-phishing phrase is removed from the settings, it can't be decrypted anymore
+///
+/// Finally the firmware version counter and the magic register are cleared, so that the next
+/// boot is a full init. The other device settings are kept. Steps 2 to 4 are done by
+/// [`model::storage::wipe_key_material`].
+pub fn factory_reset(
+    flash: &mut Flash,
+    rtc: &Rtc,
+    rng: &mut impl rand::RngCore,
+) -> Result<(), FlashError> {
+    log::info!("Factory reset");
+
+    rtc.write_backup_register(checkpoint::MAGIC_REGISTER, checkpoint::WIPE_MAGIC);
+    checkpoint::wipe_registers(rtc);
+
+    model::storage::wipe_key_material(flash, CONFIG_PAGE, &checkpoint::CHECKPOINT_RING, rng)?;
+
+    let mut settings = read_settings(flash);
+    if settings.anti_phishing_phrase.take().is_some() {
+        write_settings(flash, &settings)?;
+    }
+
+    rtc.write_backup_register(crate::version::FW_VERSION_REGISTER, 0);
+    rtc.write_backup_register(checkpoint::MAGIC_REGISTER, 0);
+
+    Ok(())
+}
+
+/// Load the stored TSC calibration, or calibrate the sensor and store the result
+///
+/// Called at boot before the TSC interrupt is enabled. A failed calibration, e.g. because the
+/// sensor was touched, is retried at the next boot and the default threshold is used meanwhile.
+#[cfg(feature = "device")]
+pub fn calibrate_tsc(tsc: &mut crate::hw::Tsc, flash: &mut Flash) {
+    let mut settings = read_settings(flash);
+    let calibration = match settings.tsc_calibration {
+        Some(calibration) => calibration,
+        None => match tsc.calibrate() {
+            Ok(calibration) => {
+                log::info!("TSC calibrated: {:?}", calibration);
+
+                settings.tsc_calibration = Some(calibration);
+                if let Err(e) = write_settings(flash, &settings) {
+                    log::warn!("Unable to store the TSC calibration: {:?}", e);
+                }
+                calibration
+            }
+            Err(e) => {
+                log::warn!("TSC calibration failed: {:?}", e);
+                return;
+            }
+        },
+    };
+
+    tsc.set_calibration(calibration);
+}
+
+/// Apply the stored settings to the peripherals, called at every boot
+pub fn apply_settings<T>(
+    peripherals: &mut crate::handlers::HandlerPeripherals<T>,
+) -> Result<(), crate::Error> {
+    let settings = read_settings(&mut peripherals.flash);
+    if let Some(level) = settings.brightness {
+        let brightness = crate::hw_common::set_display_brightness(&mut peripherals.display, level)?;
+        peripherals.power.set_brightness(brightness);
+    }
+    if let Some(rotation) = settings.rotation {
+        crate::hw_common::set_display_rotation(&mut peripherals.display, rotation)?;
+    }
+
+    Ok(())
 }
@@ -30,6 +30,7 @@ use embedded_graphics_core::geometry::OriginDimensions;
 use embedded_graphics_core::prelude::*;
 
 use model::emulator as emu_model;
+use model::peripheral_config::PeripheralConfig;
 use model::{reg::NS_REG, Message, MessageFragment, Reply, Request};
 
 use super::*;
@@ -45,23 +46,12 @@ pub struct EmulatorChannels {
     pub emulated_nt3h: EmulatedNT3H,
 }
 
+/// The emulated peripherals are not on real buses, so `_config` is ignored
 pub fn init_peripherals(
     dp: hal::pac::Peripherals,
     cp: cortex_m::Peripherals,
-) -> Result<
-    (
-        NfcIc,
-        NfcInterrupt,
-        hw_common::ChannelReceiver<()>,
-        Display,
-        Tsc,
-        rand_chacha::ChaCha20Rng,
-        Flash,
-        Rtc,
-        bool,
-    ),
-    crate::Error,
-> {
+    _config: PeripheralConfig,
+) -> Result<hw_common::Peripherals, crate::Error> {
     let clocks = unsafe { create_fake_clocks_pclk2_8mhz() };
 
     let systick_token = rtic_monotonics::create_systick_token!();
@@ -89,17 +79,34 @@ pub fn init_peripherals(
     let (nfc, nfc_interrupt, nfc_finished) = NfcIc::new();
     let rng = rand_chacha::ChaCha20Rng::from_seed([0u8; 32]);
 
-    Ok((
+    Ok(hw_common::Peripherals {
         nfc,
         nfc_interrupt,
         nfc_finished,
-        Display::new(),
-        Tsc::new(),
+        display: Display::new(),
+        display_status: model::peripheral_config::DisplayStatus::Ok,
+        tsc: Tsc::new(),
         rng,
-        Flash::new(),
-        Rtc::new(),
-        false, // TODO!!
-    ))
+        flash: Flash::new(),
+        rtc: Rtc::new(),
+        fast_boot: false, // TODO!!
+    })
+}
+
+/// Same as [`init_peripherals`] but seeds the RNG with `seed`, which is then kept instead of the
+/// entropy sent by the host on boot
+#[cfg(feature = "deterministic-rng")]
+pub fn init_peripherals_with_seed(
+    dp: hal::pac::Peripherals,
+    cp: cortex_m::Peripherals,
+    config: PeripheralConfig,
+    seed: [u8; 32],
+) -> Result<hw_common::Peripherals, crate::Error> {
+    let mut peripherals = init_peripherals(dp, cp, config)?;
+    log::warn!("Using a deterministic RNG seed");
+    peripherals.rng = rand_chacha::ChaCha20Rng::from_seed(seed);
+
+    Ok(peripherals)
 }
 
 pub struct Tsc {
@@ -204,6 +211,11 @@ impl NfcIc {
         Ok(())
     }
 
+    /// The emulated link has no RF field, the reader is always considered close
+    pub fn field_present(&mut self) -> Result<bool, Error> {
+        Ok(true)
+    }
+
     pub async fn read_handshake(&mut self) -> Result<alloc::vec::Vec<u8>, Error> {
         let msg = self.read_raw_message().await?;
         Ok(msg.data().to_vec())
@@ -219,12 +231,16 @@ impl NfcIc {
     pub async fn accept_request(
         &mut self,
         decrypt: &mut ::model::encryption::CipherState,
+        aad: &model::SessionAad,
     ) -> Result<Request, Error> {
         let msg = self.read_raw_message().await?;
         let mut decrypt_buf = alloc::vec::Vec::new();
 
-        match msg.deserialize(&mut decrypt_buf, decrypt) {
+        match msg.deserialize(&mut decrypt_buf, aad.request(), decrypt) {
             Ok(v) => Ok(v),
+            // The message was decrypted fine, so the session is still valid. The caller replies
+            // with the version we support.
+            Err(e @ model::MessageError::UnsupportedProtocol(_)) => Err(e.into()),
             Err(e) => {
                 self.write_to_mailbox([MessageFragment::new_failed_decryption()].into_iter())
                     .await?;
@@ -237,13 +253,18 @@ impl NfcIc {
         &mut self,
         reply: &Reply,
         encrypt: &mut ::model::encryption::CipherState,
+        aad: &model::SessionAad,
     ) -> Result<(), Error> {
-        let message = Message::new_serialize(reply, encrypt)?;
+        let message = Message::new_serialize(reply, aad.reply(), encrypt)?;
         self.write_to_mailbox(message.get_fragments().into_iter())
             .await?;
 
         match reply {
-            Reply::Pong | Reply::DelayedReply => {}
+            Reply::Pong
+            | Reply::DelayedReply
+            | Reply::ProtocolVersion(_)
+            | Reply::UnsupportedProtocol(_)
+            | Reply::DeviceInfo { .. } => {}
             _ => {
                 let _ = self.finished.send(()).await;
             }
@@ -328,17 +349,24 @@ impl EmulatedNT3H {
         };
 
         let msg = emu_model::CardMessage::Nfc(reply);
-        super::write_serial(msg.write_to());
+        super::write_message(&msg);
     }
 }
 
 pub fn report_tick() {
     let msg = emu_model::CardMessage::Tick;
-    super::write_serial(msg.write_to());
+    super::write_message(&msg);
 }
 pub fn report_finish_boot() {
     let msg = emu_model::CardMessage::FinishBoot;
-    super::write_serial(msg.write_to());
+    super::write_message(&msg);
+}
+/// Everything that needs to be snapshotted already lives on the host, so this only tells it which
+/// format we expect. Since serial messages are processed in order, by the time the host receives
+/// this all the previous flash and RTC writes have been received as well.
+pub fn report_snapshot() {
+    let msg = emu_model::CardMessage::SnapshotData(alloc::vec![emu_model::SNAPSHOT_VERSION]);
+    super::write_message(&msg);
 }
 
 pub struct Display;
@@ -350,7 +378,38 @@ impl Display {
 
     pub fn flush(&mut self) -> Result<(), crate::Error> {
         let msg = emu_model::CardMessage::FlushDisplay;
-        super::write_serial(msg.write_to());
+        super::write_message(&msg);
+        Ok(())
+    }
+
+    /// Report the brightness level to the emulator, which records it without changing the rendering
+    pub fn set_brightness(
+        &mut self,
+        brightness: ssd1306::prelude::Brightness,
+    ) -> Result<(), crate::Error> {
+        let level = (0..hw_common::BRIGHTNESS_LEVELS)
+            .find(|l| hw_common::brightness_from_level(*l) == Some(brightness))
+            .unwrap_or(hw_common::BRIGHTNESS_LEVELS - 1);
+        let msg = emu_model::CardMessage::DisplayBrightness(level);
+        super::write_message(&msg);
+        Ok(())
+    }
+
+    /// Report the rotation to the emulator, which records it without changing the rendering
+    pub fn set_rotation(
+        &mut self,
+        rotation: ssd1306::prelude::DisplayRotation,
+    ) -> Result<(), crate::Error> {
+        use ssd1306::prelude::DisplayRotation;
+
+        let quarter_turns = match rotation {
+            DisplayRotation::Rotate0 => 0,
+            DisplayRotation::Rotate90 => 1,
+            DisplayRotation::Rotate180 => 2,
+            DisplayRotation::Rotate270 => 3,
+        };
+        let msg = emu_model::CardMessage::DisplayRotation(quarter_turns);
+        super::write_message(&msg);
         Ok(())
     }
 }
@@ -381,7 +440,7 @@ impl DrawTarget for Display {
 
         if !pixels.is_empty() {
             let msg = emu_model::CardMessage::Display(pixels);
-            super::write_serial(msg.write_to());
+            super::write_message(&msg);
         }
 
         Ok(())
@@ -411,7 +470,7 @@ impl Flash {
 
     pub fn read(&self, page: u16) -> Vec<u8> {
         let msg = emu_model::CardMessage::ReadFlash(page);
-        super::write_serial(msg.write_to());
+        super::write_message(&msg);
 
         loop {
             if let Ok(data) = self
@@ -428,7 +487,7 @@ impl Flash {
 
     pub fn write(&self, page: u16, data: &[u8]) {
         let msg = emu_model::CardMessage::WriteFlash(page, data.to_vec());
-        super::write_serial(msg.write_to());
+        super::write_message(&msg);
     }
 }
 
@@ -447,6 +506,10 @@ unsafe fn create_fake_clocks_pclk2_8mhz() -> hal::rcc::Clocks {
     unreachable!()
 }
 
+pub fn device_serial() -> [u8; model::SERIAL_LEN] {
+    model::emulator::EMULATOR_SERIAL
+}
+
 pub fn enable_debug_during_sleep(_: &mut hal::pac::Peripherals) {}
 
 #[derive(Debug)]
@@ -458,6 +521,11 @@ impl From<minicbor::decode::Error> for FlashError {
         FlashError::CorruptedData
     }
 }
+impl From<()> for FlashError {
+    fn from(_: ()) -> Self {
+        FlashError::CorruptedData
+    }
+}
 
 pub fn read_flash<'b>(
     flash: &mut Flash,
@@ -465,19 +533,14 @@ pub fn read_flash<'b>(
     buf: &'b mut [u8; 2048],
 ) -> Result<&'b [u8], FlashError> {
     let data = flash.read(page as u16);
-    let len = core::cmp::min(
-        u16::from_be_bytes(data[..2].try_into().unwrap()) as usize,
-        crate::hw_common::PAGE_SIZE - 2,
-    );
-    buf[..len].copy_from_slice(&data[2..2 + len]);
+    buf.copy_from_slice(&data[..crate::hw_common::PAGE_SIZE]);
 
-    Ok(&buf[..len])
+    crate::hw_common::parse_flash_page(buf).ok_or(FlashError::CorruptedData)
 }
 
 pub fn write_flash(flash: &mut Flash, page: usize, serialized: &[u8]) -> Result<(), FlashError> {
-    let mut data = alloc::vec![];
-    data.extend(u16::to_be_bytes(serialized.len() as u16));
-    data.extend(serialized);
+    let data =
+        crate::hw_common::serialize_flash_page(serialized).ok_or(FlashError::CorruptedData)?;
     flash.write(page as u16, &data);
     Ok(())
 }
@@ -504,7 +567,7 @@ impl Rtc {
 
         if borrow.1 {
             let msg = emu_model::CardMessage::ReadRtcRegister(register as u8);
-            super::write_serial(msg.write_to());
+            super::write_message(&msg);
 
             loop {
                 if let Ok(data) = self
@@ -533,6 +596,6 @@ impl Rtc {
         self.registers.borrow_mut().1 = true;
 
         let msg = emu_model::CardMessage::WriteRtcRegister(register as u8, value);
-        super::write_serial(msg.write_to());
+        super::write_message(&msg);
     }
 }
@@ -75,13 +75,27 @@ pub(super) fn read_serial() -> Vec<u8> {
     })
 }
 
-pub(super) fn write_serial(data: impl Iterator<Item = u8>) {
+/// Messages up to this size are encoded on the stack, larger ones like display updates and flash
+/// writes get a single allocation of the right size
+const MESSAGE_BUFFER_LEN: usize = 64;
+
+pub(super) fn write_message(msg: &model::emulator::CardMessage) {
+    let mut buffer = [0u8; MESSAGE_BUFFER_LEN];
+    let mut large_buffer;
+    let data = match msg.write_into(&mut buffer) {
+        Some(len) => &buffer[..len],
+        None => {
+            large_buffer = alloc::vec![0u8; msg.encoded_len()];
+            let len = msg.write_into(&mut large_buffer).expect("Exact length");
+            &large_buffer[..len]
+        }
+    };
+
     free(|cs| {
         let mut serial = SERIAL.borrow(cs).borrow_mut();
         let serial = serial.as_mut().unwrap();
 
-        let data = data.collect::<alloc::vec::Vec<_>>();
-        serial.bwrite_all(&data).unwrap();
+        serial.bwrite_all(data).unwrap();
         serial.bflush().unwrap();
     });
 }
@@ -106,6 +120,8 @@ pub enum PeripheralIncomingMsg {
     Reset,
     Entropy,
     RtcRegister,
+    TscGesture,
+    Snapshot,
 }
 
 impl PeripheralIncomingMsg {
@@ -117,11 +133,27 @@ impl PeripheralIncomingMsg {
             0x04 => Some(PeripheralIncomingMsg::Reset),
             0x05 => Some(PeripheralIncomingMsg::Entropy),
             0x06 => Some(PeripheralIncomingMsg::RtcRegister),
+            0x07 => Some(PeripheralIncomingMsg::TscGesture),
+            0x08 => Some(PeripheralIncomingMsg::Snapshot),
             _ => None,
         }
     }
 }
 
+/// How long a finger stays on the sensor for a tap or between the two taps of a double tap
+pub const TAP_MILLIS: u16 = 100;
+
+/// Turn a gesture into the number of presses and how long each of them is held
+pub fn gesture_presses(gesture: model::emulator::Gesture) -> (u8, u16) {
+    use model::emulator::Gesture;
+
+    match gesture {
+        Gesture::Tap => (1, TAP_MILLIS),
+        Gesture::DoubleTap => (2, TAP_MILLIS),
+        Gesture::LongPress { ms } => (1, ms),
+    }
+}
+
 /// Semihosting target that writes using SYS_WRITEC
 ///
 /// This differs from the more commonly used stdout (SYS_OPEN and then SYS_WRITE). Specifically,
@@ -15,6 +15,9 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use alloc::format;
+use alloc::string::String;
+
 use hal::i2c;
 
 use crate::hw;
@@ -26,15 +29,49 @@ pub enum Error {
     LostRf,
 
     TooManyNacks,
+    WriteTimeout,
 
     HandshakeError,
+    /// No complete handshake message was received within
+    /// [`model::encryption::HANDSHAKE_TIMEOUT_MS`]
+    HandshakeTimeout,
     BrokenProtocol,
     InvalidFirmware,
 
     Wallet,
+    /// The PSBT input at this index doesn't belong to our wallet
+    ForeignInput(usize),
+    /// The previous transaction of the PSBT input at this index doesn't match what it spends
+    InvalidNonWitnessUtxo(usize),
+    /// The PSBT input at this index doesn't include its previous transaction
+    MissingNonWitnessUtxo(usize),
+    /// The taproot PSBT input at this index doesn't include the output it spends
+    MissingWitnessUtxo(usize),
+    /// The fee of the PSBT is higher than the limit
+    FeeTooHigh {
+        fee: u64,
+        limit: u64,
+    },
+    /// The outputs of the PSBT spend more than its inputs
+    NegativeFee,
+    /// The amounts of the PSBT overflow when computing the fee
+    FeeOverflow,
+    /// The PSBT output at this index claims a derivation from our wallet, but its script is
+    /// different from the one we derive
+    OutputScriptMismatch(usize),
+    /// The outputs we control return less than our inputs minus the allowed fee
+    ValueLoss {
+        lost: u64,
+        max_fee: u64,
+    },
+    /// The values of our inputs or outputs don't fit in a `u64`
+    ValueOverflow,
+    /// The app sent a [`model::Request::Cancel`]
+    Canceled,
     Unknown,
 
     FlashError,
+    InvalidSetting,
     I2c(i2c::Error),
     // State(state::StateError),
     Config(hw::FlashError),
@@ -42,6 +79,44 @@ pub enum Error {
     Display(display_interface::DisplayError),
 }
 
+impl Error {
+    /// Why a PSBT was rejected, for the errors caused by the content of the PSBT
+    ///
+    /// The PSBT comes from the app, so these are reported back to it instead of halting the
+    /// device.
+    pub fn psbt_rejection(&self) -> Option<String> {
+        let reason = match self {
+            Error::ForeignInput(index) => format!("Input {} is not ours", index),
+            Error::InvalidNonWitnessUtxo(index) => {
+                format!("Invalid non_witness_utxo for input {}", index)
+            }
+            Error::MissingNonWitnessUtxo(index) => {
+                format!("Missing non_witness_utxo for input {}", index)
+            }
+            Error::MissingWitnessUtxo(index) => format!("Missing witness_utxo for input {}", index),
+            Error::FeeTooHigh { fee, limit } => {
+                format!(
+                    "Fee of {} sat is higher than the limit of {} sat",
+                    fee, limit
+                )
+            }
+            Error::NegativeFee => "Outputs spend more than the inputs".into(),
+            Error::FeeOverflow => "Amounts overflow when computing the fee".into(),
+            Error::OutputScriptMismatch(index) => {
+                format!("Script of output {} doesn't match its derivation", index)
+            }
+            Error::ValueLoss { lost, max_fee } => format!(
+                "Our outputs lose {} sat, more than the fee of {} sat",
+                lost, max_fee
+            ),
+            Error::ValueOverflow => "Input or output values overflow".into(),
+            _ => return None,
+        };
+
+        Some(reason)
+    }
+}
+
 impl From<i2c::Error> for Error {
     fn from(e: i2c::Error) -> Self {
         Error::I2c(e)
@@ -62,6 +137,50 @@ impl From<model::MessageError> for Error {
         Error::Message(e)
     }
 }
+impl From<model::prev_utxos::PrevUtxoError> for Error {
+    fn from(e: model::prev_utxos::PrevUtxoError) -> Self {
+        match e {
+            model::prev_utxos::PrevUtxoError::InvalidNonWitnessUtxo(index) => {
+                Error::InvalidNonWitnessUtxo(index)
+            }
+            model::prev_utxos::PrevUtxoError::MissingNonWitnessUtxo(index) => {
+                Error::MissingNonWitnessUtxo(index)
+            }
+        }
+    }
+}
+impl From<model::output_scripts::OutputScriptError> for Error {
+    fn from(e: model::output_scripts::OutputScriptError) -> Self {
+        match e {
+            model::output_scripts::OutputScriptError::Mismatch(index) => {
+                Error::OutputScriptMismatch(index)
+            }
+        }
+    }
+}
+impl From<model::fee::FeeError> for Error {
+    fn from(e: model::fee::FeeError) -> Self {
+        match e {
+            model::fee::FeeError::FeeTooHigh { fee, limit } => Error::FeeTooHigh { fee, limit },
+            model::fee::FeeError::MissingWitnessUtxo(index) => Error::MissingWitnessUtxo(index),
+            model::fee::FeeError::MissingNonWitnessUtxo(index) => {
+                Error::MissingNonWitnessUtxo(index)
+            }
+            model::fee::FeeError::NegativeFee => Error::NegativeFee,
+            model::fee::FeeError::Overflow => Error::FeeOverflow,
+        }
+    }
+}
+impl From<model::value_loss::ValueLossError> for Error {
+    fn from(e: model::value_loss::ValueLossError) -> Self {
+        match e {
+            model::value_loss::ValueLossError::ValueLoss { lost, max_fee } => {
+                Error::ValueLoss { lost, max_fee }
+            }
+            model::value_loss::ValueLossError::Overflow => Error::ValueOverflow,
+        }
+    }
+}
 impl From<display_interface::DisplayError> for Error {
     fn from(e: display_interface::DisplayError) -> Self {
         Error::Display(e)
@@ -72,6 +191,14 @@ impl From<bdk::Error> for Error {
         Error::Wallet
     }
 }
+impl From<bdk::wallet::signer::SignerError> for Error {
+    fn from(e: bdk::wallet::signer::SignerError) -> Self {
+        match e {
+            bdk::wallet::signer::SignerError::UserCanceled => Error::Canceled,
+            _ => Error::Wallet,
+        }
+    }
+}
 impl<T> From<bdk::wallet::NewError<T>> for Error {
     fn from(_: bdk::wallet::NewError<T>) -> Self {
         Error::Wallet
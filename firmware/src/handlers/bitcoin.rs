@@ -22,35 +22,67 @@ use alloc::vec::Vec;
 
 use futures::prelude::*;
 
+use rtic_monotonics::systick::ExtU32;
+
+use bdk::bitcoin::consensus::encode::Encodable;
+use bdk::bitcoin::hashes::{sha256, Hash};
 use bdk::bitcoin::util::{bip32, psbt, taproot};
-use bdk::bitcoin::{Address, Amount, PublicKey, XOnlyPublicKey};
+use bdk::bitcoin::{Address, Amount, PublicKey, TxOut, XOnlyPublicKey};
 use bdk::descriptor::{
     DerivedDescriptor, DescriptorError, DescriptorXKey, ExtendedDescriptor, TapKeyOrigins, Wildcard,
 };
 use bdk::keys::SinglePubKey;
 use bdk::miniscript::descriptor::{DescriptorType, InnerXKey};
+use bdk::miniscript::psbt::PsbtExt;
 use bdk::miniscript::{DescriptorPublicKey, ForEachKey};
+use bdk::wallet::signer::SignerError;
 use bdk::HdKeyPaths;
 
 use gui::{
-    GenericTwoLinePage, LoadingPage, Page, ShowScrollingAddressPage, SummaryPage, TxOutputPage,
-    TxSummaryPage,
+    GenericTwoLinePage, LoadingPage, Page, QrPage, ShowScrollingAddressPage, SummaryPage,
+    TxOutputPage, TxSummaryPage,
 };
+use model::change::OutputKind;
+use model::sign_session::{InputSigner, SavedSignSession, SignProgress, SignSession};
+use model::taproot_spend::TaprootSpendPolicy;
 use model::{
     DescriptorVariant, ExtendedKey, MultisigKey, ScriptType, SerializedDerivationPath,
     SetDescriptorVariant, WalletDescriptor,
 };
 
+use zeroize::{Zeroize, Zeroizing};
+
 use super::*;
 use crate::{checkpoint, Error};
 
 type SecpCtx = secp256k1::Secp256k1<secp256k1::All>;
 
+/// Highest fee of a transaction we sign, the default `-maxtxfee` of Bitcoin Core
+const MAX_FEE_SAT: u64 = 10_000_000;
+/// Highest fee rate of a transaction we sign, the default `maxfeerate` of Bitcoin Core
+const MAX_FEE_RATE_SAT_VB: f32 = 10_000.0;
+
+/// Map the policy to the options of bdk, the signatures bdk adds anyway are then dropped by
+/// [`TaprootSpendPolicy::filter_new_signatures`]
+fn taproot_sign_options(policy: TaprootSpendPolicy, options: bdk::SignOptions) -> bdk::SignOptions {
+    let tap_leaves_options = match policy.signs_script_path() {
+        true => bdk::wallet::signer::TapLeavesOptions::All,
+        false => bdk::wallet::signer::TapLeavesOptions::None,
+    };
+
+    bdk::SignOptions {
+        sign_with_tap_internal_key: policy.signs_key_path(),
+        tap_leaves_options,
+        ..options
+    }
+}
+
 #[derive(Default)]
 struct CurrentSignatures {
     partial_sigs: BTreeSet<PublicKey>,
     tap_key_sig: bool,
     tap_script_sigs: BTreeSet<(XOnlyPublicKey, taproot::TapLeafHash)>,
+    finalized: bool,
 }
 
 impl CurrentSignatures {
@@ -61,46 +93,394 @@ impl CurrentSignatures {
                 partial_sigs: i.partial_sigs.iter().map(|(k, _)| k.clone()).collect(),
                 tap_key_sig: i.tap_key_sig.is_some(),
                 tap_script_sigs: i.tap_script_sigs.iter().map(|(k, _)| k.clone()).collect(),
+                finalized: model::finalize::is_finalized(i),
             })
             .collect()
     }
 
+    /// Signatures of `i` that weren't there before
+    fn diff_input(&self, mut i: psbt::Input) -> psbt::Input {
+        i.partial_sigs.retain(|k, _| !self.partial_sigs.contains(k));
+        i.tap_script_sigs
+            .retain(|k, _| !self.tap_script_sigs.contains(k));
+
+        let mut input = psbt::Input::default();
+        input.partial_sigs = i.partial_sigs;
+        input.tap_script_sigs = i.tap_script_sigs;
+        input.tap_key_sig = match (i.tap_key_sig, self.tap_key_sig) {
+            (Some(sig), false) => Some(sig),
+            _ => None,
+        };
+        if !self.finalized {
+            input.final_script_sig = i.final_script_sig;
+            input.final_script_witness = i.final_script_witness;
+        }
+
+        input
+    }
+
     fn diff(sigs: &Vec<Self>, psbt: psbt::PartiallySignedTransaction) -> Vec<psbt::Input> {
         psbt.inputs
             .into_iter()
             .zip(sigs.iter())
-            .map(|(mut i, s)| {
-                i.partial_sigs.retain(|k, _| !s.partial_sigs.contains(k));
-                i.tap_script_sigs
-                    .retain(|k, _| !s.tap_script_sigs.contains(k));
-
-                let mut input = psbt::Input::default();
-                input.partial_sigs = i.partial_sigs;
-                input.tap_script_sigs = i.tap_script_sigs;
-                input.tap_key_sig = match (i.tap_key_sig, s.tap_key_sig) {
-                    (Some(sig), false) => Some(sig),
-                    _ => None,
-                };
+            .map(|(i, s)| s.diff_input(i))
+            .collect()
+    }
+}
+
+/// Finalize the inputs that are fully signed with miniscript, see [`model::finalize`]
+fn finalize_psbt(
+    psbt: &mut psbt::PartiallySignedTransaction,
+    secp: &SecpCtx,
+) -> Result<(), SignerError> {
+    let finalized =
+        model::finalize::finalize_psbt(psbt, |psbt, index| psbt.finalize_inp_mut(secp, index))
+            .map_err(|_| SignerError::InputIndexOutOfRange)?;
+    log::debug!("Finalized inputs {:?}", finalized);
+
+    Ok(())
+}
+
+/// Like [`DescriptorXKey::matches`], but also check that the step replacing the wildcard is of the
+/// same kind as the wildcard
+///
+/// `matches` drops the last step of the key source whenever the key has a wildcard, so a hardened
+/// index would be accepted for an unhardened wildcard and derived under the wrong path. Returns
+/// `None` if the two are inconsistent.
+fn xpub_matches(
+    xpub: &DescriptorXKey<bip32::ExtendedPubKey>,
+    keysource: &bip32::KeySource,
+    secp: &SecpCtx,
+) -> Option<bip32::DerivationPath> {
+    let prefix = xpub.matches(keysource, secp)?;
+    let wildcard_step = keysource.1.into_iter().nth(prefix.len());
+
+    match (xpub.wildcard, wildcard_step) {
+        (Wildcard::None, None)
+        | (Wildcard::Unhardened, Some(bip32::ChildNumber::Normal { .. }))
+        | (Wildcard::Hardened, Some(bip32::ChildNumber::Hardened { .. })) => Some(prefix),
+        _ => None,
+    }
+}
+
+/// Classify the outputs of `psbt` as change or payments, see [`model::change`]
+///
+/// The key derived at an origin comes from one of our extended keys, if [`xpub_matches`] finds
+/// that the origin is under it.
+fn classify_outputs(
+    psbt: &psbt::PartiallySignedTransaction,
+    wallet: &PortalWallet,
+    secp: &SecpCtx,
+) -> Vec<OutputKind> {
+    let xkeys = [bdk::KeychainKind::External, bdk::KeychainKind::Internal]
+        .into_iter()
+        .flat_map(|keychain| {
+            wallet
+                .get_descriptor_for_keychain(keychain)
+                .get_extended_keys()
+                .unwrap_or_default()
+        })
+        .collect::<Vec<_>>();
+
+    model::change::classify_outputs(psbt, secp, |keysource| {
+        xkeys.iter().find_map(|xkey| {
+            let prefix = xpub_matches(xkey, keysource, secp)?;
+            let derive_path = keysource
+                .1
+                .into_iter()
+                .skip(prefix.len())
+                .cloned()
+                .collect::<bip32::DerivationPath>();
+
+            xkey.xkey
+                .derive_pub(secp, &xkey.derivation_path.extend(derive_path))
+                .ok()
+                .map(|derived| derived.public_key)
+        })
+    })
+}
+
+/// Keychain of `wallet` that `psbt_out` was derived from
+///
+/// Each key is stored once and expanded into its `/0/*` and `/1/*` branches, like a `/<0;1>/*`
+/// multipath key, so the keychain tells which of the two branches matched.
+fn output_keychain(
+    wallet: &PortalWallet,
+    psbt_out: &psbt::Output,
+    secp: &SecpCtx,
+) -> Option<bdk::KeychainKind> {
+    [bdk::KeychainKind::External, bdk::KeychainKind::Internal]
+        .into_iter()
+        .find(|keychain| {
+            wallet
+                .get_descriptor_for_keychain(*keychain)
+                .derive_from_psbt_output(psbt_out, secp)
+                .is_some()
+        })
+}
+
+/// Keychain of `wallet` that `input` was derived from, see [`output_keychain`]
+fn input_keychain(
+    wallet: &PortalWallet,
+    input: &psbt::Input,
+    utxo: &TxOut,
+    secp: &SecpCtx,
+) -> Option<bdk::KeychainKind> {
+    [bdk::KeychainKind::External, bdk::KeychainKind::Internal]
+        .into_iter()
+        .find(|keychain| {
+            wallet
+                .get_descriptor_for_keychain(*keychain)
+                .derive_from_psbt_input(input, Some(utxo), secp)
+                .is_some()
+        })
+}
+
+/// Indices of the inputs that `wallet` can sign, without producing any signature
+///
+/// An input is signable when it's derived from one of our descriptors, the derived script matches
+/// the output being spent and one of its key origins is our fingerprint. The PSBT is not modified.
+fn signable_inputs(
+    psbt: &psbt::PartiallySignedTransaction,
+    prev_utxos: &[&TxOut],
+    wallet: &PortalWallet,
+) -> Vec<usize> {
+    let secp = wallet.secp_ctx();
+    let fingerprint = wallet.xprv.fingerprint(secp);
+
+    model::signable::signable_inputs(psbt, fingerprint, |i, input| {
+        input_keychain(wallet, input, prev_utxos[i], secp).is_some()
+    })
+}
 
-                input
+/// Signs one input at a time with the wallet signers
+///
+/// The key origins of the other inputs are hidden from the signers while signing, and restored
+/// afterwards. Only the `signable` inputs are signed, the others belong to someone else.
+struct PsbtInputSigner<'a> {
+    wallet: &'a PortalWallet,
+    psbt: &'a mut psbt::PartiallySignedTransaction,
+    options: bdk::SignOptions,
+    taproot_policy: TaprootSpendPolicy,
+    signable: Vec<usize>,
+}
+
+impl InputSigner for PsbtInputSigner<'_> {
+    type Error = bdk::Error;
+
+    fn sign_input(&mut self, index: usize) -> Result<(), bdk::Error> {
+        let hidden = self
+            .psbt
+            .inputs
+            .iter_mut()
+            .enumerate()
+            .filter(|(i, _)| *i != index)
+            .map(|(i, input)| {
+                (
+                    i,
+                    core::mem::take(&mut input.bip32_derivation),
+                    core::mem::take(&mut input.tap_key_origins),
+                )
             })
-            .collect()
+            .collect::<Vec<_>>();
+
+        let before = self.psbt.inputs[index].clone();
+        let result = self.wallet.sign(self.psbt, self.options.clone());
+        self.taproot_policy
+            .filter_new_signatures(&before, &mut self.psbt.inputs[index]);
+
+        for (i, bip32_derivation, tap_key_origins) in hidden {
+            self.psbt.inputs[i].bip32_derivation = bip32_derivation;
+            self.psbt.inputs[i].tap_key_origins = tap_key_origins;
+        }
+
+        result.map(|_| ())
+    }
+
+    fn can_sign(&self, index: usize) -> bool {
+        self.signable.contains(&index)
     }
 }
 
-pub async fn handle_sign_request(
-    wallet: &mut Rc<PortalWallet>,
-    psbt: &[u8],
-    peripherals: &mut HandlerPeripherals,
-) -> Result<CurrentState, Error> {
-    log::info!("handle_sign_request");
+/// Indexes of the inputs without a key origin with our fingerprint
+fn foreign_inputs(psbt: &psbt::PartiallySignedTransaction, wallet: &PortalWallet) -> Vec<usize> {
+    let fingerprint = wallet.xprv.fingerprint(wallet.secp_ctx());
 
-    peripherals
-        .nfc
-        .send(model::Reply::DelayedReply)
-        .await
-        .unwrap();
+    psbt.inputs
+        .iter()
+        .enumerate()
+        .filter(|(_, input)| !model::signable::has_key_from(input, fingerprint))
+        .map(|(index, _)| index)
+        .collect()
+}
+
+/// Ensure every input has a key origin with our fingerprint, otherwise return the index of the
+/// first foreign input
+///
+/// Inputs from someone else would be counted in the fees shown to the user.
+fn verify_all_inputs_ours(
+    psbt: &psbt::PartiallySignedTransaction,
+    wallet: &PortalWallet,
+) -> Result<(), Error> {
+    match foreign_inputs(psbt, wallet).first() {
+        Some(index) => Err(Error::ForeignInput(*index)),
+        None => Ok(()),
+    }
+}
+
+/// Ensure that the outputs derived from our descriptors pay to the script derived at the claimed
+/// index, see [`model::output_scripts::verify_output_scripts`]
+fn verify_output_scripts(
+    psbt: &psbt::PartiallySignedTransaction,
+    wallet: &PortalWallet,
+    secp: &SecpCtx,
+) -> Result<(), Error> {
+    let descriptors = [bdk::KeychainKind::External, bdk::KeychainKind::Internal]
+        .map(|keychain| wallet.get_descriptor_for_keychain(keychain));
+
+    Ok(model::output_scripts::verify_output_scripts(
+        psbt,
+        |_, psbt_out| {
+            descriptors
+                .iter()
+                .filter_map(|d| d.derive_from_psbt_output(psbt_out, secp))
+                .map(|derived| derived.script_pubkey())
+                .collect::<Vec<_>>()
+        },
+    )?)
+}
+
+/// Ensure that the outputs we control return the value of our inputs, minus at most `max_fee`
+///
+/// Meant for transactions built by someone else, like a CoinJoin, see
+/// [`model::value_loss::verify_no_value_loss`]. Outputs are only counted as ours if their script
+/// matches the derivation they claim, see [`verify_output_scripts`].
+fn verify_no_value_loss(
+    psbt: &psbt::PartiallySignedTransaction,
+    prev_utxos: &[&TxOut],
+    signable: &[usize],
+    wallet: &PortalWallet,
+    secp: &SecpCtx,
+    max_fee: u64,
+) -> Result<u64, Error> {
+    let descriptors = [bdk::KeychainKind::External, bdk::KeychainKind::Internal]
+        .map(|keychain| wallet.get_descriptor_for_keychain(keychain));
+    let our_output = |index: usize| {
+        descriptors.iter().any(|d| {
+            d.derive_from_psbt_output(&psbt.outputs[index], secp)
+                .map(|derived| {
+                    derived.script_pubkey() == psbt.unsigned_tx.output[index].script_pubkey
+                })
+                .unwrap_or(false)
+        })
+    };
+
+    Ok(model::value_loss::verify_no_value_loss(
+        psbt,
+        prev_utxos,
+        |index| signable.contains(&index),
+        our_output,
+        max_fee,
+    )?)
+}
+
+/// Sign again the ECDSA signatures added to `psbt` since `current_sigs` that have a high R value
+///
+/// bdk already grinds the nonce, so this is only a safety net in case it stops doing so. The
+/// signatures are replaced with the ones from [`model::low_r::sign_ecdsa_low_r`], which gives up
+/// after [`model::low_r::MAX_LOW_R_ATTEMPTS`].
+fn resign_high_r(
+    psbt: &mut psbt::PartiallySignedTransaction,
+    current_sigs: &[CurrentSignatures],
+    wallet: &PortalWallet,
+) -> Result<(), Error> {
+    let secp = wallet.secp_ctx();
+    let fingerprint = wallet.xprv.fingerprint(secp);
+
+    let high_r = psbt
+        .inputs
+        .iter()
+        .zip(current_sigs.iter())
+        .enumerate()
+        .flat_map(|(index, (input, s))| {
+            input
+                .partial_sigs
+                .iter()
+                .filter(|(k, sig)| !s.partial_sigs.contains(k) && !model::low_r::is_low_r(&sig.sig))
+                .map(move |(k, sig)| (index, *k, sig.hash_ty))
+        })
+        .collect::<Vec<_>>();
+
+    let mut cache = bdk::bitcoin::util::sighash::SighashCache::new(&psbt.unsigned_tx);
+    let mut resigned = Vec::with_capacity(high_r.len());
+    for (index, key, hash_ty) in high_r {
+        log::warn!(
+            "Signature with a high R value for input {}, signing again",
+            index
+        );
+
+        let path = match psbt.inputs[index].bip32_derivation.get(&key.inner) {
+            Some((f, path)) if *f == fingerprint => path,
+            _ => return Err(Error::Wallet),
+        };
+        let xprv = wallet
+            .xprv
+            .derive_priv(secp, path)
+            .map_err(|_| Error::Wallet)?;
+        let msg = psbt
+            .sighash_msg(index, &mut cache, None)
+            .map_err(|_| Error::Wallet)?
+            .to_secp_msg();
+        let sig = model::low_r::sign_ecdsa_low_r(
+            secp,
+            &msg,
+            &xprv.private_key,
+            model::low_r::MAX_LOW_R_ATTEMPTS,
+        )
+        .map_err(|e| {
+            log::warn!("Couldn't produce a low R signature: {:?}", e);
+            Error::Wallet
+        })?;
+
+        resigned.push((index, key, bdk::bitcoin::EcdsaSig { sig, hash_ty }));
+    }
+
+    for (index, key, sig) in resigned {
+        psbt.inputs[index].partial_sigs.insert(key, sig);
+    }
+
+    Ok(())
+}
+
+/// Restore the signatures of the inputs signed before a reset
+fn restore_signatures(
+    psbt: &mut psbt::PartiallySignedTransaction,
+    saved: &SavedSignSession,
+) -> Result<(), Error> {
+    for (index, signatures) in saved.signed.iter().zip(saved.signatures.iter()) {
+        let restored: psbt::Input =
+            bdk::bitcoin::consensus::encode::deserialize(signatures).map_err(|_| Error::Wallet)?;
+        let input = psbt.inputs.get_mut(*index).ok_or(Error::Wallet)?;
+
+        input.partial_sigs.extend(restored.partial_sigs);
+        input.tap_script_sigs.extend(restored.tap_script_sigs);
+        input.tap_key_sig = input.tap_key_sig.or(restored.tap_key_sig);
+    }
 
+    Ok(())
+}
+
+/// Sign `psbt`, returning the signatures with what has to be confirmed by the user
+///
+/// `on_progress` is called after every input, with the signatures produced so far. If `resume`
+/// was saved while signing the same PSBT, the inputs it contains are not signed again.
+async fn sign_psbt(
+    wallet: &PortalWallet,
+    psbt: &[u8],
+    resume: Option<SavedSignSession>,
+    mut on_progress: impl FnMut(SignProgress, &SavedSignSession),
+) -> Result<checkpoint::SignPsbtState, Error> {
+    let digest = sha256::Hash::hash(psbt).into_inner();
     let mut psbt: psbt::PartiallySignedTransaction =
         bdk::bitcoin::consensus::encode::deserialize(&psbt).unwrap();
 
@@ -111,47 +491,44 @@ pub async fn handle_sign_request(
         bdk::miniscript::Descriptor::Tr(_)
     );
 
-    let prev_utxos = psbt
-        .unsigned_tx
-        .input
-        .iter()
-        .zip(psbt.inputs.iter())
-        .map(|(txin, input)| {
-            if let Some(prev_tx) = &input.non_witness_utxo {
-                if prev_tx.txid() == txin.previous_output.txid
-                    && prev_tx.output.len() > txin.previous_output.vout as usize
-                {
-                    Ok(&prev_tx.output[txin.previous_output.vout as usize])
-                } else {
-                    Err("Invalid non_witness_utxo")
-                }
-            } else if allow_witness_utxo && input.witness_utxo.is_some() {
-                Ok(input.witness_utxo.as_ref().unwrap())
-            } else {
-                Err("Missing NonWitnessUtxo")
-            }
-        })
-        .collect::<Result<alloc::vec::Vec<_>, _>>()
-        .unwrap();
-    let total_input_value = prev_utxos.iter().fold(0, |sum, utxo| sum + utxo.value);
-    let total_output_value = psbt
-        .unsigned_tx
-        .output
+    // Checks that the witness_utxo agrees with the non_witness_utxo, so that the fee shown to the
+    // user is the one actually paid
+    let prev_utxos = model::prev_utxos::prev_utxos(&psbt, allow_witness_utxo)?;
+    let fees = model::fee::check_fee_bounds(&psbt, MAX_FEE_SAT, MAX_FEE_RATE_SAT_VB)?;
+
+    verify_output_scripts(&psbt, wallet, wallet.secp_ctx())?;
+
+    let timelocks = model::policy::extract_timelocks(&psbt);
+    if !timelocks.is_empty() {
+        log::warn!("PSBT is timelocked: {:?}", timelocks.conditions());
+    }
+    // Shown to the user before the outputs. Inputs usually share the same sequence, so it's
+    // only shown once
+    let mut timelocks = timelocks
+        .conditions()
         .iter()
-        .fold(0, |sum, utxo| sum + utxo.value);
-    let fees = total_input_value.checked_sub(total_output_value).unwrap();
+        .map(|condition| condition.to_string())
+        .collect::<Vec<_>>();
+    timelocks.dedup();
 
+    let kinds = classify_outputs(&psbt, wallet, wallet.secp_ctx());
     let outputs = psbt
         .unsigned_tx
         .output
         .iter()
         .zip(psbt.outputs.iter())
-        .filter_map(|(out, psbt_out)| {
-            if wallet
-                .get_descriptor_for_keychain(bdk::KeychainKind::Internal)
-                .derive_from_psbt_output(psbt_out, &wallet.secp_ctx())
-                .is_some()
-            {
+        .zip(kinds.iter())
+        .filter_map(|((out, psbt_out), kind)| {
+            // Only wpkh and tr outputs are classified, for the other descriptors bdk tells whether
+            // the output is derived from the internal one
+            let is_change = matches!(kind, OutputKind::Change { .. })
+                || output_keychain(wallet, psbt_out, wallet.secp_ctx())
+                    == Some(bdk::KeychainKind::Internal);
+            if let OutputKind::Change { derivation } = kind {
+                log::debug!("Change output at {}", derivation);
+            }
+
+            if is_change {
                 // Hide our change outputs
                 None
             } else {
@@ -166,33 +543,171 @@ pub async fn handle_sign_request(
     // page.draw_to(&mut peripherals.display)?;
     // peripherals.display.flush()?;
 
+    let signable = signable_inputs(&psbt, &prev_utxos, wallet);
+    let mut foreign = alloc::vec![];
+    if let Err(e) = verify_all_inputs_ours(&psbt, wallet) {
+        log::warn!("PSBT contains inputs that are not ours: {:?}", e);
+        // Shown to the user before the outputs
+        foreign = foreign_inputs(&psbt, wallet);
+
+        // Someone else contributes inputs, like in a CoinJoin: in the worst case we pay the whole
+        // fee, anything else going missing was taken by the other parties
+        let lost = verify_no_value_loss(
+            &psbt,
+            &prev_utxos,
+            &signable,
+            wallet,
+            wallet.secp_ctx(),
+            fees,
+        )?;
+        log::info!("Our share of the fee is {} sat", lost);
+    }
+
     let current_sigs = CurrentSignatures::from_psbt(&psbt);
+    let resume = resume.filter(|saved| saved.matches(&digest));
+    if let Some(saved) = &resume {
+        log::info!(
+            "Resuming signing, {} inputs already signed",
+            saved.signed.len()
+        );
+        restore_signatures(&mut psbt, saved)?;
+    }
 
-    wallet
-        .sign(
-            &mut psbt,
+    let num_inputs = psbt.inputs.len();
+    let taproot_policy = TaprootSpendPolicy::default();
+    let signer = PsbtInputSigner {
+        wallet,
+        psbt: &mut psbt,
+        options: taproot_sign_options(
+            taproot_policy,
             bdk::SignOptions {
                 try_finalize: false,
+                allow_grinding: true,
                 ..Default::default()
             },
-        )
-        .unwrap();
+        ),
+        taproot_policy,
+        signable,
+    };
+    let mut session = match &resume {
+        Some(saved) => SignSession::resume(signer, num_inputs, &saved.signed),
+        None => SignSession::for_all_inputs(signer, num_inputs),
+    };
+    while let Some(progress) = session.next() {
+        let progress = progress?;
+        log::debug!("Signed {}/{} inputs", progress.signed, progress.total);
+
+        let saved = session.save(digest, |signer, index| {
+            let mut bytes = alloc::vec![];
+            current_sigs[index]
+                .diff_input(signer.psbt.inputs[index].clone())
+                .consensus_encode(&mut bytes)
+                .expect("Encoding succeeds");
+            bytes
+        });
+        on_progress(progress, &saved);
+
+        // Let the other tasks run between inputs
+        rtic_monotonics::systick::Systick::delay(1_u32.millis()).await;
+    }
+    let summary = session.summary();
+    if !summary.skipped.is_empty() {
+        log::info!(
+            "Signed inputs {:?}, left inputs {:?} to the other parties",
+            summary.signed,
+            summary.skipped
+        );
+    }
+    drop(session);
+
+    // Every ECDSA signature must have a low R so that it's at most 70 bytes DER-encoded, which
+    // keeps the size of the transaction predictable for fee estimation. This is checked before
+    // finalizing, since that moves the signatures into the final scripts
+    resign_high_r(&mut psbt, &current_sigs, wallet)?;
+
+    finalize_psbt(&mut psbt, wallet.secp_ctx())?;
 
     let diff = CurrentSignatures::diff(&current_sigs, psbt);
-    let mut sig_bytes = alloc::vec![];
 
-    use bdk::bitcoin::consensus::encode::Encodable;
+    let mut sig_bytes = alloc::vec![];
     for input in &diff {
         input
             .consensus_encode(&mut sig_bytes)
             .expect("Encoding succeeds");
     }
 
-    let sign_state = checkpoint::SignPsbtState {
+    Ok(checkpoint::SignPsbtState {
         fees,
         outputs,
-        sig_bytes: sig_bytes.clone().into(),
+        sig_bytes: sig_bytes.into(),
+        warnings: checkpoint::SignWarnings {
+            foreign_inputs: foreign,
+            num_inputs,
+            timelocks,
+        },
+    })
+}
+
+pub async fn handle_sign_request<T: Transport>(
+    wallet: &mut Rc<PortalWallet>,
+    psbt: &[u8],
+    peripherals: &mut HandlerPeripherals<T>,
+) -> Result<CurrentState, Error> {
+    log::info!("handle_sign_request");
+
+    peripherals
+        .nfc
+        .send_reply(model::Reply::DelayedReply)
+        .await
+        .unwrap();
+
+    // A reset while signing leaves the progress in a checkpoint, which is used if the same PSBT
+    // is sent again
+    let resume = match checkpoint::Checkpoint::load(peripherals) {
+        Ok(checkpoint::Checkpoint {
+            variant: checkpoint::CheckpointVariant::SignPsbtProgress,
+            aux: Some(aux),
+            ..
+        }) => minicbor::decode::<SavedSignSession>(&aux).ok(),
+        _ => None,
+    };
+
+    let mut progress_bar = ProgressBar::new("SIGNING");
+    progress_bar.draw_progress(&mut peripherals.display, 0.0)?;
+
+    let progress_key = checkpoint::Checkpoint::gen_key(&mut peripherals.rng);
+    let on_progress = |progress: SignProgress, saved: &SavedSignSession| {
+        let fraction = progress.signed as f32 / progress.total as f32;
+        if let Err(e) = progress_bar.draw_progress(&mut peripherals.display, fraction) {
+            log::warn!("Couldn't draw the signing progress: {:?}", e);
+        }
+
+        // Once done the progress is replaced by the SignPsbt checkpoint
+        if progress.is_done() {
+            return;
+        }
+        let checkpoint = checkpoint::Checkpoint::new_with_key(
+            checkpoint::CheckpointVariant::SignPsbtProgress,
+            Some(minicbor::to_vec(saved).expect("Encoding works")),
+            None,
+            progress_key,
+        );
+        if let Err(e) = checkpoint.commit(peripherals) {
+            log::warn!("Couldn't save the signing progress: {:?}", e);
+        }
     };
+    let result = sign_psbt(wallet, psbt, resume, on_progress).await;
+    let sign_state = match result {
+        Ok(state) => state,
+        Err(e) => {
+            // Nothing to resume if the PSBT can't be signed
+            checkpoint::discard(peripherals)?;
+            return Err(e);
+        }
+    };
+    let fees = sign_state.fees;
+    let sig_bytes = sign_state.sig_bytes.to_vec();
+
     let aux_data = minicbor::to_vec(&sign_state).expect("Encoding works");
     let resumable = checkpoint::Resumable::fresh();
     let checkpoint = checkpoint::Checkpoint::new(
@@ -206,6 +721,7 @@ pub async fn handle_sign_request(
     Ok(CurrentState::ConfirmSignPsbt {
         wallet: Rc::clone(wallet),
         outputs: sign_state.outputs,
+        warnings: sign_state.warnings,
         fees,
         sig_bytes,
         encryption_key: (*checkpoint.encryption_key).into(),
@@ -213,18 +729,173 @@ pub async fn handle_sign_request(
     })
 }
 
-pub async fn handle_confirm_sign_psbt(
+/// Wrap the signatures of the inputs in an almost-PSBT with an empty transaction, which is what
+/// the app expects
+fn signatures_psbt(sig_bytes: Vec<u8>) -> Vec<u8> {
+    #[rustfmt::skip]
+    let mut empty_psbt = alloc::vec![
+        0x70, 0x73, 0x62, 0x74, 0xFF, // PSBT magic
+            0x01, 0x00, 0x33, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, // Empty raw tx
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0x00,
+            0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00 // End global map
+    ];
+    empty_psbt.extend(sig_bytes);
+    empty_psbt
+}
+
+/// Consensus-encoded network transaction of `psbt`, which must have every input finalized
+pub fn extract_tx(psbt: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let psbt: psbt::PartiallySignedTransaction =
+        bdk::bitcoin::consensus::encode::deserialize(psbt).map_err(|_| "Invalid PSBT")?;
+    if psbt
+        .inputs
+        .iter()
+        .any(|input| !model::finalize::is_finalized(input))
+    {
+        return Err("PSBT is not finalized");
+    }
+
+    Ok(bdk::bitcoin::consensus::encode::serialize(
+        &psbt.extract_tx(),
+    ))
+}
+
+/// Zero the signatures of a batch that won't be sent
+fn discard_sign_states(sign_states: &mut [checkpoint::SignPsbtState]) {
+    for state in sign_states {
+        state.sig_bytes.zeroize();
+    }
+}
+
+/// Sign every PSBT of the batch, then show their warnings and ask for a single confirmation of
+/// the total
+///
+/// Unlike [`handle_sign_request`] no checkpoint is saved, if the device loses power the batch
+/// has to be sent again. The batch can be canceled between two PSBTs and during the
+/// confirmation, in which case the signatures produced so far are discarded.
+pub async fn handle_sign_batch<T: Transport>(
+    wallet: &mut Rc<PortalWallet>,
+    psbts: Vec<Vec<u8>>,
+    mut events: impl Stream<Item = Event> + Unpin,
+    peripherals: &mut HandlerPeripherals<T>,
+) -> Result<CurrentState, Error> {
+    log::info!("handle_sign_batch");
+
+    peripherals
+        .nfc
+        .send_reply(model::Reply::DelayedReply)
+        .await
+        .unwrap();
+
+    let mut sign_states = Vec::with_capacity(psbts.len());
+    for (i, psbt) in psbts.iter().enumerate() {
+        if cancel_requested(&mut events, peripherals).await {
+            discard_sign_states(&mut sign_states);
+            return Err(SignerError::UserCanceled.into());
+        }
+
+        let label = alloc::format!("SIGNING {}/{}", i + 1, psbts.len());
+        let mut progress_bar = ProgressBar::new(&label);
+        progress_bar.draw_progress(&mut peripherals.display, 0.0)?;
+        let on_progress = |progress: SignProgress, _: &SavedSignSession| {
+            let fraction = progress.signed as f32 / progress.total as f32;
+            if let Err(e) = progress_bar.draw_progress(&mut peripherals.display, fraction) {
+                log::warn!("Couldn't draw the signing progress: {:?}", e);
+            }
+        };
+        match sign_psbt(wallet, psbt, None, on_progress).await {
+            Ok(state) => sign_states.push(state),
+            Err(e) => {
+                discard_sign_states(&mut sign_states);
+                return Err(e);
+            }
+        }
+    }
+    drop(psbts);
+
+    let total = sign_states
+        .iter()
+        .flat_map(|state| state.outputs.iter().map(|(_, value)| *value))
+        .chain(sign_states.iter().map(|state| state.fees))
+        .sum::<u64>();
+    let message = alloc::format!("Sign {} transactions?", sign_states.len());
+    let total = Amount::from_sat(total).to_string();
+
+    peripherals.tsc_enabled.enable();
+
+    let warnings = sign_states
+        .iter()
+        .enumerate()
+        .flat_map(|(i, state)| {
+            state
+                .warnings
+                .pages()
+                .into_iter()
+                // The details can already take both lines of the large font
+                .map(move |(message, details)| {
+                    (alloc::format!("TX {}: {}", i + 1, message), details)
+                })
+        })
+        .collect::<Vec<_>>();
+    for (message, details) in &warnings {
+        let mut page = GenericTwoLinePage::new(message, details, "HOLD BTN TO CONTINUE", 50);
+        page.init_display(&mut peripherals.display)?;
+        page.draw_to(&mut peripherals.display)?;
+        peripherals.display.flush()?;
+
+        if let Err(e) = manage_confirmation_loop(&mut events, peripherals, &mut page).await {
+            discard_sign_states(&mut sign_states);
+            return Err(e);
+        }
+    }
+
+    let mut page = GenericTwoLinePage::new(&message, &total, "HOLD BTN TO SIGN", 100);
+    page.init_display(&mut peripherals.display)?;
+    page.draw_to(&mut peripherals.display)?;
+    peripherals.display.flush()?;
+
+    if let Err(e) = manage_confirmation_loop(&mut events, peripherals, &mut page).await {
+        discard_sign_states(&mut sign_states);
+        return Err(e);
+    }
+    peripherals.tsc_enabled.disable();
+
+    let signed = sign_states
+        .into_iter()
+        .map(|state| signatures_psbt(state.sig_bytes.to_vec()).into())
+        .collect();
+    peripherals
+        .nfc
+        .send_reply(model::Reply::SignedBatch(signed))
+        .await
+        .unwrap();
+    peripherals.nfc_finished.recv().await.unwrap();
+
+    Ok(CurrentState::Idle {
+        wallet: Rc::clone(wallet),
+    })
+}
+
+pub async fn handle_confirm_sign_psbt<T: Transport>(
     wallet: &mut Rc<PortalWallet>,
     outputs: &[(checkpoint::CborAddress, u64)],
+    warnings: &checkpoint::SignWarnings,
     fees: u64,
     resumable: checkpoint::Resumable,
     sig_bytes: Vec<u8>,
     encryption_key: [u8; 24],
     mut events: impl Stream<Item = Event> + Unpin,
-    peripherals: &mut HandlerPeripherals,
+    peripherals: &mut HandlerPeripherals<T>,
 ) -> Result<CurrentState, Error> {
     log::info!("handle_confirm_sign_psbt");
 
+    // Zeroed on every return, also when the confirmation is canceled
+    let sig_bytes = Zeroizing::new(sig_bytes);
+
     peripherals.tsc_enabled.enable();
     let mut checkpoint = checkpoint::Checkpoint::new_with_key(
         checkpoint::CheckpointVariant::SignPsbt,
@@ -233,7 +904,29 @@ pub async fn handle_confirm_sign_psbt(
         encryption_key.clone(),
     );
 
-    for ((address, value), state, draw) in resumable.wrap_iter(outputs.iter()) {
+    // The warnings come first, so that the user knows about them while checking the outputs
+    let warnings = warnings.pages();
+    for ((message, details), state, draw) in resumable.wrap_iter(warnings.iter()) {
+        let mut page = GenericTwoLinePage::new(message, details, "HOLD BTN TO CONTINUE", 50);
+        page.init_display(&mut peripherals.display)?;
+        page.draw_to(&mut peripherals.display)?;
+        if draw {
+            peripherals.display.flush()?;
+        }
+
+        manage_confirmation_loop_with_checkpoint(
+            &mut events,
+            peripherals,
+            &mut page,
+            &mut checkpoint,
+            state,
+        )
+        .await?;
+    }
+
+    for ((address, value), state, draw) in
+        resumable.wrap_iter_with_offset(warnings.len(), outputs.iter())
+    {
         let value = Amount::from_sat(*value);
 
         let mut page = TxOutputPage::new(&address, value);
@@ -253,7 +946,7 @@ pub async fn handle_confirm_sign_psbt(
         .await?;
     }
 
-    if let Some((state, draw)) = resumable.single_page_with_offset(outputs.len()) {
+    if let Some((state, draw)) = resumable.single_page_with_offset(warnings.len() + outputs.len()) {
         let mut page = TxSummaryPage::new(Amount::from_sat(fees));
         page.init_display(&mut peripherals.display)?;
         page.draw_to(&mut peripherals.display)?;
@@ -271,59 +964,70 @@ pub async fn handle_confirm_sign_psbt(
         .await?;
     }
 
-    #[rustfmt::skip]
-    let mut empty_psbt = alloc::vec![
-        0x70, 0x73, 0x62, 0x74, 0xFF, // PSBT magic
-            0x01, 0x00, 0x33, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, // Empty raw tx
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0x00,
-            0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00 // End global map
-    ];
-    empty_psbt.extend(sig_bytes);
-
     peripherals
         .nfc
-        .send(model::Reply::SignedPsbt(empty_psbt.into()))
+        .send_reply(model::Reply::SignedPsbt(
+            signatures_psbt(sig_bytes.to_vec()).into(),
+        ))
         .await
         .unwrap();
 
     peripherals.nfc_finished.recv().await.unwrap();
 
-    checkpoint.remove(&peripherals.rtc);
+    checkpoint.wipe(peripherals)?;
 
     Ok(CurrentState::Idle {
         wallet: Rc::clone(wallet),
     })
 }
 
-pub async fn handle_waiting_for_psbt(
+pub async fn handle_waiting_for_psbt<T: Transport>(
     wallet: &mut Rc<PortalWallet>,
     mut events: impl Stream<Item = Event> + Unpin,
-    peripherals: &mut HandlerPeripherals,
+    peripherals: &mut HandlerPeripherals<T>,
 ) -> Result<CurrentState, Error> {
     let page = LoadingPage::new();
     page.init_display(&mut peripherals.display)?;
     page.draw_to(&mut peripherals.display)?;
     peripherals.display.flush()?;
 
-    peripherals.nfc.send(model::Reply::Ok).await.unwrap();
+    peripherals.nfc.send_reply(model::Reply::Ok).await.unwrap();
     peripherals.nfc_finished.recv().await.unwrap();
 
     let events = only_requests(&mut events);
     pin_mut!(events);
 
     match events.next().await {
+        Some(model::Request::Cancel) => Err(Error::Canceled),
         Some(model::Request::SignPsbt(psbt)) => Ok(CurrentState::SignPsbt {
             psbt: psbt.into(),
             wallet: Rc::clone(wallet),
         }),
+        Some(model::Request::SignBatch(psbts))
+            if psbts.is_empty() || psbts.len() > model::MAX_BATCH_PSBTS =>
+        {
+            peripherals
+                .nfc
+                .send_reply(model::Reply::Error(alloc::format!(
+                    "Batches must contain between 1 and {} PSBTs",
+                    model::MAX_BATCH_PSBTS
+                )))
+                .await
+                .unwrap();
+            peripherals.nfc_finished.recv().await.unwrap();
+
+            Ok(CurrentState::Idle {
+                wallet: Rc::clone(wallet),
+            })
+        }
+        Some(model::Request::SignBatch(psbts)) => Ok(CurrentState::SignBatch {
+            psbts: psbts.into_iter().map(Into::into).collect(),
+            wallet: Rc::clone(wallet),
+        }),
         _ => {
             peripherals
                 .nfc
-                .send(model::Reply::UnexpectedMessage)
+                .send_reply(model::Reply::UnexpectedMessage)
                 .await
                 .unwrap();
             peripherals.nfc_finished.recv().await.unwrap();
@@ -333,18 +1037,29 @@ pub async fn handle_waiting_for_psbt(
     }
 }
 
-pub async fn handle_display_address_request(
+pub async fn handle_display_address_request<T: Transport>(
     wallet: &mut Rc<PortalWallet>,
+    keychain: model::KeychainKind,
     index: u32,
     resumable: checkpoint::Resumable,
     is_fast_boot: bool,
     mut events: impl Stream<Item = Event> + Unpin,
-    peripherals: &mut HandlerPeripherals,
+    peripherals: &mut HandlerPeripherals<T>,
 ) -> Result<CurrentState, Error> {
     log::info!("handle_display_address_request");
 
+    let (variant, label) = match keychain {
+        model::KeychainKind::External => (
+            checkpoint::CheckpointVariant::DisplayAddress(index),
+            "Address",
+        ),
+        model::KeychainKind::Internal => (
+            checkpoint::CheckpointVariant::DisplayInternalAddress(index),
+            "Change",
+        ),
+    };
     let mut checkpoint = checkpoint::Checkpoint::new_with_key(
-        checkpoint::CheckpointVariant::DisplayAddress(index),
+        variant,
         None,
         Some(resumable),
         checkpoint::Checkpoint::gen_key(&mut peripherals.rng),
@@ -352,7 +1067,7 @@ pub async fn handle_display_address_request(
     if !is_fast_boot {
         peripherals
             .nfc
-            .send(model::Reply::DelayedReply)
+            .send_reply(model::Reply::DelayedReply)
             .await
             .unwrap();
     }
@@ -360,7 +1075,7 @@ pub async fn handle_display_address_request(
     peripherals.tsc_enabled.enable();
 
     if let Some((state, draw)) = resumable.single_page_with_offset(0) {
-        let s = alloc::format!("Display\nAddress #{}?", index);
+        let s = alloc::format!("Display\n{} #{}?", label, index);
         let mut page = SummaryPage::new_with_threshold(&s, "HOLD BTN TO CONTINUE", 50);
         page.init_display(&mut peripherals.display)?;
         page.draw_to(&mut peripherals.display)?;
@@ -377,13 +1092,19 @@ pub async fn handle_display_address_request(
         .await?;
     }
 
-    let addr = Rc::get_mut(wallet)
-        .unwrap()
-        .get_address(bdk::wallet::AddressIndex::Peek(index));
+    let wallet_mut = Rc::get_mut(wallet).unwrap();
+    let addr = match keychain {
+        model::KeychainKind::External => {
+            wallet_mut.get_address(bdk::wallet::AddressIndex::Peek(index))
+        }
+        model::KeychainKind::Internal => {
+            wallet_mut.get_internal_address(bdk::wallet::AddressIndex::Peek(index))
+        }
+    };
     let addr = addr.to_string();
 
     if let Some((state, draw)) = resumable.single_page_with_offset(1) {
-        let message = alloc::format!("Address #{}", index);
+        let message = alloc::format!("{} #{}", label, index);
         let mut page = ShowScrollingAddressPage::new(&addr, &message, "HOLD BTN TO EXIT");
         page.init_display(&mut peripherals.display)?;
         page.draw_to(&mut peripherals.display)?;
@@ -402,7 +1123,7 @@ pub async fn handle_display_address_request(
 
     peripherals
         .nfc
-        .send(model::Reply::Address(addr))
+        .send_reply(model::Reply::Address(addr))
         .await
         .unwrap();
 
@@ -413,17 +1134,22 @@ pub async fn handle_display_address_request(
     })
 }
 
-pub async fn handle_public_descriptor_request(
+pub async fn handle_public_descriptor_request<T: Transport>(
     wallet: &mut Rc<PortalWallet>,
+    keychain: Option<model::KeychainKind>,
     resumable: checkpoint::Resumable,
     is_fast_boot: bool,
     mut events: impl Stream<Item = Event> + Unpin,
-    peripherals: &mut HandlerPeripherals,
+    peripherals: &mut HandlerPeripherals<T>,
 ) -> Result<CurrentState, Error> {
     log::info!("handle_public_descriptor_request");
 
+    let variant = match keychain {
+        None => checkpoint::CheckpointVariant::PublicDescriptor,
+        Some(keychain) => checkpoint::CheckpointVariant::ExportDescriptor(keychain),
+    };
     let mut checkpoint = checkpoint::Checkpoint::new_with_key(
-        checkpoint::CheckpointVariant::PublicDescriptor,
+        variant,
         None,
         Some(resumable),
         checkpoint::Checkpoint::gen_key(&mut peripherals.rng),
@@ -431,7 +1157,7 @@ pub async fn handle_public_descriptor_request(
     if !is_fast_boot {
         peripherals
             .nfc
-            .send(model::Reply::DelayedReply)
+            .send_reply(model::Reply::DelayedReply)
             .await
             .unwrap();
     }
@@ -455,24 +1181,35 @@ pub async fn handle_public_descriptor_request(
         .await?;
     }
 
-    let descriptor = wallet
-        .public_descriptor(bdk::KeychainKind::External)
-        .unwrap();
-    let descriptor = descriptor.to_string();
+    let reply = match keychain {
+        None => {
+            let descriptor = wallet
+                .public_descriptor(bdk::KeychainKind::External)
+                .unwrap();
+            let descriptor = descriptor.to_string();
 
-    let internal_descriptor = wallet
-        .public_descriptor(bdk::KeychainKind::Internal)
-        .unwrap();
-    let internal_descriptor = internal_descriptor.to_string();
+            let internal_descriptor = wallet
+                .public_descriptor(bdk::KeychainKind::Internal)
+                .unwrap();
+            let internal_descriptor = internal_descriptor.to_string();
 
-    peripherals
-        .nfc
-        .send(model::Reply::Descriptor {
-            external: descriptor,
-            internal: Some(internal_descriptor),
-        })
-        .await
-        .unwrap();
+            model::Reply::Descriptor {
+                external: descriptor,
+                internal: Some(internal_descriptor),
+            }
+        }
+        Some(keychain) => {
+            let keychain = match keychain {
+                model::KeychainKind::External => bdk::KeychainKind::External,
+                model::KeychainKind::Internal => bdk::KeychainKind::Internal,
+            };
+            // The `Display` impl of descriptors appends the checksum
+            let descriptor = wallet.public_descriptor(keychain).unwrap();
+            model::Reply::ExportedDescriptor(descriptor.to_string())
+        }
+    };
+
+    peripherals.nfc.send_reply(reply).await.unwrap();
 
     checkpoint.remove(&peripherals.rtc);
 
@@ -481,14 +1218,14 @@ pub async fn handle_public_descriptor_request(
     })
 }
 
-pub async fn handle_get_xpub_request(
+pub async fn handle_get_xpub_request<T: Transport>(
     wallet: &mut Rc<PortalWallet>,
     derivation_path: bip32::DerivationPath,
     resumable: checkpoint::Resumable,
     is_fast_boot: bool,
     encryption_key: [u8; 24],
     mut events: impl Stream<Item = Event> + Unpin,
-    peripherals: &mut HandlerPeripherals,
+    peripherals: &mut HandlerPeripherals<T>,
 ) -> Result<CurrentState, Error> {
     log::info!("handle_get_xpub_request");
 
@@ -507,7 +1244,7 @@ pub async fn handle_get_xpub_request(
 
         peripherals
             .nfc
-            .send(model::Reply::DelayedReply)
+            .send_reply(model::Reply::DelayedReply)
             .await
             .unwrap();
     }
@@ -560,9 +1297,30 @@ pub async fn handle_get_xpub_request(
         wallet.secp_ctx(),
     );
 
+    if let Some((state, draw)) = resumable.single_page_with_offset(1) {
+        match QrPage::new(xpub.as_bytes(), "HOLD BTN TO EXIT") {
+            Some(mut page) => {
+                page.init_display(&mut peripherals.display)?;
+                page.draw_to(&mut peripherals.display)?;
+                if draw {
+                    peripherals.display.flush()?;
+                }
+                manage_confirmation_loop_with_checkpoint(
+                    &mut events,
+                    peripherals,
+                    &mut page,
+                    &mut checkpoint,
+                    state,
+                )
+                .await?;
+            }
+            None => log::warn!("Xpub too long to show as a QR code"),
+        }
+    }
+
     peripherals
         .nfc
-        .send(model::Reply::Xpub { xpub, bsms })
+        .send_reply(model::Reply::Xpub { xpub, bsms })
         .await
         .unwrap();
 
@@ -573,7 +1331,7 @@ pub async fn handle_get_xpub_request(
     })
 }
 
-pub async fn handle_set_descriptor_request(
+pub async fn handle_set_descriptor_request<T: Transport>(
     wallet: &mut Rc<PortalWallet>,
     variant: SetDescriptorVariant,
     script_type: ScriptType,
@@ -582,7 +1340,7 @@ pub async fn handle_set_descriptor_request(
     is_fast_boot: bool,
     encryption_key: [u8; 24],
     mut events: impl Stream<Item = Event> + Unpin,
-    peripherals: &mut HandlerPeripherals,
+    peripherals: &mut HandlerPeripherals<T>,
 ) -> Result<CurrentState, Error> {
     let is_local_key = |key: &ExtendedKey| -> Result<bool, String> {
         let xpub = key.key.as_xpub().map_err(|_| "Invalid xpub".to_string())?;
@@ -704,7 +1462,11 @@ pub async fn handle_set_descriptor_request(
         Err(e) => {
             log::warn!("Checks failed: {}", e);
 
-            peripherals.nfc.send(model::Reply::Error(e)).await.unwrap();
+            peripherals
+                .nfc
+                .send_reply(model::Reply::Error(e))
+                .await
+                .unwrap();
             return Ok(CurrentState::Idle {
                 wallet: Rc::clone(wallet),
             });
@@ -725,7 +1487,7 @@ pub async fn handle_set_descriptor_request(
         // Also send the `DelayedReply` message if this is not a resumed state
         peripherals
             .nfc
-            .send(model::Reply::DelayedReply)
+            .send_reply(model::Reply::DelayedReply)
             .await
             .unwrap();
     }
@@ -938,7 +1700,7 @@ pub async fn handle_set_descriptor_request(
     )?;
     log::debug!("Config saved!");
 
-    peripherals.nfc.send(model::Reply::Ok).await.unwrap();
+    peripherals.nfc.send_reply(model::Reply::Ok).await.unwrap();
     checkpoint.remove(&peripherals.rtc);
 
     Ok(CurrentState::Idle {
@@ -973,6 +1735,12 @@ pub(crate) trait DescriptorMeta {
         psbt_output: &psbt::Output,
         secp: &'s SecpCtx,
     ) -> Option<DerivedDescriptor>;
+    fn derive_from_psbt_input<'s>(
+        &self,
+        psbt_input: &psbt::Input,
+        utxo: Option<&TxOut>,
+        secp: &'s SecpCtx,
+    ) -> Option<DerivedDescriptor>;
 }
 
 impl DescriptorMeta for ExtendedDescriptor {
@@ -1045,14 +1813,16 @@ impl DescriptorMeta for ExtendedDescriptor {
                 };
                 let derive_path = key_origins
                     .get_key_value(&root_fingerprint)
+                    // Hardened steps can't be derived from an xpub
+                    .filter(|_| xpub.wildcard != Wildcard::Hardened)
                     .and_then(|(fingerprint, (path, expected))| {
-                        xpub.matches(&(*fingerprint, (*path).clone()), secp)
+                        xpub_matches(xpub, &(*fingerprint, (*path).clone()), secp)
                             .zip(Some((path, expected)))
                     })
                     .and_then(|(prefix, (full_path, expected))| {
                         let derive_path = full_path
                             .into_iter()
-                            .skip(prefix.into_iter().count())
+                            .skip(prefix.len())
                             .cloned()
                             .collect::<bip32::DerivationPath>();
 
@@ -1078,7 +1848,7 @@ impl DescriptorMeta for ExtendedDescriptor {
 
                 match derive_path {
                     Some(path) if xpub.wildcard != Wildcard::None && path.len() == 1 => {
-                        // Ignore hardened wildcards
+                        // Always unhardened, `xpub_matches` checks it against the wildcard
                         if let bip32::ChildNumber::Normal { index } = path[0] {
                             path_found = Some(index);
                             return true;
@@ -1144,4 +1914,19 @@ impl DescriptorMeta for ExtendedDescriptor {
 
         None
     }
+    fn derive_from_psbt_input<'s>(
+        &self,
+        psbt_input: &psbt::Input,
+        utxo: Option<&TxOut>,
+        secp: &'s SecpCtx,
+    ) -> Option<DerivedDescriptor> {
+        let derived = self
+            .derive_from_hd_keypaths(&psbt_input.bip32_derivation, secp)
+            .or_else(|| self.derive_from_tap_key_origins(&psbt_input.tap_key_origins, secp))?;
+
+        match utxo {
+            Some(utxo) if derived.script_pubkey() != utxo.script_pubkey => None,
+            _ => Some(derived),
+        }
+    }
 }
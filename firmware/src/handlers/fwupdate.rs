@@ -24,19 +24,21 @@ use futures::prelude::*;
 
 use rtic_monotonics::systick::ExtU32;
 
-#[cfg(feature = "emulator")]
-use crate::emulator::flash;
 #[cfg(feature = "device")]
-use stm32l4xx_hal::{flash, flash::Read, flash::WriteErase, stm32};
+use stm32l4xx_hal::{flash::Read, flash::WriteErase, stm32};
 
 use bitcoin_hashes::{sha256, Hash, HashEngine};
 
 use minicbor::bytes::ByteArray;
 
-use gui::{FwUpdateProgressPage, SingleLineTextPage, SummaryPage};
+use gui::{SingleLineTextPage, SummaryPage};
+
+use model::fw_manifest::{validate_fw_size, FwError, FwManifest};
+use model::peripheral_config::DisplayStatus;
 
 use super::*;
 use crate::checkpoint;
+use crate::storage::{BankStatus, BankToFlash, FlashBank, UnlockedFlash};
 use crate::version;
 use crate::Error;
 
@@ -55,81 +57,6 @@ const CHECKPOINT_PAGE_INTERVAL: usize = 4;
 // #[cfg_attr(feature = "emulator", allow(dead_code))]
 // const FLASH_OPTKEY2: u32 = 0x4C5D_6E7F;
 
-#[cfg(feature = "device")]
-type UnlockedFlash<'a> = flash::FlashProgramming<'a>;
-#[cfg(feature = "emulator")]
-type UnlockedFlash<'a> = crate::emulator::flash::UnlockedFlash<'a>;
-
-#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-struct BankToFlash {
-    physical: FlashBank,
-}
-
-impl BankToFlash {
-    pub fn new(physical: FlashBank) -> Self {
-        BankToFlash { physical }
-    }
-
-    fn physical_bank_page(bank: FlashBank, page: usize) -> flash::FlashPage {
-        match bank {
-            FlashBank::Bank1 => flash::FlashPage(page),
-            FlashBank::Bank2 => flash::FlashPage(page + 256),
-        }
-    }
-
-    fn get_logical_address(&self, which: BankStatus, page: usize) -> usize {
-        let physical_bank = match which {
-            BankStatus::Active => FlashBank::Bank1,
-            BankStatus::Spare => FlashBank::Bank2,
-        };
-        Self::physical_bank_page(physical_bank, page).to_address()
-    }
-
-    fn get_physical_page(&self, which: BankStatus, page: usize) -> flash::FlashPage {
-        let physical_bank = match which {
-            BankStatus::Active => self.physical.opposite(),
-            BankStatus::Spare => self.physical,
-        };
-        Self::physical_bank_page(physical_bank, page)
-    }
-}
-
-#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-enum BankStatus {
-    Active,
-    Spare,
-}
-
-/// Flash bank to target for a read/write/erase operation
-///
-/// **NOTE**: unfortunately the meaning of `Bank1` and `Bank2` is not always consistent
-/// in the code: specifically, when peforming an erase operation the `FlashBank` refers
-/// to the actual physical bank being erased, no matter what bank is booted at the moment.
-///
-/// When performing a read or write operation `Bank1` refers to the currently-booted bank,
-/// while `Bank2` refers to the spare bank. This is because the stm32l4xx-hal crate writes
-/// directly to the flash memory address, and when using dual bank boot the "current bank"
-/// is always mapped at 0x0000_0000 and 0x0800_0000, independently of which physical bank
-/// is backing it.
-///
-/// A good rule of thumb is that when an API takes an address it uses the "relative",
-/// mapping-dependent bank, while when it takes a `FlashPage` it's probably using absolute
-/// addressing.
-#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-enum FlashBank {
-    Bank1,
-    Bank2,
-}
-
-impl FlashBank {
-    fn opposite(&self) -> Self {
-        match self {
-            FlashBank::Bank1 => FlashBank::Bank2,
-            FlashBank::Bank2 => FlashBank::Bank1,
-        }
-    }
-}
-
 #[derive(minicbor::Encode, minicbor::Decode)]
 struct Checkpoint {
     #[cbor(n(0))]
@@ -154,6 +81,7 @@ struct FwUpdater<'h> {
     bank_to_flash: BankToFlash,
     erase_window_start: Option<usize>,
     tail: [u8; version::TAIL_SIZE],
+    manifest: FwManifest,
 }
 
 impl<'h> FwUpdater<'h> {
@@ -163,6 +91,10 @@ impl<'h> FwUpdater<'h> {
         state: Option<checkpoint::FwUpdateState>,
         bank_to_flash: BankToFlash,
     ) -> Result<Self, Error> {
+        // Also checked when resuming, nothing must be erased for an image that can't fit
+        validate_fw_size(header.size.div_ceil(hw_common::PAGE_SIZE))
+            .map_err(|_| Error::InvalidFirmware)?;
+
         let checkpoint: Option<Checkpoint> = {
             if let Some(state) = state {
                 Some(Checkpoint {
@@ -274,36 +206,51 @@ impl<'h> FwUpdater<'h> {
 
             log::debug!("Mass-erase finished!");
 
+            // The new firmware reads the config and the settings from its own bank
             let mut buf = alloc::vec![0x00; hw_common::PAGE_SIZE];
+            for page in [crate::config::CONFIG_PAGE, crate::config::SETTINGS_PAGE] {
+                flash.read(
+                    bank_to_flash.get_logical_address(BankStatus::Active, page),
+                    &mut buf,
+                );
+
+                flash
+                    .erase_page(bank_to_flash.get_physical_page(BankStatus::Spare, page))
+                    .map_err(|_| Error::FlashError)?;
+                flash
+                    .write(
+                        bank_to_flash.get_logical_address(BankStatus::Spare, page),
+                        &buf,
+                    )
+                    .map_err(|_| Error::FlashError)?;
+            }
+            log::debug!("Configuration and settings copied successfully");
+        }
+
+        let page = checkpoint.as_ref().map(|ckpt| ckpt.next_page).unwrap_or(1);
+
+        let mut manifest = FwManifest::new(header.size, hw_common::PAGE_SIZE, **header.signature);
+        // Pages written before the checkpoint are hashed from flash, they are still covered by the
+        // root check during verification
+        let mut buf = alloc::vec![0x00; hw_common::PAGE_SIZE];
+        for p in 1..page {
             flash.read(
-                bank_to_flash.get_logical_address(BankStatus::Active, crate::config::CONFIG_PAGE),
+                bank_to_flash.get_logical_address(BankStatus::Spare, p),
                 &mut buf,
             );
-
-            flash
-                .erase_page(
-                    bank_to_flash.get_physical_page(BankStatus::Spare, crate::config::CONFIG_PAGE),
-                )
-                .map_err(|_| Error::FlashError)?;
-            flash
-                .write(
-                    bank_to_flash
-                        .get_logical_address(BankStatus::Spare, crate::config::CONFIG_PAGE),
-                    &buf,
-                )
-                .map_err(|_| Error::FlashError)?;
-            log::debug!("Configuration copied successfully");
+            manifest.record_page(p, &buf);
         }
 
         Ok(FwUpdater {
             header,
             hash,
-            page: checkpoint.as_ref().map(|ckpt| ckpt.next_page).unwrap_or(1),
+            page,
             bank_to_flash,
             erase_window_start: checkpoint.as_ref().and_then(|ckpt| ckpt.erase_from),
             tail: checkpoint
                 .map(|ckpt| ckpt.tail)
                 .unwrap_or([0u8; version::TAIL_SIZE]),
+            manifest,
         })
     }
 
@@ -396,6 +343,7 @@ impl<'h> FwUpdater<'h> {
             Some(x) => hw_common::PAGE_SIZE - x,
         };
 
+        self.manifest.record_page(self.page, data);
         self.page += 1;
         self.hash.input(&data[..data_end]);
 
@@ -423,6 +371,7 @@ impl<'h> FwUpdater<'h> {
         flash: &mut UnlockedFlash,
         header: &FwUpdateHeader,
         data: &[u8],
+        rtc: &crate::hw::Rtc,
     ) -> Result<(), Error> {
         let mut first_page_midstate = sha256::HashEngine::default();
         first_page_midstate.input(data);
@@ -436,8 +385,7 @@ impl<'h> FwUpdater<'h> {
         let hash = sha256::Hash::from_engine(self.hash.clone());
         log::debug!("FW hash: {:02X?}", hash);
 
-        let signing_key = secp256k1::XOnlyPublicKey::from_str(FIRMWARE_SIGNING_KEY)
-            .expect("Valid signing pubkey");
+        let signing_key = signing_key();
         let message = secp256k1::Message::from_slice(&hash).expect("Correct length");
         let signature = secp256k1::schnorr::Signature::from_slice(header.signature.deref().deref())
             .map_err(|_| Error::InvalidFirmware)?;
@@ -466,6 +414,10 @@ impl<'h> FwUpdater<'h> {
             log::warn!("Invalid version or variant: variant {:02X} vs {:02X}(current), version {} vs {}(current)", parsed.variant, version::CURRENT_VARIANT, parsed.version, version::CURRENT_VERSION);
             return Err(Error::InvalidFirmware);
         }
+        if let Err(e) = version::check_fw_version(rtc, parsed.version) {
+            log::warn!("Refusing firmware rollback: {:?}", e);
+            return Err(Error::InvalidFirmware);
+        }
 
         // Write first page
         flash
@@ -477,10 +429,21 @@ impl<'h> FwUpdater<'h> {
                 data,
             )
             .map_err(|_| Error::FlashError)?;
+        self.manifest.record_page(0, data);
+
+        verify_firmware_image(flash, &self.bank_to_flash, &self.manifest).map_err(|e| {
+            log::warn!("Firmware image verification failed: {:?}", e);
+            Error::InvalidFirmware
+        })?;
+        log::info!("Firmware image verified");
 
         Ok(())
     }
 
+    /// The emulator doesn't emulate the option bytes, so the boot sector of the booted bank is
+    /// wiped to make it boot from the other one. On the device the bank is swapped with
+    /// [`crate::hw::swap_active_bank`] right after the image is verified.
+    #[cfg(feature = "emulator")]
     fn switch_and_reboot(self, flash: &mut UnlockedFlash) -> ! {
         {
             // Wipe the boot sector of the booted bank to force the switch
@@ -492,20 +455,49 @@ impl<'h> FwUpdater<'h> {
     }
 }
 
-pub async fn handle_begin_fw_update(
+fn signing_key() -> secp256k1::XOnlyPublicKey {
+    secp256k1::XOnlyPublicKey::from_str(FIRMWARE_SIGNING_KEY).expect("Valid signing pubkey")
+}
+
+/// Read back the image written to the spare bank and check it against `manifest`
+fn verify_firmware_image(
+    flash: &mut UnlockedFlash,
+    bank_to_flash: &BankToFlash,
+    manifest: &FwManifest,
+) -> Result<(), FwError> {
+    manifest.verify_image(&signing_key(), |page, buf| {
+        flash.read(
+            bank_to_flash.get_logical_address(BankStatus::Spare, page),
+            buf,
+        )
+    })
+}
+
+/// Ignore the display errors if it already failed to initialize, see
+/// [`super::handle_display_failure`]
+fn allow_display_failure(status: DisplayStatus, result: Result<(), Error>) -> Result<(), Error> {
+    match result {
+        Err(Error::Display(_)) if status == DisplayStatus::Failed => Ok(()),
+        result => result,
+    }
+}
+
+pub async fn handle_begin_fw_update<T: Transport>(
     header: &FwUpdateHeader,
     fast_boot: Option<(checkpoint::FwUpdateState, [u8; 24])>,
     mut events: impl Stream<Item = Event> + Unpin,
-    peripherals: &mut HandlerPeripherals,
+    peripherals: &mut HandlerPeripherals<T>,
 ) -> Result<CurrentState, Error> {
     log::info!("handle_begin_fw_update");
 
+    let display_status = peripherals.display_status;
+
     let (state, fb_key) = match fast_boot {
         None => {
-            if header.size > hw_common::MAX_FW_PAGES * hw_common::PAGE_SIZE {
+            if validate_fw_size(header.size.div_ceil(hw_common::PAGE_SIZE)).is_err() {
                 peripherals
                     .nfc
-                    .send(model::Reply::Error("Firmware file too big".into()))
+                    .send_reply(model::Reply::Error("Firmware file too big".into()))
                     .await
                     .unwrap();
                 return Err(Error::InvalidFirmware);
@@ -513,14 +505,17 @@ pub async fn handle_begin_fw_update(
 
             peripherals
                 .nfc
-                .send(model::Reply::DelayedReply)
+                .send_reply(model::Reply::DelayedReply)
                 .await
                 .unwrap();
 
             let mut page = SummaryPage::new_with_threshold("Update FW?", "HOLD BTN TO BEGIN", 70);
             page.init_display(&mut peripherals.display)?;
             page.draw_to(&mut peripherals.display)?;
-            peripherals.display.flush()?;
+            allow_display_failure(
+                display_status,
+                peripherals.display.flush().map_err(Into::into),
+            )?;
 
             peripherals.tsc_enabled.enable();
             manage_confirmation_loop(&mut events, peripherals, &mut page).await?;
@@ -547,10 +542,12 @@ pub async fn handle_begin_fw_update(
     };
     let mut drop_next_message = state.is_some();
 
-    let mut page = FwUpdateProgressPage::new(header.size as u32);
-    page.init_display(&mut peripherals.display)?;
-    page.draw_to(&mut peripherals.display)?;
-    peripherals.display.flush()?;
+    let progress = |page: usize| (hw_common::PAGE_SIZE * page) as f32 / header.size as f32;
+    let mut progress_bar = ProgressBar::new("UPDATE IN PROGRESS");
+    allow_display_failure(
+        display_status,
+        progress_bar.draw_progress(&mut peripherals.display, 0.0),
+    )?;
 
     let events = only_requests(&mut events);
     pin_mut!(events);
@@ -568,21 +565,20 @@ pub async fn handle_begin_fw_update(
     #[cfg(feature = "emulator")]
     let mut lock = peripherals.flash.unlock();
 
-    let bank_to_flash = match peripherals.flash.fb_mode {
-        false => FlashBank::Bank2,
-        true => FlashBank::Bank1,
-    };
+    let bank_to_flash = FlashBank::spare(peripherals.flash.fb_mode);
     log::debug!("Flashing to bank: {:?}", bank_to_flash);
     let mut updater = FwUpdater::new(&mut lock, header, state, BankToFlash::new(bank_to_flash))?;
-    page.add_confirm((hw_common::PAGE_SIZE * updater.page) as u32); // account for the potential checkpoint
-    page.draw_to(&mut peripherals.display)?;
-    peripherals.display.flush()?;
+    // account for the potential checkpoint
+    allow_display_failure(
+        display_status,
+        progress_bar.draw_progress(&mut peripherals.display, progress(updater.page)),
+    )?;
 
     if !drop_next_message {
         // Re-request page if we are not resuming via fastboot
         peripherals
             .nfc
-            .send(model::Reply::NextPage(updater.page))
+            .send_reply(model::Reply::NextPage(updater.page))
             .await
             .unwrap();
         peripherals.nfc_finished.recv().await.unwrap();
@@ -603,25 +599,32 @@ pub async fn handle_begin_fw_update(
                 }
                 peripherals
                     .nfc
-                    .send(model::Reply::NextPage(updater.page))
+                    .send_reply(model::Reply::NextPage(updater.page))
                     .await
                     .unwrap();
                 peripherals.nfc_finished.recv().await.unwrap();
 
-                page.add_confirm(hw_common::PAGE_SIZE as u32);
-                page.draw_to(&mut peripherals.display)?;
-                peripherals.display.flush()?;
+                allow_display_failure(
+                    display_status,
+                    progress_bar.draw_progress(&mut peripherals.display, progress(updater.page)),
+                )?;
             }
             Some(model::Request::CompleteFwUpdate(data)) => {
-                updater.finish(&mut lock, &header, data.deref().deref())?;
-                peripherals.nfc.send(model::Reply::Ok).await.unwrap();
+                updater.finish(&mut lock, &header, data.deref().deref(), &peripherals.rtc)?;
+                #[cfg(feature = "device")]
+                {
+                    // Relock the flash before touching the option bytes
+                    drop(lock);
+                    crate::hw::swap_active_bank(&mut peripherals.flash)?;
+                }
+                peripherals.nfc.send_reply(model::Reply::Ok).await.unwrap();
 
                 break;
             }
             _ => {
                 peripherals
                     .nfc
-                    .send(model::Reply::UnexpectedMessage)
+                    .send_reply(model::Reply::UnexpectedMessage)
                     .await
                     .unwrap();
                 peripherals.nfc_finished.recv().await.unwrap();
@@ -634,11 +637,17 @@ pub async fn handle_begin_fw_update(
     let page = SingleLineTextPage::new("UPDATE COMPLETE");
     page.init_display(&mut peripherals.display)?;
     page.draw_to(&mut peripherals.display)?;
-    peripherals.display.flush()?;
+    allow_display_failure(
+        display_status,
+        peripherals.display.flush().map_err(Into::into),
+    )?;
 
     rtic_monotonics::systick::Systick::delay(1000_u32.millis()).await;
 
     peripherals.nfc_finished.recv().await.unwrap();
 
+    #[cfg(feature = "device")]
+    crate::hw::reload_option_bytes();
+    #[cfg(feature = "emulator")]
     updater.switch_and_reboot(&mut lock);
 }
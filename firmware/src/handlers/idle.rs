@@ -19,33 +19,52 @@ use alloc::rc::Rc;
 
 use futures::prelude::*;
 
-use gui::InitialPage;
+use gui::{GenericTwoLinePage, InitialPage, LoadingPage};
 use model::{DeviceInfo, Reply};
 
 use super::*;
 use crate::Error;
 
-pub async fn handle_idle(
+pub async fn handle_idle<T: Transport>(
     wallet: &mut Rc<PortalWallet>,
     mut events: impl Stream<Item = Event> + Unpin,
-    peripherals: &mut HandlerPeripherals,
+    peripherals: &mut HandlerPeripherals<T>,
 ) -> Result<CurrentState, Error> {
     log::info!("handle_idle");
 
-    let page = InitialPage::new("Portal ready", "");
+    let phrase = crate::config::read_anti_phishing_phrase(&mut peripherals.flash, &wallet.xprv)
+        .unwrap_or_default();
+    let page = InitialPage::new("Portal ready", &phrase);
     page.init_display(&mut peripherals.display)?;
     page.draw_to(&mut peripherals.display)?;
     peripherals.display.flush()?;
 
-    let events = only_requests(&mut events);
-    pin_mut!(events);
-
     loop {
-        match events.next().await {
+        let request = match events.next().await {
+            // Dim the display while nobody is holding a phone to the device
+            Some(Event::Tick) => {
+                let present = peripherals.field.is_present();
+                peripherals
+                    .power
+                    .on_field_change(present, &mut peripherals.display)?;
+                continue;
+            }
+            Some(Event::Input(_)) => continue,
+            Some(Event::Request(request)) => {
+                // Requests can only come through the field
+                peripherals
+                    .power
+                    .on_field_change(true, &mut peripherals.display)?;
+                Some(request)
+            }
+            None => None,
+        };
+
+        match request {
             Some(model::Request::GetInfo) => {
                 peripherals
                     .nfc
-                    .send(Reply::Info(DeviceInfo::new_unlocked_initialized(
+                    .send_reply(Reply::Info(DeviceInfo::new_unlocked_initialized(
                         wallet.network(),
                         wallet.xprv.fingerprint(wallet.secp_ctx()).into_bytes(),
                         env!("CARGO_PKG_VERSION"),
@@ -55,8 +74,95 @@ pub async fn handle_idle(
                 peripherals.nfc_finished.recv().await.unwrap();
                 continue;
             }
+            Some(model::Request::GetFingerprint) => {
+                peripherals
+                    .nfc
+                    .send_reply(Reply::Fingerprint(
+                        wallet.xprv.fingerprint(wallet.secp_ctx()).to_bytes(),
+                    ))
+                    .await
+                    .unwrap();
+                peripherals.nfc_finished.recv().await.unwrap();
+                continue;
+            }
+            Some(model::Request::SetBrightness(level)) => {
+                let reply = match hw_common::set_display_brightness(&mut peripherals.display, level)
+                {
+                    Ok(brightness) => {
+                        peripherals.power.set_brightness(brightness);
+                        let mut settings = crate::config::read_settings(&mut peripherals.flash);
+                        settings.brightness = Some(level);
+                        crate::config::write_settings(&mut peripherals.flash, &settings)?;
+
+                        Reply::Ok
+                    }
+                    Err(Error::InvalidSetting) => Reply::Error("Invalid brightness".into()),
+                    Err(e) => return Err(e),
+                };
+                peripherals.nfc.send_reply(reply).await.unwrap();
+                peripherals.nfc_finished.recv().await.unwrap();
+                continue;
+            }
+            Some(model::Request::SetRotation(rotation)) => {
+                hw_common::set_display_rotation(&mut peripherals.display, rotation)?;
+
+                let mut settings = crate::config::read_settings(&mut peripherals.flash);
+                settings.rotation = Some(rotation);
+                crate::config::write_settings(&mut peripherals.flash, &settings)?;
+
+                // Redraw with the new orientation
+                page.init_display(&mut peripherals.display)?;
+                page.draw_to(&mut peripherals.display)?;
+                peripherals.display.flush()?;
+
+                peripherals.nfc.send_reply(Reply::Ok).await.unwrap();
+                peripherals.nfc_finished.recv().await.unwrap();
+                continue;
+            }
+            Some(model::Request::SetAntiPhishingPhrase(phrase))
+                if phrase.chars().count() > model::MAX_ANTI_PHISHING_LEN =>
+            {
+                peripherals
+                    .nfc
+                    .send_reply(Reply::Error("Phrase too long".into()))
+                    .await
+                    .unwrap();
+                peripherals.nfc_finished.recv().await.unwrap();
+                continue;
+            }
+            Some(model::Request::SetAntiPhishingPhrase(phrase)) => {
+                break Ok(CurrentState::SetAntiPhishingPhrase {
+                    wallet: Rc::clone(wallet),
+                    phrase,
+                });
+            }
+            Some(model::Request::ExtractTx(psbt)) => {
+                let reply = match super::bitcoin::extract_tx(&psbt) {
+                    Ok(tx) => Reply::RawTx(tx.into()),
+                    Err(e) => Reply::Error(e.into()),
+                };
+                peripherals.nfc.send_reply(reply).await.unwrap();
+                peripherals.nfc_finished.recv().await.unwrap();
+                continue;
+            }
+            Some(model::Request::Cancel) => {
+                // Nothing in progress
+                peripherals.nfc.send_reply(Reply::Canceled).await.unwrap();
+                peripherals.nfc_finished.recv().await.unwrap();
+                continue;
+            }
             Some(model::Request::DisplayAddress(index)) => {
                 break Ok(CurrentState::DisplayAddress {
+                    keychain: model::KeychainKind::External,
+                    index,
+                    wallet: Rc::clone(wallet),
+                    resumable: checkpoint::Resumable::fresh(),
+                    is_fast_boot: false,
+                });
+            }
+            Some(model::Request::DisplayKeychainAddress { keychain, index }) => {
+                break Ok(CurrentState::DisplayAddress {
+                    keychain,
                     index,
                     wallet: Rc::clone(wallet),
                     resumable: checkpoint::Resumable::fresh(),
@@ -71,6 +177,15 @@ pub async fn handle_idle(
             Some(model::Request::PublicDescriptor) => {
                 break Ok(CurrentState::PublicDescriptor {
                     wallet: Rc::clone(wallet),
+                    keychain: None,
+                    resumable: checkpoint::Resumable::fresh(),
+                    is_fast_boot: false,
+                });
+            }
+            Some(model::Request::ExportDescriptor { keychain }) => {
+                break Ok(CurrentState::PublicDescriptor {
+                    wallet: Rc::clone(wallet),
+                    keychain: Some(keychain),
                     resumable: checkpoint::Resumable::fresh(),
                     is_fast_boot: false,
                 });
@@ -99,6 +214,9 @@ pub async fn handle_idle(
                     encryption_key: checkpoint::Checkpoint::gen_key(&mut peripherals.rng),
                 });
             }
+            Some(model::Request::FactoryReset) => {
+                break Ok(CurrentState::FactoryReset);
+            }
             Some(model::Request::BeginFwUpdate(header)) => {
                 break Ok(CurrentState::UpdatingFw {
                     header,
@@ -108,7 +226,7 @@ pub async fn handle_idle(
             Some(_) => {
                 peripherals
                     .nfc
-                    .send(model::Reply::UnexpectedMessage)
+                    .send_reply(model::Reply::UnexpectedMessage)
                     .await
                     .unwrap();
                 peripherals.nfc_finished.recv().await.unwrap();
@@ -118,3 +236,77 @@ pub async fn handle_idle(
         }
     }
 }
+
+pub async fn handle_set_anti_phishing_phrase<T: Transport>(
+    wallet: &mut Rc<PortalWallet>,
+    phrase: &str,
+    mut events: impl Stream<Item = Event> + Unpin,
+    peripherals: &mut HandlerPeripherals<T>,
+) -> Result<CurrentState, Error> {
+    log::info!("handle_set_anti_phishing_phrase");
+
+    let (message, shown) = match phrase.is_empty() {
+        true => ("Remove phrase?", "Anti-phishing"),
+        false => ("Set phrase?", phrase),
+    };
+    let mut page = GenericTwoLinePage::new(message, shown, "HOLD BTN TO CONFIRM", 100);
+    page.init_display(&mut peripherals.display)?;
+    page.draw_to(&mut peripherals.display)?;
+    peripherals.display.flush()?;
+
+    peripherals.tsc_enabled.enable();
+    manage_confirmation_loop(&mut events, peripherals, &mut page).await?;
+    peripherals.tsc_enabled.disable();
+
+    crate::config::write_anti_phishing_phrase(
+        &mut peripherals.flash,
+        &wallet.xprv,
+        phrase,
+        &mut peripherals.rng,
+    )?;
+
+    peripherals.nfc.send_reply(Reply::Ok).await.unwrap();
+    peripherals.nfc_finished.recv().await.unwrap();
+
+    Ok(CurrentState::Idle {
+        wallet: Rc::clone(wallet),
+    })
+}
+
+pub async fn handle_factory_reset<T: Transport>(
+    mut events: impl Stream<Item = Event> + Unpin,
+    peripherals: &mut HandlerPeripherals<T>,
+) -> Result<CurrentState, Error> {
+    log::info!("handle_factory_reset");
+
+    let mut page = GenericTwoLinePage::new(
+        "Factory reset?",
+        "Erases wallet",
+        "HOLD BTN TO CONFIRM",
+        100,
+    );
+    page.init_display(&mut peripherals.display)?;
+    page.draw_to(&mut peripherals.display)?;
+    peripherals.display.flush()?;
+
+    peripherals.tsc_enabled.enable();
+    manage_confirmation_loop(&mut events, peripherals, &mut page).await?;
+    peripherals.tsc_enabled.disable();
+
+    let page = LoadingPage::new();
+    page.init_display(&mut peripherals.display)?;
+    page.draw_to(&mut peripherals.display)?;
+    peripherals.display.flush()?;
+
+    crate::config::factory_reset(
+        &mut peripherals.flash,
+        &peripherals.rtc,
+        &mut peripherals.rng,
+    )?;
+
+    peripherals.nfc.send_reply(Reply::Ok).await.unwrap();
+    peripherals.nfc_finished.recv().await.unwrap();
+
+    // Reads back the empty config and starts over as a new device
+    Ok(CurrentState::POR)
+}
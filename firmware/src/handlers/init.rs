@@ -17,7 +17,6 @@
 
 use core::str::FromStr;
 
-use alloc::string::ToString;
 use futures::prelude::*;
 
 use rand::RngCore;
@@ -25,7 +24,7 @@ use rand::RngCore;
 use gui::{ConfirmPairCodePage, SingleLineTextPage};
 use model::{
     Entropy, ExtendedKey, InitializedConfig, MultisigKey, ScriptType, UnlockedConfig,
-    UnverifiedConfig, WalletDescriptor,
+    UnverifiedConfig,
 };
 
 use bdk::bitcoin::util::bip32;
@@ -262,8 +261,8 @@ impl TryIntoCurrentState for Config {
     }
 }
 
-pub async fn handle_por(
-    peripherals: &mut HandlerPeripherals,
+pub async fn handle_por<T: Transport>(
+    peripherals: &mut HandlerPeripherals<T>,
     fast_boot: bool,
 ) -> Result<CurrentState, Error> {
     if !fast_boot {
@@ -314,9 +313,9 @@ fn read_serial() -> alloc::string::String {
     Default::default()
 }
 
-pub async fn handle_init(
+pub async fn handle_init<T: Transport>(
     mut events: impl Stream<Item = Event> + Unpin,
-    peripherals: &mut HandlerPeripherals,
+    peripherals: &mut HandlerPeripherals<T>,
 ) -> Result<CurrentState, Error> {
     let serial = read_serial();
 
@@ -333,7 +332,7 @@ pub async fn handle_init(
             Some(model::Request::GetInfo) => {
                 peripherals
                     .nfc
-                    .send(model::Reply::Info(DeviceInfo::new_locked_uninitialized(
+                    .send_reply(model::Reply::Info(DeviceInfo::new_locked_uninitialized(
                         env!("CARGO_PKG_VERSION"),
                     )))
                     .await
@@ -345,22 +344,26 @@ pub async fn handle_init(
                 num_words,
                 network,
                 password,
+                passphrase,
             }) => {
                 break Ok(CurrentState::GenerateSeed {
                     num_words,
                     network,
                     password,
+                    passphrase,
                 });
             }
             Some(model::Request::SetMnemonic {
                 mnemonic,
                 network,
                 password,
+                passphrase,
             }) => {
                 break Ok(CurrentState::ImportSeed {
                     mnemonic,
                     network,
                     password,
+                    passphrase,
                 });
             }
             #[cfg(feature = "emulator")]
@@ -373,7 +376,7 @@ pub async fn handle_init(
             Some(_) => {
                 peripherals
                     .nfc
-                    .send(model::Reply::UnexpectedMessage)
+                    .send_reply(model::Reply::UnexpectedMessage)
                     .await
                     .unwrap();
                 peripherals.nfc_finished.recv().await.unwrap();
@@ -384,10 +387,10 @@ pub async fn handle_init(
     }
 }
 
-pub async fn handle_locked(
+pub async fn handle_locked<T: Transport>(
     config: InitializedConfig,
     mut events: impl Stream<Item = Event> + Unpin,
-    peripherals: &mut HandlerPeripherals,
+    peripherals: &mut HandlerPeripherals<T>,
 ) -> Result<CurrentState, Error> {
     let page = SingleLineTextPage::new("LOCKED");
     page.init_display(&mut peripherals.display)?;
@@ -402,7 +405,7 @@ pub async fn handle_locked(
             Some(model::Request::GetInfo) => {
                 peripherals
                     .nfc
-                    .send(model::Reply::Info(DeviceInfo::new_locked_initialized(
+                    .send_reply(model::Reply::Info(DeviceInfo::new_locked_initialized(
                         config.network,
                         env!("CARGO_PKG_VERSION"),
                     )))
@@ -415,7 +418,7 @@ pub async fn handle_locked(
                 if !config.pair_code.check(&password) {
                     peripherals
                         .nfc
-                        .send(model::Reply::WrongPassword)
+                        .send_reply(model::Reply::WrongPassword)
                         .await
                         .unwrap();
                     peripherals.nfc_finished.recv().await.unwrap();
@@ -435,7 +438,7 @@ pub async fn handle_locked(
                     .cached_xprv
                     .as_xprv()
                     .map_err(map_err_config)?;
-                peripherals.nfc.send(model::Reply::Ok).await.unwrap();
+                peripherals.nfc.send_reply(model::Reply::Ok).await.unwrap();
 
                 // Set key for fastboot
                 if let Some(key) = unlocked.get_key() {
@@ -446,8 +449,17 @@ pub async fn handle_locked(
                     wallet: Rc::new(make_wallet_from_xprv(xprv, unlocked.network, unlocked)?),
                 });
             }
+            // Allowed without the password, so that a device with a forgotten password can be
+            // reused. The user still has to confirm it on the device.
+            Some(model::Request::FactoryReset) => {
+                break Ok(CurrentState::FactoryReset);
+            }
             Some(_) => {
-                peripherals.nfc.send(model::Reply::Locked).await.unwrap();
+                peripherals
+                    .nfc
+                    .send_reply(model::Reply::Locked)
+                    .await
+                    .unwrap();
                 peripherals.nfc_finished.recv().await.unwrap();
                 continue;
             }
@@ -456,10 +468,10 @@ pub async fn handle_locked(
     }
 }
 
-pub async fn display_mnemonic(
+pub async fn display_mnemonic<T: Transport>(
     mut config: UnverifiedConfig,
     mut events: impl Stream<Item = Event> + Unpin,
-    peripherals: &mut HandlerPeripherals,
+    peripherals: &mut HandlerPeripherals<T>,
 ) -> Result<CurrentState, Error> {
     peripherals.tsc_enabled.enable();
 
@@ -498,7 +510,7 @@ pub async fn display_mnemonic(
     let (initialized, unlocked, xprv) = config.upgrade(salt);
     config::write_config(&mut peripherals.flash, &Config::Initialized(initialized))?;
 
-    peripherals.nfc.send(model::Reply::Ok).await.unwrap();
+    peripherals.nfc.send_reply(model::Reply::Ok).await.unwrap();
     peripherals.nfc_finished.recv().await.unwrap();
 
     Ok(CurrentState::Idle {
@@ -506,9 +518,9 @@ pub async fn display_mnemonic(
     })
 }
 
-async fn save_unverified_config(
+async fn save_unverified_config<T: Transport>(
     unverified_config: UnverifiedConfig,
-    peripherals: &mut HandlerPeripherals,
+    peripherals: &mut HandlerPeripherals<T>,
 ) -> Result<UnverifiedConfig, Error> {
     let config = Config::Unverified(unverified_config);
     config::write_config(&mut peripherals.flash, &config)?;
@@ -520,12 +532,13 @@ async fn save_unverified_config(
     Ok(unverified_config)
 }
 
-pub async fn handle_generate_seed(
+pub async fn handle_generate_seed<T: Transport>(
     num_words: model::NumWordsMnemonic,
     network: Network,
     password: Option<&str>,
+    passphrase: Option<&str>,
     events: impl Stream<Item = Event> + Unpin,
-    peripherals: &mut HandlerPeripherals,
+    peripherals: &mut HandlerPeripherals<T>,
 ) -> Result<CurrentState, Error> {
     let page = GeneratingMnemonicPage::new(num_words);
     page.init_display(&mut peripherals.display)?;
@@ -539,27 +552,25 @@ pub async fn handle_generate_seed(
     };
     rand_chacha::rand_core::RngCore::fill_bytes(&mut peripherals.rng, entropy);
 
-    let descriptor = WalletDescriptor::make_bip84(network);
-
-    let unverified_config = UnverifiedConfig {
-        entropy: Entropy {
+    let unverified_config = UnverifiedConfig::new(
+        Entropy {
             bytes: alloc::vec::Vec::from(entropy).into(),
         },
         network,
-        pair_code: password.map(ToString::to_string),
-        descriptor,
-        page: 0,
-    };
+        password,
+        passphrase,
+    );
     let unverified_config = save_unverified_config(unverified_config, peripherals).await?;
     display_mnemonic(unverified_config, events, peripherals).await
 }
 
-pub async fn handle_import_seed(
+pub async fn handle_import_seed<T: Transport>(
     mnemonic: &str,
     network: Network,
     password: Option<&str>,
+    passphrase: Option<&str>,
     events: impl Stream<Item = Event> + Unpin,
-    peripherals: &mut HandlerPeripherals,
+    peripherals: &mut HandlerPeripherals<T>,
 ) -> Result<CurrentState, Error> {
     let page = LoadingPage::new();
     page.init_display(&mut peripherals.display)?;
@@ -570,25 +581,22 @@ pub async fn handle_import_seed(
     let (entropy, len) = mnemonic.to_entropy_array();
     let entropy = &entropy[..len];
 
-    let descriptor = WalletDescriptor::make_bip84(network);
-
-    let unverified_config = UnverifiedConfig {
-        entropy: Entropy {
+    let unverified_config = UnverifiedConfig::new(
+        Entropy {
             bytes: alloc::vec::Vec::from(entropy).into(),
         },
         network,
-        pair_code: password.map(ToString::to_string),
-        descriptor,
-        page: 0,
-    };
+        password,
+        passphrase,
+    );
     let unverified_config = save_unverified_config(unverified_config, peripherals).await?;
     display_mnemonic(unverified_config, events, peripherals).await
 }
 
-pub async fn handle_unverified_config(
+pub async fn handle_unverified_config<T: Transport>(
     config: UnverifiedConfig,
     mut events: impl Stream<Item = Event> + Unpin,
-    peripherals: &mut HandlerPeripherals,
+    peripherals: &mut HandlerPeripherals<T>,
 ) -> Result<CurrentState, Error> {
     let page = LoadingPage::new();
     page.init_display(&mut peripherals.display)?;
@@ -604,7 +612,7 @@ pub async fn handle_unverified_config(
                 Some(model::Request::GetInfo) => {
                     peripherals
                         .nfc
-                        .send(model::Reply::Info(DeviceInfo::new_unverified_config(
+                        .send_reply(model::Reply::Info(DeviceInfo::new_unverified_config(
                             config.network,
                             config.pair_code.is_some(),
                             env!("CARGO_PKG_VERSION"),
@@ -617,7 +625,7 @@ pub async fn handle_unverified_config(
                 Some(model::Request::Resume) => {
                     peripherals
                         .nfc
-                        .send(model::Reply::DelayedReply)
+                        .send_reply(model::Reply::DelayedReply)
                         .await
                         .unwrap();
                     break;
@@ -625,7 +633,7 @@ pub async fn handle_unverified_config(
                 Some(_) => {
                     peripherals
                         .nfc
-                        .send(model::Reply::Unverified)
+                        .send_reply(model::Reply::Unverified)
                         .await
                         .unwrap();
                     peripherals.nfc_finished.recv().await.unwrap();
@@ -17,13 +17,13 @@
 
 use alloc::rc::Rc;
 use alloc::string::String;
-use core::cell::RefCell;
 
 use futures::pin_mut;
 use futures::prelude::*;
 
 use gui::{ConfirmBarPage, ErrorPage, MainContent, Page};
 use model::bitcoin::util::bip32;
+use model::transport::Transport;
 use model::{FwUpdateHeader, NumWordsMnemonic, Reply};
 
 use crate::{checkpoint, hw, hw_common, Error};
@@ -78,12 +78,14 @@ pub enum CurrentState {
         num_words: NumWordsMnemonic,
         network: bdk::bitcoin::Network,
         password: Option<String>,
+        passphrase: Option<String>,
     },
     /// Importing seed
     ImportSeed {
         mnemonic: String,
         network: bdk::bitcoin::Network,
         password: Option<String>,
+        passphrase: Option<String>,
     },
     /// Device ready
     Idle { wallet: Rc<PortalWallet> },
@@ -94,25 +96,38 @@ pub enum CurrentState {
         wallet: Rc<PortalWallet>,
         psbt: alloc::vec::Vec<u8>,
     },
+    /// Sign a batch of PSBTs with a single confirmation
+    SignBatch {
+        wallet: Rc<PortalWallet>,
+        psbts: alloc::vec::Vec<alloc::vec::Vec<u8>>,
+    },
     /// Confirm sign request
     ConfirmSignPsbt {
         wallet: Rc<PortalWallet>,
         outputs: alloc::vec::Vec<(checkpoint::CborAddress, u64)>,
+        warnings: checkpoint::SignWarnings,
         fees: u64,
         sig_bytes: alloc::vec::Vec<u8>,
         resumable: checkpoint::Resumable,
         encryption_key: [u8; 24],
     },
+    /// Confirm a new anti-phishing phrase
+    SetAntiPhishingPhrase {
+        wallet: Rc<PortalWallet>,
+        phrase: String,
+    },
     /// Display an address
     DisplayAddress {
         wallet: Rc<PortalWallet>,
+        keychain: model::KeychainKind,
         index: u32,
         resumable: checkpoint::Resumable,
         is_fast_boot: bool,
     },
-    /// Request the public descriptor
+    /// Request the public descriptor, either both keychains or a single one
     PublicDescriptor {
         wallet: Rc<PortalWallet>,
+        keychain: Option<model::KeychainKind>,
         resumable: checkpoint::Resumable,
         is_fast_boot: bool,
     },
@@ -134,6 +149,8 @@ pub enum CurrentState {
         is_fast_boot: bool,
         encryption_key: [u8; 24],
     },
+    /// Confirm and perform a factory reset
+    FactoryReset,
     /// Updating firmware
     UpdatingFw {
         header: FwUpdateHeader,
@@ -143,6 +160,25 @@ pub enum CurrentState {
     Error,
 }
 
+impl CurrentState {
+    /// The wallet, for the states of an unlocked device
+    fn wallet(&self) -> Option<&Rc<PortalWallet>> {
+        match self {
+            CurrentState::Idle { wallet }
+            | CurrentState::WaitingForPsbt { wallet }
+            | CurrentState::SignPsbt { wallet, .. }
+            | CurrentState::SignBatch { wallet, .. }
+            | CurrentState::ConfirmSignPsbt { wallet, .. }
+            | CurrentState::SetAntiPhishingPhrase { wallet, .. }
+            | CurrentState::DisplayAddress { wallet, .. }
+            | CurrentState::PublicDescriptor { wallet, .. }
+            | CurrentState::SetDescriptor { wallet, .. }
+            | CurrentState::GetXpub { wallet, .. } => Some(wallet),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Event {
     Tick,
@@ -150,14 +186,50 @@ pub enum Event {
     Request(model::Request),
 }
 
-pub struct HandlerPeripherals {
-    pub nfc: hw_common::ChannelSender<Reply>,
+pub struct HandlerPeripherals<T> {
+    /// Also the source of the requests in the event stream, see [`Transport`]
+    pub nfc: Rc<T>,
     pub nfc_finished: hw_common::ChannelReceiver<()>,
     pub display: hw::Display,
+    pub display_status: model::peripheral_config::DisplayStatus,
     pub rng: rand_chacha::ChaCha20Rng,
     pub flash: hw::Flash,
     pub rtc: hw::Rtc,
     pub tsc_enabled: hw_common::TscEnable,
+    /// Updated by the NFC task, applied to the display through [`Self::power`]
+    pub field: hw_common::FieldStatus,
+    pub power: hw_common::PowerManager,
+}
+
+/// Draws a [`gui::ProgressPage`], flushing the display only when the percentage changes
+pub struct ProgressBar<'s> {
+    label: &'s str,
+    last_percent: Option<u8>,
+}
+
+impl<'s> ProgressBar<'s> {
+    pub fn new(label: &'s str) -> Self {
+        ProgressBar {
+            label,
+            last_percent: None,
+        }
+    }
+
+    pub fn draw_progress(&mut self, display: &mut hw::Display, fraction: f32) -> Result<(), Error> {
+        let page = gui::ProgressPage::new(fraction, self.label);
+        if self.last_percent == Some(page.percent()) {
+            return Ok(());
+        }
+
+        if self.last_percent.is_none() {
+            page.init_display(display)?;
+        }
+        page.draw_to(display)?;
+        display.flush()?;
+
+        self.last_percent = Some(page.percent());
+        Ok(())
+    }
 }
 
 #[allow(dead_code)]
@@ -171,16 +243,16 @@ fn only_requests(stream: impl Stream<Item = Event>) -> impl Stream<Item = model:
 }
 
 #[allow(dead_code)]
-fn only_input<'s>(
+fn only_input<'s, T: Transport>(
     stream: impl Stream<Item = Event> + 's,
-    nfc: &'s RefCell<&'s mut hw_common::ChannelSender<Reply>>,
+    nfc: &'s T,
 ) -> impl Stream<Item = bool> + 's {
     stream
         .zip(futures::stream::repeat(nfc))
         .filter_map(|(e, nfc)| async move {
             match e {
                 Event::Request(_) => {
-                    let _ = nfc.borrow_mut().send(Reply::Busy).await;
+                    let _ = nfc.send_reply(Reply::Busy).await;
                     None
                 }
                 Event::Input(v) => Some(v),
@@ -190,9 +262,9 @@ fn only_input<'s>(
 }
 
 #[allow(dead_code)]
-async fn wait_ticks<'s>(
+async fn wait_ticks<'s, T: Transport>(
     stream: impl Stream<Item = Event> + 's,
-    nfc: &'s RefCell<&'s mut hw_common::ChannelSender<Reply>>,
+    nfc: &'s T,
     num_ticks: usize,
 ) {
     let stream = stream
@@ -200,7 +272,7 @@ async fn wait_ticks<'s>(
         .filter_map(|(e, nfc)| async move {
             match e {
                 Event::Request(_) => {
-                    let _ = nfc.borrow_mut().send(Reply::Busy).await;
+                    let _ = nfc.send_reply(Reply::Busy).await;
                     None
                 }
                 Event::Tick => Some(()),
@@ -213,16 +285,43 @@ async fn wait_ticks<'s>(
     while let Some(_) = stream.next().await {}
 }
 
-pub async fn dispatch_handler(
+/// Go through the events received so far without waiting, returning whether the app sent a
+/// [`model::Request::Cancel`]
+///
+/// Used between the steps of long operations that don't otherwise look at the events. Other
+/// requests are answered with a `DelayedReply`, like in the confirmation loop.
+async fn cancel_requested<T: Transport>(
+    events: &mut (impl Stream<Item = Event> + Unpin),
+    peripherals: &mut HandlerPeripherals<T>,
+) -> bool {
+    while let Some(Some(event)) = events.next().now_or_never() {
+        match event {
+            Event::Request(model::Request::Cancel) => return true,
+            Event::Request(_) => {
+                peripherals
+                    .nfc
+                    .send_reply(Reply::DelayedReply)
+                    .await
+                    .expect("Send should work");
+            }
+            _ => {}
+        }
+    }
+
+    false
+}
+
+pub async fn dispatch_handler<T: Transport>(
     current_state: &mut CurrentState,
     events: impl Stream<Item = Event> + Unpin,
-    peripherals: &mut HandlerPeripherals,
+    peripherals: &mut HandlerPeripherals<T>,
     fast_boot: bool,
 ) {
     pin_mut!(events);
 
     let mut moved_state = CurrentState::Init;
     core::mem::swap(&mut moved_state, current_state);
+    let wallet = moved_state.wallet().map(Rc::clone);
     let result = match moved_state {
         CurrentState::POR => init::handle_por(peripherals, fast_boot).await,
         CurrentState::Init => init::handle_init(events, peripherals).await,
@@ -234,33 +333,53 @@ pub async fn dispatch_handler(
             num_words,
             network,
             password,
+            passphrase,
         } => {
             peripherals
                 .nfc
-                .send(model::Reply::DelayedReply)
+                .send_reply(model::Reply::DelayedReply)
                 .await
                 .unwrap();
 
-            init::handle_generate_seed(num_words, network, password.as_deref(), events, peripherals)
-                .await
+            init::handle_generate_seed(
+                num_words,
+                network,
+                password.as_deref(),
+                passphrase.as_deref(),
+                events,
+                peripherals,
+            )
+            .await
         }
         CurrentState::ImportSeed {
             mnemonic,
             network,
             password,
+            passphrase,
         } => {
             peripherals
                 .nfc
-                .send(model::Reply::DelayedReply)
+                .send_reply(model::Reply::DelayedReply)
                 .await
                 .unwrap();
 
-            init::handle_import_seed(&mnemonic, network, password.as_deref(), events, peripherals)
-                .await
+            init::handle_import_seed(
+                &mnemonic,
+                network,
+                password.as_deref(),
+                passphrase.as_deref(),
+                events,
+                peripherals,
+            )
+            .await
         }
         CurrentState::Idle { ref mut wallet } => {
             idle::handle_idle(wallet, events, peripherals).await
         }
+        CurrentState::SetAntiPhishingPhrase {
+            ref mut wallet,
+            phrase,
+        } => idle::handle_set_anti_phishing_phrase(wallet, &phrase, events, peripherals).await,
         CurrentState::WaitingForPsbt { ref mut wallet } => {
             bitcoin::handle_waiting_for_psbt(wallet, events, peripherals).await
         }
@@ -268,9 +387,14 @@ pub async fn dispatch_handler(
             ref mut wallet,
             psbt,
         } => bitcoin::handle_sign_request(wallet, &psbt, peripherals).await,
+        CurrentState::SignBatch {
+            ref mut wallet,
+            psbts,
+        } => bitcoin::handle_sign_batch(wallet, psbts, events, peripherals).await,
         CurrentState::ConfirmSignPsbt {
             ref mut wallet,
             outputs,
+            warnings,
             fees,
             resumable,
             sig_bytes,
@@ -279,6 +403,7 @@ pub async fn dispatch_handler(
             bitcoin::handle_confirm_sign_psbt(
                 wallet,
                 &outputs,
+                &warnings,
                 fees,
                 resumable,
                 sig_bytes,
@@ -290,12 +415,14 @@ pub async fn dispatch_handler(
         }
         CurrentState::DisplayAddress {
             ref mut wallet,
+            keychain,
             index,
             resumable,
             is_fast_boot,
         } => {
             bitcoin::handle_display_address_request(
                 wallet,
+                keychain,
                 index,
                 resumable,
                 is_fast_boot,
@@ -306,11 +433,13 @@ pub async fn dispatch_handler(
         }
         CurrentState::PublicDescriptor {
             ref mut wallet,
+            keychain,
             resumable,
             is_fast_boot,
         } => {
             bitcoin::handle_public_descriptor_request(
                 wallet,
+                keychain,
                 resumable,
                 is_fast_boot,
                 events,
@@ -358,6 +487,7 @@ pub async fn dispatch_handler(
             )
             .await
         }
+        CurrentState::FactoryReset => idle::handle_factory_reset(events, peripherals).await,
         CurrentState::UpdatingFw { header, fast_boot } => {
             fwupdate::handle_begin_fw_update(&header, fast_boot, events, peripherals).await
         }
@@ -374,26 +504,121 @@ pub async fn dispatch_handler(
 
     *current_state = match result {
         Ok(new_state) => new_state,
-        Err(e) => handle_error(e, peripherals).await,
+        Err(Error::Canceled) => handle_canceled(wallet, peripherals).await,
+        Err(e) => match (e.psbt_rejection(), wallet) {
+            (Some(reason), Some(wallet)) => handle_rejected_psbt(reason, wallet, peripherals).await,
+            _ => handle_error(e, peripherals).await,
+        },
+    }
+}
+
+/// Tell the app why its PSBT was rejected and go back to idle
+///
+/// Like a descriptor that fails the checks, a PSBT that can't be signed safely is not an error
+/// of the device. The signing handler has already discarded its checkpoint.
+async fn handle_rejected_psbt<T: Transport>(
+    reason: String,
+    wallet: Rc<PortalWallet>,
+    peripherals: &mut HandlerPeripherals<T>,
+) -> CurrentState {
+    log::warn!("PSBT rejected: {}", reason);
+
+    peripherals
+        .nfc
+        .send_reply(Reply::Error(reason))
+        .await
+        .unwrap();
+    peripherals.nfc_finished.recv().await.unwrap();
+
+    CurrentState::Idle { wallet }
+}
+
+/// Reply to a [`model::Request::Cancel`] and go back to idle, or to the initial state if the
+/// device wasn't unlocked yet
+///
+/// The canceled handler has already dropped its state, here only the checkpoint is left to wipe.
+async fn handle_canceled<T: Transport>(
+    wallet: Option<Rc<PortalWallet>>,
+    peripherals: &mut HandlerPeripherals<T>,
+) -> CurrentState {
+    log::info!("Operation canceled");
+
+    if let Err(e) = checkpoint::discard(peripherals) {
+        return handle_error(e.into(), peripherals).await;
+    }
+
+    peripherals.nfc.send_reply(Reply::Canceled).await.unwrap();
+    peripherals.nfc_finished.recv().await.unwrap();
+
+    match wallet {
+        Some(wallet) => CurrentState::Idle { wallet },
+        None => CurrentState::POR,
+    }
+}
+
+/// Answer every request with an error, used when the display couldn't be initialized
+///
+/// Nothing can be confirmed on the device without the display, and most handlers would fail at
+/// their first draw. Instead of halting on a blank screen the device keeps telling the app
+/// what's wrong. Firmware updates are the exception: they are still confirmed by holding the
+/// button, so that a firmware that can't drive the display can be replaced.
+pub async fn handle_display_failure<T: Transport>(
+    mut events: impl Stream<Item = Event> + Unpin,
+    peripherals: &mut HandlerPeripherals<T>,
+) -> ! {
+    log::error!("Running without a display");
+
+    loop {
+        match events.next().await {
+            Some(Event::Request(model::Request::BeginFwUpdate(header))) => {
+                // A successful update reboots into the new firmware
+                if let Err(e) =
+                    fwupdate::handle_begin_fw_update(&header, None, &mut events, peripherals).await
+                {
+                    handle_error(e, peripherals).await;
+                }
+            }
+            Some(Event::Request(_)) => {
+                peripherals
+                    .nfc
+                    .send_reply(Reply::Error("Display failure".into()))
+                    .await
+                    .unwrap();
+                peripherals.nfc_finished.recv().await.unwrap();
+            }
+            _ => continue,
+        }
     }
 }
 
-async fn handle_error(err: Error, peripherals: &mut HandlerPeripherals) -> ! {
+async fn handle_error<T: Transport>(err: Error, peripherals: &mut HandlerPeripherals<T>) -> ! {
     #[cfg(feture = "panic-log")]
     log::error!("{:?}", _err);
 
-    let try_draw_message = |peripherals: &mut HandlerPeripherals| -> Result<(), Error> {
+    let try_draw_message = |peripherals: &mut HandlerPeripherals<T>| -> Result<(), Error> {
         let error_msg = match err {
             Error::InvalidFirmware => "Invalid Firmware",
             Error::InvalidPassword => "Invalid Pair Code",
             Error::BrokenProtocol
             | Error::HandshakeError
+            | Error::HandshakeTimeout
             | Error::LostRf
             | Error::TooManyNacks
+            | Error::WriteTimeout
             | Error::Message(_) => "Communication Error",
             Error::Config(_) | Error::FlashError => "Memory Error",
+            Error::InvalidSetting => "Invalid Setting",
             Error::Display(_) | Error::I2c(_) => "Display Error",
             Error::Wallet => "Wallet Error",
+            Error::ForeignInput(_) => "Foreign Input",
+            Error::InvalidNonWitnessUtxo(_)
+            | Error::MissingNonWitnessUtxo(_)
+            | Error::MissingWitnessUtxo(_) => "Invalid Input",
+            Error::FeeTooHigh { .. } => "Fee Too High",
+            Error::NegativeFee | Error::FeeOverflow => "Invalid Fee",
+            Error::OutputScriptMismatch(_) => "Invalid Output",
+            Error::ValueLoss { .. } | Error::ValueOverflow => "Value Loss",
+            Error::Canceled => "Canceled",
             Error::Unknown => "General Failure",
         };
 
@@ -410,17 +635,17 @@ async fn handle_error(err: Error, peripherals: &mut HandlerPeripherals) -> ! {
     loop {}
 }
 
-async fn manage_confirmation_loop<'s, C: MainContent>(
+async fn manage_confirmation_loop<'s, C: MainContent, T: Transport>(
     events: impl Stream<Item = Event> + Unpin,
-    peripherals: &mut HandlerPeripherals,
+    peripherals: &mut HandlerPeripherals<T>,
     page: &mut ConfirmBarPage<'s, C>,
 ) -> Result<(), crate::Error> {
     manage_confirmation_loop_with_callback(events, peripherals, page, |_, _, _| {}, 0).await
 }
 
-async fn manage_confirmation_loop_with_checkpoint<'s, C: MainContent>(
+async fn manage_confirmation_loop_with_checkpoint<'s, C: MainContent, T: Transport>(
     events: impl Stream<Item = Event> + Unpin,
-    peripherals: &mut HandlerPeripherals,
+    peripherals: &mut HandlerPeripherals<T>,
     page: &mut ConfirmBarPage<'s, C>,
     checkpoint: &mut checkpoint::Checkpoint,
     state: checkpoint::Resumable,
@@ -445,11 +670,11 @@ async fn manage_confirmation_loop_with_checkpoint<'s, C: MainContent>(
     .await
 }
 
-async fn manage_confirmation_loop_with_callback<'s, C: MainContent>(
+async fn manage_confirmation_loop_with_callback<'s, C: MainContent, T: Transport>(
     mut events: impl Stream<Item = Event> + Unpin,
-    peripherals: &mut HandlerPeripherals,
+    peripherals: &mut HandlerPeripherals<T>,
     page: &mut ConfirmBarPage<'s, C>,
-    mut progress_update: impl FnMut(&mut HandlerPeripherals, u32, usize),
+    mut progress_update: impl FnMut(&mut HandlerPeripherals<T>, u32, usize),
     mut ticks: usize,
 ) -> Result<(), crate::Error> {
     #[cfg(feature = "device")]
@@ -476,10 +701,11 @@ async fn manage_confirmation_loop_with_callback<'s, C: MainContent>(
         draw = false;
 
         match events.next().await.expect("Event") {
+            Event::Request(model::Request::Cancel) => return Err(Error::Canceled),
             Event::Request(_) => {
                 peripherals
                     .nfc
-                    .send(Reply::DelayedReply)
+                    .send_reply(Reply::DelayedReply)
                     .await
                     .expect("Send should work");
             }
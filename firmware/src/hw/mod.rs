@@ -29,7 +29,12 @@ pub mod tsc;
 
 use nt3h::Nt3h;
 
+use gui::{ErrorPage, Page};
+use model::bitcoin::hashes::{sha256, Hash};
+use model::peripheral_config::{DisplayStatus, PeripheralConfig};
+
 use crate::checkpoint;
+use crate::hw_common::Peripherals;
 
 pub type AltOpenDrain<const A: u8> = gpio::Alternate<gpio::OpenDrain, A>;
 pub type AltPushPull<const A: u8> = gpio::Alternate<gpio::PushPull, A>;
@@ -77,23 +82,33 @@ pub type Display = Ssd1306<
 >;
 pub type NfcInterrupt = nt3h::NfcInterrupt<gpio::gpioa::PA6<FloatingInput>>;
 
+/// Initialize all the peripherals, running the I2C buses at the speeds in `config`
 pub fn init_peripherals(
+    dp: stm32::Peripherals,
+    cp: cortex_m::Peripherals,
+    config: PeripheralConfig,
+) -> Result<Peripherals, crate::Error> {
+    init_peripherals_inner(dp, cp, None, config)
+}
+
+/// Same as [`init_peripherals`] but seeds the RNG with `seed` instead of reading the hardware
+/// RNG, to make tests reproducible
+#[cfg(feature = "deterministic-rng")]
+pub fn init_peripherals_with_seed(
+    dp: stm32::Peripherals,
+    cp: cortex_m::Peripherals,
+    config: PeripheralConfig,
+    seed: [u8; 32],
+) -> Result<Peripherals, crate::Error> {
+    init_peripherals_inner(dp, cp, Some(seed), config)
+}
+
+fn init_peripherals_inner(
     mut dp: stm32::Peripherals,
     cp: cortex_m::Peripherals,
-) -> Result<
-    (
-        NfcIc,
-        NfcInterrupt,
-        crate::hw_common::ChannelReceiver<()>,
-        Display,
-        Tsc,
-        rand_chacha::ChaCha20Rng,
-        Flash,
-        Rtc,
-        bool,
-    ),
-    crate::Error,
-> {
+    seed: Option<[u8; 32]>,
+    config: PeripheralConfig,
+) -> Result<Peripherals, crate::Error> {
     let mut rcc = dp.RCC.constrain();
     let mut pwr = dp.PWR.constrain(&mut rcc.apb1r1);
 
@@ -107,8 +122,9 @@ pub fn init_peripherals(
         &mut pwr.cr1,
         rtc::RtcConfig::default(),
     );
-    let fast_boot = rtc.read_backup_register(checkpoint::MAGIC_REGISTER) == Some(checkpoint::MAGIC);
-    if !fast_boot {
+    let status = checkpoint::validate(rtc.read_backup_register(checkpoint::MAGIC_REGISTER));
+    let fast_boot = status.can_fast_boot();
+    if status.needs_new_magic() {
         rtc.write_backup_register(checkpoint::MAGIC_REGISTER, checkpoint::MAGIC);
     }
 
@@ -124,7 +140,10 @@ pub fn init_peripherals(
 
     // Seed the RNG *before* we switch to LPR. LPR works at most with MSI 2MHz
     // and the PLL needs at least MSI 4MHz to work (which is the default after reset)
-    let rng = {
+    let (rng, rng_health) = if let Some(seed) = seed {
+        log::warn!("Using a deterministic RNG seed");
+        (rand_chacha::ChaCha20Rng::from_seed(seed), Ok(()))
+    } else {
         // Generate the 48MHz clock using PLL48M1CLK
 
         let rcc_reg = unsafe { &*hal::pac::RCC::ptr() };
@@ -156,20 +175,22 @@ pub fn init_peripherals(
             .ccipr
             .modify(|_, w| unsafe { w.clk48sel().bits(0b10) });
 
-        let clocks = unsafe { create_fake_clocks_with_hsi48_on() };
+        let mut hw_rng = HwRng::enable(dp.RNG, &mut rcc.ahb2);
 
-        let mut stm32_rng = dp.RNG.enable(&mut rcc.ahb2, clocks);
+        let mut raw = [0u8; model::rng_health::RNG_HEALTH_SAMPLES];
+        let rng_health = hw_rng
+            .try_fill_bytes(&mut raw)
+            .and_then(|_| model::rng_health::rng_health_check(&raw));
 
-        let mut seed = [0u8; 32];
-        stm32_rng.fill_bytes(&mut seed);
-
-        hal::stm32::RNG::disable(&mut rcc.ahb2);
+        hw_rng.disable(&mut rcc.ahb2);
 
         // Disable PLL
         rcc_reg.cr.modify(|_, w| w.pllon().clear_bit());
         while rcc_reg.cr.read().pllrdy().bit_is_set() {}
 
-        rand_chacha::ChaCha20Rng::from_seed(seed)
+        // Condense all the raw bytes into the seed
+        let seed = sha256::Hash::hash(&raw).into_inner();
+        (rand_chacha::ChaCha20Rng::from_seed(seed), rng_health)
     };
 
     // Switch to MSI 24MHz
@@ -200,7 +221,7 @@ pub fn init_peripherals(
     let i2c1 = I2c::i2c1(
         dp.I2C1,
         (scl, sda),
-        i2c::Config::new(100.kHz(), clocks),
+        i2c::Config::new(config.nfc_i2c_hz().Hz(), clocks),
         &mut rcc.apb1r1,
     );
     let mut gpo = gpioa
@@ -222,7 +243,7 @@ pub fn init_peripherals(
     let i2c2 = I2c::i2c2(
         dp.I2C2,
         (scl, sda),
-        i2c::Config::new(100.kHz(), clocks),
+        i2c::Config::new(config.display_i2c_hz().Hz(), clocks),
         &mut rcc.apb1r1,
     );
 
@@ -232,11 +253,32 @@ pub fn init_peripherals(
 
     let mut display = Ssd1306::new(interface, DisplaySize128x64, DisplayRotation::Rotate180)
         .into_buffered_graphics_mode();
-    if !fast_boot {
-        display.init()?;
-        display.set_brightness(Brightness::DIMMEST)?;
+    let display_init = if !fast_boot {
+        display
+            .init()
+            .and_then(|_| display.set_brightness(Brightness::DIMMEST))
     } else {
-        display.set_addr_mode(ssd1306::command::AddrMode::Horizontal)?;
+        display.set_addr_mode(ssd1306::command::AddrMode::Horizontal)
+    };
+    if let Err(e) = &display_init {
+        log::error!("Display init failed: {:?}", e);
+    }
+    let display_status = DisplayStatus::from_init(display_init);
+
+    // Never generate keys from a degraded source
+    if let Err(e) = rng_health {
+        log::error!("RNG health check failed: {:?}", e);
+
+        if display_status == DisplayStatus::Ok {
+            let page = ErrorPage::new("Entropy error");
+            page.init_display(&mut display)?;
+            page.draw_to(&mut display)?;
+            display.flush()?;
+        }
+
+        loop {
+            cortex_m::asm::wfi();
+        }
     }
 
     let sample_pin =
@@ -264,17 +306,18 @@ pub fn init_peripherals(
 
     let tsc = Tsc::new(tsc, channel_pin);
 
-    Ok((
-        nt3h,
+    Ok(Peripherals {
+        nfc: nt3h,
         nfc_interrupt,
         nfc_finished,
         display,
+        display_status,
         tsc,
         rng,
         flash,
         rtc,
         fast_boot,
-    ))
+    })
 }
 
 pub struct Flash {
@@ -294,12 +337,7 @@ pub fn read_flash<'b>(
     let page_to_read = flash::FlashPage(page).to_address();
 
     prog.read(page_to_read, buf);
-    let len = u16::from_be_bytes(buf[..2].try_into().unwrap()) as usize;
-    if len >= super::hw_common::PAGE_SIZE - 2 {
-        return Err(FlashError::CorruptedData);
-    }
-
-    Ok(&buf[2..2 + len])
+    super::hw_common::parse_flash_page(buf).ok_or(FlashError::CorruptedData)
 }
 
 pub fn write_flash(flash: &mut Flash, page: usize, serialized: &[u8]) -> Result<(), FlashError> {
@@ -307,14 +345,8 @@ pub fn write_flash(flash: &mut Flash, page: usize, serialized: &[u8]) -> Result<
 
     let mut prog = flash.keyr.unlock_flash(&mut flash.sr, &mut flash.cr)?;
 
-    if serialized.len() > super::hw_common::PAGE_SIZE - 2 {
-        return Err(FlashError::CorruptedData);
-    }
-
-    let mut data = alloc::vec![];
-    let len = (serialized.len() as u16).to_be_bytes();
-    data.extend_from_slice(&len);
-    data.extend(serialized);
+    let mut data =
+        super::hw_common::serialize_flash_page(serialized).ok_or(FlashError::CorruptedData)?;
     data.resize(super::hw_common::PAGE_SIZE, 0x00);
 
     let page = flash::FlashPage(page);
@@ -324,6 +356,127 @@ pub fn write_flash(flash: &mut Flash, page: usize, serialized: &[u8]) -> Result<
     Ok(())
 }
 
+const FLASH_KEY1: u32 = 0x4567_0123;
+const FLASH_KEY2: u32 = 0xCDEF_89AB;
+const FLASH_OPTKEY1: u32 = 0x0819_2A3B;
+const FLASH_OPTKEY2: u32 = 0x4C5D_6E7F;
+
+const SRAM1: core::ops::Range<u32> = 0x2000_0000..0x2000_0000 + 96 * 1024;
+const SRAM2: core::ops::Range<u32> = 0x1000_0000..0x1000_0000 + 32 * 1024;
+const FLASH_BANK: core::ops::Range<u32> = 0x0800_0000..0x0800_0000 + 256 * 2048;
+
+/// Sanity check the vector table at the start of the spare bank: the initial stack pointer
+/// must be in RAM and the reset handler in flash
+fn spare_bank_has_image(flash: &mut Flash) -> Result<bool, FlashError> {
+    let spare = crate::storage::BankToFlash::spare(flash);
+
+    let parts = &mut flash.parts;
+    let prog = parts.keyr.unlock_flash(&mut parts.sr, &mut parts.cr)?;
+
+    let mut vector_table = [0u8; 8];
+    prog.read(
+        spare.get_logical_address(crate::storage::BankStatus::Spare, 0),
+        &mut vector_table,
+    );
+
+    let sp = u32::from_le_bytes(vector_table[..4].try_into().unwrap());
+    let reset = u32::from_le_bytes(vector_table[4..].try_into().unwrap());
+    Ok((SRAM1.contains(&sp) || SRAM2.contains(&sp)) && FLASH_BANK.contains(&reset))
+}
+
+/// Address of the 96-bit unique ID, programmed in the factory
+const UID_BASE: usize = 0x1FFF_7590;
+
+/// Unique ID of the MCU, used as serial number
+pub fn device_serial() -> [u8; model::SERIAL_LEN] {
+    let mut serial = [0; model::SERIAL_LEN];
+    for (i, byte) in serial.iter_mut().enumerate() {
+        *byte = unsafe { core::ptr::read_volatile((UID_BASE + i) as *const u8) };
+    }
+    serial
+}
+
+/// Program the BFB2 option bit so that the bootloader picks the spare bank
+///
+/// The image in the spare bank must have been verified beforehand (see the firmware update
+/// handler), this only checks that it looks bootable. Option bytes are only reloaded on a
+/// power-on reset or through `OBL_LAUNCH`: a regular system reset keeps booting the current
+/// bank, so the swap takes effect at the next power cycle or after [`reload_option_bytes`].
+pub fn swap_active_bank(flash: &mut Flash) -> Result<(), FlashError> {
+    if !spare_bank_has_image(flash)? {
+        log::warn!("Refusing to swap to a bank without a valid vector table");
+        return Err(FlashError::CorruptedData);
+    }
+
+    // BFB2 makes the bootloader try bank 2 first. `fb_mode` is set when bank 2 is booted.
+    let boot_bank2 = !flash.fb_mode;
+
+    let regs = unsafe { &(*stm32::FLASH::ptr()) };
+    let wait = || while regs.sr.read().bsy().bit_is_set() {};
+
+    wait();
+    if regs.cr.read().lock().bit_is_set() {
+        regs.keyr.write(|w| unsafe { w.bits(FLASH_KEY1) });
+        regs.keyr.write(|w| unsafe { w.bits(FLASH_KEY2) });
+    }
+    if regs.cr.read().optlock().bit_is_set() {
+        regs.optkeyr.write(|w| unsafe { w.bits(FLASH_OPTKEY1) });
+        regs.optkeyr.write(|w| unsafe { w.bits(FLASH_OPTKEY2) });
+    }
+
+    regs.optr.modify(|_, w| w.bfb2().bit(boot_bank2));
+    regs.cr.modify(|_, w| w.optstrt().set_bit());
+    wait();
+
+    let sr = regs.sr.read();
+    let failed = sr.pgaerr().bit_is_set() || sr.progerr().bit_is_set() || sr.wrperr().bit_is_set();
+
+    // Locking the flash also locks the option bytes
+    regs.cr.modify(|_, w| w.lock().set_bit());
+
+    if failed {
+        return Err(FlashError::CorruptedData);
+    }
+
+    log::info!("Option bytes updated, BFB2 = {}", boot_bank2);
+    Ok(())
+}
+
+/// Reload the option bytes, which resets the device and applies a [`swap_active_bank`]
+pub fn reload_option_bytes() -> ! {
+    let regs = unsafe { &(*stm32::FLASH::ptr()) };
+    regs.keyr.write(|w| unsafe { w.bits(FLASH_KEY1) });
+    regs.keyr.write(|w| unsafe { w.bits(FLASH_KEY2) });
+    regs.optkeyr.write(|w| unsafe { w.bits(FLASH_OPTKEY1) });
+    regs.optkeyr.write(|w| unsafe { w.bits(FLASH_OPTKEY2) });
+    regs.cr.modify(|_, w| w.obl_launch().set_bit());
+
+    // Not reached, `OBL_LAUNCH` resets the device
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+/// RTC ticks sampled by [`reseed_from_rtc`], each one takes about 4ms
+const RTC_JITTER_SAMPLES: usize = 8;
+
+/// Mix timing jitter between the RTC and the core clock into `rng`
+///
+/// The RNG is seeded from the hardware RNG at boot, this is only used to forward-secure its
+/// stream between operations. For every sample we wait for the RTC subsecond counter, driven by
+/// the LSE, to tick and record the SysTick counter, driven by the core clock. `_rtc` is only
+/// borrowed to make sure the RTC is running, its registers are read directly.
+pub fn reseed_from_rtc(rng: &mut rand_chacha::ChaCha20Rng, _rtc: &Rtc) {
+    let regs = unsafe { &(*stm32::RTC::ptr()) };
+
+    let mut jitter = [0u8; RTC_JITTER_SAMPLES * 4];
+    for sample in jitter.chunks_mut(4) {
+        let ssr = regs.ssr.read().ss().bits();
+        while regs.ssr.read().ss().bits() == ssr {}
+        sample.copy_from_slice(&cortex_m::peripheral::SYST::get_current().to_le_bytes());
+    }
+
+    model::encryption::reseed_with_entropy(rng, &jitter);
+}
+
 #[derive(Debug)]
 pub enum FlashError {
     CorruptedData,
@@ -343,18 +496,45 @@ impl From<flash::Error> for FlashError {
     }
 }
 
-unsafe fn create_fake_clocks_with_hsi48_on() -> hal::rcc::Clocks {
-    // TODO: try to get the offset of `hsi48` from the compiler instead of guessing it
-    const SIZE: usize = core::mem::size_of::<hal::rcc::Clocks>();
+/// The hardware RNG, driven through its registers
+///
+/// `hal::rng` only enables it given [`hal::rcc::Clocks`] with the HSI48 on, but we clock it from
+/// PLL48M1CLK and the HAL has no way to build `Clocks` for that.
+struct HwRng {
+    rng: stm32::RNG,
+}
+
+impl HwRng {
+    /// The 48MHz clock must already be running
+    fn enable(rng: stm32::RNG, ahb2: &mut hal::rcc::AHB2) -> Self {
+        <stm32::RNG as Enable>::enable(ahb2);
+        rng.cr.modify(|_, w| w.rngen().set_bit());
 
-    let mut data = [0u8; SIZE];
-    for i in 0..SIZE {
-        data[i] = 0xFF;
-        let copy = core::mem::transmute_copy::<_, hal::rcc::Clocks>(&data);
-        if copy.hsi48() {
-            return copy;
+        HwRng { rng }
+    }
+
+    /// Fill `buf`, failing if the RNG reports a seed or clock error
+    fn try_fill_bytes(&mut self, buf: &mut [u8]) -> Result<(), model::rng_health::RngError> {
+        for chunk in buf.chunks_mut(4) {
+            loop {
+                let sr = self.rng.sr.read();
+                if sr.secs().bit_is_set() || sr.cecs().bit_is_set() {
+                    return Err(model::rng_health::RngError::SourceError);
+                }
+                if sr.drdy().bit_is_set() {
+                    break;
+                }
+            }
+
+            let value = self.rng.dr.read().bits().to_le_bytes();
+            chunk.copy_from_slice(&value[..chunk.len()]);
         }
+
+        Ok(())
     }
 
-    unreachable!()
+    fn disable(self, ahb2: &mut hal::rcc::AHB2) {
+        self.rng.cr.modify(|_, w| w.rngen().clear_bit());
+        <stm32::RNG as Enable>::disable(ahb2);
+    }
 }
@@ -37,8 +37,6 @@ pub const NT3H_ADDR: u8 = 0x55;
 pub const BLOCK_SESSION_REGISTERS: u8 = 0xFE;
 #[allow(dead_code)]
 pub const BLOCK_CONFIGURATION_REGISTERS: u8 = 0x3A;
-#[allow(dead_code)]
-pub const BLOCK_SRAM: u8 = 0xF8;
 
 #[allow(dead_code)]
 pub const SESSION_REG_NC_REG: u8 = 0x00;
@@ -57,6 +55,11 @@ pub const SESSION_REG_NS_REG: u8 = 0x06;
 
 const MAX_TRIES: usize = 8;
 
+/// Default number of `EEPROM_WR_BUSY` polls before giving up on an EEPROM write
+#[allow(dead_code)]
+pub const EEPROM_WRITE_RETRIES: usize = 10;
+const EEPROM_POLL_DELAY_MS: u32 = 1;
+
 struct HostWriteBuffer;
 
 impl WriteBufferInit<17, 4, 0> for HostWriteBuffer {
@@ -153,9 +156,7 @@ where
         fragments: I,
     ) -> Result<(), Error> {
         // Flip the direction
-        let new_nc_reg = NC_REG::new()
-            .with_TRANSFER_DIR(TransferDir::HostToNfc)
-            .with_PTHRU_ON_OFF(true);
+        let new_nc_reg = NC_REG::passthrough_i2c_to_nfc();
         self.write_exp_delay(
             NT3H_ADDR,
             &[
@@ -204,6 +205,53 @@ where
         Ok(fragment)
     }
 
+    /// Enable SRAM pass-through in the given direction
+    ///
+    /// SRAM mirroring is turned off, since the NT3H doesn't support it together with pass-through.
+    #[allow(dead_code)]
+    pub async fn enable_passthrough(&mut self, dir: TransferDir) -> Result<(), Error> {
+        let new_nc_reg = NcRegBuilder::new()
+            .transfer_dir(dir)
+            .passthrough(true)
+            .build()
+            .expect("Valid configuration");
+        self.write_exp_delay(
+            NT3H_ADDR,
+            &[
+                BLOCK_SESSION_REGISTERS,
+                SESSION_REG_NC_REG,
+                0b01000011,
+                new_nc_reg.into_bytes()[0],
+            ],
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Write a 64-byte block to the SRAM, waiting for the RF side to read the previous one first
+    ///
+    /// Pass-through must have been enabled with [`TransferDir::HostToNfc`].
+    #[allow(dead_code)]
+    pub async fn write_sram(&mut self, block: &[u8; 64]) -> Result<(), Error> {
+        self.wait_for_rf_read(WaitMode::Interrupt).await?;
+
+        for part in sram_block_writes(block) {
+            self.write_exp_delay(NT3H_ADDR, &part).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Read a 64-byte block from the SRAM, waiting for the RF side to finish writing it first
+    ///
+    /// Pass-through must have been enabled with [`TransferDir::NfcToHost`].
+    #[allow(dead_code)]
+    pub async fn read_sram(&mut self, buf: &mut [u8; 64]) -> Result<(), Error> {
+        self.wait_for(WaitFor::Write, WaitMode::Interrupt).await?;
+        self.read_from_mailbox(buf).await
+    }
+
     async fn wait_for(&mut self, what: WaitFor, mode: WaitMode) -> Result<(), Error> {
         macro_rules! do_wait {
             ($s:expr, $what:expr) => {
@@ -226,13 +274,14 @@ where
     }
 
     async fn check_rf_read(&mut self) -> Result<bool, Error> {
-        Ok(!self.read_NS_REG().await?.SRAM_RF_READY())
+        let ns_reg = self.read_NS_REG().await?;
+        Ok(sram_ready(TransferDir::HostToNfc, &ns_reg))
     }
 
     async fn check_rf_write(&mut self) -> Result<bool, Error> {
         let ns_reg = self.read_NS_REG().await?;
 
-        if ns_reg.SRAM_I2C_READY() {
+        if sram_ready(TransferDir::NfcToHost, &ns_reg) {
             Ok(true)
         } else if !ns_reg.RF_LOCKED() {
             let new_nc_reg = NC_REG::new().with_PTHRU_ON_OFF(true);
@@ -266,6 +315,45 @@ where
         Ok(NS_REG::from_bytes(buffer))
     }
 
+    /// Single read of `NS_REG` to check whether an RF field is present
+    ///
+    /// Unlike the other accessors this doesn't retry on NACK: the error is returned to the caller
+    /// instead of being interpreted as "no field".
+    pub fn field_present(&mut self) -> Result<bool, Error> {
+        model::reg::field_present(self)
+    }
+
+    /// Poll `EEPROM_WR_BUSY` up to `max_retries` times, returning [`Error::WriteTimeout`] if
+    /// the EEPROM is still busy afterwards
+    async fn wait_eeprom_ready(&mut self, max_retries: usize) -> Result<(), Error> {
+        let mut poll = EepromWritePoll::new(max_retries);
+        loop {
+            match poll.poll(&self.read_NS_REG().await?) {
+                EepromPoll::Ready => return Ok(()),
+                EepromPoll::Busy => Systick::delay(EEPROM_POLL_DELAY_MS.millis()).await,
+                EepromPoll::TimedOut => return Err(Error::WriteTimeout),
+            }
+        }
+    }
+
+    /// Write a 16-byte block to the EEPROM, retrying while the chip reports `EEPROM_WR_BUSY`
+    #[allow(dead_code)]
+    pub async fn write_eeprom_block(
+        &mut self,
+        block: u8,
+        data: &[u8; 16],
+        max_retries: usize,
+    ) -> Result<(), Error> {
+        self.wait_eeprom_ready(max_retries).await?;
+
+        let mut buf = [0u8; 17];
+        buf[0] = block;
+        buf[1..].copy_from_slice(data);
+        self.write_exp_delay(NT3H_ADDR, &buf).await?;
+
+        self.wait_eeprom_ready(max_retries).await
+    }
+
     pub async fn apply_configuration(&mut self) -> Result<(), Error> {
         let new_nc_reg = NC_REG::new()
             .with_FD_ON(FdOn::NfcDone)
@@ -290,9 +378,7 @@ where
 
     async fn wait_for_rf_write(&mut self, mode: WaitMode) -> Result<(), Error> {
         // Set transfer direction
-        let new_nc_reg = NC_REG::new()
-            .with_TRANSFER_DIR(TransferDir::NfcToHost)
-            .with_PTHRU_ON_OFF(true);
+        let new_nc_reg = NC_REG::passthrough_nfc_to_i2c();
         self.write_exp_delay(
             NT3H_ADDR,
             &[
@@ -342,12 +428,16 @@ where
     pub async fn accept_request(
         &mut self,
         decrypt: &mut ::model::encryption::CipherState,
+        aad: &model::SessionAad,
     ) -> Result<Request, Error> {
         let msg = self.read_raw_message().await?;
         let mut decrypt_buf = alloc::vec::Vec::new();
 
-        match msg.deserialize(&mut decrypt_buf, decrypt) {
+        match msg.deserialize(&mut decrypt_buf, aad.request(), decrypt) {
             Ok(v) => Ok(v),
+            // The message was decrypted fine, so the session is still valid. The caller replies
+            // with the version we support.
+            Err(e @ model::MessageError::UnsupportedProtocol(_)) => Err(e.into()),
             Err(e) => {
                 self.write_to_mailbox([MessageFragment::new_failed_decryption()].into_iter())
                     .await?;
@@ -360,13 +450,18 @@ where
         &mut self,
         reply: &Reply,
         encrypt: &mut ::model::encryption::CipherState,
+        aad: &model::SessionAad,
     ) -> Result<(), Error> {
-        let message = Message::new_serialize(reply, encrypt)?;
+        let message = Message::new_serialize(reply, aad.reply(), encrypt)?;
         self.write_to_mailbox(message.get_fragments().into_iter())
             .await?;
 
         match reply {
-            Reply::Pong | Reply::DelayedReply => {}
+            Reply::Pong
+            | Reply::DelayedReply
+            | Reply::ProtocolVersion(_)
+            | Reply::UnsupportedProtocol(_)
+            | Reply::DeviceInfo { .. } => {}
             _ => {
                 let _ = self.finished.send(()).await;
             }
@@ -376,6 +471,25 @@ where
     }
 }
 
+impl<I2C, I2C_PINS> ReadNsReg for Nt3h<I2C, I2C_PINS>
+where
+    I2c<I2C, I2C_PINS>: ehal::blocking::i2c::WriteRead,
+    Error: From<<I2c<I2C, I2C_PINS> as ehal::blocking::i2c::WriteRead>::Error>,
+{
+    type Error = Error;
+
+    fn read_ns_reg(&mut self) -> Result<NS_REG, Error> {
+        let mut buffer = [0u8; 1];
+        self.i2c.write_read(
+            NT3H_ADDR,
+            &[BLOCK_SESSION_REGISTERS, SESSION_REG_NS_REG],
+            &mut buffer,
+        )?;
+
+        Ok(NS_REG::from_bytes(buffer))
+    }
+}
+
 #[derive(Debug)]
 pub enum WaitMode {
     #[allow(dead_code)]
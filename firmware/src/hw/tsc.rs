@@ -18,14 +18,26 @@
 use alloc::rc::Rc;
 use core::cell::RefCell;
 
+use alloc::vec::Vec;
+
 use hal::{stm32, tsc};
 
+use model::tsc::{Calibration, CalibrationError, CALIBRATION_SAMPLES};
+
+/// Used until the sensor is calibrated
 const TSC_THRESHOLD: u16 = 1200;
 
+/// Number of channels of the slider read by [`Tsc::read_position`]
+#[cfg(feature = "tsc-slider")]
+pub const SLIDER_CHANNELS: usize = 3;
+
 pub struct Tsc<SAMPLE_PIN, CHANNEL_PIN> {
     tsc: tsc::Tsc<SAMPLE_PIN>,
     channel_pin: CHANNEL_PIN,
     enabled: Rc<RefCell<bool>>,
+    calibration: Option<Calibration>,
+    #[cfg(feature = "tsc-slider")]
+    slider_calibration: Option<[Calibration; SLIDER_CHANNELS]>,
 }
 
 impl<SAMPLE_PIN, CHANNEL_PIN> Tsc<SAMPLE_PIN, CHANNEL_PIN>
@@ -38,6 +50,9 @@ where
             tsc,
             channel_pin,
             enabled: Rc::new(RefCell::new(false)),
+            calibration: None,
+            #[cfg(feature = "tsc-slider")]
+            slider_calibration: None,
         }
     }
 
@@ -62,7 +77,88 @@ where
         }
     }
 
+    /// Sample the untouched sensor with blocking acquisitions
+    ///
+    /// Must be called before the TSC interrupt is used.
+    pub fn calibrate(&mut self) -> Result<Calibration, CalibrationError> {
+        let samples = (0..CALIBRATION_SAMPLES)
+            .map(|_| self.tsc.acquire(&mut self.channel_pin).unwrap_or(0))
+            .collect::<Vec<_>>();
+        Calibration::from_samples(&samples)
+    }
+
+    pub fn set_calibration(&mut self, calibration: Calibration) {
+        self.calibration = Some(calibration);
+    }
+
+    /// Whether the last acquisition is a touch, using the calibrated threshold if available
+    pub fn is_touched_calibrated(&self) -> bool {
+        let count = self.tsc.read_unchecked();
+        match &self.calibration {
+            Some(calibration) => calibration.is_touched(count),
+            None => count < TSC_THRESHOLD,
+        }
+    }
+
     pub fn perform_read(&self) -> bool {
-        self.tsc.read_unchecked() < TSC_THRESHOLD
+        self.is_touched_calibrated()
+    }
+}
+
+/// Slider made of three channels sharing the same sampling capacitor
+///
+/// The Portal board only wires the single pad on PB5, so this is only built with the
+/// `tsc-slider` feature for boards that lay out more channels as a slider.
+#[cfg(feature = "tsc-slider")]
+impl<SAMPLE_PIN, C0, C1, C2> Tsc<SAMPLE_PIN, (C0, C1, C2)>
+where
+    SAMPLE_PIN: tsc::SamplePin<stm32::TSC>,
+    C0: tsc::ChannelPin<stm32::TSC>,
+    C1: tsc::ChannelPin<stm32::TSC>,
+    C2: tsc::ChannelPin<stm32::TSC>,
+{
+    pub fn new_slider(tsc: tsc::Tsc<SAMPLE_PIN>, channel_pins: (C0, C1, C2)) -> Self {
+        Tsc {
+            tsc,
+            channel_pin: channel_pins,
+            enabled: Rc::new(RefCell::new(false)),
+            calibration: None,
+            slider_calibration: None,
+        }
+    }
+
+    fn acquire_slider(&mut self) -> [u16; SLIDER_CHANNELS] {
+        let (c0, c1, c2) = &mut self.channel_pin;
+        [
+            self.tsc.acquire(c0).unwrap_or(0),
+            self.tsc.acquire(c1).unwrap_or(0),
+            self.tsc.acquire(c2).unwrap_or(0),
+        ]
+    }
+
+    /// Sample each channel of the untouched slider with blocking acquisitions
+    pub fn calibrate_slider(&mut self) -> Result<(), CalibrationError> {
+        let samples = (0..CALIBRATION_SAMPLES)
+            .map(|_| self.acquire_slider())
+            .collect::<Vec<_>>();
+
+        let mut calibration = [Calibration {
+            baseline: 0,
+            threshold: 0,
+        }; SLIDER_CHANNELS];
+        for (i, c) in calibration.iter_mut().enumerate() {
+            let channel = samples.iter().map(|s| s[i]).collect::<Vec<_>>();
+            *c = Calibration::from_samples(&channel)?;
+        }
+
+        self.slider_calibration = Some(calibration);
+        Ok(())
+    }
+
+    /// Position of the finger along the slider, `None` if not touched or not calibrated
+    pub fn read_position(&mut self) -> Option<u8> {
+        let calibration = self.slider_calibration?;
+        let counts = self.acquire_slider();
+        model::tsc::slider_position(&counts, &calibration)
     }
 }
@@ -18,8 +18,12 @@
 use alloc::rc::Rc;
 use core::cell::RefCell;
 
+use model::power::{PowerPolicy, PowerTransition};
+use model::transport::Disconnected;
 use model::{Reply, Request};
 
+use ssd1306::prelude::{Brightness, DisplayRotation};
+
 #[cfg(feature = "device")]
 use cortex_m::peripheral::NVIC;
 #[cfg(feature = "device")]
@@ -28,16 +32,36 @@ use hal::interrupt;
 pub type ChannelSender<T> = rtic_sync::channel::Sender<'static, T, 1>;
 pub type ChannelReceiver<T> = rtic_sync::channel::Receiver<'static, T, 1>;
 
-pub const PAGE_SIZE: usize = 2048;
-pub const MAX_FW_PAGES: usize = 508;
+/// Seed used instead of the hardware RNG with the `deterministic-rng` feature
+#[cfg(feature = "deterministic-rng")]
+pub const DETERMINISTIC_RNG_SEED: [u8; 32] = [0x42; 32];
+
+pub use model::fw_manifest::MAX_FW_PAGES;
+pub use model::storage::{parse_flash_page, serialize_flash_page, FLASH_HEADER_LEN, PAGE_SIZE};
+
+/// Everything set up by `init_peripherals`, both on the device and in the emulator
+pub struct Peripherals {
+    pub nfc: crate::hw::NfcIc,
+    pub nfc_interrupt: crate::hw::NfcInterrupt,
+    pub nfc_finished: ChannelReceiver<()>,
+    pub display: crate::hw::Display,
+    pub display_status: model::peripheral_config::DisplayStatus,
+    pub tsc: crate::hw::Tsc,
+    pub rng: rand_chacha::ChaCha20Rng,
+    pub flash: crate::hw::Flash,
+    pub rtc: crate::hw::Rtc,
+    /// A valid checkpoint was found, see [`crate::checkpoint::validate`]
+    pub fast_boot: bool,
+}
 
 pub struct NfcChannelsLocal {
     pub outgoing: ChannelReceiver<Reply>,
     pub incoming: ChannelSender<Request>,
 }
+/// The handlers' end of the NFC channels, used through [`model::transport::Transport`]
 pub struct NfcChannelsShared {
-    pub outgoing: ChannelSender<Reply>,
-    pub incoming: ChannelReceiver<Request>,
+    outgoing: RefCell<ChannelSender<Reply>>,
+    incoming: RefCell<ChannelReceiver<Request>>,
 }
 
 pub fn make_nfc_channels() -> (NfcChannelsLocal, NfcChannelsShared) {
@@ -49,13 +73,27 @@ pub fn make_nfc_channels() -> (NfcChannelsLocal, NfcChannelsShared) {
         incoming: request_sender,
     };
     let shared = NfcChannelsShared {
-        outgoing: reply_sender,
-        incoming: request_receiver,
+        outgoing: RefCell::new(reply_sender),
+        incoming: RefCell::new(request_receiver),
     };
 
     (local, shared)
 }
 
+// The two directions are borrowed separately, so a reply can be sent while the event stream is
+// waiting for the next request
+impl model::transport::Transport for NfcChannelsShared {
+    async fn recv_request(&self) -> Result<Request, Disconnected> {
+        let mut incoming = self.incoming.borrow_mut();
+        incoming.recv().await.map_err(|_| Disconnected)
+    }
+
+    async fn send_reply(&self, reply: Reply) -> Result<(), Disconnected> {
+        let mut outgoing = self.outgoing.borrow_mut();
+        outgoing.send(reply).await.map_err(|_| Disconnected)
+    }
+}
+
 pub struct TscEnable {
     bool_ref: Rc<RefCell<bool>>,
 }
@@ -75,4 +113,121 @@ impl TscEnable {
     pub fn disable(&self) {
         *self.bool_ref.borrow_mut() = false;
     }
+
+    pub fn is_enabled(&self) -> bool {
+        *self.bool_ref.borrow()
+    }
+}
+
+/// Whether an RF field was present the last time the NFC task looked for it
+#[derive(Clone)]
+pub struct FieldStatus {
+    bool_ref: Rc<RefCell<bool>>,
+}
+
+impl FieldStatus {
+    pub fn new(present: bool) -> Self {
+        FieldStatus {
+            bool_ref: Rc::new(RefCell::new(present)),
+        }
+    }
+
+    pub fn set_present(&self, present: bool) {
+        *self.bool_ref.borrow_mut() = present;
+    }
+
+    pub fn is_present(&self) -> bool {
+        *self.bool_ref.borrow()
+    }
+}
+
+/// Number of brightness levels accepted by [`set_display_brightness`]
+pub const BRIGHTNESS_LEVELS: u8 = 5;
+
+/// Map a level between `0` (dimmest) and `BRIGHTNESS_LEVELS - 1` (brightest) to the
+/// ssd1306 presets
+pub fn brightness_from_level(level: u8) -> Option<Brightness> {
+    match level {
+        0 => Some(Brightness::DIMMEST),
+        1 => Some(Brightness::DIM),
+        2 => Some(Brightness::NORMAL),
+        3 => Some(Brightness::BRIGHT),
+        4 => Some(Brightness::BRIGHTEST),
+        _ => None,
+    }
+}
+
+pub fn set_display_brightness(
+    display: &mut crate::hw::Display,
+    level: u8,
+) -> Result<Brightness, crate::Error> {
+    let brightness = brightness_from_level(level).ok_or(crate::Error::InvalidSetting)?;
+    display.set_brightness(brightness)?;
+
+    Ok(brightness)
+}
+
+/// The display is mounted upside down, so [`model::Rotation::Normal`] is `Rotate180`
+pub fn set_display_rotation(
+    display: &mut crate::hw::Display,
+    rotation: model::Rotation,
+) -> Result<(), crate::Error> {
+    let rotation = match rotation {
+        model::Rotation::Normal => DisplayRotation::Rotate180,
+        model::Rotation::Flipped => DisplayRotation::Rotate0,
+    };
+    display.set_rotation(rotation)?;
+
+    Ok(())
+}
+
+/// Turns off the TSC and dims the display while there's no RF field
+///
+/// When the field comes back the display brightness is restored, and the TSC is re-enabled
+/// only if it was enabled when the field was lost. The transitions are decided by
+/// [`PowerPolicy`].
+pub struct PowerManager {
+    tsc_enabled: TscEnable,
+    brightness: Brightness,
+    policy: PowerPolicy,
+}
+
+impl PowerManager {
+    pub fn new(tsc_enabled: TscEnable, brightness: Brightness) -> Self {
+        PowerManager {
+            tsc_enabled,
+            brightness,
+            policy: PowerPolicy::default(),
+        }
+    }
+
+    /// Brightness restored when the field comes back
+    pub fn set_brightness(&mut self, brightness: Brightness) {
+        self.brightness = brightness;
+    }
+
+    pub fn on_field_change(
+        &mut self,
+        present: bool,
+        display: &mut crate::hw::Display,
+    ) -> Result<(), crate::Error> {
+        match self
+            .policy
+            .on_field_change(present, self.tsc_enabled.is_enabled())
+        {
+            Some(PowerTransition::Wake { enable_tsc }) => {
+                display.set_brightness(self.brightness)?;
+                if enable_tsc {
+                    self.tsc_enabled.enable();
+                }
+            }
+            Some(PowerTransition::Idle) => {
+                self.tsc_enabled.disable();
+                display.set_brightness(Brightness::DIMMEST)?;
+            }
+            None => {}
+        }
+
+        Ok(())
+    }
 }
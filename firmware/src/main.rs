@@ -30,6 +30,8 @@ extern crate embedded_hal_1 as ehal;
 
 #[cfg(all(feature = "device", feature = "emulator"))]
 compile_error!("Cannot enable both the `device` and `emulator` features at the same time");
+#[cfg(all(feature = "deterministic-rng", feature = "production"))]
+compile_error!("The `deterministic-rng` feature is only meant for tests");
 
 #[cfg(feature = "emulator")]
 extern crate stm32f4xx_hal as hal;
@@ -45,10 +47,12 @@ mod handlers;
 #[cfg(feature = "device")]
 mod hw;
 mod hw_common;
+mod storage;
 mod version;
 #[cfg(feature = "emulator")]
 pub use emulator::*;
 
+use alloc::rc::Rc;
 use core::cell::RefCell;
 use core::mem::MaybeUninit;
 use core::ops::DerefMut;
@@ -64,6 +68,7 @@ use rtic_monotonics::systick::ExtU32;
 
 use crate::handlers::*;
 pub use error::Error;
+use model::transport::Transport;
 use model::*;
 
 #[cfg(not(feature = "emulator-fast-ticks"))]
@@ -114,7 +119,7 @@ pub mod unified_hal {
 
 #[rtic::app(device = unified_hal, peripherals = true, dispatchers = [CAN1_RX0, CAN1_RX1])]
 mod app {
-    use crate::hw_common::TscEnable;
+    use crate::hw_common::{FieldStatus, PowerManager, TscEnable};
 
     use super::*;
 
@@ -125,17 +130,18 @@ mod app {
 
     #[local]
     struct Local {
-        nfc: (hw::NfcIc, hw_common::NfcChannelsLocal),
+        nfc: (hw::NfcIc, hw_common::NfcChannelsLocal, FieldStatus),
         nfc_interrupt: hw::NfcInterrupt,
         tsc: (hw::Tsc, hw_common::ChannelSender<bool>),
         current_state: CurrentState,
         events: (
-            RefCell<hw_common::ChannelReceiver<Request>>,
+            Rc<hw_common::NfcChannelsShared>,
             RefCell<hw_common::ChannelReceiver<bool>>,
             RefCell<hw_common::ChannelReceiver<()>>,
         ),
         timer_sender: hw_common::ChannelSender<()>,
-        peripherals: handlers::HandlerPeripherals,
+        gesture_sender: hw_common::ChannelSender<bool>,
+        peripherals: handlers::HandlerPeripherals<hw_common::NfcChannelsShared>,
 
         #[cfg(feature = "emulator")]
         emulator_channels: hw::EmulatorChannels,
@@ -177,24 +183,51 @@ mod app {
         dp.RCC.apb2enr.write(|w| w.syscfgen().set_bit());
 
         #[allow(unused_mut)]
-        let (
+        let hw_common::Peripherals {
             mut nfc,
             nfc_interrupt,
             nfc_finished,
             display,
-            tsc,
+            display_status,
+            mut tsc,
             mut rng,
-            flash,
+            mut flash,
             rtc,
             mut fast_boot,
-        ) = hw::init_peripherals(dp, cp).unwrap();
+        } = {
+            let peripheral_config = model::peripheral_config::PeripheralConfig::new(
+                config::DISPLAY_I2C_HZ,
+                config::NFC_I2C_HZ,
+            )
+            .expect("Valid I2C speeds");
+            #[cfg(not(feature = "deterministic-rng"))]
+            let peripherals = hw::init_peripherals(dp, cp, peripheral_config);
+            #[cfg(feature = "deterministic-rng")]
+            let peripherals = hw::init_peripherals_with_seed(
+                dp,
+                cp,
+                peripheral_config,
+                hw_common::DETERMINISTIC_RNG_SEED,
+            );
+            peripherals.unwrap()
+        };
 
         log::debug!("Initialized peripherals");
 
+        #[cfg(feature = "device")]
+        config::calibrate_tsc(&mut tsc, &mut flash);
+
         let tsc_enabled = TscEnable::new(tsc.get_enabled_ref());
+        // Same brightness set by `init_peripherals`, replaced by the stored setting if any
+        let power = PowerManager::new(
+            TscEnable::new(tsc.get_enabled_ref()),
+            ssd1306::prelude::Brightness::DIMMEST,
+        );
+        let field = FieldStatus::new(true);
 
         type Empty = ();
         let (nfc_local, nfc_shared) = hw_common::make_nfc_channels();
+        let nfc_shared = Rc::new(nfc_shared);
         let (tsc_sender, tsc_receiver) = rtic_sync::make_channel!(bool, 1);
         let (timer_sender, timer_receiver) = rtic_sync::make_channel!(Empty, 1);
 
@@ -204,6 +237,7 @@ mod app {
         #[cfg(feature = "emulator")]
         let emulator_channels = {
             use crate::hw::EmulatedNT3H;
+            #[cfg(not(feature = "deterministic-rng"))]
             use rand::SeedableRng;
 
             let (flash_sender, flash_receiver) = rtic_sync::make_channel!(alloc::vec::Vec::<u8>, 1);
@@ -214,6 +248,7 @@ mod app {
             hw::report_finish_boot();
 
             let mut entropy = alloc::vec![];
+            let mut new_magic = true;
             let mut found = 0;
             // A bit hacky but at this point serial interrupts aren't setup yet so we have to wait for the entropy here
             while found < 2 {
@@ -226,22 +261,28 @@ mod app {
                             found += 1;
                         } else if val == crate::emulator::PeripheralIncomingMsg::RtcRegister {
                             log::debug!("Registers = {:02X?}", &data[..4]);
-                            fast_boot = u32::from_be_bytes(data[..4].try_into().unwrap())
-                                == checkpoint::MAGIC;
+                            let magic = u32::from_be_bytes(data[..4].try_into().unwrap());
+                            let status = checkpoint::validate(Some(magic));
+                            fast_boot = status.can_fast_boot();
+                            new_magic = status.needs_new_magic();
                             found += 1;
                         }
                     }
                 }
             }
-            log::debug!("Seeding rng with {:02X?}", entropy);
-            rng = rand_chacha::ChaCha20Rng::from_seed(entropy.try_into().unwrap());
+            // With `deterministic-rng` the fixed seed is kept, the entropy is only consumed
+            #[cfg(not(feature = "deterministic-rng"))]
+            {
+                log::debug!("Seeding rng with {:02X?}", entropy);
+                rng = rand_chacha::ChaCha20Rng::from_seed(entropy.try_into().unwrap());
+            }
 
-            if !fast_boot {
+            if new_magic {
                 let msg = model::emulator::CardMessage::WriteRtcRegister(
                     checkpoint::MAGIC_REGISTER as u8,
                     checkpoint::MAGIC,
                 );
-                super::write_serial(msg.write_to());
+                super::write_message(&msg);
             }
 
             hw::EmulatorChannels {
@@ -254,12 +295,15 @@ mod app {
 
         let peripherals = HandlerPeripherals {
             display,
+            display_status,
             rng,
             flash,
             rtc,
-            nfc: nfc_shared.outgoing,
+            nfc: Rc::clone(&nfc_shared),
             nfc_finished,
             tsc_enabled,
+            field: field.clone(),
+            power,
         };
 
         nfc_read_loop::spawn(noise_rng).unwrap();
@@ -269,16 +313,17 @@ mod app {
         (
             Shared { fast_boot },
             Local {
-                nfc: (nfc, nfc_local),
+                nfc: (nfc, nfc_local, field),
                 nfc_interrupt,
-                tsc: (tsc, tsc_sender),
+                tsc: (tsc, tsc_sender.clone()),
                 current_state: CurrentState::POR,
                 events: (
-                    RefCell::new(nfc_shared.incoming),
+                    nfc_shared,
                     RefCell::new(tsc_receiver),
                     RefCell::new(timer_receiver),
                 ),
                 timer_sender,
+                gesture_sender: tsc_sender,
                 peripherals,
 
                 #[cfg(feature = "emulator")]
@@ -297,13 +342,12 @@ mod app {
     #[task(priority = 1, local = [current_state, peripherals, events], shared = [fast_boot])]
     async fn main_task(mut cx: main_task::Context) {
         let stream = futures::stream::repeat(&cx.local.events);
-        let stream = stream.then(|(nfc_incoming, last_tsc_read, timer)| async move {
-            let mut nfc_incoming = nfc_incoming.borrow_mut();
+        let stream = stream.then(|(nfc, last_tsc_read, timer)| async move {
             let mut last_tsc_read = last_tsc_read.borrow_mut();
             let mut timer = timer.borrow_mut();
 
             let input = last_tsc_read.recv().fuse();
-            let request = nfc_incoming.recv().fuse();
+            let request = nfc.recv_request().fuse();
             let timer = timer.recv().fuse();
 
             pin_mut!(input);
@@ -320,6 +364,41 @@ mod app {
         pin_mut!(stream);
         let fast_boot = cx.shared.fast_boot.lock(|v| *v);
 
+        let peripherals = &mut *cx.local.peripherals;
+        if checkpoint::validate(
+            peripherals
+                .rtc
+                .read_backup_register(checkpoint::MAGIC_REGISTER),
+        ) == checkpoint::CheckpointStatus::WipePending
+        {
+            log::warn!("Completing interrupted factory reset");
+            if let Err(e) = config::factory_reset(
+                &mut peripherals.flash,
+                &peripherals.rtc,
+                &mut peripherals.rng,
+            ) {
+                log::warn!("Unable to complete factory reset: {:?}", e);
+            }
+        }
+        if let Err(e) = storage::recover_flash_transaction(&mut cx.local.peripherals.flash) {
+            log::warn!("Unable to recover flash transaction: {:?}", e);
+        }
+        match checkpoint::compact_aux(&mut cx.local.peripherals.flash) {
+            Ok(0) => {}
+            Ok(freed) => log::debug!("Freed {} checkpoint pages", freed),
+            Err(e) => log::warn!("Unable to compact the checkpoint pages: {:?}", e),
+        }
+        if let Err(e) = config::apply_settings(cx.local.peripherals) {
+            log::warn!("Unable to apply settings: {:?}", e);
+        }
+        if peripherals.display_status == model::peripheral_config::DisplayStatus::Failed {
+            // Not committing the version, so that a firmware that can't drive the display can
+            // be replaced by an older one
+            handle_display_failure(&mut stream, cx.local.peripherals).await;
+        }
+        // Reaching this point means the running firmware booted successfully
+        version::commit_fw_version(&cx.local.peripherals.rtc, version::CURRENT_VERSION);
+
         *cx.local.current_state = if fast_boot {
             checkpoint::Checkpoint::load(cx.local.peripherals)
                 .and_then(|checkpoint| checkpoint.into_current_state(cx.local.peripherals))
@@ -336,55 +415,102 @@ mod app {
                 fast_boot,
             )
             .await;
+
+            #[cfg(feature = "device")]
+            hw::reseed_from_rtc(&mut cx.local.peripherals.rng, &cx.local.peripherals.rtc);
         }
     }
 
     #[task(priority = 2, local = [nfc])]
     async fn nfc_read_loop(cx: nfc_read_loop::Context, mut noise_rng: rand_chacha::ChaCha20Rng) {
-        let (ref mut nfc, ref mut nfc_channels) = cx.local.nfc;
+        let (ref mut nfc, ref mut nfc_channels, ref field) = cx.local.nfc;
 
         nfc.apply_configuration()
             .await
             .expect("Initial config should work");
 
+        let mut replay_cache = model::encryption::ReplayCache::new();
+
         loop {
-            let (mut decrypt, mut encrypt) = loop {
+            let ((mut decrypt, mut encrypt), session_aad) = loop {
                 async fn do_handshake<R: RngCore>(
                     noise_rng: &mut R,
                     nfc: &mut hw::NfcIc,
+                    replay_cache: &mut model::encryption::ReplayCache,
                 ) -> Result<
                     (
-                        model::encryption::CipherState,
-                        model::encryption::CipherState,
+                        (
+                            model::encryption::CipherState,
+                            model::encryption::CipherState,
+                        ),
+                        model::SessionAad,
                     ),
                     Error,
                 > {
                     log::info!("Starting Noise handshake...");
 
-                    let mut ephemeral_key = model::encryption::wrap_sensitive([0; 32]);
-                    noise_rng.fill_bytes(ephemeral_key.deref_mut());
-
-                    let handshake_incoming = nfc.read_handshake().await?;
-                    let mut handshake_state =
-                        model::encryption::handhake_state_responder(ephemeral_key);
-                    let _ = handshake_state
-                        .read_message_vec(&handshake_incoming)
+                    // A fresh ID for every session, so that messages can't be replayed across
+                    // sessions
+                    let mut session_id = [0; model::SESSION_ID_LEN];
+                    noise_rng.fill_bytes(&mut session_id);
+
+                    let now = || rtic_monotonics::systick::Systick::now().ticks() as u64;
+                    // Every reset starts over with a new ephemeral key, the old state is zeroized
+                    // when it's dropped
+                    let mut handshake = model::encryption::TimedHandshake::new(
+                        || {
+                            let mut ephemeral_key = model::encryption::wrap_sensitive([0; 32]);
+                            noise_rng.fill_bytes(ephemeral_key.deref_mut());
+                            model::encryption::handhake_state_responder(ephemeral_key)
+                        },
+                        now(),
+                    );
+
+                    // The app may go away halfway through a message: give up once the timeout
+                    // expires, so that the state is dropped and the handshake starts over
+                    let timeout = model::encryption::HANDSHAKE_TIMEOUT_MS as u32;
+                    let handshake_incoming = select_biased! {
+                        msg = nfc.read_handshake().fuse() => msg?,
+                        _ = rtic_monotonics::systick::Systick::delay(timeout.millis()).fuse() => {
+                            log::debug!("Handshake timed out");
+                            return Err(Error::HandshakeTimeout);
+                        }
+                    };
+                    if !replay_cache.check_and_insert(&handshake_incoming) {
+                        log::warn!("Replayed handshake message");
+                        return Err(Error::HandshakeError);
+                    }
+                    let _ = handshake
+                        .read_message(&handshake_incoming, now())
                         .map_err(|_| Error::HandshakeError)?;
-                    let reply = handshake_state
-                        .write_message_vec(&[])
+
+                    let reply = handshake
+                        .write_message(&session_id, now())
                         .map_err(|_| Error::HandshakeError)?;
                     nfc.send_handshake_reply(&reply).await?;
 
-                    if !handshake_state.completed() {
+                    if !handshake.completed() {
                         Err(Error::HandshakeError)
                     } else {
                         log::info!("Handshake completed");
-                        Ok(handshake_state.get_ciphers())
+                        Ok((
+                            handshake.into_state().get_ciphers(),
+                            model::SessionAad::new(&session_id),
+                        ))
                     }
                 }
 
-                match do_handshake(&mut noise_rng, nfc).await {
+                match do_handshake(&mut noise_rng, nfc, &mut replay_cache).await {
                     Ok(v) => break v,
+                    // Nobody is talking to us, not worth a warning. Check whether a phone is
+                    // still close, the main task dims the display otherwise
+                    Err(Error::HandshakeTimeout) => {
+                        match nfc.field_present() {
+                            Ok(present) => field.set_present(present),
+                            Err(e) => log::warn!("Unable to read the field status: {:?}", e),
+                        }
+                        continue;
+                    }
                     Err(e) => {
                         log::warn!("Handshake error: {:?}", e);
                         continue;
@@ -393,8 +519,21 @@ mod app {
             };
 
             'inner: loop {
-                let req = match nfc.accept_request(&mut decrypt).await {
-                    Ok(req) => req,
+                let req = match nfc.accept_request(&mut decrypt, &session_aad).await {
+                    Ok(req) => {
+                        field.set_present(true);
+                        req
+                    }
+                    Err(Error::Message(model::MessageError::UnsupportedProtocol(version))) => {
+                        log::warn!("Request with unsupported protocol version {}", version);
+
+                        let reply = model::Reply::UnsupportedProtocol(model::PROTOCOL_VERSION);
+                        if let Err(e) = nfc.send_reply(&reply, &mut encrypt, &session_aad).await {
+                            log::error!("Error writing unsupported protocol reply: {:?}", e);
+                        }
+
+                        continue 'inner;
+                    }
                     Err(e) => {
                         // `accept_request` sends a special packet back to the RF side to
                         // let them know we couldn't decrypt the message, so we don't reply
@@ -412,12 +551,32 @@ mod app {
                         _ = rtic_monotonics::systick::Systick::delay(1000.millis()).fuse() => model::Reply::Pong,
                     };
 
-                    if let Err(e) = nfc.send_reply(&reply, &mut encrypt).await {
+                    if let Err(e) = nfc.send_reply(&reply, &mut encrypt, &session_aad).await {
                         log::error!("Error writing pong reply: {:?}", e);
                     }
 
                     continue 'inner;
                 }
+                if let model::Request::GetProtocolVersion = req {
+                    let reply = model::Reply::ProtocolVersion(model::PROTOCOL_VERSION);
+                    if let Err(e) = nfc.send_reply(&reply, &mut encrypt, &session_aad).await {
+                        log::error!("Error writing protocol version reply: {:?}", e);
+                    }
+
+                    continue 'inner;
+                }
+                if let model::Request::GetDeviceInfo = req {
+                    let reply = model::Reply::DeviceInfo {
+                        fw_version: env!("CARGO_PKG_VERSION").into(),
+                        hw_rev: version::HW_REVISION,
+                        serial: hw::device_serial(),
+                    };
+                    if let Err(e) = nfc.send_reply(&reply, &mut encrypt, &session_aad).await {
+                        log::error!("Error writing device info reply: {:?}", e);
+                    }
+
+                    continue 'inner;
+                }
 
                 nfc_channels
                     .incoming
@@ -430,7 +589,7 @@ mod app {
                     .await
                     .expect("Receive should work");
 
-                if let Err(e) = nfc.send_reply(&reply, &mut encrypt).await {
+                if let Err(e) = nfc.send_reply(&reply, &mut encrypt, &session_aad).await {
                     log::error!("Error writing reply: {:?}", e);
                 }
             }
@@ -452,6 +611,23 @@ mod app {
         }
     }
 
+    #[task(priority = 2, local = [gesture_sender])]
+    async fn play_gesture(_cx: play_gesture::Context, _presses: u8, _hold_ms: u16) {
+        // Gestures are only injected by the emulator, on the device the TSC interrupt
+        // reports the raw readings
+        #[cfg(feature = "emulator")]
+        for i in 0.._presses {
+            if i > 0 {
+                let gap = emulator::TAP_MILLIS as u32;
+                rtic_monotonics::systick::Systick::delay(gap.millis()).await;
+            }
+
+            let _ = _cx.local.gesture_sender.send(true).await;
+            rtic_monotonics::systick::Systick::delay((_hold_ms as u32).millis()).await;
+            let _ = _cx.local.gesture_sender.send(false).await;
+        }
+    }
+
     #[task(binds = USART1, local = [emulator_channels], priority = 3)]
     fn emulator_hook(_cx: emulator_hook::Context) {
         #[cfg(feature = "emulator")]
@@ -465,6 +641,21 @@ mod app {
 
                 let _ = _cx.local.emulator_channels.tsc.try_send(v);
             }
+            Some(emulator::PeripheralIncomingMsg::TscGesture) => {
+                let data = emulator::read_serial();
+                match model::emulator::Gesture::decode(&data) {
+                    Some(gesture) => {
+                        let (presses, hold_ms) = emulator::gesture_presses(gesture);
+                        if play_gesture::spawn(presses, hold_ms).is_err() {
+                            log::warn!("Another gesture is still being played");
+                        }
+                    }
+                    None => log::warn!("Invalid gesture: {:02X?}", data),
+                }
+            }
+            Some(emulator::PeripheralIncomingMsg::Snapshot) => {
+                hw::report_snapshot();
+            }
             Some(emulator::PeripheralIncomingMsg::Reset) => {
                 cortex_m::peripheral::SCB::sys_reset();
             }
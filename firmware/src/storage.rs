@@ -0,0 +1,176 @@
+// Portal Hardware Wallet firmware and supporting software libraries
+//
+// Copyright (C) 2024 Alekos Filini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use core::ops::Range;
+
+#[cfg(feature = "emulator")]
+use crate::emulator::flash;
+#[cfg(feature = "device")]
+use stm32l4xx_hal::{flash, flash::Read, flash::WriteErase};
+
+use model::storage::{PageStorage, StorageError};
+
+use crate::hw::{Flash, FlashError};
+use crate::hw_common::PAGE_SIZE;
+
+pub use model::storage::{BankStatus, FlashBank, FlashRing};
+
+#[cfg(feature = "device")]
+pub type UnlockedFlash<'a> = flash::FlashProgramming<'a>;
+#[cfg(feature = "emulator")]
+pub type UnlockedFlash<'a> = crate::emulator::flash::UnlockedFlash<'a>;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BankToFlash {
+    pub physical: FlashBank,
+}
+
+impl BankToFlash {
+    pub fn new(physical: FlashBank) -> Self {
+        BankToFlash { physical }
+    }
+
+    /// Target the bank that is not currently booted
+    pub fn spare(flash: &Flash) -> Self {
+        BankToFlash::new(FlashBank::spare(flash.fb_mode))
+    }
+
+    pub fn physical_bank_page(bank: FlashBank, page: usize) -> flash::FlashPage {
+        flash::FlashPage(model::storage::physical_page_index(bank, page))
+    }
+
+    /// Address of `page` in the active or spare bank, which doesn't depend on the booted bank
+    pub fn get_logical_address(&self, which: BankStatus, page: usize) -> usize {
+        flash::FlashPage(model::storage::logical_page_index(which, page)).to_address()
+    }
+
+    pub fn get_physical_page(&self, which: BankStatus, page: usize) -> flash::FlashPage {
+        Self::physical_bank_page(FlashBank::with_status(self.physical, which), page)
+    }
+}
+
+pub fn read_flash_wear_leveled<'b>(
+    flash: &mut Flash,
+    ring: &FlashRing,
+    buf: &'b mut [u8; PAGE_SIZE],
+) -> Result<&'b [u8], FlashError> {
+    ring.read(flash, buf)
+}
+
+pub fn write_flash_wear_leveled(
+    flash: &mut Flash,
+    ring: &FlashRing,
+    serialized: &[u8],
+) -> Result<(), FlashError> {
+    ring.write(flash, serialized)
+}
+
+/// Write a page of the active bank only if its content differs from what is currently stored
+///
+/// Returns whether the page was actually written, see [`model::storage::update_if_changed`].
+pub fn update_flash_if_changed(
+    flash: &mut Flash,
+    page: usize,
+    serialized: &[u8],
+) -> Result<bool, FlashError> {
+    model::storage::update_if_changed(flash, BankStatus::Active, page, serialized)
+}
+
+/// Erase every page of a [`FlashRing`] that still holds a version of the record
+pub fn erase_flash_ring(flash: &mut Flash, ring: &FlashRing) -> Result<(), FlashError> {
+    ring.erase(flash)
+}
+
+/// Reclaim the pages of the ring over `logical_pages` that only contain old versions of the
+/// record, see [`FlashRing::compact`]
+///
+/// Returns the number of pages freed.
+pub fn compact_flash(flash: &mut Flash, logical_pages: Range<usize>) -> Result<usize, FlashError> {
+    FlashRing::new(logical_pages.start, logical_pages.len()).compact(flash)
+}
+
+impl From<StorageError> for FlashError {
+    fn from(_: StorageError) -> Self {
+        FlashError::CorruptedData
+    }
+}
+
+impl PageStorage for Flash {
+    type Error = FlashError;
+
+    fn read_page(
+        &mut self,
+        bank: BankStatus,
+        page: usize,
+        buf: &mut [u8; PAGE_SIZE],
+    ) -> Result<(), FlashError> {
+        let bank_to_flash = BankToFlash::spare(self);
+        let prog = unlock_flash(self)?;
+        prog.read(bank_to_flash.get_logical_address(bank, page), buf);
+
+        Ok(())
+    }
+
+    fn erase_page(&mut self, bank: BankStatus, page: usize) -> Result<(), FlashError> {
+        let bank_to_flash = BankToFlash::spare(self);
+        let mut prog = unlock_flash(self)?;
+        prog.erase_page(bank_to_flash.get_physical_page(bank, page))?;
+
+        Ok(())
+    }
+
+    fn write_page(
+        &mut self,
+        bank: BankStatus,
+        page: usize,
+        data: &[u8; PAGE_SIZE],
+    ) -> Result<(), FlashError> {
+        let bank_to_flash = BankToFlash::spare(self);
+        let mut prog = unlock_flash(self)?;
+        prog.write(bank_to_flash.get_logical_address(bank, page), data)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "device")]
+fn unlock_flash(flash: &mut Flash) -> Result<UnlockedFlash<'_>, FlashError> {
+    Ok(flash
+        .parts
+        .keyr
+        .unlock_flash(&mut flash.parts.sr, &mut flash.parts.cr)?)
+}
+#[cfg(feature = "emulator")]
+fn unlock_flash(flash: &mut Flash) -> Result<UnlockedFlash<'_>, FlashError> {
+    Ok(flash.unlock())
+}
+
+/// Atomically write multiple pages, see [`model::storage::write_transaction`]
+pub fn write_flash_transaction(
+    flash: &mut Flash,
+    pages: &[(usize, &[u8])],
+) -> Result<(), FlashError> {
+    model::storage::write_transaction(flash, pages)
+}
+
+/// Complete a transaction that was committed but not fully copied to its target pages
+///
+/// This is a no-op if there's no pending transaction, see
+/// [`model::storage::recover_transaction`].
+pub fn recover_flash_transaction(flash: &mut Flash) -> Result<(), FlashError> {
+    model::storage::recover_transaction(flash)
+}
@@ -1,3 +1,5 @@
+use model::fw_manifest::{check_rollback, FwError};
+
 const fn parse_int(s: &str) -> u32 {
     let mut v = 0;
     let mut i = 0;
@@ -36,10 +38,36 @@ const fn get_current_version() -> u32 {
 }
 
 pub const CURRENT_VERSION: u32 = get_current_version();
+
+/// Revision of the board the firmware is built for
+pub const HW_REVISION: u8 = 1;
 pub const CURRENT_VARIANT: u8 = 0x00;
 
 pub const TAIL_SIZE: usize = 5;
 
+/// RTC backup register storing the highest firmware version ever installed
+///
+/// Checkpoints never touch this register, so it survives resets and fast boots. It's only
+/// cleared by a reset of the backup domain, which also wipes the fast boot key.
+pub const FW_VERSION_REGISTER: usize = 31;
+
+fn read_installed_version(rtc: &crate::hw::Rtc) -> Option<u32> {
+    rtc.read_backup_register(FW_VERSION_REGISTER)
+        .filter(|v| *v != 0)
+}
+
+/// Refuse to flash an image older than the highest version ever installed
+pub fn check_fw_version(rtc: &crate::hw::Rtc, new: u32) -> Result<(), FwError> {
+    check_rollback(read_installed_version(rtc), new)
+}
+
+/// Record `installed` as the minimum version for future updates. The counter never goes down.
+pub fn commit_fw_version(rtc: &crate::hw::Rtc, installed: u32) {
+    if read_installed_version(rtc).map_or(true, |v| installed > v) {
+        rtc.write_backup_register(FW_VERSION_REGISTER, installed);
+    }
+}
+
 #[derive(Debug)]
 pub struct UpdateTail {
     pub version: u32,
@@ -28,6 +28,9 @@ use embedded_graphics::text::{Alignment, Baseline, Text, TextStyleBuilder};
 
 use model::bitcoin::{Address, Amount, Denomination};
 
+mod qr;
+pub use qr::{draw_qr, QrCode, QrError, MAX_VERSION as MAX_QR_VERSION};
+
 const AMOUNT_Y_OFFSET: i32 = 6;
 
 pub trait Page {
@@ -170,11 +173,11 @@ macro_rules! impl_wrapper_page {
 
 pub struct InitialPage<'s> {
     welcome: Text<'s, MonoTextStyle<'static, BinaryColor>>,
-    version: Text<'static, MonoTextStyle<'static, BinaryColor>>,
+    version: Text<'s, MonoTextStyle<'static, BinaryColor>>,
 }
 
 impl<'s> InitialPage<'s> {
-    pub fn new(welcome: &'s str, version: &'static str) -> Self {
+    pub fn new(welcome: &'s str, version: &'s str) -> Self {
         InitialPage {
             welcome: Text::with_text_style(
                 welcome,
@@ -399,21 +402,94 @@ impl MainContent for EmptyContent {
         Ok(())
     }
 }
-pub struct FwUpdateProgressPage(ConfirmBarPage<'static, EmptyContent>);
-impl_wrapper_page!(FwUpdateProgressPage, ConfirmBarPage<'static, EmptyContent>);
-impl FwUpdateProgressPage {
-    pub fn new(threshold: u32) -> Self {
-        FwUpdateProgressPage(ConfirmBarPage::new(
-            threshold,
-            EmptyContent,
-            "",
-            "UPDATE IN PROGRESS",
-            52,
-            true,
-        ))
+/// Progress bar with a label and a percentage, for long operations
+pub struct ProgressPage<'s> {
+    fraction: f32,
+    label: &'s str,
+}
+
+impl<'s> ProgressPage<'s> {
+    /// `fraction` is clamped to `0..=1`, `NaN` is treated as `0`
+    pub fn new(fraction: f32, label: &'s str) -> Self {
+        let fraction = if fraction.is_nan() {
+            0.0
+        } else {
+            fraction.clamp(0.0, 1.0)
+        };
+
+        ProgressPage { fraction, label }
+    }
+
+    pub fn percent(&self) -> u8 {
+        (self.fraction * 100.0) as u8
+    }
+}
+
+impl<'s> Page for ProgressPage<'s> {
+    fn draw_to<T>(&self, target: &mut T) -> Result<(), <T as DrawTarget>::Error>
+    where
+        T: DrawTarget<Color = BinaryColor>,
+    {
+        let screen_size = target.bounding_box();
+
+        let text = Text::with_text_style(
+            self.label,
+            Point::new((screen_size.size.width / 2) as i32, 16),
+            MonoTextStyle::new(&ascii::FONT_6X10, On),
+            TextStyleBuilder::new()
+                .alignment(Alignment::Center)
+                .baseline(Baseline::Middle)
+                .build(),
+        );
+        text.draw(target)?;
+
+        let outline = Rectangle::new(Point::new(4, 30), Size::new(screen_size.size.width - 8, 12));
+        outline
+            .into_styled(PrimitiveStyle::with_stroke(On, 1))
+            .draw(target)?;
+
+        let inner_width = outline.size.width - 4;
+        let filled = (inner_width as f32 * self.fraction) as u32;
+        Rectangle::new(Point::new(6, 32), Size::new(inner_width, 8))
+            .into_styled(PrimitiveStyle::with_fill(Off))
+            .draw(target)?;
+        Rectangle::new(Point::new(6, 32), Size::new(filled, 8))
+            .into_styled(PrimitiveStyle::with_fill(On))
+            .draw(target)?;
+
+        let percent = alloc::format!("{}%", self.percent());
+        Rectangle::new(Point::new(0, 46), Size::new(screen_size.size.width, 8))
+            .into_styled(PrimitiveStyle::with_fill(Off))
+            .draw(target)?;
+        let text = Text::with_text_style(
+            &percent,
+            Point::new((screen_size.size.width / 2) as i32, 46),
+            MonoTextStyle::new(&ascii::FONT_5X8, On),
+            TextStyleBuilder::new()
+                .alignment(Alignment::Center)
+                .baseline(Baseline::Top)
+                .build(),
+        );
+        text.draw(target)?;
+
+        Ok(())
     }
 }
 
+/// Clear the target and draw a [`ProgressPage`]
+pub fn draw_progress<T>(
+    target: &mut T,
+    fraction: f32,
+    label: &str,
+) -> Result<(), <T as DrawTarget>::Error>
+where
+    T: DrawTarget<Color = BinaryColor> + Dimensions,
+{
+    let page = ProgressPage::new(fraction, label);
+    page.init_display(target)?;
+    page.draw_to(target)
+}
+
 pub struct SummaryPageContent<'s>(&'s str);
 impl<'s> MainContent for SummaryPageContent<'s> {
     fn draw_to<T>(&self, target: &mut T) -> Result<(), <T as DrawTarget>::Error>
@@ -727,6 +803,51 @@ impl<'s> ShowScrollingAddressPage<'s> {
     }
 }
 
+/// Height of the area above the confirmation bar of a [`QrPage`]
+const QR_AREA_HEIGHT: u32 = 54;
+
+pub struct QrPageContent {
+    qr: QrCode,
+}
+
+impl MainContent for QrPageContent {
+    fn draw_to<T>(&self, target: &mut T) -> Result<(), <T as DrawTarget>::Error>
+    where
+        T: DrawTarget<Color = BinaryColor>,
+    {
+        let area = Rectangle::new(
+            Point::zero(),
+            Size::new(target.bounding_box().size.width, QR_AREA_HEIGHT),
+        );
+        match self.qr.draw_to(&mut target.cropped(&area)) {
+            // Checked in `QrPage::new`
+            Ok(()) | Err(QrError::DataTooLong) => Ok(()),
+            Err(QrError::Draw(e)) => Err(e),
+        }
+    }
+}
+
+pub struct QrPage<'s>(ConfirmBarPage<'s, QrPageContent>);
+impl_wrapper_page!(QrPage<'s>, ConfirmBarPage<'s, QrPageContent>);
+impl<'s> QrPage<'s> {
+    /// Show `data` as a QR code above the confirmation bar, `None` if it doesn't fit
+    pub fn new(data: &[u8], bar_message: &'s str) -> Option<Self> {
+        let qr = QrCode::encode(data)?;
+        if !qr.fits(Rectangle::new(Point::zero(), Size::new(128, QR_AREA_HEIGHT))) {
+            return None;
+        }
+
+        Some(QrPage(ConfirmBarPage::new(
+            100,
+            QrPageContent { qr },
+            bar_message,
+            "KEEP HOLDING...",
+            QR_AREA_HEIGHT as i32,
+            false,
+        )))
+    }
+}
+
 pub struct TxSummaryPageContent {
     fees: Amount,
 }
@@ -903,3 +1024,90 @@ impl<'s> Page for ErrorPage<'s> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::convert::Infallible;
+
+    struct FrameBuffer([[bool; 128]; 64]);
+
+    impl OriginDimensions for FrameBuffer {
+        fn size(&self) -> Size {
+            Size::new(128, 64)
+        }
+    }
+
+    impl DrawTarget for FrameBuffer {
+        type Color = BinaryColor;
+        type Error = Infallible;
+
+        fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+        where
+            I: IntoIterator<Item = Pixel<Self::Color>>,
+        {
+            for Pixel(p, color) in pixels {
+                self.0[p.y as usize][p.x as usize] = color.is_on();
+            }
+            Ok(())
+        }
+    }
+
+    /// Columns lit in `rows`
+    fn lit_columns(fb: &FrameBuffer, rows: core::ops::Range<usize>) -> Vec<usize> {
+        (0..128)
+            .filter(|x| rows.clone().any(|y| fb.0[y][*x]))
+            .collect()
+    }
+
+    #[test]
+    fn test_progress_page_extents() {
+        // The bar is 116 pixels wide, starting at x = 6
+        for (fraction, filled, percent) in [
+            (0.0, 0, "0%"),
+            (0.5, 58, "50%"),
+            (1.0, 116, "100%"),
+            (-1.0, 0, "0%"),
+            (2.0, 116, "100%"),
+            (f32::NAN, 0, "0%"),
+        ] {
+            let mut fb = FrameBuffer([[false; 128]; 64]);
+            draw_progress(&mut fb, fraction, "SIGNING").unwrap();
+
+            // Outline
+            for y in 30..42 {
+                assert!(fb.0[y][4] && fb.0[y][123], "{} row {}", fraction, y);
+            }
+            for x in 4..124 {
+                assert!(fb.0[30][x] && fb.0[41][x], "{} column {}", fraction, x);
+            }
+            assert!(!fb.0[29][4] && !fb.0[42][4] && !fb.0[36][3] && !fb.0[36][124]);
+
+            // Fill, with a one pixel gap from the outline
+            for y in 32..40 {
+                let row = (5..123).filter(|x| fb.0[y][*x]).collect::<Vec<_>>();
+                assert_eq!(row, (6..6 + filled).collect::<Vec<_>>(), "{}", fraction);
+            }
+            assert!((5..123).all(|x| !fb.0[31][x] && !fb.0[40][x]));
+
+            // Percentage centered below the bar, 5 pixels per character
+            let columns = lit_columns(&fb, 46..54);
+            let width = 5 * percent.len();
+            assert!(!columns.is_empty());
+            assert!(
+                columns[0] >= 64 - width / 2 - 1,
+                "{}: {:?}",
+                percent,
+                columns
+            );
+            assert!(*columns.last().unwrap() < 64 + width / 2 + 1);
+
+            // Nothing outside of the label, the bar and the percentage
+            for y in (0..8).chain(24..30).chain(42..46).chain(54..64) {
+                assert!(fb.0[y].iter().all(|p| !p), "{} row {}", fraction, y);
+            }
+            assert!(!lit_columns(&fb, 8..24).is_empty());
+        }
+    }
+}
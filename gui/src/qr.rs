@@ -0,0 +1,656 @@
+// Portal Hardware Wallet firmware and supporting software libraries
+//
+// Copyright (C) 2024 Alekos Filini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Minimal QR code encoder (byte mode, error correction level L) sized for the 128x64 panel
+
+use embedded_graphics::pixelcolor::BinaryColor::{self, *};
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::Rectangle;
+
+/// Largest version whose symbol and quiet zone still fit in 64 pixels
+pub const MAX_VERSION: u8 = 10;
+
+const MAX_SIZE: usize = 17 + 4 * MAX_VERSION as usize;
+const MAX_CODEWORDS: usize = 346;
+const MAX_BLOCKS: usize = 4;
+const MAX_EC_LEN: usize = 30;
+const QUIET_ZONE: u32 = 2;
+
+/// Block layout for level L: (ec codewords per block, blocks in group 1, data codewords per
+/// block in group 1, blocks in group 2). Group 2 blocks carry one extra data codeword.
+const ECC_L_BLOCKS: [(usize, usize, usize, usize); MAX_VERSION as usize] = [
+    (7, 1, 19, 0),
+    (10, 1, 34, 0),
+    (15, 1, 55, 0),
+    (20, 1, 80, 0),
+    (26, 1, 108, 0),
+    (18, 2, 68, 0),
+    (20, 2, 78, 0),
+    (24, 2, 97, 0),
+    (30, 2, 116, 0),
+    (18, 2, 68, 2),
+];
+
+const ALIGNMENT_POSITIONS: [&[usize]; MAX_VERSION as usize] = [
+    &[],
+    &[6, 18],
+    &[6, 22],
+    &[6, 26],
+    &[6, 30],
+    &[6, 34],
+    &[6, 22, 38],
+    &[6, 24, 42],
+    &[6, 26, 46],
+    &[6, 28, 50],
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QrError<E> {
+    /// The data doesn't fit in the largest version that can be shown on the panel
+    DataTooLong,
+    Draw(E),
+}
+
+/// Encoded QR symbol. Each row is stored as a bitset, `true` means dark.
+#[derive(Debug, Clone)]
+pub struct QrCode {
+    version: u8,
+    size: usize,
+    modules: [u64; MAX_SIZE],
+    function: [u64; MAX_SIZE],
+}
+
+impl QrCode {
+    /// Encode `data` using the smallest version that fits, `None` if it's larger than
+    /// [`MAX_VERSION`] allows
+    pub fn encode(data: &[u8]) -> Option<Self> {
+        let version = (1..=MAX_VERSION).find(|v| data.len() <= byte_capacity(*v))?;
+
+        let mut qr = QrCode::with_function_patterns(version);
+        let mut codewords = [0u8; MAX_CODEWORDS];
+        let len = build_codewords(version, data, &mut codewords);
+        qr.place_codewords(&codewords[..len]);
+
+        let mut best = (u32::MAX, 0);
+        for mask in 0..8 {
+            qr.apply_mask(mask);
+            qr.draw_format(mask);
+            let penalty = qr.penalty();
+            if penalty < best.0 {
+                best = (penalty, mask);
+            }
+            // Masking is an xor, applying it again reverts it
+            qr.apply_mask(mask);
+        }
+        qr.apply_mask(best.1);
+        qr.draw_format(best.1);
+
+        Some(qr)
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Number of modules per side
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> bool {
+        (self.modules[y] >> x) & 1 == 1
+    }
+
+    fn with_function_patterns(version: u8) -> Self {
+        let size = 17 + 4 * version as usize;
+        let mut qr = QrCode {
+            version,
+            size,
+            modules: [0; MAX_SIZE],
+            function: [0; MAX_SIZE],
+        };
+
+        for i in 0..size {
+            qr.set_function(6, i, i % 2 == 0);
+            qr.set_function(i, 6, i % 2 == 0);
+        }
+
+        for (x, y) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            for dy in -4..=4isize {
+                for dx in -4..=4isize {
+                    let (xx, yy) = (x as isize + dx, y as isize + dy);
+                    if xx < 0 || yy < 0 || xx >= size as isize || yy >= size as isize {
+                        continue;
+                    }
+                    let dist = dx.abs().max(dy.abs());
+                    qr.set_function(xx as usize, yy as usize, dist != 2 && dist != 4);
+                }
+            }
+        }
+
+        let positions = ALIGNMENT_POSITIONS[version as usize - 1];
+        let last = positions.len().saturating_sub(1);
+        for (i, &x) in positions.iter().enumerate() {
+            for (j, &y) in positions.iter().enumerate() {
+                // Skip the ones overlapping the finder patterns
+                if (i == 0 && (j == 0 || j == last)) || (i == last && j == 0) {
+                    continue;
+                }
+                for dy in -2..=2isize {
+                    for dx in -2..=2isize {
+                        qr.set_function(
+                            (x as isize + dx) as usize,
+                            (y as isize + dy) as usize,
+                            dx.abs().max(dy.abs()) != 1,
+                        );
+                    }
+                }
+            }
+        }
+
+        // Reserve the format area, the real value is written once the mask is chosen
+        qr.draw_format(0);
+
+        if version >= 7 {
+            let bits = version_bits(version);
+            for i in 0..18 {
+                let dark = (bits >> i) & 1 == 1;
+                let (a, b) = (size - 11 + i % 3, i / 3);
+                qr.set_function(a, b, dark);
+                qr.set_function(b, a, dark);
+            }
+        }
+
+        qr
+    }
+
+    fn set(&mut self, x: usize, y: usize, dark: bool) {
+        if dark {
+            self.modules[y] |= 1 << x;
+        } else {
+            self.modules[y] &= !(1 << x);
+        }
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.set(x, y, dark);
+        self.function[y] |= 1 << x;
+    }
+
+    fn is_function(&self, x: usize, y: usize) -> bool {
+        (self.function[y] >> x) & 1 == 1
+    }
+
+    fn draw_format(&mut self, mask: u8) {
+        let bits = format_bits(mask);
+        let bit = |i: usize| (bits >> i) & 1 == 1;
+        let size = self.size;
+
+        for i in 0..6 {
+            self.set_function(8, i, bit(i));
+        }
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i));
+        }
+
+        for i in 0..8 {
+            self.set_function(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function(8, size - 15 + i, bit(i));
+        }
+        // Always dark
+        self.set_function(8, size - 8, true);
+    }
+
+    fn place_codewords(&mut self, codewords: &[u8]) {
+        // Function modules don't change from here on, iterate over a snapshot
+        let layout = self.clone();
+        for (i, (x, y)) in data_positions(&layout).enumerate() {
+            if i >= codewords.len() * 8 {
+                break;
+            }
+            self.set(x, y, (codewords[i / 8] >> (7 - i % 8)) & 1 == 1);
+        }
+    }
+
+    fn apply_mask(&mut self, mask: u8) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                if !self.is_function(x, y) && mask_bit(mask, x, y) {
+                    self.modules[y] ^= 1 << x;
+                }
+            }
+        }
+    }
+
+    fn penalty(&self) -> u32 {
+        const FINDER_LIKE: [bool; 7] = [true, false, true, true, true, false, true];
+
+        let size = self.size;
+        let mut penalty = 0;
+        let mut dark = 0;
+
+        for horizontal in [true, false] {
+            let get = |a: usize, b: usize| match horizontal {
+                true => self.get(b, a),
+                false => self.get(a, b),
+            };
+
+            for a in 0..size {
+                let mut run = 1;
+                for b in 1..size {
+                    if get(a, b) == get(a, b - 1) {
+                        run += 1;
+                        if run == 5 {
+                            penalty += 3;
+                        } else if run > 5 {
+                            penalty += 1;
+                        }
+                    } else {
+                        run = 1;
+                    }
+                }
+
+                let light = |b: isize| b < 0 || b >= size as isize || !get(a, b as usize);
+                for b in 0..=size - 7 {
+                    if (0..7).any(|i| get(a, b + i) != FINDER_LIKE[i]) {
+                        continue;
+                    }
+                    let b = b as isize;
+                    if (1..=4).all(|i| light(b - i)) || (7..11).all(|i| light(b + i)) {
+                        penalty += 40;
+                    }
+                }
+            }
+        }
+
+        for y in 0..size {
+            for x in 0..size {
+                let color = self.get(x, y);
+                dark += color as usize;
+                if x + 1 < size
+                    && y + 1 < size
+                    && color == self.get(x + 1, y)
+                    && color == self.get(x, y + 1)
+                    && color == self.get(x + 1, y + 1)
+                {
+                    penalty += 3;
+                }
+            }
+        }
+
+        // 10 points for every 5% of deviation from a 50% dark ratio
+        let total = size * size;
+        let deviation = (dark * 20).abs_diff(total * 10);
+        penalty += deviation.div_ceil(total).saturating_sub(1) as u32 * 10;
+
+        penalty
+    }
+}
+
+fn char_count_bits(version: u8) -> usize {
+    if version < 10 {
+        8
+    } else {
+        16
+    }
+}
+
+fn data_codewords(version: u8) -> usize {
+    let (_, g1, len, g2) = ECC_L_BLOCKS[version as usize - 1];
+    g1 * len + g2 * (len + 1)
+}
+
+fn byte_capacity(version: u8) -> usize {
+    (data_codewords(version) * 8 - 4 - char_count_bits(version)) / 8
+}
+
+fn format_bits(mask: u8) -> u32 {
+    // Level L is `01`
+    let data = (1 << 3) | mask as u32;
+    let mut rem = data;
+    for _ in 0..10 {
+        rem = (rem << 1) ^ ((rem >> 9) * 0x537);
+    }
+    ((data << 10) | rem) ^ 0x5412
+}
+
+fn version_bits(version: u8) -> u32 {
+    let mut rem = version as u32;
+    for _ in 0..12 {
+        rem = (rem << 1) ^ ((rem >> 11) * 0x1F25);
+    }
+    ((version as u32) << 12) | rem
+}
+
+fn mask_bit(mask: u8, x: usize, y: usize) -> bool {
+    match mask {
+        0 => (x + y) % 2 == 0,
+        1 => y % 2 == 0,
+        2 => x % 3 == 0,
+        3 => (x + y) % 3 == 0,
+        4 => (x / 3 + y / 2) % 2 == 0,
+        5 => x * y % 2 + x * y % 3 == 0,
+        6 => (x * y % 2 + x * y % 3) % 2 == 0,
+        7 => ((x + y) % 2 + x * y % 3) % 2 == 0,
+        _ => unreachable!(),
+    }
+}
+
+/// Positions of the data modules, in the zig-zag order codewords are placed in
+fn data_positions(qr: &QrCode) -> impl Iterator<Item = (usize, usize)> + '_ {
+    let size = qr.size;
+    (0..size / 2)
+        .map(move |col| {
+            // Columns are taken in pairs from the right, skipping the vertical timing pattern
+            let right = size - 1 - col * 2;
+            if right <= 6 {
+                right - 1
+            } else {
+                right
+            }
+        })
+        .flat_map(move |right| {
+            let upward = (right + 1) & 2 == 0;
+            (0..size).flat_map(move |vert| {
+                let y = if upward { size - 1 - vert } else { vert };
+                [(right, y), (right - 1, y)]
+            })
+        })
+        .filter(|&(x, y)| !qr.is_function(x, y))
+}
+
+/// Write the data codewords followed by the error correction ones, interleaved across blocks.
+/// Returns the total number of codewords.
+fn build_codewords(version: u8, data: &[u8], out: &mut [u8; MAX_CODEWORDS]) -> usize {
+    let data_len = data_codewords(version);
+
+    let mut buf = [0u8; MAX_CODEWORDS];
+    let mut pos = 0;
+    let mut push = |value: usize, bits: usize| {
+        for i in (0..bits).rev() {
+            buf[pos / 8] |= (((value >> i) & 1) as u8) << (7 - pos % 8);
+            pos += 1;
+        }
+    };
+    push(0b0100, 4);
+    push(data.len(), char_count_bits(version));
+    for byte in data {
+        push(*byte as usize, 8);
+    }
+    // The terminator is already zeroed, round up to the next byte and pad
+    for (i, pad) in buf[(pos + 4).min(data_len * 8).div_ceil(8)..data_len]
+        .iter_mut()
+        .enumerate()
+    {
+        *pad = if i % 2 == 0 { 0xEC } else { 0x11 };
+    }
+
+    let (ec_len, g1, block_len, g2) = ECC_L_BLOCKS[version as usize - 1];
+    let divisor = rs_divisor(ec_len);
+    let mut ec = [[0u8; MAX_EC_LEN]; MAX_BLOCKS];
+    let mut offset = 0;
+    for (i, ec) in ec.iter_mut().enumerate().take(g1 + g2) {
+        let len = block_len + (i >= g1) as usize;
+        *ec = rs_remainder(&buf[offset..offset + len], &divisor, ec_len);
+        offset += len;
+    }
+
+    let mut written = 0;
+    for j in 0..=block_len {
+        let mut offset = 0;
+        for i in 0..g1 + g2 {
+            let len = block_len + (i >= g1) as usize;
+            if j < len {
+                out[written] = buf[offset + j];
+                written += 1;
+            }
+            offset += len;
+        }
+    }
+    for j in 0..ec_len {
+        for ec in &ec[..g1 + g2] {
+            out[written] = ec[j];
+            written += 1;
+        }
+    }
+
+    written
+}
+
+fn gf_mul(x: u8, y: u8) -> u8 {
+    let mut z: u8 = 0;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x1D);
+        z ^= ((y >> i) & 1) * x;
+    }
+    z
+}
+
+fn rs_divisor(degree: usize) -> [u8; MAX_EC_LEN] {
+    let mut result = [0u8; MAX_EC_LEN];
+    result[degree - 1] = 1;
+    let mut root = 1;
+    for _ in 0..degree {
+        for j in 0..degree {
+            result[j] = gf_mul(result[j], root);
+            if j + 1 < degree {
+                result[j] ^= result[j + 1];
+            }
+        }
+        root = gf_mul(root, 0x02);
+    }
+    result
+}
+
+fn rs_remainder(data: &[u8], divisor: &[u8; MAX_EC_LEN], degree: usize) -> [u8; MAX_EC_LEN] {
+    let mut result = [0u8; MAX_EC_LEN];
+    for byte in data {
+        let factor = byte ^ result[0];
+        result.copy_within(1..degree, 0);
+        result[degree - 1] = 0;
+        for i in 0..degree {
+            result[i] ^= gf_mul(divisor[i], factor);
+        }
+    }
+    result
+}
+
+/// Top-left corner of the symbol and pixels per module, centered in `bounds`
+fn layout(bounds: Rectangle, size: usize) -> Option<(Point, u32)> {
+    let side = bounds.size.width.min(bounds.size.height);
+    let scale = side / (size as u32 + 2 * QUIET_ZONE);
+    if scale == 0 {
+        return None;
+    }
+
+    let symbol = size as u32 * scale;
+    let offset = Point::new(
+        ((bounds.size.width - symbol) / 2) as i32,
+        ((bounds.size.height - symbol) / 2) as i32,
+    );
+    Some((bounds.top_left + offset, scale))
+}
+
+impl QrCode {
+    /// Whether the symbol and its quiet zone fit in `bounds`
+    pub fn fits(&self, bounds: Rectangle) -> bool {
+        layout(bounds, self.size).is_some()
+    }
+
+    /// Clear the target and draw the symbol, centered and as large as possible
+    pub fn draw_to<T>(&self, target: &mut T) -> Result<(), QrError<<T as DrawTarget>::Error>>
+    where
+        T: DrawTarget<Color = BinaryColor> + Dimensions,
+    {
+        let bounds = target.bounding_box();
+        let (origin, scale) = layout(bounds, self.size).ok_or(QrError::DataTooLong)?;
+
+        target.clear(Off).map_err(QrError::Draw)?;
+
+        // Light background including the quiet zone, modules are drawn dark on top
+        let quiet = (QUIET_ZONE * scale) as i32;
+        let side = (self.size as u32 + 2 * QUIET_ZONE) * scale;
+        target
+            .fill_solid(
+                &Rectangle::new(origin - Point::new(quiet, quiet), Size::new(side, side)),
+                On,
+            )
+            .map_err(QrError::Draw)?;
+
+        for y in 0..self.size {
+            for x in 0..self.size {
+                if self.get(x, y) {
+                    let module = Rectangle::new(
+                        origin + Point::new((x as u32 * scale) as i32, (y as u32 * scale) as i32),
+                        Size::new(scale, scale),
+                    );
+                    target.fill_solid(&module, Off).map_err(QrError::Draw)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Clear the target and draw `data` as a QR code, centered and as large as possible
+pub fn draw_qr<T>(target: &mut T, data: &[u8]) -> Result<(), QrError<<T as DrawTarget>::Error>>
+where
+    T: DrawTarget<Color = BinaryColor> + Dimensions,
+{
+    QrCode::encode(data)
+        .ok_or(QrError::DataTooLong)?
+        .draw_to(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::convert::Infallible;
+
+    struct FrameBuffer([[bool; 128]; 64]);
+
+    impl OriginDimensions for FrameBuffer {
+        fn size(&self) -> Size {
+            Size::new(128, 64)
+        }
+    }
+
+    impl DrawTarget for FrameBuffer {
+        type Color = BinaryColor;
+        type Error = Infallible;
+
+        fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+        where
+            I: IntoIterator<Item = Pixel<Self::Color>>,
+        {
+            for Pixel(p, color) in pixels {
+                self.0[p.y as usize][p.x as usize] = color.is_on();
+            }
+            Ok(())
+        }
+    }
+
+    /// Decode the QR code on the panel with an independent decoder
+    ///
+    /// The frame is scaled up so that the decoder sees a few pixels per module. Lit pixels are
+    /// light, like on the OLED.
+    fn decode(fb: &FrameBuffer) -> Vec<u8> {
+        const UPSCALE: usize = 4;
+
+        let mut img = rqrr::PreparedImage::prepare_from_greyscale(
+            128 * UPSCALE,
+            64 * UPSCALE,
+            |x, y| match fb.0[y / UPSCALE][x / UPSCALE] {
+                true => 0xFF,
+                false => 0x00,
+            },
+        );
+        let grids = img.detect_grids();
+        assert_eq!(grids.len(), 1);
+
+        let mut data = vec![];
+        grids[0].decode_to(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_format_and_version_bits() {
+        assert_eq!(format_bits(0), 0b111011111000100);
+        assert_eq!(format_bits(7), 0b110100101110110);
+        assert_eq!(version_bits(7), 0b000111110010010100);
+    }
+
+    #[test]
+    fn test_reed_solomon() {
+        // "HELLO WORLD" as 1-M, from the ISO/IEC 18004 walkthrough
+        let data = [
+            32, 91, 11, 120, 209, 114, 220, 77, 67, 64, 236, 17, 236, 17, 236, 17,
+        ];
+        let ec = rs_remainder(&data, &rs_divisor(10), 10);
+        assert_eq!(&ec[..10], &[196, 35, 39, 119, 235, 215, 231, 226, 93, 23]);
+    }
+
+    #[test]
+    fn test_smallest_version() {
+        assert_eq!(QrCode::encode(&[0x42; 17]).unwrap().version(), 1);
+        assert_eq!(QrCode::encode(&[0x42; 18]).unwrap().version(), 2);
+        assert_eq!(QrCode::encode(&[0x42; 271]).unwrap().version(), 10);
+        assert!(QrCode::encode(&[0x42; 272]).is_none());
+    }
+
+    #[test]
+    fn test_draw_qr_roundtrip() {
+        for data in [
+            &b"tb1qfdflx45k5v0q452s9l75gurm6ej6y5zkfq5xtv"[..],
+            &b"tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M"[..],
+            &[0x00, 0xFF, 0x42][..],
+        ] {
+            let mut fb = FrameBuffer([[false; 128]; 64]);
+            draw_qr(&mut fb, data).unwrap();
+
+            assert_eq!(decode(&fb), data);
+        }
+    }
+
+    #[test]
+    fn test_qr_page() {
+        use crate::{Page, QrPage};
+
+        let xpub = b"[73c5da0a/48'/1'/0'/2']tpubDFH9dgzveyD8zTbPUFuLrGmCydNvxehyNdUXKJAQN8x4aZ4j6UZqGfnqFrD4NqyaTVGKbvEW54tsvPTK2UoSbCC1PJY8iCNiwTL3RWZEheQ";
+
+        let mut fb = FrameBuffer([[false; 128]; 64]);
+        let page = QrPage::new(xpub, "HOLD BTN TO EXIT").unwrap();
+        page.init_display(&mut fb).unwrap();
+        page.draw_to(&mut fb).unwrap();
+
+        assert_eq!(decode(&fb), xpub);
+        assert!(QrPage::new(&[0; 200], "HOLD BTN TO EXIT").is_none());
+    }
+
+    #[test]
+    fn test_draw_qr_too_long() {
+        let mut fb = FrameBuffer([[false; 128]; 64]);
+        assert_eq!(draw_qr(&mut fb, &[0; 300]), Err(QrError::DataTooLong));
+    }
+}
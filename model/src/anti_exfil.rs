@@ -0,0 +1,299 @@
+// Portal Hardware Wallet firmware and supporting software libraries
+//
+// Copyright (C) 2024 Alekos Filini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Anti-exfil protocol for BIP-340 schnorr signatures
+//!
+//! A malicious signer could leak its key by choosing biased nonces. With this protocol the final
+//! nonce is randomized by the host, and the host can check that it was:
+//!
+//! 1. The host picks a random `host_nonce` and sends its [`host_commitment`] to the signer
+//! 2. The signer replies with its [`signer_commitment`], the public part of a nonce that is
+//!    derived deterministically from its key, the message and the host commitment
+//! 3. The host reveals `host_nonce`, the signer checks it against the commitment and [`sign`]s
+//!    with its nonce tweaked by a hash of its commitment and `host_nonce`
+//! 4. The host [`verify`]s that the signature uses the tweaked nonce
+//!
+//! Since the signer commits to its nonce before seeing `host_nonce`, it can't choose the final
+//! nonce.
+
+use core::fmt;
+
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{
+    self, schnorr, KeyPair, Message, Parity, PublicKey, Scalar, Secp256k1, SecretKey, Signing,
+    Verification, XOnlyPublicKey,
+};
+
+use zeroize::Zeroizing;
+
+const COMMITMENT_TAG: &str = "PortalAntiExfil/commitment";
+const NONCE_TAG: &str = "PortalAntiExfil/nonce";
+const TWEAK_TAG: &str = "PortalAntiExfil/tweak";
+const CHALLENGE_TAG: &str = "BIP0340/challenge";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AntiExfilError {
+    /// The host nonce doesn't match the commitment sent by the host
+    InvalidHostNonce,
+    /// The signature doesn't use the nonce committed to by the signer and tweaked by the host
+    NonceMismatch,
+    InvalidSignature,
+    /// A hash is out of the range of valid scalars, which happens with negligible probability
+    OutOfRange,
+}
+
+impl fmt::Display for AntiExfilError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl From<secp256k1::Error> for AntiExfilError {
+    fn from(_: secp256k1::Error) -> Self {
+        AntiExfilError::OutOfRange
+    }
+}
+
+fn tagged_hash(tag: &str, parts: &[&[u8]]) -> [u8; 32] {
+    let tag = sha256::Hash::hash(tag.as_bytes());
+
+    let mut engine = sha256::HashEngine::default();
+    engine.input(&tag);
+    engine.input(&tag);
+    for part in parts {
+        engine.input(part);
+    }
+
+    sha256::Hash::from_engine(engine).into_inner()
+}
+
+fn scalar(bytes: [u8; 32]) -> Result<Scalar, AntiExfilError> {
+    Scalar::from_be_bytes(bytes).map_err(|_| AntiExfilError::OutOfRange)
+}
+
+/// Commitment to `host_nonce`, sent by the host before the signer commits to its own nonce
+pub fn host_commitment(host_nonce: &[u8; 32]) -> [u8; 32] {
+    tagged_hash(COMMITMENT_TAG, &[host_nonce])
+}
+
+fn signer_nonce(
+    keypair: &KeyPair,
+    msg: &Message,
+    host_commitment: &[u8; 32],
+) -> Result<SecretKey, AntiExfilError> {
+    let secret_bytes = Zeroizing::new(keypair.secret_bytes());
+    let nonce = Zeroizing::new(tagged_hash(
+        NONCE_TAG,
+        &[secret_bytes.as_ref(), msg.as_ref(), host_commitment],
+    ));
+    Ok(SecretKey::from_slice(nonce.as_ref())?)
+}
+
+fn nonce_tweak(
+    signer_commitment: &PublicKey,
+    host_nonce: &[u8; 32],
+) -> Result<Scalar, AntiExfilError> {
+    scalar(tagged_hash(
+        TWEAK_TAG,
+        &[&signer_commitment.serialize(), host_nonce],
+    ))
+}
+
+/// Public nonce the signer commits to before the host reveals its nonce
+pub fn signer_commitment<C: Signing>(
+    secp: &Secp256k1<C>,
+    keypair: &KeyPair,
+    msg: &Message,
+    host_commitment: &[u8; 32],
+) -> Result<PublicKey, AntiExfilError> {
+    let nonce = signer_nonce(keypair, msg, host_commitment)?;
+    Ok(PublicKey::from_secret_key(secp, &nonce))
+}
+
+/// Sign `msg` with the nonce committed to in [`signer_commitment`], tweaked with `host_nonce`
+pub fn sign<C: Signing>(
+    secp: &Secp256k1<C>,
+    keypair: &KeyPair,
+    msg: &Message,
+    host_commitment: &[u8; 32],
+    host_nonce: &[u8; 32],
+) -> Result<schnorr::Signature, AntiExfilError> {
+    if self::host_commitment(host_nonce) != *host_commitment {
+        return Err(AntiExfilError::InvalidHostNonce);
+    }
+
+    let nonce = signer_nonce(keypair, msg, host_commitment)?;
+    let tweak = nonce_tweak(&PublicKey::from_secret_key(secp, &nonce), host_nonce)?;
+    let nonce = nonce.add_tweak(&tweak)?;
+
+    // BIP-340 uses the keys with an even Y coordinate
+    let (nonce_x, nonce_parity) = PublicKey::from_secret_key(secp, &nonce).x_only_public_key();
+    let nonce = match nonce_parity {
+        Parity::Even => nonce,
+        Parity::Odd => nonce.negate(),
+    };
+    let (pubkey, key_parity) = keypair.x_only_public_key();
+    let seckey = match key_parity {
+        Parity::Even => keypair.secret_key(),
+        Parity::Odd => keypair.secret_key().negate(),
+    };
+
+    let challenge = scalar(tagged_hash(
+        CHALLENGE_TAG,
+        &[&nonce_x.serialize(), &pubkey.serialize(), msg.as_ref()],
+    ))?;
+    let s = seckey
+        .mul_tweak(&challenge)?
+        .add_tweak(&scalar(nonce.secret_bytes())?)?;
+
+    let mut signature = [0; 64];
+    signature[..32].copy_from_slice(&nonce_x.serialize());
+    signature[32..].copy_from_slice(&s.secret_bytes());
+    schnorr::Signature::from_slice(&signature).map_err(|_| AntiExfilError::InvalidSignature)
+}
+
+/// Verify on the host that `signature` is valid and uses the nonce committed to by the signer,
+/// tweaked with `host_nonce`
+pub fn verify<C: Verification>(
+    secp: &Secp256k1<C>,
+    pubkey: &XOnlyPublicKey,
+    msg: &Message,
+    signer_commitment: &PublicKey,
+    host_nonce: &[u8; 32],
+    signature: &schnorr::Signature,
+) -> Result<(), AntiExfilError> {
+    let tweak = nonce_tweak(signer_commitment, host_nonce)?;
+    let (expected_nonce, _) = signer_commitment
+        .add_exp_tweak(secp, &tweak)?
+        .x_only_public_key();
+    if signature[..32] != expected_nonce.serialize() {
+        return Err(AntiExfilError::NonceMismatch);
+    }
+
+    secp.verify_schnorr(signature, msg, pubkey)
+        .map_err(|_| AntiExfilError::InvalidSignature)
+}
+
+#[cfg(all(test, not(feature = "stm32")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_anti_exfil_vector() {
+        use bitcoin::hashes::hex::ToHex;
+        use bitcoin::secp256k1::{KeyPair, Message, PublicKey, Secp256k1};
+        use core::str::FromStr;
+
+        let secp = Secp256k1::new();
+        let keypair = KeyPair::from_seckey_slice(&secp, &[0x42; 32]).unwrap();
+        let msg = Message::from_slice(&[0x07; 32]).unwrap();
+        let host_nonce = [0xAB; 32];
+
+        let host_commitment = host_commitment(&host_nonce);
+        assert_eq!(
+            host_commitment[..].to_hex(),
+            "8e1b8da4555b354daf9906e922371c3f19174bad829e41ff6e1dfdb094f594be"
+        );
+        let signer_commitment = signer_commitment(&secp, &keypair, &msg, &host_commitment).unwrap();
+        assert_eq!(
+            signer_commitment,
+            PublicKey::from_str(
+                "02e4430e6ff6e89034ab03efe1950ba3420867d10f8e327abb2c7d790d4a18e500"
+            )
+            .unwrap()
+        );
+
+        let signature = sign(&secp, &keypair, &msg, &host_commitment, &host_nonce).unwrap();
+        assert_eq!(
+            signature.as_ref().to_hex(),
+            "6b828177e2c4e28274508cdf49be422ca9bf58ecfcae2ada4b024ab937d0ed07fec4dfef4da8de5426714c77b23241920ec7acdcfc23bee9d1f915f02804cf77"
+        );
+
+        let (pubkey, _) = keypair.x_only_public_key();
+        assert!(secp.verify_schnorr(&signature, &msg, &pubkey).is_ok());
+        assert_eq!(
+            verify(
+                &secp,
+                &pubkey,
+                &msg,
+                &signer_commitment,
+                &host_nonce,
+                &signature
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_anti_exfil_detects_exfiltration() {
+        use bitcoin::secp256k1::{KeyPair, Message, Secp256k1};
+        let secp = Secp256k1::new();
+        // Odd Y coordinate, exercises the negation of the key
+        let keypair = KeyPair::from_seckey_slice(&secp, &[0x03; 32]).unwrap();
+        let (pubkey, _) = keypair.x_only_public_key();
+        let msg = Message::from_slice(&[0x11; 32]).unwrap();
+        let host_nonce = [0x5A; 32];
+        let host_commitment = host_commitment(&host_nonce);
+
+        let signer_commitment = signer_commitment(&secp, &keypair, &msg, &host_commitment).unwrap();
+
+        // A valid signature that ignores the host nonce is detected
+        let standalone = secp.sign_schnorr_no_aux_rand(&msg, &keypair);
+        assert_eq!(
+            verify(
+                &secp,
+                &pubkey,
+                &msg,
+                &signer_commitment,
+                &host_nonce,
+                &standalone
+            ),
+            Err(AntiExfilError::NonceMismatch)
+        );
+
+        // The signer refuses a host nonce that doesn't match the commitment
+        assert_eq!(
+            sign(&secp, &keypair, &msg, &host_commitment, &[0x00; 32]),
+            Err(AntiExfilError::InvalidHostNonce)
+        );
+
+        // The host can't be fooled with a different host nonce either
+        let signature = sign(&secp, &keypair, &msg, &host_commitment, &host_nonce).unwrap();
+        assert_eq!(
+            verify(
+                &secp,
+                &pubkey,
+                &msg,
+                &signer_commitment,
+                &[0x00; 32],
+                &signature
+            ),
+            Err(AntiExfilError::NonceMismatch)
+        );
+        assert_eq!(
+            verify(
+                &secp,
+                &pubkey,
+                &Message::from_slice(&[0x12; 32]).unwrap(),
+                &signer_commitment,
+                &host_nonce,
+                &signature
+            ),
+            Err(AntiExfilError::InvalidSignature)
+        );
+    }
+}
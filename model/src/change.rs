@@ -0,0 +1,240 @@
+// Portal Hardware Wallet firmware and supporting software libraries
+//
+// Copyright (C) 2024 Alekos Filini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Change outputs of a PSBT, so that the display can tell them apart from the payments
+//!
+//! An output is only change if one of its key origins is derived by our wallet under the internal
+//! keychain, and the key derived there reproduces the script of the output. Single key `wpkh` and
+//! key path `tr` scripts are recognized, other outputs are always [`OutputKind::External`].
+
+use alloc::vec::Vec;
+
+use bitcoin::secp256k1::{self, Secp256k1, Verification};
+use bitcoin::util::bip32::{ChildNumber, DerivationPath, KeySource};
+use bitcoin::util::psbt::{Output, PartiallySignedTransaction};
+use bitcoin::{PublicKey, Script, TxOut};
+
+use crate::KeychainKind;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputKind {
+    /// Pays back to our internal keychain, at `derivation`
+    Change {
+        derivation: DerivationPath,
+    },
+    External,
+}
+
+/// Keychain of a `.../<keychain>/<index>` derivation path
+pub fn keychain_of(path: &DerivationPath) -> Option<KeychainKind> {
+    let steps = path.as_ref();
+    match steps.len().checked_sub(2).map(|i| steps[i]) {
+        Some(ChildNumber::Normal { index: 0 }) => Some(KeychainKind::External),
+        Some(ChildNumber::Normal { index: 1 }) => Some(KeychainKind::Internal),
+        _ => None,
+    }
+}
+
+/// Classify an output
+///
+/// `derive_key` returns the key our wallet derives at a key origin, or `None` if the origin
+/// isn't ours.
+pub fn classify_output<C: Verification>(
+    secp: &Secp256k1<C>,
+    txout: &TxOut,
+    psbt_out: &Output,
+    mut derive_key: impl FnMut(&KeySource) -> Option<secp256k1::PublicKey>,
+) -> OutputKind {
+    let mut is_change = |key_source: &KeySource, script: &dyn Fn(secp256k1::PublicKey) -> bool| {
+        keychain_of(&key_source.1) == Some(KeychainKind::Internal)
+            && derive_key(key_source).map(script).unwrap_or(false)
+    };
+
+    for (key, key_source) in &psbt_out.bip32_derivation {
+        let wpkh = |derived: secp256k1::PublicKey| {
+            derived == *key
+                && PublicKey::new(derived)
+                    .wpubkey_hash()
+                    .map(|hash| Script::new_v0_p2wpkh(&hash) == txout.script_pubkey)
+                    .unwrap_or(false)
+        };
+        if is_change(key_source, &wpkh) {
+            return OutputKind::Change {
+                derivation: key_source.1.clone(),
+            };
+        }
+    }
+    for (key, (leaves, key_source)) in &psbt_out.tap_key_origins {
+        // Only the key path, a key in a leaf doesn't tell who can spend the output
+        if !leaves.is_empty() || psbt_out.tap_tree.is_some() {
+            continue;
+        }
+
+        let tr = |derived: secp256k1::PublicKey| {
+            let (x_only, _) = derived.x_only_public_key();
+            x_only == *key && Script::new_v1_p2tr(secp, x_only, None) == txout.script_pubkey
+        };
+        if is_change(key_source, &tr) {
+            return OutputKind::Change {
+                derivation: key_source.1.clone(),
+            };
+        }
+    }
+
+    OutputKind::External
+}
+
+/// Classify every output of `psbt`, see [`classify_output`]
+pub fn classify_outputs<C: Verification>(
+    psbt: &PartiallySignedTransaction,
+    secp: &Secp256k1<C>,
+    mut derive_key: impl FnMut(&KeySource) -> Option<secp256k1::PublicKey>,
+) -> Vec<OutputKind> {
+    psbt.unsigned_tx
+        .output
+        .iter()
+        .zip(psbt.outputs.iter())
+        .map(|(txout, psbt_out)| classify_output(secp, txout, psbt_out, &mut derive_key))
+        .collect()
+}
+
+#[cfg(all(test, not(feature = "stm32")))]
+mod tests {
+    use super::*;
+    use crate::test_utils::coinjoin_psbt;
+    use bitcoin::hashes::Hash;
+
+    #[test]
+    fn test_classify_outputs() {
+        use bitcoin::secp256k1::Secp256k1;
+        use bitcoin::util::bip32::{DerivationPath, ExtendedPrivKey, Fingerprint, KeySource};
+        use bitcoin::util::psbt::Output;
+        use bitcoin::{Network, PublicKey, Script, TxOut};
+        use core::str::FromStr;
+
+        let secp = Secp256k1::new();
+        let master = ExtendedPrivKey::new_master(Network::Testnet, &[0x01; 32]).unwrap();
+        let ours = master.fingerprint(&secp);
+        let derive = |path: &str| {
+            let path = DerivationPath::from_str(path).unwrap();
+            let key = master.derive_priv(&secp, &path).unwrap().private_key;
+            (key.public_key(&secp), path)
+        };
+        let derive_key = |key_source: &KeySource| {
+            (key_source.0 == ours).then(|| {
+                master
+                    .derive_priv(&secp, &key_source.1)
+                    .unwrap()
+                    .private_key
+                    .public_key(&secp)
+            })
+        };
+        let wpkh = |key| Script::new_v0_p2wpkh(&PublicKey::new(key).wpubkey_hash().unwrap());
+        let tr = |key: bitcoin::secp256k1::PublicKey| {
+            Script::new_v1_p2tr(&secp, key.x_only_public_key().0, None)
+        };
+        let wpkh_output = |path: &str, fingerprint| {
+            let (key, path) = derive(path);
+            let mut out = Output::default();
+            out.bip32_derivation.insert(key, (fingerprint, path));
+            (wpkh(key), out)
+        };
+
+        let (change_script, change) = wpkh_output("m/84'/1'/0'/1/3", ours);
+        let (receive_script, receive) = wpkh_output("m/84'/1'/0'/0/3", ours);
+        let (foreign_script, foreign) = wpkh_output("m/84'/1'/0'/1/3", Fingerprint::default());
+
+        let (tr_key, tr_path) = derive("m/86'/1'/0'/1/0");
+        let mut tr_change = Output::default();
+        tr_change.tap_key_origins.insert(
+            tr_key.x_only_public_key().0,
+            (vec![], (ours, tr_path.clone())),
+        );
+
+        let (mut psbt, _) = coinjoin_psbt(&[1_000; 6]);
+        let outputs = [
+            (change_script.clone(), change.clone()),
+            // Claims our change, but pays someone else
+            (wpkh(derive("m/0/0").0), change.clone()),
+            // Ours, but on the external keychain
+            (receive_script, receive),
+            (foreign_script, foreign),
+            (tr(tr_key), tr_change.clone()),
+            // A tr output spendable through a leaf too isn't recognized
+            (tr(tr_key), {
+                let mut out = tr_change;
+                out.tap_key_origins.values_mut().for_each(|(leaves, _)| {
+                    leaves.push(bitcoin::util::taproot::TapLeafHash::all_zeros())
+                });
+                out
+            }),
+        ];
+        for (i, (script, out)) in outputs.into_iter().enumerate() {
+            psbt.unsigned_tx.output[i] = TxOut {
+                value: 1_000,
+                script_pubkey: script,
+            };
+            psbt.outputs[i] = out;
+        }
+
+        assert_eq!(
+            classify_outputs(&psbt, &secp, derive_key),
+            vec![
+                OutputKind::Change {
+                    derivation: DerivationPath::from_str("m/84'/1'/0'/1/3").unwrap()
+                },
+                OutputKind::External,
+                OutputKind::External,
+                OutputKind::External,
+                OutputKind::Change {
+                    derivation: tr_path
+                },
+                OutputKind::External,
+            ]
+        );
+
+        // The key in the PSBT must be the one derived at its origin
+        let (other_key, _) = derive("m/84'/1'/0'/1/4");
+        let mut out = Output::default();
+        out.bip32_derivation.insert(
+            other_key,
+            change.bip32_derivation.values().next().unwrap().clone(),
+        );
+        assert_eq!(
+            classify_output(
+                &secp,
+                &TxOut {
+                    value: 1_000,
+                    script_pubkey: wpkh(other_key)
+                },
+                &out,
+                derive_key
+            ),
+            OutputKind::External
+        );
+
+        assert_eq!(
+            keychain_of(&DerivationPath::from_str("m/1/0").unwrap()),
+            Some(KeychainKind::Internal)
+        );
+        assert_eq!(keychain_of(&DerivationPath::from_str("m/5").unwrap()), None);
+        assert_eq!(
+            keychain_of(&DerivationPath::from_str("m/1'/0").unwrap()),
+            None
+        );
+    }
+}
@@ -0,0 +1,111 @@
+// Portal Hardware Wallet firmware and supporting software libraries
+//
+// Copyright (C) 2024 Alekos Filini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Version of the checkpoint stored in the backup registers of the RTC
+//!
+//! The magic register tells the firmware at boot whether it can resume from a checkpoint (fast
+//! boot) or has to do a full init. A checkpoint written by a firmware with a different format is
+//! never resumed, the full init then rewrites the magic with the current version.
+
+/// The upper half of the magic register identifies a checkpoint, the lower half stores the
+/// version of the checkpoint format
+pub const MAGIC_PREFIX: u32 = 0xFA57_0000;
+const MAGIC_PREFIX_MASK: u32 = 0xFFFF_0000;
+/// Bump this whenever the layout of the checkpoint registers or the aux data changes
+pub const CHECKPOINT_VERSION: u16 = 2;
+pub const MAGIC: u32 = MAGIC_PREFIX | CHECKPOINT_VERSION as u32;
+/// Stored in the magic register for the whole duration of a factory reset
+pub const WIPE_MAGIC: u32 = 0xD1E7_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckpointStatus {
+    /// Written by this version of the firmware, fast boot is possible
+    Valid,
+    /// Written by a firmware with a different checkpoint format
+    StaleVersion,
+    /// No checkpoint at all
+    Absent,
+    /// A factory reset was interrupted and must be completed before anything else
+    WipePending,
+}
+
+impl CheckpointStatus {
+    /// Whether the firmware can resume from the checkpoint instead of doing a full init
+    pub fn can_fast_boot(&self) -> bool {
+        *self == CheckpointStatus::Valid
+    }
+
+    /// Whether the current [`MAGIC`] has to be written back during the full init
+    pub fn needs_new_magic(&self) -> bool {
+        matches!(
+            self,
+            CheckpointStatus::StaleVersion | CheckpointStatus::Absent
+        )
+    }
+}
+
+/// Check the value of the magic register
+///
+/// Anything other than [`CheckpointStatus::Valid`] should result in a full init, after which
+/// the current [`MAGIC`] must be written back. The only exception is
+/// [`CheckpointStatus::WipePending`], where the magic is left untouched until the factory reset
+/// is completed.
+pub fn validate(magic: Option<u32>) -> CheckpointStatus {
+    match magic {
+        Some(MAGIC) => CheckpointStatus::Valid,
+        Some(WIPE_MAGIC) => CheckpointStatus::WipePending,
+        Some(v) if v & MAGIC_PREFIX_MASK == MAGIC_PREFIX => CheckpointStatus::StaleVersion,
+        _ => CheckpointStatus::Absent,
+    }
+}
+
+#[cfg(all(test, not(feature = "stm32")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checkpoint_validate() {
+        assert_eq!(validate(Some(MAGIC)), CheckpointStatus::Valid);
+        assert_eq!(validate(Some(WIPE_MAGIC)), CheckpointStatus::WipePending);
+
+        // The magic from before the version was stored, and any other version
+        assert_eq!(validate(Some(0xFA57B007)), CheckpointStatus::StaleVersion);
+        assert_eq!(
+            validate(Some(MAGIC_PREFIX | (CHECKPOINT_VERSION + 1) as u32)),
+            CheckpointStatus::StaleVersion
+        );
+
+        assert_eq!(validate(None), CheckpointStatus::Absent);
+        assert_eq!(validate(Some(0)), CheckpointStatus::Absent);
+        assert_eq!(validate(Some(0xFA56_0001)), CheckpointStatus::Absent);
+    }
+
+    #[test]
+    fn test_checkpoint_status_boot() {
+        // What `init_peripherals` puts in `Peripherals::fast_boot`, and whether it rewrites the
+        // magic register
+        let boot = |magic| {
+            let status = validate(magic);
+            (status.can_fast_boot(), status.needs_new_magic())
+        };
+        assert_eq!(boot(Some(MAGIC)), (true, false));
+        assert_eq!(boot(Some(0xFA57B007)), (false, true));
+        assert_eq!(boot(None), (false, true));
+        // The magic stays there until the factory reset is completed
+        assert_eq!(boot(Some(WIPE_MAGIC)), (false, false));
+    }
+}
@@ -17,6 +17,12 @@
 
 use noise_protocol::CipherState;
 
+/// Version of the snapshot format, bumped every time the checkpoint format changes
+pub const SNAPSHOT_VERSION: u8 = 1;
+
+/// Serial number reported by the emulated device
+pub const EMULATOR_SERIAL: [u8; crate::SERIAL_LEN] = *b"PORTAL-EMU-1";
+
 #[derive(Debug)]
 pub enum CardMessage {
     Display(alloc::vec::Vec<u16>),
@@ -28,16 +34,198 @@ pub enum CardMessage {
     FlushDisplay,
     ReadRtcRegister(u8),
     WriteRtcRegister(u8, u32),
+    SnapshotData(alloc::vec::Vec<u8>),
+    /// Brightness level applied to the display, from 0 (dimmest) to 4 (brightest)
+    DisplayBrightness(u8),
+    /// Rotation applied to the display, in quarter turns
+    DisplayRotation(u8),
+}
+
+/// Result of reading a [`CardMessage`] from a buffer that may only contain part of a frame
+#[derive(Debug)]
+pub enum CardFrame {
+    /// At least `needed` more bytes are required to decode the frame
+    Incomplete { needed: usize },
+    /// The buffer doesn't start with a valid frame, whatever bytes follow
+    Invalid,
+    /// The message, with the number of bytes it used
+    Complete(CardMessage, usize),
+}
+
+impl CardFrame {
+    /// Read the frame at the start of `bytes`, written with `write_to`
+    ///
+    /// The declared length is checked against the type of the message as soon as the header is
+    /// available, so that a streaming transport doesn't wait for the payload of a frame that is
+    /// invalid anyway.
+    pub fn read(bytes: &[u8]) -> CardFrame {
+        let ty = match bytes.first() {
+            Some(ty) => *ty,
+            None => return CardFrame::Incomplete { needed: 1 },
+        };
+        match ty {
+            0x02 => return CardFrame::Complete(CardMessage::Tick, 1),
+            0x05 => return CardFrame::Complete(CardMessage::FinishBoot, 1),
+            0x06 => return CardFrame::Complete(CardMessage::FlushDisplay, 1),
+            0x00 | 0x01 | 0x03 | 0x04 | 0x07 | 0x08 | 0x09 | 0x0A | 0x0B => {}
+            _ => return CardFrame::Invalid,
+        }
+
+        if bytes.len() < 3 {
+            return CardFrame::Incomplete {
+                needed: 3 - bytes.len(),
+            };
+        }
+        let len = u16::from_be_bytes([bytes[1], bytes[2]]) as usize;
+        let valid_len = match ty {
+            0x00 => len % 2 == 0,
+            0x03 => len >= 2,
+            0x04 => len == 2,
+            0x07 | 0x0A | 0x0B => len == 1,
+            0x08 => len == 5,
+            _ => true,
+        };
+        if !valid_len {
+            return CardFrame::Invalid;
+        }
+
+        let data = match bytes.get(3..3 + len) {
+            Some(data) => data,
+            None => {
+                return CardFrame::Incomplete {
+                    needed: 3 + len - bytes.len(),
+                }
+            }
+        };
+        let message = match (ty, data) {
+            (0x00, data) => CardMessage::Display(
+                data.chunks_exact(2)
+                    .map(|arr| u16::from_be_bytes([arr[0], arr[1]]))
+                    .collect(),
+            ),
+            (0x01, data) => CardMessage::Nfc(data.to_vec()),
+            (0x03, [a, b, data @ ..]) => {
+                CardMessage::WriteFlash(u16::from_be_bytes([*a, *b]), data.to_vec())
+            }
+            (0x04, [a, b]) => CardMessage::ReadFlash(u16::from_be_bytes([*a, *b])),
+            (0x07, [register]) => CardMessage::ReadRtcRegister(*register),
+            (0x08, [register, a, b, c, d]) => {
+                CardMessage::WriteRtcRegister(*register, u32::from_be_bytes([*a, *b, *c, *d]))
+            }
+            (0x09, data) => CardMessage::SnapshotData(data.to_vec()),
+            (0x0A, [level]) => CardMessage::DisplayBrightness(*level),
+            (0x0B, [rotation]) => CardMessage::DisplayRotation(*rotation),
+            _ => return CardFrame::Invalid,
+        };
+
+        CardFrame::Complete(message, 3 + len)
+    }
+}
+
+impl CardMessage {
+    /// Parse a message written with `write_to`, returning it together with the number of bytes
+    /// consumed
+    ///
+    /// Returns `None` if the buffer doesn't contain a complete and valid message, use
+    /// [`CardFrame::read`] to tell the two cases apart.
+    pub fn read_from(bytes: &[u8]) -> Option<(CardMessage, usize)> {
+        match CardFrame::read(bytes) {
+            CardFrame::Complete(message, len) => Some((message, len)),
+            CardFrame::Incomplete { .. } | CardFrame::Invalid => None,
+        }
+    }
 }
 
-#[cfg(feature = "stm32")]
 impl CardMessage {
+    fn tag(&self) -> u8 {
+        match self {
+            CardMessage::Display(_) => 0x00,
+            CardMessage::Nfc(_) => 0x01,
+            CardMessage::Tick => 0x02,
+            CardMessage::WriteFlash(_, _) => 0x03,
+            CardMessage::ReadFlash(_) => 0x04,
+            CardMessage::FinishBoot => 0x05,
+            CardMessage::FlushDisplay => 0x06,
+            CardMessage::ReadRtcRegister(_) => 0x07,
+            CardMessage::WriteRtcRegister(_, _) => 0x08,
+            CardMessage::SnapshotData(_) => 0x09,
+            CardMessage::DisplayBrightness(_) => 0x0A,
+            CardMessage::DisplayRotation(_) => 0x0B,
+        }
+    }
+
+    /// Length of the payload, `None` for the messages made of the tag alone
+    fn payload_len(&self) -> Option<usize> {
+        match self {
+            CardMessage::Display(pixels) => Some(pixels.len() * 2),
+            CardMessage::Nfc(data) | CardMessage::SnapshotData(data) => Some(data.len()),
+            CardMessage::WriteFlash(_, data) => Some(data.len() + 2),
+            CardMessage::ReadFlash(_) => Some(2),
+            CardMessage::ReadRtcRegister(_)
+            | CardMessage::DisplayBrightness(_)
+            | CardMessage::DisplayRotation(_) => Some(1),
+            CardMessage::WriteRtcRegister(_, _) => Some(5),
+            CardMessage::Tick | CardMessage::FinishBoot | CardMessage::FlushDisplay => None,
+        }
+    }
+
+    /// Number of bytes written by [`write_into`](Self::write_into)
+    pub fn encoded_len(&self) -> usize {
+        match self.payload_len() {
+            Some(len) => 3 + len,
+            None => 1,
+        }
+    }
+
+    /// Write the message into `buf` without allocating, with the same encoding as `write_to`
+    ///
+    /// Returns the number of bytes written, or `None` if `buf` is shorter than
+    /// [`encoded_len`](Self::encoded_len).
+    pub fn write_into(&self, buf: &mut [u8]) -> Option<usize> {
+        let len = self.encoded_len();
+        let buf = buf.get_mut(..len)?;
+
+        buf[0] = self.tag();
+        let payload_len = match self.payload_len() {
+            Some(payload_len) => payload_len,
+            None => return Some(len),
+        };
+        buf[1..3].copy_from_slice(&(payload_len as u16).to_be_bytes());
+
+        let payload = &mut buf[3..];
+        match self {
+            CardMessage::Display(pixels) => {
+                for (chunk, pixel) in payload.chunks_exact_mut(2).zip(pixels) {
+                    chunk.copy_from_slice(&pixel.to_be_bytes());
+                }
+            }
+            CardMessage::Nfc(data) | CardMessage::SnapshotData(data) => {
+                payload.copy_from_slice(data)
+            }
+            CardMessage::WriteFlash(page, data) => {
+                payload[..2].copy_from_slice(&page.to_be_bytes());
+                payload[2..].copy_from_slice(data);
+            }
+            CardMessage::ReadFlash(page) => payload.copy_from_slice(&page.to_be_bytes()),
+            CardMessage::ReadRtcRegister(value)
+            | CardMessage::DisplayBrightness(value)
+            | CardMessage::DisplayRotation(value) => payload[0] = *value,
+            CardMessage::WriteRtcRegister(register, value) => {
+                payload[0] = *register;
+                payload[1..].copy_from_slice(&value.to_be_bytes());
+            }
+            CardMessage::Tick | CardMessage::FinishBoot | CardMessage::FlushDisplay => {}
+        }
+
+        Some(len)
+    }
+
     pub fn write_to(self) -> alloc::boxed::Box<dyn Iterator<Item = u8>> {
         match self {
             CardMessage::Display(pixels) => alloc::boxed::Box::new(
                 [0x00]
                     .into_iter()
-                    .chain(u16::to_be_bytes(pixels.len() as u16 * 2).into_iter())
+                    .chain(u16::to_be_bytes(pixels.len() as u16 * 2))
                     .chain(
                         pixels
                             .into_iter()
@@ -48,22 +236,20 @@ impl CardMessage {
             CardMessage::Nfc(reply) => alloc::boxed::Box::new(
                 [0x01]
                     .into_iter()
-                    .chain(u16::to_be_bytes(reply.len() as _).into_iter())
-                    .chain(reply.into_iter()),
+                    .chain(u16::to_be_bytes(reply.len() as _))
+                    .chain(reply),
             ),
             CardMessage::Tick => alloc::boxed::Box::new([0x02].into_iter()),
             CardMessage::WriteFlash(page, data) => alloc::boxed::Box::new(
                 [0x03]
                     .into_iter()
-                    .chain(u16::to_be_bytes(data.len() as u16 + 2).into_iter())
-                    .chain(u16::to_be_bytes(page).into_iter())
-                    .chain(data.into_iter()),
-            ),
-            CardMessage::ReadFlash(page) => alloc::boxed::Box::new(
-                [0x04, 0x00, 0x02]
-                    .into_iter()
-                    .chain(u16::to_be_bytes(page).into_iter()),
+                    .chain(u16::to_be_bytes(data.len() as u16 + 2))
+                    .chain(u16::to_be_bytes(page))
+                    .chain(data),
             ),
+            CardMessage::ReadFlash(page) => {
+                alloc::boxed::Box::new([0x04, 0x00, 0x02].into_iter().chain(u16::to_be_bytes(page)))
+            }
             CardMessage::FinishBoot => alloc::boxed::Box::new([0x05].into_iter()),
             CardMessage::FlushDisplay => alloc::boxed::Box::new([0x06].into_iter()),
             CardMessage::ReadRtcRegister(register) => {
@@ -74,6 +260,111 @@ impl CardMessage {
                     .into_iter()
                     .chain(u32::to_be_bytes(value)),
             ),
+            CardMessage::SnapshotData(data) => alloc::boxed::Box::new(
+                [0x09]
+                    .into_iter()
+                    .chain(u16::to_be_bytes(data.len() as _))
+                    .chain(data),
+            ),
+            CardMessage::DisplayBrightness(level) => {
+                alloc::boxed::Box::new([0x0A, 0x00, 0x01, level].into_iter())
+            }
+            CardMessage::DisplayRotation(rotation) => {
+                alloc::boxed::Box::new([0x0B, 0x00, 0x01, rotation].into_iter())
+            }
+        }
+    }
+}
+
+/// A [`CardMessage`] together with the number of ticks the card reported before sending it
+#[derive(Debug)]
+pub struct LoggedEvent {
+    pub tick: u64,
+    pub message: CardMessage,
+}
+
+/// Log of all the messages sent by the card
+///
+/// The card doesn't have a clock that is shared with the host, so events are timestamped by
+/// counting the [`CardMessage::Tick`]s received so far.
+#[derive(Debug, Default)]
+pub struct EventLog {
+    tick: u64,
+    events: alloc::vec::Vec<LoggedEvent>,
+}
+
+impl EventLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, message: CardMessage) -> &LoggedEvent {
+        if let CardMessage::Tick = message {
+            self.tick += 1;
+        }
+
+        self.events.push(LoggedEvent {
+            tick: self.tick,
+            message,
+        });
+        self.events.last().expect("Just pushed")
+    }
+
+    pub fn current_tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn events(&self) -> &[LoggedEvent] {
+        &self.events
+    }
+
+    /// Record messages from `source` until one of them matches `predicate`
+    ///
+    /// Returns `None` if `source` ends before a match is found.
+    pub fn wait_for(
+        &mut self,
+        source: impl IntoIterator<Item = CardMessage>,
+        mut predicate: impl FnMut(&LoggedEvent) -> bool,
+    ) -> Option<&LoggedEvent> {
+        for message in source {
+            if predicate(self.record(message)) {
+                return self.events.last();
+            }
+        }
+
+        None
+    }
+}
+
+/// Touch gestures that the emulator can play back on the TSC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum Gesture {
+    Tap,
+    DoubleTap,
+    LongPress { ms: u16 },
+}
+
+impl Gesture {
+    pub fn encode(&self) -> alloc::vec::Vec<u8> {
+        match self {
+            Gesture::Tap => alloc::vec![0x00],
+            Gesture::DoubleTap => alloc::vec![0x01],
+            Gesture::LongPress { ms } => {
+                let mut v = alloc::vec![0x02];
+                v.extend_from_slice(&ms.to_be_bytes());
+                v
+            }
+        }
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        match data {
+            [0x00] => Some(Gesture::Tap),
+            [0x01] => Some(Gesture::DoubleTap),
+            [0x02, a, b] => Some(Gesture::LongPress {
+                ms: u16::from_be_bytes([*a, *b]),
+            }),
+            _ => None,
         }
     }
 }
@@ -86,14 +377,18 @@ pub enum EmulatorMessage {
     Reset,
     Entropy([u8; 32]),
     Rtc([u32; 32]),
+    TscGesture(Gesture),
+    /// Ask the card for a [`Snapshot`] of its persistent state
+    Snapshot,
 }
 
 impl EmulatorMessage {
     pub fn from_request<C: noise_protocol::Cipher>(
         req: &super::Request,
         cipher: &mut CipherState<C>,
+        aad: &crate::SessionAad,
     ) -> Self {
-        let msg = crate::Message::new_serialize(req, cipher).unwrap();
+        let msg = crate::Message::new_serialize(req, aad.request(), cipher).unwrap();
         EmulatorMessage::Nfc(msg.data().to_vec())
     }
 
@@ -105,13 +400,13 @@ impl EmulatorMessage {
             EmulatorMessage::Nfc(req) => {
                 let mut v = alloc::vec![0x02];
                 v.extend_from_slice(&u16::to_be_bytes(req.len() as u16));
-                v.extend_from_slice(&req);
+                v.extend_from_slice(req);
                 v
             }
             EmulatorMessage::FlashContent(data) => {
                 let mut v = alloc::vec![0x03];
                 v.extend_from_slice(&u16::to_be_bytes(data.len() as u16));
-                v.extend_from_slice(&data);
+                v.extend_from_slice(data);
                 v
             }
             EmulatorMessage::Reset => {
@@ -127,6 +422,16 @@ impl EmulatorMessage {
                 v.extend(value.iter().map(|v| v.to_be_bytes()).flatten());
                 v
             }
+            EmulatorMessage::TscGesture(gesture) => {
+                let data = gesture.encode();
+                let mut v = alloc::vec![0x07];
+                v.extend_from_slice(&u16::to_be_bytes(data.len() as u16));
+                v.extend_from_slice(&data);
+                v
+            }
+            EmulatorMessage::Snapshot => {
+                alloc::vec![0x08]
+            }
         }
     }
 
@@ -141,6 +446,292 @@ impl EmulatorMessage {
             EmulatorMessage::FlashContent(_) => "FlashContent(...)".to_string(),
             EmulatorMessage::Entropy(data) => alloc::format!("Entropy({:02X?})", data),
             EmulatorMessage::Rtc(_) => alloc::format!("Rtc"),
+            EmulatorMessage::TscGesture(gesture) => alloc::format!("TscGesture({:?})", gesture),
+            EmulatorMessage::Snapshot => "Snapshot".to_string(),
         }
     }
 }
+
+/// Everything that survives a reset of the emulated card
+///
+/// Together with the entropy that is sent on boot this is enough to deterministically bring the
+/// card back to the last checkpoint it committed. RAM isn't captured: the Noise session and any
+/// progress since the last checkpoint are lost, so after a restore the app has to start a new
+/// handshake, exactly like after a fast boot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub rtc: [u32; 32],
+    pub entropy: [u8; 32],
+    pub flash: alloc::vec::Vec<u8>,
+}
+
+impl Snapshot {
+    const HEADER_LEN: usize = 1 + 32 * 4 + 32;
+
+    pub fn encode(&self) -> alloc::vec::Vec<u8> {
+        let mut v = alloc::vec![SNAPSHOT_VERSION];
+        v.extend(self.rtc.iter().flat_map(|v| v.to_be_bytes()));
+        v.extend_from_slice(&self.entropy);
+        v.extend_from_slice(&self.flash);
+        v
+    }
+
+    /// Parse and validate a snapshot, returning `None` if it was made with a different version
+    /// or the flash content isn't made of whole pages
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::HEADER_LEN || data[0] != SNAPSHOT_VERSION {
+            return None;
+        }
+
+        let flash = &data[Self::HEADER_LEN..];
+        if flash.len() % 2048 != 0 {
+            return None;
+        }
+
+        let mut rtc = [0; 32];
+        for (reg, bytes) in rtc.iter_mut().zip(data[1..1 + 32 * 4].chunks_exact(4)) {
+            *reg = u32::from_be_bytes(bytes.try_into().unwrap());
+        }
+        let entropy = data[1 + 32 * 4..Self::HEADER_LEN].try_into().unwrap();
+
+        Some(Snapshot {
+            rtc,
+            entropy,
+            flash: flash.to_vec(),
+        })
+    }
+}
+
+#[cfg(all(test, not(feature = "stm32")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gesture_roundtrip() {
+        for gesture in [
+            Gesture::Tap,
+            Gesture::DoubleTap,
+            Gesture::LongPress { ms: 0 },
+            Gesture::LongPress { ms: 1500 },
+        ] {
+            assert_eq!(Gesture::decode(&gesture.encode()), Some(gesture));
+
+            let encoded = EmulatorMessage::TscGesture(gesture).encode();
+            assert_eq!(encoded[0], 0x07);
+            let len = u16::from_be_bytes([encoded[1], encoded[2]]) as usize;
+            assert_eq!(Gesture::decode(&encoded[3..3 + len]), Some(gesture));
+        }
+
+        assert_eq!(Gesture::decode(&[]), None);
+        assert_eq!(Gesture::decode(&[0x02, 0x01]), None);
+        assert_eq!(Gesture::decode(&[0x03]), None);
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let mut rtc = [0; 32];
+        rtc[0] = 0xFA57B007;
+        rtc[31] = 0x01020304;
+        let snapshot = Snapshot {
+            rtc,
+            entropy: [0x42; 32],
+            flash: vec![0xAA; 2048 * 2],
+        };
+
+        let encoded = snapshot.encode();
+        assert_eq!(encoded[0], SNAPSHOT_VERSION);
+        assert_eq!(Snapshot::decode(&encoded), Some(snapshot));
+
+        // Wrong version
+        let mut wrong_version = encoded.clone();
+        wrong_version[0] = SNAPSHOT_VERSION + 1;
+        assert_eq!(Snapshot::decode(&wrong_version), None);
+
+        // Partial flash page
+        assert_eq!(Snapshot::decode(&encoded[..encoded.len() - 1]), None);
+        // Truncated header
+        assert_eq!(Snapshot::decode(&encoded[..10]), None);
+    }
+
+    #[test]
+    fn test_card_message_read_from() {
+        let frames: &[&[u8]] = &[
+            &[0x00, 0x00, 0x04, 0x01, 0x02, 0x03, 0x04],
+            &[0x01, 0x00, 0x02, 0xAA, 0xBB],
+            &[0x02],
+            &[0x03, 0x00, 0x03, 0x00, 0xFB, 0xCC],
+            &[0x04, 0x00, 0x02, 0x00, 0xFF],
+            &[0x05],
+            &[0x06],
+            &[0x07, 0x00, 0x01, 0x09],
+            &[0x08, 0x00, 0x05, 0x00, 0xFA, 0x57, 0xB0, 0x07],
+            &[0x09, 0x00, 0x01, 0x01],
+            &[0x0A, 0x00, 0x01, 0x04],
+            &[0x0B, 0x00, 0x01, 0x02],
+        ];
+
+        for frame in frames {
+            let (_, consumed) = CardMessage::read_from(frame).expect("Valid frame");
+            assert_eq!(consumed, frame.len());
+
+            for len in 0..frame.len() {
+                assert!(CardMessage::read_from(&frame[..len]).is_none());
+            }
+        }
+
+        let stream = frames.concat();
+        let mut offset = 0;
+        let mut count = 0;
+        while let Some((_, consumed)) = CardMessage::read_from(&stream[offset..]) {
+            offset += consumed;
+            count += 1;
+        }
+        assert_eq!((offset, count), (stream.len(), frames.len()));
+
+        assert!(matches!(
+            CardMessage::read_from(&frames[8]),
+            Some((CardMessage::WriteRtcRegister(0x00, 0xFA57B007), 8))
+        ));
+        // Invalid tag and payloads with the wrong length
+        assert!(CardMessage::read_from(&[0xFF]).is_none());
+        assert!(CardMessage::read_from(&[0x04, 0x00, 0x01, 0x00]).is_none());
+        assert!(CardMessage::read_from(&[0x00, 0x00, 0x01, 0x00]).is_none());
+
+        // Random garbage should never panic
+        let mut state = 0x12345678u32;
+        let mut garbage = [0u8; 32];
+        for _ in 0..1000 {
+            for b in garbage.iter_mut() {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                *b = (state >> 16) as u8;
+            }
+            garbage[1] = 0x00;
+            let _ = CardMessage::read_from(&garbage);
+        }
+    }
+
+    #[test]
+    fn test_card_frame_read() {
+        assert!(matches!(
+            CardFrame::read(&[]),
+            CardFrame::Incomplete { needed: 1 }
+        ));
+        assert!(matches!(
+            CardFrame::read(&[0x01, 0x00]),
+            CardFrame::Incomplete { needed: 1 }
+        ));
+
+        // Truncated payloads report how much is missing, bytes after the frame are not consumed
+        let frame = [0x01, 0x00, 0x03, 0xAA, 0xBB, 0xCC, 0x02];
+        for len in 3..6 {
+            assert!(matches!(
+                CardFrame::read(&frame[..len]),
+                CardFrame::Incomplete { needed } if needed == 6 - len
+            ));
+        }
+        assert!(matches!(
+            CardFrame::read(&frame),
+            CardFrame::Complete(CardMessage::Nfc(data), 6) if data == [0xAA, 0xBB, 0xCC]
+        ));
+
+        // Lengths that don't fit the type are rejected without waiting for the payload
+        assert!(matches!(
+            CardFrame::read(&[0x04, 0x00, 0x03]),
+            CardFrame::Invalid
+        ));
+        assert!(matches!(
+            CardFrame::read(&[0x08, 0xFF, 0xFF]),
+            CardFrame::Invalid
+        ));
+        assert!(matches!(
+            CardFrame::read(&[0x00, 0x00, 0x03]),
+            CardFrame::Invalid
+        ));
+        assert!(matches!(
+            CardFrame::read(&[0x03, 0x00, 0x01]),
+            CardFrame::Invalid
+        ));
+        assert!(matches!(CardFrame::read(&[0xFF]), CardFrame::Invalid));
+
+        // A declared length longer than the data is incomplete, never a panic
+        assert!(matches!(
+            CardFrame::read(&[0x09, 0xFF, 0xFF, 0x01]),
+            CardFrame::Incomplete { needed: 0xFFFE }
+        ));
+    }
+
+    #[test]
+    fn test_card_message_write_into() {
+        let messages = || {
+            vec![
+                CardMessage::Display(vec![0x0102, 0x0304]),
+                CardMessage::Nfc(vec![0xAA, 0xBB]),
+                CardMessage::Tick,
+                CardMessage::WriteFlash(0xFB, vec![0xCC]),
+                CardMessage::ReadFlash(0xFF),
+                CardMessage::FinishBoot,
+                CardMessage::FlushDisplay,
+                CardMessage::ReadRtcRegister(0x09),
+                CardMessage::WriteRtcRegister(0x00, 0xFA57B007),
+                CardMessage::SnapshotData(vec![0x01]),
+                CardMessage::DisplayBrightness(0x04),
+                CardMessage::DisplayRotation(0x02),
+                CardMessage::Nfc(vec![]),
+            ]
+        };
+
+        for (message, boxed) in messages().into_iter().zip(messages()) {
+            let expected = boxed.write_to().collect::<Vec<_>>();
+            assert_eq!(message.encoded_len(), expected.len());
+
+            let mut buf = [0xEE; 16];
+            let len = message.write_into(&mut buf).expect("Large enough");
+            assert_eq!(&buf[..len], expected.as_slice());
+            // Bytes past the message are left untouched
+            assert!(buf[len..].iter().all(|b| *b == 0xEE));
+
+            assert!(message.write_into(&mut buf[..len - 1]).is_none());
+        }
+    }
+
+    #[test]
+    fn test_event_log_wait_for() {
+        let messages = vec![
+            CardMessage::FlushDisplay,
+            CardMessage::Tick,
+            CardMessage::FinishBoot,
+            CardMessage::Tick,
+            CardMessage::Tick,
+            CardMessage::Display(vec![]),
+            CardMessage::FlushDisplay,
+            CardMessage::Tick,
+            CardMessage::FlushDisplay,
+        ];
+        let mut source = messages.into_iter();
+
+        let mut log = EventLog::new();
+        let mut booted = false;
+        let event = log
+            .wait_for(source.by_ref(), |e| match e.message {
+                CardMessage::FinishBoot => {
+                    booted = true;
+                    false
+                }
+                CardMessage::FlushDisplay => booted,
+                _ => false,
+            })
+            .expect("Found");
+        assert_eq!(event.tick, 3);
+        assert_eq!(log.events().len(), 7);
+
+        let event = log
+            .wait_for(source.by_ref(), |e| {
+                matches!(e.message, CardMessage::FlushDisplay)
+            })
+            .expect("Found");
+        assert_eq!(event.tick, 4);
+        assert_eq!(log.current_tick(), 4);
+
+        assert!(log.wait_for(source, |_| true).is_none());
+    }
+}
@@ -16,17 +16,67 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use core::ops::Deref;
+#[cfg(feature = "rng")]
+use core::ops::DerefMut;
 
 use bitcoin::hashes::{sha256, Hash, HashEngine};
 use bitcoin::secp256k1::{ecdh::SharedSecret, PublicKey, Secp256k1, SecretKey, SignOnly};
 
 pub use noise_rust_crypto::sensitive::Sensitive;
-use noise_rust_crypto::Aes256Gcm;
+
+#[cfg(not(feature = "noise-chachapoly"))]
+use noise_rust_crypto::Aes256Gcm as SelectedCipher;
+#[cfg(feature = "noise-chachapoly")]
+use noise_rust_crypto::ChaCha20Poly1305 as SelectedCipher;
+
+/// Cipher of the Noise sessions, AES256GCM unless the `noise-chachapoly` feature is enabled
+///
+/// The name of the cipher is part of the Noise protocol name, so a device and an app built with
+/// different ciphers fail the handshake instead of exchanging garbage.
+pub type NoiseCipher = SelectedCipher;
 
 pub const NOISE_PROLOGUE: &'static [u8] = b"nfc-hardware-signer";
+#[cfg(feature = "rng")]
+const RESEED_TAG: &[u8] = b"portal-reseed";
 
 pub struct SecpDH;
 
+#[cfg(feature = "rng")]
+impl SecpDH {
+    /// Generate a valid secret key, drawing new random bytes until they are in range
+    pub fn genkey_from_rng(rng: &mut impl rand_core::RngCore) -> Sensitive<[u8; 32]> {
+        let mut key = wrap_sensitive([0; 32]);
+        loop {
+            rng.fill_bytes(key.deref_mut());
+            if SecretKey::from_slice(key.deref()).is_ok() {
+                return key;
+            }
+        }
+    }
+}
+
+/// Replace the state of `rng` with a hash of its next output and `entropy`
+///
+/// This is meant to mix some extra entropy into an RNG that was seeded once at boot. Since the
+/// old state can't be recovered from the new one, past outputs stay secret even if the RNG state
+/// leaks later.
+#[cfg(feature = "rng")]
+pub fn reseed_with_entropy<R>(rng: &mut R, entropy: &[u8])
+where
+    R: rand_core::RngCore + rand_core::SeedableRng<Seed = [u8; 32]>,
+{
+    let mut output = wrap_sensitive([0; 32]);
+    rng.fill_bytes(output.deref_mut());
+
+    let mut engine = sha256::HashEngine::default();
+    engine.input(RESEED_TAG);
+    engine.input(output.deref());
+    engine.input(entropy);
+    let seed = wrap_sensitive(sha256::Hash::from_engine(engine).into_inner());
+
+    *rng = R::from_seed(*seed.deref());
+}
+
 impl noise_protocol::DH for SecpDH {
     type Key = Sensitive<[u8; 32]>;
     type Pubkey = [u8; 64];
@@ -82,8 +132,27 @@ pub fn wrap_sensitive(bytes: [u8; 32]) -> Sensitive<[u8; 32]> {
     Sensitive::from(From::from(bytes))
 }
 
-pub type CipherState = noise_protocol::CipherState<Aes256Gcm>;
-pub type HandshakeState = noise_protocol::HandshakeState<SecpDH, Aes256Gcm, BitcoinHashesSha256>;
+pub type CipherState = noise_protocol::CipherState<NoiseCipher>;
+
+/// Number of messages after which both ends rekey a cipher state
+///
+/// This must be the same on the SDK and the firmware, otherwise the two ends will go out of sync.
+pub const REKEY_INTERVAL: u64 = 1024;
+
+pub fn rekey(encrypt: &mut CipherState, decrypt: &mut CipherState) {
+    encrypt.rekey();
+    decrypt.rekey();
+}
+
+/// Rekey a cipher state every [`REKEY_INTERVAL`] messages
+///
+/// Must be called after every successful encryption or decryption.
+pub fn maybe_rekey<C: noise_protocol::Cipher>(cipher: &mut noise_protocol::CipherState<C>) {
+    if cipher.get_next_n() % REKEY_INTERVAL == 0 {
+        cipher.rekey();
+    }
+}
+pub type HandshakeState = noise_protocol::HandshakeState<SecpDH, NoiseCipher, BitcoinHashesSha256>;
 
 pub fn handhake_state_initiator(ephemeral_key: Sensitive<[u8; 32]>) -> HandshakeState {
     HandshakeState::new(
@@ -107,3 +176,824 @@ pub fn handhake_state_responder(ephemeral_key: Sensitive<[u8; 32]>) -> Handshake
         None,
     )
 }
+
+/// Number of initiator ephemeral keys remembered by a [`ReplayCache`]
+pub const REPLAY_CACHE_LEN: usize = 16;
+/// Length of a compressed secp256k1 key, also the start of the first NN handshake message
+const EPHEMERAL_KEY_LEN: usize = 33;
+
+/// Ephemeral keys of the latest handshakes started by the initiator
+///
+/// The app picks a fresh ephemeral key for every handshake, so seeing the same one twice means
+/// that a captured first message is being replayed. The cache is a fixed-size ring buffer, once
+/// full the oldest key is forgotten.
+#[derive(Debug, Clone)]
+pub struct ReplayCache {
+    keys: [[u8; EPHEMERAL_KEY_LEN]; REPLAY_CACHE_LEN],
+    len: usize,
+    next: usize,
+}
+
+impl Default for ReplayCache {
+    fn default() -> Self {
+        ReplayCache {
+            keys: [[0; EPHEMERAL_KEY_LEN]; REPLAY_CACHE_LEN],
+            len: 0,
+            next: 0,
+        }
+    }
+}
+
+impl ReplayCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remember the ephemeral key of the first handshake message `message`
+    ///
+    /// Returns `false` if the key was already seen, or if the message is too short to contain a
+    /// key.
+    pub fn check_and_insert(&mut self, message: &[u8]) -> bool {
+        let key: [u8; EPHEMERAL_KEY_LEN] = match message.get(..EPHEMERAL_KEY_LEN) {
+            Some(key) => key.try_into().expect("Correct length"),
+            None => return false,
+        };
+        if self.keys[..self.len].contains(&key) {
+            return false;
+        }
+
+        self.keys[self.next] = key;
+        self.next = (self.next + 1) % REPLAY_CACHE_LEN;
+        self.len = (self.len + 1).min(REPLAY_CACHE_LEN);
+
+        true
+    }
+}
+
+/// Milliseconds after which a handshake that didn't complete is started again
+pub const HANDSHAKE_TIMEOUT_MS: u64 = 5_000;
+
+/// Operations on a handshake in progress, common to [`HandshakeState`] and [`XxHandshake`]
+pub trait Handshake {
+    type Error;
+
+    fn read_message_vec(&mut self, data: &[u8]) -> Result<alloc::vec::Vec<u8>, Self::Error>;
+    fn write_message_vec(&mut self, payload: &[u8]) -> Result<alloc::vec::Vec<u8>, Self::Error>;
+    fn completed(&self) -> bool;
+}
+
+impl Handshake for HandshakeState {
+    type Error = noise_protocol::Error;
+
+    fn read_message_vec(&mut self, data: &[u8]) -> Result<alloc::vec::Vec<u8>, Self::Error> {
+        HandshakeState::read_message_vec(self, data)
+    }
+    fn write_message_vec(&mut self, payload: &[u8]) -> Result<alloc::vec::Vec<u8>, Self::Error> {
+        HandshakeState::write_message_vec(self, payload)
+    }
+    fn completed(&self) -> bool {
+        HandshakeState::completed(self)
+    }
+}
+
+/// Handshake that starts again from a fresh state if it stalls or fails
+///
+/// Over a flaky NFC link the other end may disappear halfway through a handshake. Instead of
+/// waiting forever for the next message, the state is replaced with a new one from `new_state`
+/// once [`HANDSHAKE_TIMEOUT_MS`] have elapsed, or as soon as a message is rejected. The keys of
+/// the partial state are zeroized when it's dropped.
+///
+/// Expiration is only checked when a message is processed, so the caller must also stop waiting
+/// for a message after [`HANDSHAKE_TIMEOUT_MS`] and drop the handshake.
+pub struct TimedHandshake<F, H = HandshakeState> {
+    new_state: F,
+    state: H,
+    started_at: u64,
+}
+
+impl<H: Handshake, F: FnMut() -> H> TimedHandshake<F, H> {
+    /// `now` is a timestamp in milliseconds from any monotonic clock
+    pub fn new(mut new_state: F, now: u64) -> Self {
+        let state = new_state();
+        TimedHandshake {
+            new_state,
+            state,
+            started_at: now,
+        }
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        !self.state.completed() && now.saturating_sub(self.started_at) > HANDSHAKE_TIMEOUT_MS
+    }
+
+    pub fn reset(&mut self, now: u64) {
+        self.state = (self.new_state)();
+        self.started_at = now;
+    }
+
+    /// The current state, reset first if it expired
+    pub fn state(&mut self, now: u64) -> &mut H {
+        if self.is_expired(now) {
+            self.reset(now);
+        }
+
+        &mut self.state
+    }
+
+    pub fn read_message(&mut self, data: &[u8], now: u64) -> Result<alloc::vec::Vec<u8>, H::Error> {
+        let result = self.state(now).read_message_vec(data);
+        if result.is_err() {
+            self.reset(now);
+        }
+
+        result
+    }
+
+    pub fn write_message(
+        &mut self,
+        payload: &[u8],
+        now: u64,
+    ) -> Result<alloc::vec::Vec<u8>, H::Error> {
+        let result = self.state(now).write_message_vec(payload);
+        if result.is_err() {
+            self.reset(now);
+        }
+
+        result
+    }
+
+    pub fn completed(&self) -> bool {
+        self.state.completed()
+    }
+
+    pub fn into_state(self) -> H {
+        self.state
+    }
+}
+
+/// Public key corresponding to a static identity key
+///
+/// This is what the remote end will see as its `rs` after an XX handshake, so it can be used to
+/// recognize the same device across sessions.
+pub fn static_pubkey(static_key: &Sensitive<[u8; 32]>) -> [u8; 64] {
+    <SecpDH as noise_protocol::DH>::pubkey(static_key)
+}
+
+/// Short hash of a static public key, for the user to compare the identity of a device across
+/// sessions of an [`XxHandshake`]
+///
+/// Only the compressed key at the start of `pubkey` is hashed, the rest is padding.
+pub fn static_key_fingerprint(pubkey: &[u8; 64]) -> [u8; 4] {
+    let hash = sha256::Hash::hash(&pubkey[..EPHEMERAL_KEY_LEN]);
+    hash[..4].try_into().expect("Correct length")
+}
+
+/// Fingerprint formatted for display, like `0F71-5BAF`
+pub fn format_static_key_fingerprint(fingerprint: &[u8; 4]) -> alloc::string::String {
+    alloc::format!(
+        "{:02X}{:02X}-{:02X}{:02X}",
+        fingerprint[0],
+        fingerprint[1],
+        fingerprint[2],
+        fingerprint[3]
+    )
+}
+
+/// Error of an [`XxHandshake`]
+#[derive(Debug)]
+pub enum XxHandshakeError {
+    Noise(noise_protocol::Error),
+    /// The remote end sent a static key different from the pinned one
+    RemoteStaticMismatch,
+}
+
+impl From<noise_protocol::Error> for XxHandshakeError {
+    fn from(e: noise_protocol::Error) -> Self {
+        XxHandshakeError::Noise(e)
+    }
+}
+
+/// Noise XX handshake, where both ends send their static key
+///
+/// If the static key of the remote end is known from a previous session it can be pinned: the
+/// handshake then fails as soon as the remote end sends a different one, before any payload is
+/// trusted.
+pub struct XxHandshake {
+    state: HandshakeState,
+    remote_static: Option<[u8; 64]>,
+}
+
+impl XxHandshake {
+    fn new(
+        initiator: bool,
+        static_key: Option<Sensitive<[u8; 32]>>,
+        ephemeral_key: Sensitive<[u8; 32]>,
+        remote_static: Option<[u8; 64]>,
+    ) -> Self {
+        // Without an identity the ephemeral key is also sent as static key, so the remote end
+        // can't recognize us across sessions
+        let static_key = static_key.unwrap_or_else(|| wrap_sensitive(*ephemeral_key.deref()));
+        let state = HandshakeState::new(
+            noise_protocol::patterns::noise_xx(),
+            initiator,
+            NOISE_PROLOGUE,
+            Some(static_key),
+            Some(ephemeral_key),
+            None,
+            None,
+        );
+
+        XxHandshake {
+            state,
+            remote_static,
+        }
+    }
+
+    pub fn read_message_vec(
+        &mut self,
+        data: &[u8],
+    ) -> Result<alloc::vec::Vec<u8>, XxHandshakeError> {
+        let payload = self.state.read_message_vec(data)?;
+        match (&self.remote_static, self.state.get_rs()) {
+            (Some(expected), Some(rs)) if &rs != expected => {
+                log::warn!("Unexpected remote static key {:02X?}", &rs[..33]);
+                Err(XxHandshakeError::RemoteStaticMismatch)
+            }
+            _ => Ok(payload),
+        }
+    }
+
+    pub fn write_message_vec(
+        &mut self,
+        payload: &[u8],
+    ) -> Result<alloc::vec::Vec<u8>, XxHandshakeError> {
+        Ok(self.state.write_message_vec(payload)?)
+    }
+
+    pub fn completed(&self) -> bool {
+        self.state.completed()
+    }
+
+    /// Static key of the remote end, once received
+    pub fn get_rs(&self) -> Option<[u8; 64]> {
+        self.state.get_rs()
+    }
+
+    pub fn state(&self) -> &HandshakeState {
+        &self.state
+    }
+
+    pub fn into_state(self) -> HandshakeState {
+        self.state
+    }
+}
+
+impl Handshake for XxHandshake {
+    type Error = XxHandshakeError;
+
+    fn read_message_vec(&mut self, data: &[u8]) -> Result<alloc::vec::Vec<u8>, Self::Error> {
+        XxHandshake::read_message_vec(self, data)
+    }
+    fn write_message_vec(&mut self, payload: &[u8]) -> Result<alloc::vec::Vec<u8>, Self::Error> {
+        XxHandshake::write_message_vec(self, payload)
+    }
+    fn completed(&self) -> bool {
+        XxHandshake::completed(self)
+    }
+}
+
+/// Start an XX handshake as the app
+///
+/// `static_key` is the identity of the app, without one the device can't tell it apart from
+/// other apps. `remote_static` pins the device paired in a previous session.
+pub fn handshake_state_initiator_xx(
+    static_key: Option<Sensitive<[u8; 32]>>,
+    ephemeral_key: Sensitive<[u8; 32]>,
+    remote_static: Option<[u8; 64]>,
+) -> XxHandshake {
+    XxHandshake::new(true, static_key, ephemeral_key, remote_static)
+}
+/// Start an XX handshake as the device, see [`handshake_state_initiator_xx`]
+pub fn handshake_state_responder_xx(
+    static_key: Option<Sensitive<[u8; 32]>>,
+    ephemeral_key: Sensitive<[u8; 32]>,
+    remote_static: Option<[u8; 64]>,
+) -> XxHandshake {
+    XxHandshake::new(false, static_key, ephemeral_key, remote_static)
+}
+
+/// Check that the static key sent by the remote end matches the one we paired with
+///
+/// The XX pattern transmits the static keys during the handshake, so this should be called once
+/// the handshake is completed, before trusting the channel.
+pub fn remote_static_matches(state: &HandshakeState, expected: &[u8; 64]) -> bool {
+    state.get_rs().as_ref() == Some(expected)
+}
+
+/// Hash of the whole handshake transcript, identical on both ends of the channel
+pub fn get_handshake_hash(state: &HandshakeState) -> [u8; 32] {
+    let mut hash = [0; 32];
+    hash.copy_from_slice(state.get_hash());
+    hash
+}
+
+const SAS_EMOJIS: [char; 64] = [
+    '🐶', '🐱', '🦁', '🐎', '🦄', '🐷', '🐘', '🐰', '🐼', '🐓', '🐧', '🐢', '🐟', '🐙', '🦋', '🌷',
+    '🌳', '🌵', '🍄', '🌏', '🌙', '☁', '🔥', '🍌', '🍎', '🍓', '🌽', '🍕', '🎂', '❤', '😀', '🤖',
+    '🎩', '👓', '🔧', '🎅', '👍', '☂', '⌛', '⏰', '🎁', '💡', '📕', '✏', '📎', '✂', '🔒', '🔑',
+    '🔨', '☎', '🏁', '🚂', '🚲', '✈', '🚀', '🏆', '⚽', '🎸', '🎺', '🔔', '⚓', '🎧', '📁', '📌',
+];
+
+/// Map the handshake hash to 4 emojis that can be compared by the user on both ends
+///
+/// Each emoji encodes 6 bits from the beginning of the hash.
+pub fn short_auth_string(hash: &[u8; 32]) -> [char; 4] {
+    let bits = u32::from_be_bytes([0, hash[0], hash[1], hash[2]]);
+    let mut sas = ['\0'; 4];
+    for (i, c) in sas.iter_mut().enumerate() {
+        *c = SAS_EMOJIS[((bits >> (18 - 6 * i)) & 0x3F) as usize];
+    }
+
+    sas
+}
+
+#[cfg(all(test, not(feature = "stm32")))]
+mod tests {
+    use super::*;
+    use crate::{
+        Message, MessageError, Reply, Request, SessionAad, SessionId, REPLY_AAD, REQUEST_AAD,
+        SESSION_ID_LEN,
+    };
+
+    #[test]
+    fn test_handshake_xx() {
+        let device_static = wrap_sensitive([0x01; 32]);
+        let device_pubkey = static_pubkey(&device_static);
+        let app_static = wrap_sensitive([0x02; 32]);
+        let app_pubkey = static_pubkey(&app_static);
+
+        let mut initiator =
+            handshake_state_initiator_xx(Some(app_static), wrap_sensitive([0x03; 32]), None);
+        let mut responder =
+            handshake_state_responder_xx(Some(device_static), wrap_sensitive([0x04; 32]), None);
+
+        let msg = initiator.write_message_vec(&[]).unwrap();
+        responder.read_message_vec(&msg).unwrap();
+        let msg = responder.write_message_vec(&[]).unwrap();
+        initiator.read_message_vec(&msg).unwrap();
+        let msg = initiator.write_message_vec(&[]).unwrap();
+        responder.read_message_vec(&msg).unwrap();
+
+        assert!(initiator.completed() && responder.completed());
+        assert!(remote_static_matches(initiator.state(), &device_pubkey));
+        assert!(remote_static_matches(responder.state(), &app_pubkey));
+        assert!(!remote_static_matches(initiator.state(), &app_pubkey));
+
+        let (mut send, _) = initiator.into_state().get_ciphers();
+        let (mut recv, _) = responder.into_state().get_ciphers();
+        let ciphertext = send.encrypt_vec(b"hello");
+        assert_eq!(recv.decrypt_vec(&ciphertext).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_handshake_xx_pinned() {
+        let device_pubkey = static_pubkey(&wrap_sensitive([0x01; 32]));
+        let handshake = |device_static: [u8; 32]| {
+            // The app has no identity of its own and pins the device paired before
+            let mut initiator =
+                handshake_state_initiator_xx(None, wrap_sensitive([0x03; 32]), Some(device_pubkey));
+            let mut responder = handshake_state_responder_xx(
+                Some(wrap_sensitive(device_static)),
+                wrap_sensitive([0x04; 32]),
+                None,
+            );
+
+            let msg = initiator.write_message_vec(&[]).unwrap();
+            responder.read_message_vec(&msg).unwrap();
+            let msg = responder.write_message_vec(&[]).unwrap();
+            initiator.read_message_vec(&msg)?;
+            let msg = initiator.write_message_vec(&[]).unwrap();
+            responder.read_message_vec(&msg).unwrap();
+
+            assert!(initiator.completed() && responder.completed());
+            Ok(responder.get_rs())
+        };
+
+        // Without a static key the app sends its ephemeral one
+        let app_rs = handshake([0x01; 32]).unwrap();
+        assert_eq!(app_rs, Some(static_pubkey(&wrap_sensitive([0x03; 32]))));
+
+        // A different device is rejected while reading its static key
+        assert!(matches!(
+            handshake([0x05; 32]),
+            Err(XxHandshakeError::RemoteStaticMismatch)
+        ));
+    }
+
+    fn noise_roundtrip<C: noise_protocol::Cipher>() {
+        let new_state = |initiator: bool, ephemeral: [u8; 32]| {
+            noise_protocol::HandshakeState::<SecpDH, C, BitcoinHashesSha256>::new(
+                noise_protocol::patterns::noise_nn(),
+                initiator,
+                NOISE_PROLOGUE,
+                None,
+                Some(wrap_sensitive(ephemeral)),
+                None,
+                None,
+            )
+        };
+        let mut initiator = new_state(true, [0x01; 32]);
+        let mut responder = new_state(false, [0x02; 32]);
+
+        let msg = initiator.write_message_vec(&[]).unwrap();
+        responder.read_message_vec(&msg).unwrap();
+        let msg = responder.write_message_vec(&[]).unwrap();
+        initiator.read_message_vec(&msg).unwrap();
+        assert!(initiator.completed() && responder.completed());
+        assert_eq!(initiator.get_hash(), responder.get_hash());
+
+        let (mut send, mut app_recv) = initiator.get_ciphers();
+        let (mut recv, mut device_send) = responder.get_ciphers();
+        let ciphertext = send.encrypt_vec(b"hello");
+        assert_eq!(recv.decrypt_vec(&ciphertext).unwrap(), b"hello");
+        let ciphertext = device_send.encrypt_vec(b"world");
+        assert_eq!(app_recv.decrypt_vec(&ciphertext).unwrap(), b"world");
+    }
+
+    #[test]
+    fn test_noise_roundtrip_aes256gcm() {
+        noise_roundtrip::<noise_rust_crypto::Aes256Gcm>();
+    }
+
+    #[test]
+    fn test_noise_roundtrip_chachapoly() {
+        noise_roundtrip::<noise_rust_crypto::ChaCha20Poly1305>();
+    }
+
+    #[test]
+    fn test_replay_cache_rejects_replayed_handshake() {
+        let mut cache = ReplayCache::new();
+        let mut initiator = handhake_state_initiator(wrap_sensitive([0x01; 32]));
+        let msg = initiator.write_message_vec(&[]).unwrap();
+
+        assert!(cache.check_and_insert(&msg));
+        // The same message captured and sent again
+        assert!(!cache.check_and_insert(&msg));
+        // A new handshake from the app uses a different ephemeral key
+        let mut initiator = handhake_state_initiator(wrap_sensitive([0x02; 32]));
+        assert!(cache.check_and_insert(&initiator.write_message_vec(&[]).unwrap()));
+
+        assert!(!cache.check_and_insert(&msg[..10]));
+    }
+
+    #[test]
+    fn test_replay_cache_forgets_oldest() {
+        let mut cache = ReplayCache::new();
+        let messages = (1..=REPLAY_CACHE_LEN as u8 + 1)
+            .map(|i| {
+                handhake_state_initiator(wrap_sensitive([i; 32]))
+                    .write_message_vec(&[])
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        for msg in &messages {
+            assert!(cache.check_and_insert(msg));
+        }
+
+        // The first key was pushed out by the last one
+        assert!(cache.check_and_insert(&messages[0]));
+        assert!(!cache.check_and_insert(&messages[REPLAY_CACHE_LEN]));
+    }
+
+    #[test]
+    fn test_noise_cipher_name() {
+        let expected = match cfg!(feature = "noise-chachapoly") {
+            true => "ChaChaPoly",
+            false => "AESGCM",
+        };
+        assert_eq!(CipherState::name(), expected);
+    }
+
+    #[test]
+    fn test_static_key_fingerprint() {
+        let mut secret = [0; 32];
+        secret[31] = 0x01;
+        let pubkey = static_pubkey(&wrap_sensitive(secret));
+        let fingerprint = static_key_fingerprint(&pubkey);
+
+        // sha256 of the generator point, compressed
+        assert_eq!(fingerprint, [0x0F, 0x71, 0x5B, 0xAF]);
+        assert_eq!(format_static_key_fingerprint(&fingerprint), "0F71-5BAF");
+    }
+
+    #[test]
+    fn test_timed_handshake() {
+        let mut ephemeral = 0x10;
+        let mut resets = 0;
+        let mut responder = TimedHandshake::new(
+            || {
+                ephemeral += 1;
+                resets += 1;
+                handshake_state_responder_xx(
+                    Some(wrap_sensitive([0x01; 32])),
+                    wrap_sensitive([ephemeral; 32]),
+                    None,
+                )
+            },
+            0,
+        );
+        let initiator = |ephemeral| {
+            handshake_state_initiator_xx(
+                Some(wrap_sensitive([0x02; 32])),
+                wrap_sensitive([ephemeral; 32]),
+                None,
+            )
+        };
+
+        // The initiator goes away after the second message
+        let mut stalled = initiator(0x03);
+        let msg = stalled.write_message_vec(&[]).unwrap();
+        responder.read_message(&msg, 100).unwrap();
+        responder.write_message(&[], 200).unwrap();
+        assert!(!responder.is_expired(HANDSHAKE_TIMEOUT_MS));
+        assert!(responder.is_expired(HANDSHAKE_TIMEOUT_MS + 1));
+
+        // A new initiator completes the handshake against the fresh state
+        let now = HANDSHAKE_TIMEOUT_MS + 1;
+        let mut fresh = initiator(0x04);
+        let msg = fresh.write_message_vec(&[]).unwrap();
+        responder.read_message(&msg, now).unwrap();
+        let msg = responder.write_message(&[], now).unwrap();
+        fresh.read_message_vec(&msg).unwrap();
+        let msg = fresh.write_message_vec(&[]).unwrap();
+        responder.read_message(&msg, now).unwrap();
+        assert!(responder.completed() && fresh.completed());
+        // A completed handshake never expires
+        assert!(!responder.is_expired(u64::MAX));
+
+        let (mut send, _) = fresh.into_state().get_ciphers();
+        let (mut recv, _) = responder.into_state().into_state().get_ciphers();
+        let ciphertext = send.encrypt_vec(b"hello");
+        assert_eq!(recv.decrypt_vec(&ciphertext).unwrap(), b"hello");
+        assert_eq!(resets, 2);
+    }
+
+    #[test]
+    fn test_timed_handshake_reset_on_failure() {
+        let mut responder = TimedHandshake::new(
+            || {
+                handshake_state_responder_xx(
+                    Some(wrap_sensitive([0x01; 32])),
+                    wrap_sensitive([0x11; 32]),
+                    None,
+                )
+            },
+            0,
+        );
+        let mut initiator = handshake_state_initiator_xx(
+            Some(wrap_sensitive([0x02; 32])),
+            wrap_sensitive([0x03; 32]),
+            None,
+        );
+
+        let msg = initiator.write_message_vec(&[]).unwrap();
+        responder.read_message(&msg, 0).unwrap();
+        responder.write_message(&[], 0).unwrap();
+        // The third message is encrypted, garbage is rejected
+        assert!(responder.read_message(&[0xAA; 96], 0).is_err());
+
+        // Back to waiting for the first message
+        let mut initiator = handshake_state_initiator_xx(
+            Some(wrap_sensitive([0x02; 32])),
+            wrap_sensitive([0x04; 32]),
+            None,
+        );
+        let msg = initiator.write_message_vec(&[]).unwrap();
+        responder.read_message(&msg, 0).unwrap();
+        let msg = responder.write_message(&[], 0).unwrap();
+        initiator.read_message_vec(&msg).unwrap();
+        let msg = initiator.write_message_vec(&[]).unwrap();
+        responder.read_message(&msg, 0).unwrap();
+        assert!(responder.completed());
+    }
+
+    #[test]
+    fn test_short_auth_string() {
+        let mut initiator = handhake_state_initiator(wrap_sensitive([0x01; 32]));
+        let mut responder = handhake_state_responder(wrap_sensitive([0x02; 32]));
+
+        let msg = initiator.write_message_vec(&[]).unwrap();
+        responder.read_message_vec(&msg).unwrap();
+        let msg = responder.write_message_vec(&[]).unwrap();
+        initiator.read_message_vec(&msg).unwrap();
+
+        // Each end computes the SAS from its own copy of the transcript
+        let initiator_sas = short_auth_string(&get_handshake_hash(&initiator));
+        let responder_sas = short_auth_string(&get_handshake_hash(&responder));
+        assert_eq!(initiator_sas, responder_sas);
+
+        // Someone in the middle runs a separate handshake with each end, which shows different
+        // emojis with these keys
+        let mut mitm = handhake_state_responder(wrap_sensitive([0x03; 32]));
+        let mut initiator = handhake_state_initiator(wrap_sensitive([0x01; 32]));
+        let msg = initiator.write_message_vec(&[]).unwrap();
+        mitm.read_message_vec(&msg).unwrap();
+        let msg = mitm.write_message_vec(&[]).unwrap();
+        initiator.read_message_vec(&msg).unwrap();
+        assert_ne!(
+            short_auth_string(&get_handshake_hash(&initiator)),
+            responder_sas
+        );
+
+        // 0x123456 is split into 000100 100011 010001 010110
+        let mut hash = [0xEE; 32];
+        hash[..3].copy_from_slice(&[0x12, 0x34, 0x56]);
+        assert_eq!(short_auth_string(&hash), ['🦄', '🎅', '🌵', '🔥']);
+        assert_eq!(short_auth_string(&[0x00; 32]), ['🐶', '🐶', '🐶', '🐶']);
+        assert_eq!(short_auth_string(&[0xFF; 32]), ['📌', '📌', '📌', '📌']);
+    }
+
+    #[test]
+    #[cfg(feature = "rng")]
+    fn test_genkey_from_rng() {
+        use bitcoin::secp256k1::SecretKey;
+        use core::ops::Deref;
+
+        struct CountingRng(u8);
+        impl rand_core::RngCore for CountingRng {
+            fn next_u32(&mut self) -> u32 {
+                rand_core::impls::next_u32_via_fill(self)
+            }
+            fn next_u64(&mut self) -> u64 {
+                rand_core::impls::next_u64_via_fill(self)
+            }
+            fn fill_bytes(&mut self, dest: &mut [u8]) {
+                // The first two attempts are out of range (zero and above the curve order)
+                let byte = match self.0 {
+                    0 => 0x00,
+                    1 => 0xFF,
+                    n => n,
+                };
+                self.0 = self.0.wrapping_add(1);
+                dest.fill(byte);
+            }
+            fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+                self.fill_bytes(dest);
+                Ok(())
+            }
+        }
+
+        let mut rng = CountingRng(0);
+        for _ in 0..16 {
+            let key = SecpDH::genkey_from_rng(&mut rng);
+            assert!(SecretKey::from_slice(key.deref()).is_ok());
+        }
+        assert_eq!(rng.0, 18);
+    }
+
+    #[test]
+    #[cfg(feature = "rng")]
+    fn test_reseed_with_entropy() {
+        use rand_chacha::ChaCha20Rng;
+        use rand_core::{RngCore, SeedableRng};
+
+        let output = |rng: &mut ChaCha20Rng| {
+            let mut buf = [0; 32];
+            rng.fill_bytes(&mut buf);
+            buf
+        };
+        let reseeded = |jitter: &[u8]| {
+            let mut rng = ChaCha20Rng::from_seed([0x42; 32]);
+            reseed_with_entropy(&mut rng, jitter);
+            output(&mut rng)
+        };
+
+        let jitter = [0x12, 0x34, 0x56, 0x78];
+        // Deterministic given the same state and jitter
+        assert_eq!(reseeded(&jitter), reseeded(&jitter));
+        // Different from the stream without reseeding, and from other jitter
+        let mut rng = ChaCha20Rng::from_seed([0x42; 32]);
+        let not_reseeded = [output(&mut rng), output(&mut rng)];
+        assert!(!not_reseeded.contains(&reseeded(&jitter)));
+        assert_ne!(reseeded(&jitter), reseeded(&[0x12, 0x34, 0x56, 0x79]));
+        // Mixing nothing in still moves to a new state
+        assert!(!not_reseeded.contains(&reseeded(&[])));
+    }
+
+    #[test]
+    fn test_mismatched_aad() {
+        let mut initiator = handhake_state_initiator(wrap_sensitive([0x01; 32]));
+        let mut responder = handhake_state_responder(wrap_sensitive([0x02; 32]));
+
+        let msg = initiator.write_message_vec(&[]).unwrap();
+        responder.read_message_vec(&msg).unwrap();
+        let msg = responder.write_message_vec(&[]).unwrap();
+        initiator.read_message_vec(&msg).unwrap();
+
+        let (mut encrypt, _) = initiator.get_ciphers();
+        let (mut decrypt, _) = responder.get_ciphers();
+
+        let mut decrypt_buf = Vec::new();
+        let message = Message::new_serialize(&Request::GetInfo, REPLY_AAD, &mut encrypt).unwrap();
+        assert!(matches!(
+            message.deserialize::<Request, _>(&mut decrypt_buf, REQUEST_AAD, &mut decrypt),
+            Err(MessageError::DecryptionFailed)
+        ));
+        assert!(matches!(
+            message.deserialize::<Request, _>(&mut decrypt_buf, REPLY_AAD, &mut decrypt),
+            Ok(Request::GetInfo)
+        ));
+    }
+
+    #[test]
+    fn test_interleaved_sessions() {
+        let handshake = |app_key, device_key, session_id: &SessionId| {
+            let mut initiator = handhake_state_initiator(wrap_sensitive(app_key));
+            let mut responder = handhake_state_responder(wrap_sensitive(device_key));
+
+            let msg = initiator.write_message_vec(&[]).unwrap();
+            responder.read_message_vec(&msg).unwrap();
+            let msg = responder.write_message_vec(session_id).unwrap();
+            let payload = initiator.read_message_vec(&msg).unwrap();
+
+            let aad = SessionAad::from_handshake_payload(&payload).unwrap();
+            assert_eq!(aad, SessionAad::new(session_id));
+            let (app_encrypt, app_decrypt) = initiator.get_ciphers();
+            let (device_decrypt, device_encrypt) = responder.get_ciphers();
+            (
+                aad,
+                (app_encrypt, app_decrypt),
+                (device_decrypt, device_encrypt),
+            )
+        };
+
+        let (aad_a, (mut app_a, _), (mut device_a, mut device_a_reply)) =
+            handshake([0x01; 32], [0x02; 32], &[0xAA; SESSION_ID_LEN]);
+        let (aad_b, (mut app_b, mut app_b_reply), (mut device_b, _)) =
+            handshake([0x03; 32], [0x04; 32], &[0xBB; SESSION_ID_LEN]);
+
+        let mut decrypt_buf = Vec::new();
+
+        // A request of session A can't be read by session B, and vice versa
+        let request_a =
+            Message::new_serialize(&Request::GetInfo, aad_a.request(), &mut app_a).unwrap();
+        let request_b =
+            Message::new_serialize(&Request::GetInfo, aad_b.request(), &mut app_b).unwrap();
+        assert!(matches!(
+            request_a.deserialize::<Request, _>(&mut decrypt_buf, aad_b.request(), &mut device_b),
+            Err(MessageError::DecryptionFailed)
+        ));
+        assert!(matches!(
+            request_b.deserialize::<Request, _>(&mut decrypt_buf, aad_a.request(), &mut device_a),
+            Err(MessageError::DecryptionFailed)
+        ));
+
+        // Even with the right keys, the session ID must match
+        assert!(matches!(
+            request_a.deserialize::<Request, _>(&mut decrypt_buf, aad_b.request(), &mut device_a),
+            Err(MessageError::DecryptionFailed)
+        ));
+        assert!(matches!(
+            request_a.deserialize::<Request, _>(&mut decrypt_buf, aad_a.request(), &mut device_a),
+            Ok(Request::GetInfo)
+        ));
+        assert!(matches!(
+            request_b.deserialize::<Request, _>(&mut decrypt_buf, aad_b.request(), &mut device_b),
+            Ok(Request::GetInfo)
+        ));
+
+        // A reply of session A is rejected by session B
+        let reply_a =
+            Message::new_serialize(&Reply::Ok, aad_a.reply(), &mut device_a_reply).unwrap();
+        assert!(matches!(
+            reply_a.deserialize::<Reply, _>(&mut decrypt_buf, aad_b.reply(), &mut app_b_reply),
+            Err(MessageError::DecryptionFailed)
+        ));
+
+        assert_eq!(SessionAad::from_handshake_payload(&[]), None);
+        assert_eq!(SessionAad::from_handshake_payload(&[0xAA; 8]), None);
+    }
+
+    #[test]
+    fn test_implicit_rekey() {
+        let mut initiator = handhake_state_initiator(wrap_sensitive([0x01; 32]));
+        let mut responder = handhake_state_responder(wrap_sensitive([0x02; 32]));
+
+        let msg = initiator.write_message_vec(&[]).unwrap();
+        responder.read_message_vec(&msg).unwrap();
+        let msg = responder.write_message_vec(&[]).unwrap();
+        initiator.read_message_vec(&msg).unwrap();
+
+        let (mut encrypt, _) = initiator.get_ciphers();
+        let (mut decrypt, _) = responder.get_ciphers();
+
+        let mut decrypt_buf = Vec::new();
+        for i in 0..REKEY_INTERVAL + 10 {
+            let message = Message::new_serialize(&i, REQUEST_AAD, &mut encrypt).unwrap();
+            let decoded: u64 = message
+                .deserialize(&mut decrypt_buf, REQUEST_AAD, &mut decrypt)
+                .unwrap();
+            assert_eq!(decoded, i);
+        }
+    }
+}
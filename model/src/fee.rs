@@ -0,0 +1,208 @@
+// Portal Hardware Wallet firmware and supporting software libraries
+//
+// Copyright (C) 2024 Alekos Filini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Bounds on the fee of a PSBT, to refuse signing a transaction that would burn the funds
+//!
+//! The amount of each input is taken from its `non_witness_utxo`, like in
+//! [`prev_utxos`](crate::prev_utxos::prev_utxos), and from its `witness_utxo` otherwise.
+//!
+//! The fee rate is computed on the size of the unsigned transaction, which doesn't include the
+//! signatures yet. It's higher than the fee rate of the signed transaction, so the check errs on
+//! the side of rejecting.
+
+use core::fmt;
+
+use bitcoin::util::psbt::PartiallySignedTransaction;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeeError {
+    /// The fee is higher than the limit, which is the lowest one between the maximum fee and the
+    /// maximum fee rate multiplied by the size of the transaction
+    FeeTooHigh { fee: u64, limit: u64 },
+    /// The taproot input at this index has no `witness_utxo`
+    MissingWitnessUtxo(usize),
+    /// The input at this index has no `non_witness_utxo`, or it doesn't contain the output spent
+    MissingNonWitnessUtxo(usize),
+    /// The outputs spend more than the inputs
+    NegativeFee,
+    /// The sum of the input or output amounts overflows
+    Overflow,
+}
+
+impl fmt::Display for FeeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Compute the fee of `psbt`, and ensure it's at most `max_fee_sat` and pays at most
+/// `max_feerate_sat_vb`
+pub fn check_fee_bounds(
+    psbt: &PartiallySignedTransaction,
+    max_fee_sat: u64,
+    max_feerate_sat_vb: f32,
+) -> Result<u64, FeeError> {
+    let tx = &psbt.unsigned_tx;
+
+    let mut inputs = 0u64;
+    for (index, (txin, input)) in tx.input.iter().zip(psbt.inputs.iter()).enumerate() {
+        let vout = txin.previous_output.vout as usize;
+        let value = match (&input.non_witness_utxo, &input.witness_utxo) {
+            (Some(prev_tx), _) => {
+                prev_tx
+                    .output
+                    .get(vout)
+                    .ok_or(FeeError::MissingNonWitnessUtxo(index))?
+                    .value
+            }
+            (None, Some(witness_utxo)) => witness_utxo.value,
+            // Only taproot inputs can do without the previous transaction
+            (None, None)
+                if input.tap_internal_key.is_some() || !input.tap_key_origins.is_empty() =>
+            {
+                return Err(FeeError::MissingWitnessUtxo(index))
+            }
+            (None, None) => return Err(FeeError::MissingNonWitnessUtxo(index)),
+        };
+        inputs = inputs.checked_add(value).ok_or(FeeError::Overflow)?;
+    }
+    let outputs = tx
+        .output
+        .iter()
+        .try_fold(0u64, |sum, out| sum.checked_add(out.value))
+        .ok_or(FeeError::Overflow)?;
+    let fee = inputs.checked_sub(outputs).ok_or(FeeError::NegativeFee)?;
+
+    // The float to int conversion saturates, and turns NaN into 0
+    let rate_limit = (max_feerate_sat_vb as f64 * tx.vsize() as f64) as u64;
+    let limit = max_fee_sat.min(rate_limit);
+    if fee > limit {
+        return Err(FeeError::FeeTooHigh { fee, limit });
+    }
+
+    Ok(fee)
+}
+
+#[cfg(all(test, not(feature = "stm32")))]
+mod tests {
+    use super::*;
+    use crate::test_utils::spending_psbt;
+
+    #[test]
+    fn test_check_fee_bounds() {
+        use bitcoin::{Script, TxOut};
+        // 10k sat spent, 9k paid
+        let (mut psbt, prev_tx) = spending_psbt();
+        psbt.unsigned_tx.output.push(TxOut {
+            value: 9_000,
+            script_pubkey: Script::new(),
+        });
+        psbt.outputs.push(Default::default());
+        psbt.inputs[0].non_witness_utxo = Some(prev_tx.clone());
+        let vsize = psbt.unsigned_tx.vsize() as u64;
+
+        assert_eq!(check_fee_bounds(&psbt, 1_000, f32::INFINITY), Ok(1_000));
+        assert_eq!(
+            check_fee_bounds(&psbt, 999, f32::INFINITY),
+            Err(FeeError::FeeTooHigh {
+                fee: 1_000,
+                limit: 999
+            })
+        );
+
+        // Limited by the fee rate
+        let feerate = 1_000.0 / vsize as f32;
+        assert_eq!(check_fee_bounds(&psbt, u64::MAX, feerate + 0.1), Ok(1_000));
+        assert_eq!(
+            check_fee_bounds(&psbt, u64::MAX, 1.0),
+            Err(FeeError::FeeTooHigh {
+                fee: 1_000,
+                limit: vsize
+            })
+        );
+
+        // The witness_utxo is used without the previous transaction
+        psbt.inputs[0].non_witness_utxo = None;
+        psbt.inputs[0].witness_utxo = Some(prev_tx.output[0].clone());
+        assert_eq!(check_fee_bounds(&psbt, u64::MAX, f32::INFINITY), Ok(1_000));
+    }
+
+    #[test]
+    fn test_check_fee_bounds_invalid_amounts() {
+        use bitcoin::secp256k1::{Secp256k1, SecretKey};
+        use bitcoin::{Script, TxOut};
+        let (mut psbt, mut prev_tx) = spending_psbt();
+        psbt.unsigned_tx.output.push(TxOut {
+            value: 9_000,
+            script_pubkey: Script::new(),
+        });
+        psbt.outputs.push(Default::default());
+
+        // Nothing to take the amount from
+        assert_eq!(
+            check_fee_bounds(&psbt, u64::MAX, f32::INFINITY),
+            Err(FeeError::MissingNonWitnessUtxo(0))
+        );
+        let secp = Secp256k1::new();
+        let (internal_key, _) = SecretKey::from_slice(&[0x01; 32])
+            .unwrap()
+            .public_key(&secp)
+            .x_only_public_key();
+        psbt.inputs[0].tap_internal_key = Some(internal_key);
+        assert_eq!(
+            check_fee_bounds(&psbt, u64::MAX, f32::INFINITY),
+            Err(FeeError::MissingWitnessUtxo(0))
+        );
+
+        // The previous transaction doesn't have the output spent
+        psbt.unsigned_tx.input[0].previous_output.vout = 1;
+        psbt.inputs[0].non_witness_utxo = Some(prev_tx.clone());
+        assert_eq!(
+            check_fee_bounds(&psbt, u64::MAX, f32::INFINITY),
+            Err(FeeError::MissingNonWitnessUtxo(0))
+        );
+        psbt.unsigned_tx.input[0].previous_output.vout = 0;
+
+        // Paying more than the inputs
+        psbt.unsigned_tx.output[0].value = 10_001;
+        assert_eq!(
+            check_fee_bounds(&psbt, u64::MAX, f32::INFINITY),
+            Err(FeeError::NegativeFee)
+        );
+
+        // Sums that don't fit in a u64 don't panic
+        psbt.unsigned_tx
+            .output
+            .push(psbt.unsigned_tx.output[0].clone());
+        psbt.unsigned_tx.output[1].value = u64::MAX;
+        assert_eq!(
+            check_fee_bounds(&psbt, u64::MAX, f32::INFINITY),
+            Err(FeeError::Overflow)
+        );
+        psbt.unsigned_tx.output.truncate(1);
+        prev_tx.output[0].value = u64::MAX;
+        psbt.unsigned_tx
+            .input
+            .push(psbt.unsigned_tx.input[0].clone());
+        psbt.inputs[0].non_witness_utxo = Some(prev_tx.clone());
+        psbt.inputs.push(psbt.inputs[0].clone());
+        assert_eq!(
+            check_fee_bounds(&psbt, u64::MAX, f32::INFINITY),
+            Err(FeeError::Overflow)
+        );
+    }
+}
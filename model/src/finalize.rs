@@ -0,0 +1,186 @@
+// Portal Hardware Wallet firmware and supporting software libraries
+//
+// Copyright (C) 2024 Alekos Filini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Finalization of the inputs of a signed PSBT
+//!
+//! The inputs that are fully signed get their final scriptSig/witness, so that the app can
+//! broadcast the transaction directly. The others, like a multisig still missing the signatures
+//! of the other parties, are left as they are.
+
+use core::fmt;
+
+use alloc::vec::Vec;
+
+use bitcoin::util::psbt::{Input, PartiallySignedTransaction};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinalizeError {
+    /// The PSBT has a different number of inputs than its unsigned transaction
+    InputCountMismatch,
+}
+
+impl fmt::Display for FinalizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+pub fn is_finalized(input: &Input) -> bool {
+    input.final_script_sig.is_some() || input.final_script_witness.is_some()
+}
+
+/// Finalize every input of `psbt` that isn't finalized yet, returning the indexes of the inputs
+/// finalized now
+///
+/// `finalize_input` is the miniscript finalizer, like `PsbtExt::finalize_inp_mut`. Inputs it
+/// can't satisfy are skipped.
+pub fn finalize_psbt<E: fmt::Debug>(
+    psbt: &mut PartiallySignedTransaction,
+    mut finalize_input: impl FnMut(&mut PartiallySignedTransaction, usize) -> Result<(), E>,
+) -> Result<Vec<usize>, FinalizeError> {
+    if psbt.inputs.len() != psbt.unsigned_tx.input.len() {
+        return Err(FinalizeError::InputCountMismatch);
+    }
+
+    let mut finalized = Vec::new();
+    for index in 0..psbt.inputs.len() {
+        if is_finalized(&psbt.inputs[index]) {
+            continue;
+        }
+
+        match finalize_input(psbt, index) {
+            Ok(()) => finalized.push(index),
+            Err(e) => log::debug!("Input {} not finalized: {:?}", index, e),
+        }
+    }
+
+    Ok(finalized)
+}
+
+#[cfg(all(test, not(feature = "stm32")))]
+mod tests {
+    use super::*;
+    use crate::test_utils::spending_psbt;
+
+    /// Signature of the first input of `psbt` with `key`, for a segwit v0 `script_code`
+    fn segwit_v0_sig(
+        psbt: &bitcoin::util::psbt::PartiallySignedTransaction,
+        script_code: &bitcoin::Script,
+        key: &bitcoin::secp256k1::SecretKey,
+    ) -> bitcoin::EcdsaSig {
+        use bitcoin::secp256k1::{Message, Secp256k1};
+        use bitcoin::util::sighash::SighashCache;
+        use bitcoin::EcdsaSighashType;
+
+        let value = psbt.inputs[0].witness_utxo.as_ref().unwrap().value;
+        let sighash = SighashCache::new(&psbt.unsigned_tx)
+            .segwit_signature_hash(0, script_code, value, EcdsaSighashType::All)
+            .unwrap();
+        let sig = Secp256k1::new().sign_ecdsa(&Message::from_slice(&sighash[..]).unwrap(), key);
+
+        bitcoin::EcdsaSig::sighash_all(sig)
+    }
+
+    #[test]
+    fn test_finalize_psbt_wpkh() {
+        use bitcoin::secp256k1::{Secp256k1, SecretKey};
+        use bitcoin::util::psbt::{Input, PartiallySignedTransaction};
+        use bitcoin::{PublicKey, Script, TxOut};
+        use miniscript::psbt::PsbtExt;
+
+        let secp = Secp256k1::new();
+        let finalize =
+            |psbt: &mut PartiallySignedTransaction, index| psbt.finalize_inp_mut(&secp, index);
+
+        let secret_key = SecretKey::from_slice(&[0x01; 32]).unwrap();
+        let key = PublicKey::new(secret_key.public_key(&secp));
+        let (mut psbt, _) = spending_psbt();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 10_000,
+            script_pubkey: Script::new_v0_p2wpkh(&key.wpubkey_hash().unwrap()),
+        });
+
+        // Nothing to satisfy the script with yet
+        assert_eq!(finalize_psbt(&mut psbt, finalize), Ok(vec![]));
+        assert!(!is_finalized(&psbt.inputs[0]));
+
+        let sig = segwit_v0_sig(&psbt, &Script::new_p2pkh(&key.pubkey_hash()), &secret_key);
+        psbt.inputs[0].partial_sigs.insert(key, sig);
+        assert_eq!(finalize_psbt(&mut psbt, finalize), Ok(vec![0]));
+        assert_eq!(
+            psbt.inputs[0]
+                .final_script_witness
+                .as_ref()
+                .unwrap()
+                .to_vec(),
+            vec![sig.to_vec(), key.to_bytes()]
+        );
+        assert!(psbt.inputs[0].partial_sigs.is_empty());
+
+        // Finalized inputs are left as they are
+        assert_eq!(finalize_psbt(&mut psbt, finalize), Ok(vec![]));
+
+        psbt.inputs.push(Input::default());
+        assert_eq!(
+            finalize_psbt(&mut psbt, finalize),
+            Err(FinalizeError::InputCountMismatch)
+        );
+    }
+
+    #[test]
+    fn test_finalize_psbt_multisig() {
+        use bitcoin::secp256k1::{Secp256k1, SecretKey};
+        use bitcoin::util::psbt::PartiallySignedTransaction;
+        use bitcoin::{PublicKey, TxOut};
+        use core::str::FromStr;
+        use miniscript::psbt::PsbtExt;
+        use miniscript::Descriptor;
+
+        let secp = Secp256k1::new();
+        let finalize =
+            |psbt: &mut PartiallySignedTransaction, index| psbt.finalize_inp_mut(&secp, index);
+
+        let secret_keys =
+            [0x01, 0x02, 0x03].map(|byte| SecretKey::from_slice(&[byte; 32]).unwrap());
+        let keys = secret_keys.map(|key| PublicKey::new(key.public_key(&secp)));
+        let descriptor = Descriptor::<PublicKey>::from_str(&format!(
+            "wsh(multi(2,{},{},{}))",
+            keys[0], keys[1], keys[2]
+        ))
+        .unwrap();
+        let witness_script = descriptor.explicit_script().unwrap();
+
+        let (mut psbt, _) = spending_psbt();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 10_000,
+            script_pubkey: descriptor.script_pubkey(),
+        });
+        psbt.inputs[0].witness_script = Some(witness_script.clone());
+
+        // Only our signature, the input waits for another party
+        let sig = segwit_v0_sig(&psbt, &witness_script, &secret_keys[0]);
+        psbt.inputs[0].partial_sigs.insert(keys[0], sig);
+        assert_eq!(finalize_psbt(&mut psbt, finalize), Ok(vec![]));
+        assert!(!is_finalized(&psbt.inputs[0]));
+        assert_eq!(psbt.inputs[0].partial_sigs.len(), 1);
+
+        let sig = segwit_v0_sig(&psbt, &witness_script, &secret_keys[2]);
+        psbt.inputs[0].partial_sigs.insert(keys[2], sig);
+        assert_eq!(finalize_psbt(&mut psbt, finalize), Ok(vec![0]));
+        assert!(is_finalized(&psbt.inputs[0]));
+    }
+}
@@ -0,0 +1,260 @@
+// Portal Hardware Wallet firmware and supporting software libraries
+//
+// Copyright (C) 2024 Alekos Filini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Fragmentation of large replies
+//!
+//! A reply that doesn't fit a single transfer is serialized and split into [`Reply::Fragment`]s
+//! that share a token. The device keeps the serialized reply in a [`FragmentedReply`] and
+//! answers [`Request::GetFragment`](crate::Request::GetFragment) for any sequence number, in any
+//! order, until the token expires. The app collects the fragments with a [`Reassembler`] and
+//! re-requests the ones it's [`missing`](Reassembler::missing).
+
+use core::fmt;
+
+use alloc::vec::Vec;
+
+use crate::Reply;
+
+/// Bytes of the serialized reply carried by each fragment
+pub const FRAGMENT_SIZE: usize = 512;
+/// Milliseconds after which a fragmented reply is discarded
+pub const FRAGMENT_TIMEOUT_MS: u64 = 30_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FragmentError {
+    UnknownToken,
+    Expired,
+    InvalidSeq,
+    /// A fragment disagrees with the ones received before on the total
+    Inconsistent,
+    /// The reassembled data is not a valid reply
+    Deserialization,
+}
+
+impl fmt::Display for FragmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Device side: a serialized reply waiting to be fetched fragment by fragment
+#[derive(Debug, Clone)]
+pub struct FragmentedReply {
+    token: u32,
+    data: Vec<u8>,
+    created_at: u64,
+}
+
+impl FragmentedReply {
+    /// `now` is a timestamp in milliseconds from any monotonic clock
+    pub fn new(token: u32, reply: &Reply, now: u64) -> Self {
+        FragmentedReply {
+            token,
+            data: minicbor::to_vec(reply).expect("always succeed"),
+            created_at: now,
+        }
+    }
+
+    /// Whether `reply` is too large to be sent in a single fragment
+    pub fn needs_fragmentation(reply: &Reply) -> bool {
+        minicbor::to_vec(reply).expect("always succeed").len() > FRAGMENT_SIZE
+    }
+
+    pub fn token(&self) -> u32 {
+        self.token
+    }
+
+    pub fn total(&self) -> u16 {
+        self.data.len().div_ceil(FRAGMENT_SIZE).max(1) as u16
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        now.saturating_sub(self.created_at) > FRAGMENT_TIMEOUT_MS
+    }
+
+    /// Fragment `seq` of the reply identified by `token`
+    pub fn fragment(&self, token: u32, seq: u16, now: u64) -> Result<Reply, FragmentError> {
+        if token != self.token {
+            return Err(FragmentError::UnknownToken);
+        }
+        if self.is_expired(now) {
+            return Err(FragmentError::Expired);
+        }
+        if seq >= self.total() {
+            return Err(FragmentError::InvalidSeq);
+        }
+
+        let start = seq as usize * FRAGMENT_SIZE;
+        let end = (start + FRAGMENT_SIZE).min(self.data.len());
+        Ok(Reply::Fragment {
+            token,
+            seq,
+            total: self.total(),
+            data: self.data[start..end].to_vec().into(),
+        })
+    }
+}
+
+/// App side: collects the fragments of a reply, in any order
+#[derive(Debug, Clone, Default)]
+pub struct Reassembler {
+    token: Option<u32>,
+    fragments: Vec<Option<Vec<u8>>>,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn token(&self) -> Option<u32> {
+        self.token
+    }
+
+    /// Sequence numbers still to be requested
+    pub fn missing(&self) -> Vec<u16> {
+        self.fragments
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| f.is_none())
+            .map(|(i, _)| i as u16)
+            .collect()
+    }
+
+    /// Store a [`Reply::Fragment`], returning the full reply once every fragment was received
+    ///
+    /// Fragments received more than once are ignored.
+    pub fn push(&mut self, fragment: Reply) -> Result<Option<Reply>, FragmentError> {
+        let (token, seq, total, data) = match fragment {
+            Reply::Fragment {
+                token,
+                seq,
+                total,
+                data,
+            } => (token, seq, total, data),
+            _ => return Err(FragmentError::Inconsistent),
+        };
+
+        match self.token {
+            None => {
+                self.token = Some(token);
+                self.fragments = alloc::vec![None; total as usize];
+            }
+            Some(t) if t != token => return Err(FragmentError::UnknownToken),
+            Some(_) if self.fragments.len() != total as usize => {
+                return Err(FragmentError::Inconsistent)
+            }
+            Some(_) => {}
+        }
+
+        let slot = self
+            .fragments
+            .get_mut(seq as usize)
+            .ok_or(FragmentError::InvalidSeq)?;
+        if slot.is_none() {
+            *slot = Some(data.to_vec());
+        }
+
+        if !self.missing().is_empty() {
+            return Ok(None);
+        }
+
+        let data = self
+            .fragments
+            .iter()
+            .flatten()
+            .flatten()
+            .copied()
+            .collect::<Vec<_>>();
+        minicbor::decode(&data)
+            .map(Some)
+            .map_err(|_| FragmentError::Deserialization)
+    }
+}
+
+#[cfg(all(test, not(feature = "stm32")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fragment_reassembly() {
+        let reply = Reply::SignedPsbt((0..2000).map(|i| i as u8).collect::<Vec<_>>().into());
+        assert!(FragmentedReply::needs_fragmentation(&reply));
+        assert!(!FragmentedReply::needs_fragmentation(&Reply::Ok));
+
+        let fragmented = FragmentedReply::new(0xCAFE, &reply, 1000);
+        assert_eq!(fragmented.total(), 4);
+
+        // Fragments arrive out of order, with a duplicate, and one is re-requested
+        let mut reassembler = Reassembler::new();
+        for seq in [2, 0, 2] {
+            let fragment = fragmented.fragment(0xCAFE, seq, 1000).unwrap();
+            assert!(reassembler.push(fragment).unwrap().is_none());
+        }
+        assert_eq!(reassembler.missing(), vec![1, 3]);
+        assert!(reassembler
+            .push(fragmented.fragment(0xCAFE, 3, 2000).unwrap())
+            .unwrap()
+            .is_none());
+
+        let complete = reassembler
+            .push(fragmented.fragment(0xCAFE, 1, 3000).unwrap())
+            .unwrap();
+        assert_eq!(
+            minicbor::to_vec(complete.unwrap()).unwrap(),
+            minicbor::to_vec(&reply).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_fragment_errors() {
+        let reply = Reply::SignedPsbt(alloc::vec![0x42; 1024].into());
+        let fragmented = FragmentedReply::new(1, &reply, 0);
+
+        assert_eq!(
+            fragmented.fragment(2, 0, 0).unwrap_err(),
+            FragmentError::UnknownToken
+        );
+        assert_eq!(
+            fragmented.fragment(1, fragmented.total(), 0).unwrap_err(),
+            FragmentError::InvalidSeq
+        );
+        assert!(fragmented.fragment(1, 0, FRAGMENT_TIMEOUT_MS).is_ok());
+        assert_eq!(
+            fragmented
+                .fragment(1, 0, FRAGMENT_TIMEOUT_MS + 1)
+                .unwrap_err(),
+            FragmentError::Expired
+        );
+
+        let mut reassembler = Reassembler::new();
+        reassembler
+            .push(fragmented.fragment(1, 0, 0).unwrap())
+            .unwrap();
+        let other = FragmentedReply::new(2, &reply, 0);
+        assert_eq!(
+            reassembler
+                .push(other.fragment(2, 1, 0).unwrap())
+                .unwrap_err(),
+            FragmentError::UnknownToken
+        );
+        assert_eq!(
+            reassembler.push(Reply::Ok).unwrap_err(),
+            FragmentError::Inconsistent
+        );
+    }
+}
@@ -0,0 +1,264 @@
+// Portal Hardware Wallet firmware and supporting software libraries
+//
+// Copyright (C) 2024 Alekos Filini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Page-level integrity manifest for firmware images
+//!
+//! The manifest is built while the image is received: the hash of every page is recorded as it's
+//! written. The root is the hash of the whole image, which is the message signed by the firmware
+//! signing key. Before switching banks the image is read back and every page is checked against
+//! the manifest, then the root is recomputed from the read-back data and verified against the
+//! signature.
+
+use core::fmt;
+
+use alloc::vec::Vec;
+
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{self, schnorr, XOnlyPublicKey};
+
+/// Pages at the end of each bank that hold the settings (250), the checkpoint (251 to 254) and the
+/// config (255), the `FLASH` region in `memory.x` stops right before them
+pub const RESERVED_PAGES: core::ops::Range<usize> = 250..256;
+/// Pages available for a firmware image in a flash bank
+pub const MAX_FW_PAGES: usize = RESERVED_PAGES.start;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FwError {
+    /// The image needs more than [`MAX_FW_PAGES`] pages
+    TooBig(usize),
+    /// The page was never recorded in the manifest
+    MissingPage(usize),
+    /// The page read back doesn't match the hash recorded when it was written
+    PageMismatch(usize),
+    InvalidSignature,
+    /// The image is older than the highest version ever installed
+    Rollback {
+        installed: u32,
+        new: u32,
+    },
+}
+
+impl fmt::Display for FwError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Refuse images that don't fit in a flash bank, before anything is erased
+pub fn validate_fw_size(num_pages: usize) -> Result<(), FwError> {
+    if num_pages > MAX_FW_PAGES {
+        Err(FwError::TooBig(num_pages))
+    } else {
+        Ok(())
+    }
+}
+
+/// Refuse images older than `installed`, reinstalling the same version is allowed
+///
+/// `installed` is `None` when no version was ever recorded.
+pub fn check_rollback(installed: Option<u32>, new: u32) -> Result<(), FwError> {
+    match installed {
+        Some(installed) if new < installed => Err(FwError::Rollback { installed, new }),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FwManifest {
+    size: usize,
+    page_size: usize,
+    page_hashes: Vec<Option<sha256::Hash>>,
+    signature: [u8; 64],
+}
+
+impl FwManifest {
+    /// Empty manifest for an image of `size` bytes, signed with `signature`
+    pub fn new(size: usize, page_size: usize, signature: [u8; 64]) -> Self {
+        FwManifest {
+            size,
+            page_size,
+            page_hashes: alloc::vec![None; size.div_ceil(page_size)],
+            signature,
+        }
+    }
+
+    pub fn num_pages(&self) -> usize {
+        self.page_hashes.len()
+    }
+
+    /// Number of bytes of `page` that belong to the image, the last page may be shorter
+    pub fn page_len(&self, page: usize) -> usize {
+        self.size
+            .saturating_sub(page * self.page_size)
+            .min(self.page_size)
+    }
+
+    /// Record the hash of `page`. Only the first [`page_len`](Self::page_len) bytes of `data` are
+    /// hashed, any padding after the end of the image is ignored.
+    pub fn record_page(&mut self, page: usize, data: &[u8]) {
+        let len = self.page_len(page);
+        if let Some(hash) = self.page_hashes.get_mut(page) {
+            *hash = Some(sha256::Hash::hash(&data[..len]));
+        }
+    }
+
+    /// Read back every page with `read_page`, check it against the manifest and verify the
+    /// signature over the root
+    pub fn verify_image<F>(
+        &self,
+        signing_key: &XOnlyPublicKey,
+        mut read_page: F,
+    ) -> Result<(), FwError>
+    where
+        F: FnMut(usize, &mut [u8]),
+    {
+        let mut buf = alloc::vec![0x00; self.page_size];
+        let mut root = sha256::HashEngine::default();
+
+        for (page, expected) in self.page_hashes.iter().enumerate() {
+            let expected = expected.ok_or(FwError::MissingPage(page))?;
+
+            read_page(page, &mut buf);
+            let data = &buf[..self.page_len(page)];
+            if sha256::Hash::hash(data) != expected {
+                return Err(FwError::PageMismatch(page));
+            }
+            root.input(data);
+        }
+
+        let root = sha256::Hash::from_engine(root);
+        let message = secp256k1::Message::from_slice(&root).expect("Correct length");
+        let signature = schnorr::Signature::from_slice(&self.signature)
+            .map_err(|_| FwError::InvalidSignature)?;
+        secp256k1::Secp256k1::verification_only()
+            .verify_schnorr(&signature, &message, signing_key)
+            .map_err(|_| FwError::InvalidSignature)
+    }
+}
+
+#[cfg(all(test, not(feature = "stm32")))]
+mod tests {
+    use super::*;
+
+    fn signed_manifest(image: &[u8]) -> (FwManifest, bitcoin::XOnlyPublicKey) {
+        use bitcoin::secp256k1::{KeyPair, Message, Secp256k1, SecretKey};
+
+        let secp = Secp256k1::new();
+        let keypair = KeyPair::from_secret_key(&secp, &SecretKey::from_slice(&[0x42; 32]).unwrap());
+        let root = sha256::Hash::hash(image);
+        let signature =
+            secp.sign_schnorr_no_aux_rand(&Message::from_slice(&root).unwrap(), &keypair);
+
+        let mut manifest = FwManifest::new(image.len(), 16, *signature.as_ref());
+        for (page, data) in image.chunks(16).enumerate() {
+            manifest.record_page(page, data);
+        }
+
+        (manifest, keypair.x_only_public_key().0)
+    }
+
+    fn read_image(image: &[u8]) -> impl FnMut(usize, &mut [u8]) + '_ {
+        move |page, buf| {
+            buf.fill(0xFF);
+            let data = &image[page * 16..((page + 1) * 16).min(image.len())];
+            buf[..data.len()].copy_from_slice(data);
+        }
+    }
+
+    #[test]
+    fn test_fw_manifest_valid() {
+        let image = (0..100u8).collect::<Vec<_>>();
+        let (manifest, key) = signed_manifest(&image);
+
+        assert_eq!(manifest.num_pages(), 7);
+        assert_eq!(manifest.page_len(6), 4);
+        assert_eq!(manifest.verify_image(&key, read_image(&image)), Ok(()));
+    }
+
+    #[test]
+    fn test_fw_manifest_corrupted_page() {
+        let image = (0..100u8).collect::<Vec<_>>();
+        let (manifest, key) = signed_manifest(&image);
+
+        let mut corrupted = image.clone();
+        corrupted[40] ^= 0x01;
+        assert_eq!(
+            manifest.verify_image(&key, read_image(&corrupted)),
+            Err(FwError::PageMismatch(2))
+        );
+    }
+
+    #[test]
+    fn test_fw_manifest_missing_page() {
+        let image = (0..100u8).collect::<Vec<_>>();
+        let (_, key) = signed_manifest(&image);
+
+        let mut manifest = FwManifest::new(image.len(), 16, [0x00; 64]);
+        manifest.record_page(0, &image[..16]);
+        assert_eq!(
+            manifest.verify_image(&key, read_image(&image)),
+            Err(FwError::MissingPage(1))
+        );
+    }
+
+    #[test]
+    fn test_fw_manifest_bad_signature() {
+        let image = (0..100u8).collect::<Vec<_>>();
+        let (_, key) = signed_manifest(&image);
+
+        // A manifest consistent with a modified image passes the page checks but not the
+        // signature over the root
+        let mut modified = image.clone();
+        modified[0] ^= 0x01;
+        let (signed, _) = signed_manifest(&image);
+        let mut tampered = signed.clone();
+        for (page, data) in modified.chunks(16).enumerate() {
+            tampered.record_page(page, data);
+        }
+        assert_eq!(
+            tampered.verify_image(&key, read_image(&modified)),
+            Err(FwError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn test_check_rollback() {
+        assert_eq!(check_rollback(Some(10203), 10203), Ok(()));
+        assert_eq!(check_rollback(Some(10203), 10204), Ok(()));
+        assert_eq!(
+            check_rollback(Some(10203), 10202),
+            Err(FwError::Rollback {
+                installed: 10203,
+                new: 10202
+            })
+        );
+        assert_eq!(check_rollback(None, 1), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_fw_size() {
+        // 500K of flash in `memory.x`, with 2K pages
+        assert_eq!(MAX_FW_PAGES, 250);
+        assert_eq!(MAX_FW_PAGES * 2048, 500 * 1024);
+        // The reserved pages are the last ones of a 256-page bank
+        assert_eq!(RESERVED_PAGES.end, 256);
+        assert_eq!(validate_fw_size(0), Ok(()));
+        assert_eq!(validate_fw_size(250), Ok(()));
+        assert_eq!(validate_fw_size(251), Err(FwError::TooBig(251)));
+        assert_eq!(validate_fw_size(508), Err(FwError::TooBig(508)));
+    }
+}
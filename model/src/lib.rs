@@ -16,6 +16,8 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 #![cfg_attr(feature = "stm32", no_std)]
+// `is_multiple_of` is only available from Rust 1.87, newer than the toolchain we build with
+#![allow(unknown_lints, clippy::manual_is_multiple_of)]
 
 extern crate alloc;
 
@@ -29,6 +31,10 @@ pub use minicbor;
 pub use minicbor::bytes::{ByteArray, ByteVec};
 use minicbor::{Decode, Encode};
 
+use zeroize::Zeroizing;
+
+use subtle::ConstantTimeEq;
+
 use noise_protocol::{Cipher, CipherState};
 
 use aes_gcm::aead::AeadMut;
@@ -46,12 +52,47 @@ pub const DEFAULT_PASSWORD_ITERATIONS: usize = 1024;
 
 pub const HARDENED_FLAG: u32 = 0x80000000;
 
+/// Maximum number of PSBTs in a [`Request::SignBatch`], all of them are kept in memory while
+/// signing
+pub const MAX_BATCH_PSBTS: usize = 4;
+
+/// Maximum length of the anti-phishing phrase, so that it fits on a single line
+pub const MAX_ANTI_PHISHING_LEN: usize = 24;
+
+/// Length of the serial number, the 96-bit unique ID of the MCU
+pub const SERIAL_LEN: usize = 12;
+
+pub mod anti_exfil;
+pub mod change;
+pub mod checkpoint;
 #[cfg(feature = "emulator")]
 pub mod emulator;
 pub mod encryption;
+pub mod fee;
+pub mod finalize;
+pub mod fragment;
+pub mod fw_manifest;
+pub mod low_r;
+pub mod output_scripts;
+pub mod peripheral_config;
+pub mod policy;
+pub mod power;
+pub mod prev_utxos;
 pub mod reg;
+pub mod rng_health;
+pub mod shamir;
+pub mod sign_session;
+pub mod signable;
+pub mod storage;
+pub mod taproot_spend;
+pub mod transport;
+pub mod tsc;
+pub mod value_loss;
 pub mod write_buffer;
 
+#[cfg(all(test, not(feature = "stm32")))]
+mod test_utils;
+
 #[derive(Debug)]
 pub struct MessageFragment {
     buf: [u8; MAX_FRAGMENT_LEN],
@@ -71,7 +112,7 @@ impl MessageFragment {
         let mut fragment = MessageFragment::empty();
         fragment.buf[0] = if is_last { 0x01 } else { 0x00 };
         fragment.buf[1] = slice.len() as u8;
-        (&mut fragment.buf[2..slice.len() + 2]).copy_from_slice(slice);
+        fragment.buf[2..slice.len() + 2].copy_from_slice(slice);
 
         fragment
     }
@@ -120,7 +161,7 @@ impl From<&[u8]> for MessageFragment {
         assert!(slice.len() <= MAX_FRAGMENT_LEN);
 
         let mut buf = [0; MAX_FRAGMENT_LEN];
-        (&mut buf[..slice.len()]).copy_from_slice(slice);
+        buf[..slice.len()].copy_from_slice(slice);
         MessageFragment { buf }
     }
 }
@@ -146,6 +187,172 @@ pub struct FragmentFlags {
     reserved: B6,
 }
 
+/// Associated data authenticated with every encrypted [`Request`]
+pub const REQUEST_AAD: &[u8] = b"portal-request";
+/// Associated data authenticated with every encrypted [`Reply`]
+pub const REPLY_AAD: &[u8] = b"portal-reply";
+
+pub const SESSION_ID_LEN: usize = 16;
+/// Random identifier of an NFC session, picked by the device for every handshake
+pub type SessionId = [u8; SESSION_ID_LEN];
+
+/// Associated data of the messages exchanged in one session
+///
+/// The device sends the [`SessionId`] as the payload of its handshake reply and both sides
+/// append it to [`REQUEST_AAD`] and [`REPLY_AAD`], so that a message is rejected by any other
+/// session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionAad {
+    request: Vec<u8>,
+    reply: Vec<u8>,
+}
+
+impl SessionAad {
+    pub fn new(session_id: &SessionId) -> Self {
+        SessionAad {
+            request: [REQUEST_AAD, session_id].concat(),
+            reply: [REPLY_AAD, session_id].concat(),
+        }
+    }
+
+    /// Parse the session ID sent in the handshake reply
+    pub fn from_handshake_payload(payload: &[u8]) -> Option<Self> {
+        Some(Self::new(payload.try_into().ok()?))
+    }
+
+    pub fn request(&self) -> &[u8] {
+        &self.request
+    }
+
+    pub fn reply(&self) -> &[u8] {
+        &self.reply
+    }
+}
+
+/// Version of the encoding of [`Request`] and [`Reply`], sent in front of every message
+///
+/// A peer on a different version fails to decode with [`MessageError::UnsupportedProtocol`],
+/// which carries the version it received.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Serialize `obj` as a `[PROTOCOL_VERSION, obj]` array
+pub fn serialize_versioned<S: Encode<()>>(obj: &S) -> Vec<u8> {
+    let mut encoder = minicbor::Encoder::new(Vec::new());
+    encoder
+        .array(2)
+        .and_then(|e| e.u32(PROTOCOL_VERSION))
+        .and_then(|e| e.encode(obj))
+        .expect("always succeed");
+    encoder.into_writer()
+}
+
+/// Deserialize a message produced by [`serialize_versioned`], refusing other protocol versions
+pub fn deserialize_versioned<'d, T: Decode<'d, ()>>(buf: &'d [u8]) -> Result<T, MessageError> {
+    let mut decoder = minicbor::Decoder::new(buf);
+    if decoder.array()? != Some(2) {
+        return Err(MessageError::FailedDeserialization);
+    }
+    let version = decoder.u32()?;
+    if version != PROTOCOL_VERSION {
+        return Err(MessageError::UnsupportedProtocol(version));
+    }
+
+    check_decode_limits(&buf[decoder.position()..], &DecodeLimits::default())?;
+    Ok(decoder.decode()?)
+}
+
+/// Bounds enforced on untrusted CBOR before decoding it
+#[derive(Debug, Clone, Copy)]
+pub struct DecodeLimits {
+    /// Maximum number of elements in an array or entries in a map
+    pub max_elements: u64,
+    /// Maximum length of a byte or text string
+    pub max_bytes_len: u64,
+    pub max_depth: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            max_elements: 1024,
+            max_bytes_len: 64 * 1024,
+            max_depth: 16,
+        }
+    }
+}
+
+/// Walk the CBOR items in `buf` and make sure none exceeds `limits`
+///
+/// Lengths are also checked against the remaining input, so that a malicious length prefix can't
+/// make the decoder allocate more than what was actually received. Indefinite-length items are
+/// never produced by our encoder and are refused.
+pub fn check_decode_limits(buf: &[u8], limits: &DecodeLimits) -> Result<(), MessageError> {
+    use minicbor::data::Type;
+
+    fn check_item(
+        decoder: &mut minicbor::Decoder,
+        limits: &DecodeLimits,
+        depth: usize,
+    ) -> Result<(), MessageError> {
+        if depth > limits.max_depth {
+            return Err(MessageError::LimitExceeded);
+        }
+        let remaining = (decoder.input().len() - decoder.position()) as u64;
+
+        match decoder.datatype()? {
+            Type::Array => {
+                let len = decoder.array()?.expect("Definite length");
+                if len > limits.max_elements || len > remaining {
+                    return Err(MessageError::LimitExceeded);
+                }
+                for _ in 0..len {
+                    check_item(decoder, limits, depth + 1)?;
+                }
+            }
+            Type::Map => {
+                let len = decoder.map()?.expect("Definite length");
+                if len > limits.max_elements || len.saturating_mul(2) > remaining {
+                    return Err(MessageError::LimitExceeded);
+                }
+                for _ in 0..len * 2 {
+                    check_item(decoder, limits, depth + 1)?;
+                }
+            }
+            Type::Bytes | Type::String => {
+                let len = decoder
+                    .probe()
+                    .bytes()
+                    .map(|b| b.len())
+                    .or_else(|_| decoder.probe().str().map(|s| s.len()));
+                match len {
+                    Ok(len) if len as u64 <= limits.max_bytes_len => decoder.skip()?,
+                    _ => return Err(MessageError::LimitExceeded),
+                }
+            }
+            Type::Tag => {
+                decoder.tag()?;
+                check_item(decoder, limits, depth + 1)?;
+            }
+            Type::ArrayIndef
+            | Type::MapIndef
+            | Type::BytesIndef
+            | Type::StringIndef
+            | Type::Break
+            | Type::Unknown(_) => return Err(MessageError::LimitExceeded),
+            _ => decoder.skip()?,
+        }
+
+        Ok(())
+    }
+
+    let mut decoder = minicbor::Decoder::new(buf);
+    while decoder.position() < buf.len() {
+        check_item(&mut decoder, limits, 0)?;
+    }
+
+    Ok(())
+}
+
 #[derive(Debug)]
 pub struct Message {
     buf: Vec<u8>,
@@ -170,22 +377,29 @@ impl Message {
 
     pub fn from_slice_encrypt<C: Cipher>(
         data: &[u8],
+        aad: &[u8],
         cipher: &mut CipherState<C>,
     ) -> Result<Self, MessageError> {
-        let buf = cipher.encrypt_vec(&data);
+        let mut buf = alloc::vec![0x00; data.len() + 16];
+        cipher.encrypt_ad(aad, data, &mut buf);
+        encryption::maybe_rekey(cipher);
         Ok(Message {
             buf,
             finished: true,
         })
     }
 
-    pub fn new_serialize<S, C>(obj: &S, cipher: &mut CipherState<C>) -> Result<Self, MessageError>
+    pub fn new_serialize<S, C>(
+        obj: &S,
+        aad: &[u8],
+        cipher: &mut CipherState<C>,
+    ) -> Result<Self, MessageError>
     where
         S: Encode<()>,
         C: Cipher,
     {
-        let buf = minicbor::to_vec(&obj).expect("always succeed");
-        Self::from_slice_encrypt(&buf, cipher)
+        let buf = serialize_versioned(obj);
+        Self::from_slice_encrypt(&buf, aad, cipher)
     }
 
     pub fn is_finished(&self) -> bool {
@@ -205,7 +419,7 @@ impl Message {
         }
         self.finished = fragment.is_eof();
 
-        self.buf.extend_from_slice(&fragment.as_ref());
+        self.buf.extend_from_slice(fragment.as_ref());
 
         Ok(self.finished)
     }
@@ -213,6 +427,7 @@ impl Message {
     pub fn deserialize<'d, T, C>(
         &self,
         decrypt_buf: &'d mut Vec<u8>,
+        aad: &[u8],
         cipher: &mut CipherState<C>,
     ) -> Result<T, MessageError>
     where
@@ -224,10 +439,11 @@ impl Message {
         }
         decrypt_buf.resize(self.buf.len().saturating_sub(16), 0x00);
         cipher
-            .decrypt(&self.buf, decrypt_buf)
+            .decrypt_ad(aad, &self.buf, decrypt_buf)
             .map_err(|_| MessageError::DecryptionFailed)?;
+        encryption::maybe_rekey(cipher);
 
-        Ok(minicbor::decode(decrypt_buf)?)
+        deserialize_versioned(decrypt_buf)
     }
 
     fn iter_chunks<'s>(&'s self, chunk_size: usize) -> impl Iterator<Item = (&'s [u8], bool)> + 's {
@@ -249,7 +465,7 @@ impl Message {
                 let mut buf = [0; MAX_FRAGMENT_LEN];
                 buf[0] = if eof { 0x01 } else { 0x00 };
                 buf[1] = chunk.len() as u8;
-                (&mut buf[2..2 + chunk.len()]).copy_from_slice(chunk);
+                buf[2..2 + chunk.len()].copy_from_slice(chunk);
 
                 MessageFragment { buf }
             })
@@ -273,6 +489,22 @@ pub struct Entropy {
     pub bytes: ByteVec,
 }
 
+impl Entropy {
+    /// Derive the master key from the mnemonic and an optional BIP-39 passphrase
+    ///
+    /// Every passphrase leads to a completely different wallet. The passphrase is used as-is,
+    /// so it must already be NFKD-normalized: the SDK normalizes it before sending the request.
+    pub fn to_xprv(
+        &self,
+        network: bitcoin::Network,
+        passphrase: Option<&str>,
+    ) -> bip32::ExtendedPrivKey {
+        let mnemonic = bip39::Mnemonic::from_entropy(&self.bytes).expect("Valid entropy");
+        let seed = Zeroizing::new(mnemonic.to_seed_normalized(passphrase.unwrap_or("")));
+        bip32::ExtendedPrivKey::new_master(network, seed.as_ref()).expect("Valid entropy")
+    }
+}
+
 #[derive(Debug, Encode, Decode, Clone)]
 pub struct SerializedXprv {
     #[cbor(n(0))]
@@ -312,6 +544,10 @@ pub struct UnverifiedConfig {
     pub descriptor: WalletDescriptor,
     #[cbor(n(4))]
     pub page: usize,
+    /// Master key derived with the BIP-39 passphrase, so that the passphrase itself is never
+    /// written to flash. `None` when the wallet has no passphrase
+    #[cbor(n(5))]
+    pub passphrase_xprv: Option<SerializedXprv>,
 }
 
 #[derive(Debug, Clone, Encode, Decode)]
@@ -457,9 +693,7 @@ pub struct SerializedDerivationPath {
 }
 impl Into<bip32::DerivationPath> for SerializedDerivationPath {
     fn into(self) -> bip32::DerivationPath {
-        bip32::DerivationPath::from_iter(
-            self.value.into_iter().map(|v| bip32::ChildNumber::from(v)),
-        )
+        bip32::DerivationPath::from_iter(self.value.into_iter().map(bip32::ChildNumber::from))
     }
 }
 impl From<bip32::DerivationPath> for SerializedDerivationPath {
@@ -500,6 +734,25 @@ impl DescriptorVariant {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+#[cfg_attr(feature = "emulator", derive(serde::Serialize, serde::Deserialize))]
+pub enum KeychainKind {
+    #[cbor(n(0))]
+    External,
+    #[cbor(n(1))]
+    Internal,
+}
+
+/// Orientation of the display relative to the default mounting
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+#[cfg_attr(feature = "emulator", derive(serde::Serialize, serde::Deserialize))]
+pub enum Rotation {
+    #[cbor(n(0))]
+    Normal,
+    #[cbor(n(1))]
+    Flipped,
+}
+
 #[derive(Debug, Clone, Encode, Decode)]
 #[cfg_attr(feature = "emulator", derive(serde::Serialize, serde::Deserialize))]
 pub enum SetDescriptorVariant {
@@ -517,14 +770,32 @@ pub enum SetDescriptorVariant {
 }
 
 impl UnverifiedConfig {
+    pub fn new(
+        entropy: Entropy,
+        network: bitcoin::Network,
+        pair_code: Option<&str>,
+        passphrase: Option<&str>,
+    ) -> Self {
+        let passphrase_xprv = passphrase.map(|p| entropy.to_xprv(network, Some(p)).into());
+
+        UnverifiedConfig {
+            entropy,
+            network,
+            pair_code: pair_code.map(ToString::to_string),
+            descriptor: WalletDescriptor::make_bip84(network),
+            page: 0,
+            passphrase_xprv,
+        }
+    }
+
     pub fn upgrade(
         self,
         salt: [u8; 8],
     ) -> (InitializedConfig, UnlockedConfig, bip32::ExtendedPrivKey) {
-        let mnemonic = bip39::Mnemonic::from_entropy(&self.entropy.bytes).expect("Valid entropy");
-        let xprv =
-            bip32::ExtendedPrivKey::new_master(self.network, &mnemonic.to_seed_normalized(""))
-                .expect("Valid entropy");
+        let xprv = match &self.passphrase_xprv {
+            Some(xprv) => xprv.as_xprv().expect("Valid xprv"),
+            None => self.entropy.to_xprv(self.network, None),
+        };
 
         let unlocked = UnlockedConfig::new(
             self.entropy,
@@ -572,7 +843,9 @@ impl InitializedConfig {
             MaybeEncrypted::Encrypted { data, nonce } => {
                 let encryption_key = EncryptionKey::new(password, nonce);
                 (
-                    encryption_key.decrypt(data.deref().as_ref())?,
+                    encryption_key
+                        .decrypt(data.deref().as_ref())
+                        .map_err(|_| ())?,
                     Some(encryption_key),
                 )
             }
@@ -589,7 +862,9 @@ impl InitializedConfig {
     pub fn try_unlock_fast_boot(&self, key: &[u8; 32]) -> Result<UnlockedConfig, ()> {
         if let MaybeEncrypted::Encrypted { data, nonce } = &self.secret {
             let encryption_key = EncryptionKey::new_raw_key(key.clone(), *nonce);
-            let secret = encryption_key.decrypt(data.deref().as_ref())?;
+            let secret = encryption_key
+                .decrypt(data.deref().as_ref())
+                .map_err(|_| ())?;
 
             Ok(UnlockedConfig {
                 secret,
@@ -645,7 +920,8 @@ impl UnlockedConfig {
         let secret = match self.encryption_key {
             None => MaybeEncrypted::Unencrypted(self.secret),
             Some(ref mut encryption_key) => {
-                let data = minicbor::to_vec(self.secret).expect("Always serializable");
+                let data =
+                    Zeroizing::new(minicbor::to_vec(self.secret).expect("Always serializable"));
                 encryption_key
                     .encrypt(&data)
                     .map(|(data, nonce)| MaybeEncrypted::Encrypted {
@@ -723,12 +999,21 @@ impl Password {
         }
     }
 
+    /// Compare in constant time, so that the timing doesn't reveal how much of the hash matches
     pub fn check(&self, password: &str) -> bool {
         let check_password = Password::new(password, self.salt.clone());
-        check_password.hash == self.hash
+        check_password.hash.ct_eq(&self.hash).into()
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecryptError {
+    /// The AEAD tag doesn't match, because the key is wrong or the data was tampered with
+    Authentication,
+    /// The data is authentic but doesn't decode
+    Decoding,
+}
+
 #[derive(Debug, Clone)]
 pub struct EncryptionKey {
     key: [u8; 32],
@@ -777,9 +1062,17 @@ impl EncryptionKey {
         self.get_cipher().decrypt(&nonce, data).map_err(|_| ())
     }
 
-    pub fn decrypt(&self, data: &[u8]) -> Result<SecretData, ()> {
-        self.decrypt_raw(data)
-            .and_then(|data| minicbor::decode::<SecretData>(&data).map_err(|_| ()))
+    /// Decrypt and decode the secret data
+    ///
+    /// A wrong key is only detected by the AEAD tag check, which is constant-time. Nothing is
+    /// decoded before the data is authenticated.
+    pub fn decrypt(&self, data: &[u8]) -> Result<SecretData, DecryptError> {
+        // The plaintext is wiped once it's decoded, leaving only the copy in `SecretData`
+        let data = Zeroizing::new(
+            self.decrypt_raw(data)
+                .map_err(|_| DecryptError::Authentication)?,
+        );
+        minicbor::decode::<SecretData>(&data).map_err(|_| DecryptError::Decoding)
     }
 
     pub fn encrypt(&mut self, data: &[u8]) -> Result<(Vec<u8>, u32), ()> {
@@ -955,6 +1248,8 @@ pub enum Request {
         network: bitcoin::Network,
         #[cbor(n(2))]
         password: Option<String>,
+        #[cbor(n(3))]
+        passphrase: Option<String>,
     },
     #[cbor(n(2))]
     SetMnemonic {
@@ -965,6 +1260,8 @@ pub enum Request {
         network: bitcoin::Network,
         #[cbor(n(2))]
         password: Option<String>,
+        #[cbor(n(3))]
+        passphrase: Option<String>,
     },
     #[cbor(n(3))]
     UpdateFirmware,
@@ -973,6 +1270,7 @@ pub enum Request {
     #[cbor(n(5))]
     #[cfg_attr(feature = "emulator", serde(with = "serde_bytevec"))]
     SignPsbt(#[cbor(n(0))] ByteVec),
+    /// Display an address from the external keychain
     #[cbor(n(6))]
     DisplayAddress(#[cbor(n(0))] u32),
     #[cbor(n(7))]
@@ -1017,6 +1315,66 @@ pub enum Request {
         #[cbor(n(2))]
         bsms: Option<BsmsRound2>,
     },
+    #[cbor(n(16))]
+    GetFingerprint,
+    #[cbor(n(17))]
+    ExportDescriptor {
+        #[cbor(n(0))]
+        keychain: KeychainKind,
+    },
+    #[cbor(n(18))]
+    DisplayKeychainAddress {
+        #[cbor(n(0))]
+        keychain: KeychainKind,
+        #[cbor(n(1))]
+        index: u32,
+    },
+    /// Display brightness, from `0` (dimmest) to `4` (brightest)
+    #[cbor(n(19))]
+    SetBrightness(#[cbor(n(0))] u8),
+    #[cbor(n(20))]
+    SetRotation(#[cbor(n(0))] Rotation),
+    /// Answered in any state, before any stateful exchange
+    #[cbor(n(21))]
+    GetProtocolVersion,
+    /// Fetch one fragment of a reply split into [`Reply::Fragment`]s
+    #[cbor(n(22))]
+    GetFragment {
+        #[cbor(n(0))]
+        token: u32,
+        #[cbor(n(1))]
+        seq: u16,
+    },
+    /// Answered in any state, like [`Request::GetProtocolVersion`]
+    #[cbor(n(23))]
+    GetDeviceInfo,
+    /// Sign up to [`MAX_BATCH_PSBTS`] PSBTs with a single confirmation, sent instead of
+    /// [`Request::SignPsbt`] after [`Request::BeginSignPsbt`]
+    #[cbor(n(24))]
+    #[cfg_attr(
+        feature = "emulator",
+        serde(
+            serialize_with = "serde_bytevec::serialize_vec",
+            deserialize_with = "serde_bytevec::deserialize_vec"
+        )
+    )]
+    SignBatch(#[cbor(n(0))] Vec<ByteVec>),
+    /// Phrase shown when the device is ready, so that the user can recognize their device. At
+    /// most [`MAX_ANTI_PHISHING_LEN`] characters, empty to remove it.
+    #[cbor(n(25))]
+    SetAntiPhishingPhrase(#[cbor(n(0))] String),
+    /// Extract the network transaction from a PSBT with every input finalized
+    #[cbor(n(26))]
+    #[cfg_attr(feature = "emulator", serde(with = "serde_bytevec"))]
+    ExtractTx(#[cbor(n(0))] ByteVec),
+    /// Stop the operation in progress, discarding any signature produced so far. Answered with
+    /// [`Reply::Canceled`], also when there's nothing to cancel.
+    #[cbor(n(27))]
+    Cancel,
+    /// Erase the wallet and every key stored on the device, once the user confirms it. The
+    /// device settings are kept. Answered with [`Reply::Ok`] when the wipe is complete.
+    #[cbor(n(28))]
+    FactoryReset,
 }
 
 #[derive(Clone, Debug, Encode, Decode)]
@@ -1063,6 +1421,57 @@ pub enum Reply {
         #[cbor(n(1))]
         bsms: BsmsRound1,
     },
+    #[cbor(n(15))]
+    Fingerprint(#[cbor(n(0))] [u8; 4]),
+    /// Single ranged descriptor, including its checksum
+    #[cbor(n(16))]
+    ExportedDescriptor(#[cbor(n(0))] String),
+    #[cbor(n(17))]
+    ProtocolVersion(#[cbor(n(0))] u32),
+    /// Part of a reply too large for a single transfer, see [`fragment`]
+    #[cbor(n(18))]
+    Fragment {
+        #[cbor(n(0))]
+        token: u32,
+        #[cbor(n(1))]
+        seq: u16,
+        #[cbor(n(2))]
+        total: u16,
+        #[cbor(n(3))]
+        #[cfg_attr(feature = "emulator", serde(with = "serde_bytevec"))]
+        data: ByteVec,
+    },
+    /// Identity of the device, doesn't depend on the wallet loaded
+    #[cbor(n(19))]
+    DeviceInfo {
+        #[cbor(n(0))]
+        fw_version: String,
+        #[cbor(n(1))]
+        hw_rev: u8,
+        #[cbor(n(2))]
+        serial: [u8; SERIAL_LEN],
+    },
+    /// Signatures for each PSBT of a [`Request::SignBatch`], in the same order
+    #[cbor(n(20))]
+    #[cfg_attr(
+        feature = "emulator",
+        serde(
+            serialize_with = "serde_bytevec::serialize_vec",
+            deserialize_with = "serde_bytevec::deserialize_vec"
+        )
+    )]
+    SignedBatch(#[cbor(n(0))] Vec<ByteVec>),
+    /// Consensus-encoded transaction, ready to be broadcast
+    #[cbor(n(21))]
+    #[cfg_attr(feature = "emulator", serde(with = "serde_bytevec"))]
+    RawTx(#[cbor(n(0))] ByteVec),
+    /// The operation in progress was stopped by a [`Request::Cancel`]
+    #[cbor(n(22))]
+    Canceled,
+    /// The request was encoded with a different protocol version, this carries the version of the
+    /// device. The session stays open.
+    #[cbor(n(23))]
+    UnsupportedProtocol(#[cbor(n(0))] u32),
 }
 
 #[derive(Clone, Debug, Encode, Decode)]
@@ -1100,7 +1509,7 @@ impl BsmsRound1 {
         )
         .expect("Valid data length");
 
-        let signature = ctx.sign_ecdsa_recoverable(&message, &private_key);
+        let signature = ctx.sign_ecdsa_recoverable(&message, private_key);
         let signature = bitcoin::util::misc::MessageSignature::new(signature, true);
         let signature = signature.serialize();
 
@@ -1150,6 +1559,30 @@ mod serde_bytevec {
         let vec: alloc::vec::Vec<u8> = Deserialize::deserialize(deserializer)?;
         Ok(vec.into())
     }
+    pub(crate) fn serialize_vec<S>(
+        items: &[minicbor::bytes::ByteVec],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let vec = items
+            .iter()
+            .map(|bytes| bytes.to_vec())
+            .collect::<alloc::vec::Vec<_>>();
+        Serialize::serialize(&vec, serializer)
+    }
+
+    pub(crate) fn deserialize_vec<'de, D>(
+        deserializer: D,
+    ) -> Result<alloc::vec::Vec<minicbor::bytes::ByteVec>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let vec: alloc::vec::Vec<alloc::vec::Vec<u8>> = Deserialize::deserialize(deserializer)?;
+        Ok(vec.into_iter().map(Into::into).collect())
+    }
+
     pub(crate) fn deserialize_array<'de, D, const N: usize>(
         deserializer: D,
     ) -> Result<Box<minicbor::bytes::ByteArray<N>>, D::Error>
@@ -1189,6 +1622,10 @@ pub enum MessageError {
     FailedDeserialization,
     DecryptionFailed,
     CardCouldntDecrypt,
+    /// The message was encoded with a different protocol version
+    UnsupportedProtocol(u32),
+    /// The message exceeds the [`DecodeLimits`]
+    LimitExceeded,
     // FailedSerialization(ciborium::ser::Error<()>),
 }
 
@@ -1245,4 +1682,357 @@ mod tests {
         let frag3 = MessageFragment::from([0x01u8, 0x10].as_slice());
         assert!(message.push_fragment(frag3).is_err());
     }
+
+    #[test]
+    fn test_versioned_message() {
+        let encoded = serialize_versioned(&Request::DisplayAddress(42));
+        assert!(matches!(
+            deserialize_versioned(&encoded),
+            Ok(Request::DisplayAddress(42))
+        ));
+
+        // Same message from a different protocol version
+        let mut encoder = minicbor::Encoder::new(Vec::new());
+        encoder
+            .array(2)
+            .unwrap()
+            .u32(PROTOCOL_VERSION + 1)
+            .unwrap()
+            .encode(Request::DisplayAddress(42))
+            .unwrap();
+        assert!(matches!(
+            deserialize_versioned::<Request>(encoder.writer()),
+            Err(MessageError::UnsupportedProtocol(v)) if v == PROTOCOL_VERSION + 1
+        ));
+
+        // A reply from a different version is refused the same way
+        let mut encoder = minicbor::Encoder::new(Vec::new());
+        encoder
+            .array(2)
+            .unwrap()
+            .u32(0)
+            .unwrap()
+            .encode(Reply::Ok)
+            .unwrap();
+        assert!(matches!(
+            deserialize_versioned::<Reply>(encoder.writer()),
+            Err(MessageError::UnsupportedProtocol(0))
+        ));
+
+        // Unversioned messages are rejected
+        let unversioned = minicbor::to_vec(Request::DisplayAddress(42)).unwrap();
+        assert!(deserialize_versioned::<Request>(&unversioned).is_err());
+    }
+
+    #[test]
+    fn test_decode_limits() {
+        // Byte string claiming to be 4 GiB long
+        let huge_bytes = [0x82, 0x01, 0x82, 0x05, 0x5A, 0xFF, 0xFF, 0xFF, 0xFF, 0x00];
+        assert!(matches!(
+            deserialize_versioned::<Request>(&huge_bytes),
+            Err(MessageError::LimitExceeded)
+        ));
+
+        // Array with 2^64 - 1 elements
+        let huge_array = [
+            0x82, 0x01, 0x82, 0x0F, 0x9B, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00,
+        ];
+        assert!(matches!(
+            deserialize_versioned::<Request>(&huge_array),
+            Err(MessageError::LimitExceeded)
+        ));
+
+        // Map claiming more entries than the remaining input
+        assert!(matches!(
+            check_decode_limits(&[0xB9, 0x01, 0x00, 0x00, 0x00], &DecodeLimits::default()),
+            Err(MessageError::LimitExceeded)
+        ));
+
+        // Deeply nested arrays
+        let mut nested = alloc::vec![0x82, 0x01];
+        nested.extend([0x81; 64]);
+        nested.push(0x00);
+        assert!(matches!(
+            deserialize_versioned::<Request>(&nested),
+            Err(MessageError::LimitExceeded)
+        ));
+
+        // Indefinite-length items are refused
+        assert!(matches!(
+            check_decode_limits(&[0x9F, 0x00, 0xFF], &DecodeLimits::default()),
+            Err(MessageError::LimitExceeded)
+        ));
+
+        let large = serialize_versioned(&Request::SignPsbt(alloc::vec![0x00; 70_000].into()));
+        assert!(matches!(
+            deserialize_versioned::<Request>(&large),
+            Err(MessageError::LimitExceeded)
+        ));
+        let small = serialize_versioned(&Request::SignPsbt(alloc::vec![0x00; 4096].into()));
+        assert!(deserialize_versioned::<Request>(&small).is_ok());
+        assert!(matches!(
+            check_decode_limits(
+                &small,
+                &DecodeLimits {
+                    max_bytes_len: 1024,
+                    ..Default::default()
+                }
+            ),
+            Err(MessageError::LimitExceeded)
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "emulator")]
+    fn test_message_json_roundtrip() {
+        fn roundtrip<T>(value: &T)
+        where
+            T: serde::Serialize + serde::de::DeserializeOwned + Encode<()> + for<'b> Decode<'b, ()>,
+        {
+            let json = serde_json::to_string(value).unwrap();
+            let decoded: T = serde_json::from_str(&json).unwrap();
+            assert_eq!(serde_json::to_string(&decoded).unwrap(), json);
+            assert_eq!(
+                minicbor::to_vec(&decoded).unwrap(),
+                minicbor::to_vec(value).unwrap()
+            );
+        }
+
+        for request in [
+            Request::GetInfo,
+            Request::SignPsbt(alloc::vec![0x70, 0x73, 0x62, 0x74, 0xFF].into()),
+            Request::SignBatch(alloc::vec![
+                alloc::vec![0x70, 0x73, 0x62, 0x74, 0xFF].into(),
+                alloc::vec![0x70, 0x73, 0x62, 0x74, 0xFF, 0x00].into(),
+            ]),
+            Request::DisplayAddress(42),
+            Request::SetMnemonic {
+                mnemonic: "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about".into(),
+                network: bitcoin::Network::Testnet,
+                password: Some("password".into()),
+                passphrase: None,
+            },
+            Request::ExtractTx(alloc::vec![0x70, 0x73, 0x62, 0x74, 0xFF].into()),
+            Request::Cancel,
+            Request::FactoryReset,
+        ] {
+            roundtrip(&request);
+        }
+
+        for reply in [
+            Reply::Ok,
+            Reply::Address("tb1q3kfjt3cdd9lv9gtu9ssg2uzqvkeuppaqwr9vw5".into()),
+            Reply::SignedPsbt(alloc::vec![0x00, 0x01, 0x02].into()),
+            Reply::SignedBatch(alloc::vec![
+                alloc::vec![0x00, 0x01].into(),
+                alloc::vec![].into()
+            ]),
+            Reply::Fingerprint([0x73, 0xc5, 0xda, 0x0a]),
+            Reply::RawTx(alloc::vec![0x02, 0x00, 0x00, 0x00].into()),
+            Reply::Canceled,
+            Reply::UnsupportedProtocol(PROTOCOL_VERSION),
+        ] {
+            roundtrip(&reply);
+        }
+    }
+
+    // Key derivation tests
+
+    #[test]
+    fn test_bip39_passphrase_vectors() {
+        use core::str::FromStr;
+
+        // From the BIP-39 test vectors, which all use the "TREZOR" passphrase
+        for (words, xprv) in [
+            (
+                "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+                "xprv9s21ZrQH143K3h3fDYiay8mocZ3afhfULfb5GX8kCBdno77K4HiA15Tg23wpbeF1pLfs1c5SPmYHrEpTuuRhxMwvKDwqdKiGJS9XFKzUsAF",
+            ),
+            (
+                "legal winner thank year wave sausage worth useful legal winner thank yellow",
+                "xprv9s21ZrQH143K2gA81bYFHqU68xz1cX2APaSq5tt6MFSLeXnCKV1RVUJt9FWNTbrrryem4ZckN8k4Ls1H6nwdvDTvnV7zEXs2HgPezuVccsq",
+            ),
+        ] {
+            let (bytes, len) = bip39::Mnemonic::from_str(words).unwrap().to_entropy_array();
+            let entropy = Entropy {
+                bytes: bytes[..len].to_vec().into(),
+            };
+            assert_eq!(
+                entropy
+                    .to_xprv(bitcoin::Network::Bitcoin, Some("TREZOR"))
+                    .to_string(),
+                xprv
+            );
+        }
+    }
+
+    #[test]
+    fn test_bip39_passphrase_hidden_wallet() {
+        let entropy = Entropy {
+            bytes: alloc::vec![0x00; 16].into(),
+        };
+
+        let no_passphrase = entropy.to_xprv(bitcoin::Network::Bitcoin, None);
+        assert_eq!(
+            no_passphrase.to_string(),
+            "xprv9s21ZrQH143K3GJpoapnV8SFfukcVBSfeCficPSGfubmSFDxo1kuHnLisriDvSnRRuL2Qrg5ggqHKNVpxR86QEC8w35uxmGoggxtQTPvfUu"
+        );
+        assert_eq!(
+            entropy.to_xprv(bitcoin::Network::Bitcoin, Some("")),
+            no_passphrase
+        );
+        assert_ne!(
+            entropy.to_xprv(bitcoin::Network::Bitcoin, Some("hidden")),
+            no_passphrase
+        );
+    }
+
+    #[test]
+    fn test_unverified_config_passphrase_not_stored() {
+        let entropy = Entropy {
+            bytes: alloc::vec![0x00; 16].into(),
+        };
+        let expected = entropy.to_xprv(bitcoin::Network::Bitcoin, Some("hidden"));
+
+        let config =
+            UnverifiedConfig::new(entropy, bitcoin::Network::Bitcoin, None, Some("hidden"));
+        let encoded = minicbor::to_vec(&Config::Unverified(config)).unwrap();
+        assert!(!encoded.windows(6).any(|w| w == b"hidden"));
+
+        let config = match minicbor::decode::<Config>(&encoded).unwrap() {
+            Config::Unverified(c) => c,
+            _ => unreachable!(),
+        };
+        let (_, _, xprv) = config.upgrade([0; 8]);
+        assert_eq!(xprv, expected);
+    }
+
+    // Zeroization tests
+
+    mod probe {
+        use core::cell::Cell;
+        use std::alloc::{GlobalAlloc, Layout, System};
+
+        /// Allocator that, when armed on the current thread, looks for a marker in every buffer
+        /// that is freed
+        pub struct ProbeAllocator;
+
+        std::thread_local! {
+            static MARKER: Cell<Option<&'static [u8]>> = const { Cell::new(None) };
+            static FOUND: Cell<bool> = const { Cell::new(false) };
+        }
+
+        unsafe impl GlobalAlloc for ProbeAllocator {
+            unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+                System.alloc(layout)
+            }
+
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                if let Ok(Some(marker)) = MARKER.try_with(|m| m.get()) {
+                    let freed = core::slice::from_raw_parts(ptr, layout.size());
+                    if freed.windows(marker.len()).any(|w| w == marker) {
+                        let _ = FOUND.try_with(|f| f.set(true));
+                    }
+                }
+                System.dealloc(ptr, layout)
+            }
+        }
+
+        #[global_allocator]
+        static ALLOCATOR: ProbeAllocator = ProbeAllocator;
+
+        /// Run `f` and return whether any buffer freed meanwhile still contained `marker`
+        pub fn leaks_marker<F: FnOnce()>(marker: &'static [u8], f: F) -> bool {
+            FOUND.with(|found| found.set(false));
+            MARKER.with(|m| m.set(Some(marker)));
+            f();
+            MARKER.with(|m| m.set(None));
+            FOUND.with(|found| found.get())
+        }
+    }
+
+    #[test]
+    fn test_decrypt_wipes_plaintext() {
+        const ENTROPY: &[u8] = &[
+            0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xAB, 0xAC, 0xAD,
+            0xAE, 0xAF,
+        ];
+
+        let entropy = Entropy {
+            bytes: ENTROPY.to_vec().into(),
+        };
+        let xprv = entropy.to_xprv(bitcoin::Network::Testnet, None);
+        let locked = InitializedConfig::new(
+            entropy,
+            xprv.into(),
+            WalletDescriptor::make_bip84(bitcoin::Network::Testnet),
+            bitcoin::Network::Testnet,
+            Some("password"),
+            [0x00; 8],
+        );
+        let data = match &locked.secret {
+            MaybeEncrypted::Encrypted { data, nonce } => (data.to_vec(), *nonce),
+            _ => unreachable!(),
+        };
+
+        let mut secret = None;
+        assert!(!probe::leaks_marker(ENTROPY, || {
+            secret = Some(EncryptionKey::new("password", data.1).decrypt(&data.0));
+        }));
+        assert_eq!(&secret.unwrap().unwrap().mnemonic.bytes[..], ENTROPY);
+
+        // A plain `Vec` is freed with the secret still in it
+        assert!(probe::leaks_marker(ENTROPY, || {
+            core::mem::drop(ENTROPY.to_vec());
+        }));
+    }
+
+    #[test]
+    fn test_decrypt_wrong_key() {
+        let entropy = Entropy {
+            bytes: [0xA0; 16].to_vec().into(),
+        };
+        let xprv = entropy.to_xprv(bitcoin::Network::Testnet, None);
+        let locked = InitializedConfig::new(
+            entropy,
+            xprv.into(),
+            WalletDescriptor::make_bip84(bitcoin::Network::Testnet),
+            bitcoin::Network::Testnet,
+            Some("password"),
+            [0x00; 8],
+        );
+        let (data, nonce) = match &locked.secret {
+            MaybeEncrypted::Encrypted { data, nonce } => (data.to_vec(), *nonce),
+            _ => unreachable!(),
+        };
+
+        assert!(EncryptionKey::new("password", nonce).decrypt(&data).is_ok());
+        assert_eq!(
+            EncryptionKey::new("wrong", nonce)
+                .decrypt(&data)
+                .unwrap_err(),
+            DecryptError::Authentication
+        );
+        let mut tampered = data.clone();
+        tampered[0] ^= 0x01;
+        assert_eq!(
+            EncryptionKey::new("password", nonce)
+                .decrypt(&tampered)
+                .unwrap_err(),
+            DecryptError::Authentication
+        );
+
+        // Only authentic data reaches the decoder
+        let mut key = EncryptionKey::new("password", 0);
+        let (garbage, nonce) = key.encrypt(&[0xFF; 16]).unwrap();
+        assert_eq!(
+            EncryptionKey::new("password", nonce)
+                .decrypt(&garbage)
+                .unwrap_err(),
+            DecryptError::Decoding
+        );
+
+        assert!(locked.pair_code.check("password"));
+        assert!(!locked.pair_code.check("wrong"));
+    }
 }
@@ -0,0 +1,127 @@
+// Portal Hardware Wallet firmware and supporting software libraries
+//
+// Copyright (C) 2024 Alekos Filini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! ECDSA signatures with a low R value
+//!
+//! When the first byte of R is 0x80 or more R needs an extra zero byte in DER to stay positive,
+//! making the signature 71 bytes long, otherwise it's at most 70 bytes. Always producing
+//! low-R signatures keeps the size of the transaction predictable for fee estimation.
+//!
+//! Each nonce has about a 50% chance of giving a low R, so [`sign_ecdsa_low_r`] adds extra entropy
+//! to the nonce and signs again, like `libsecp256k1` does, but gives up after a fixed number of
+//! attempts.
+
+use core::fmt;
+
+use bitcoin::secp256k1::{ecdsa::Signature, Message, Secp256k1, SecretKey, Signing};
+
+/// Maximum length of a DER-encoded ECDSA signature with a low R value
+pub const MAX_LOW_R_DER_LEN: usize = 70;
+/// Signatures tried by [`sign_ecdsa_low_r`] before giving up, each one fails with a probability
+/// of about 1/2
+pub const MAX_LOW_R_ATTEMPTS: u32 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LowRError {
+    /// None of the signatures had a low R value
+    TooManyAttempts(u32),
+}
+
+impl fmt::Display for LowRError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Whether the first bit of R is zero, which is the check done by Bitcoin Core and
+/// `libsecp256k1` and guarantees a DER encoding of at most [`MAX_LOW_R_DER_LEN`] bytes
+pub fn is_low_r(sig: &Signature) -> bool {
+    sig.serialize_compact()[0] < 0x80
+}
+
+/// Sign `msg` with a nonce that gives a low R value, trying at most `max_attempts` nonces
+///
+/// The first attempt is the plain RFC6979 signature, the following ones add a counter as extra
+/// entropy. The result is the same as `Secp256k1::sign_ecdsa_low_r`, unless it runs out of
+/// attempts.
+pub fn sign_ecdsa_low_r<C: Signing>(
+    secp: &Secp256k1<C>,
+    msg: &Message,
+    sk: &SecretKey,
+    max_attempts: u32,
+) -> Result<Signature, LowRError> {
+    for counter in 0..max_attempts {
+        let sig = match counter {
+            0 => secp.sign_ecdsa(msg, sk),
+            _ => {
+                let mut extra_entropy = [0u8; 32];
+                extra_entropy[..4].copy_from_slice(&counter.to_le_bytes());
+                secp.sign_ecdsa_with_noncedata(msg, sk, &extra_entropy)
+            }
+        };
+
+        if is_low_r(&sig) {
+            return Ok(sig);
+        }
+    }
+
+    Err(LowRError::TooManyAttempts(max_attempts))
+}
+
+#[cfg(all(test, not(feature = "stm32")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_ecdsa_low_r() {
+        use bitcoin::secp256k1::{Message, Secp256k1, SecretKey};
+        let secp = Secp256k1::new();
+        let sk = SecretKey::from_slice(&[0x42; 32]).unwrap();
+        let pk = sk.public_key(&secp);
+
+        let mut high_r = 0;
+        for i in 0..32u8 {
+            let msg = Message::from_slice(&[i; 32]).unwrap();
+
+            let sig = sign_ecdsa_low_r(&secp, &msg, &sk, MAX_LOW_R_ATTEMPTS).unwrap();
+            assert!(sig.serialize_der().len() <= MAX_LOW_R_DER_LEN);
+            assert!(sig.serialize_compact()[0] < 0x80);
+            assert!(secp.verify_ecdsa(&msg, &sig, &pk).is_ok());
+            // Same nonces as libsecp256k1
+            assert_eq!(sig, secp.sign_ecdsa_low_r(&msg, &sk));
+
+            let plain = secp.sign_ecdsa(&msg, &sk);
+            if !is_low_r(&plain) {
+                high_r += 1;
+                assert!(plain.serialize_compact()[0] >= 0x80);
+                // A single attempt is the plain RFC6979 signature
+                assert_eq!(
+                    sign_ecdsa_low_r(&secp, &msg, &sk, 1),
+                    Err(LowRError::TooManyAttempts(1))
+                );
+                assert_eq!(
+                    sign_ecdsa_low_r(&secp, &msg, &sk, 0),
+                    Err(LowRError::TooManyAttempts(0))
+                );
+            } else {
+                assert_eq!(sign_ecdsa_low_r(&secp, &msg, &sk, 1), Ok(plain));
+            }
+        }
+        // Make sure both cases are covered
+        assert!(high_r > 0 && high_r < 32);
+    }
+}
@@ -0,0 +1,127 @@
+// Portal Hardware Wallet firmware and supporting software libraries
+//
+// Copyright (C) 2024 Alekos Filini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Scripts of the PSBT outputs that claim a derivation from our wallet
+//!
+//! Outputs derived from the internal descriptor are hidden from the user as change, so a PSBT
+//! with our key origins on an output paying someone else would hide that payment. The script of
+//! each such output must be the one our descriptors derive at the claimed index.
+
+use core::fmt;
+
+use bitcoin::util::psbt::{Output, PartiallySignedTransaction};
+use bitcoin::Script;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputScriptError {
+    /// The output at this index claims a derivation from our wallet, but its script is
+    /// different from the one we derive
+    Mismatch(usize),
+}
+
+impl fmt::Display for OutputScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Ensure that every output pays to the scripts derived from its key origins, otherwise return
+/// the index of the first output that doesn't
+///
+/// `derive_scripts` returns the scripts that our descriptors derive from the key origins of
+/// the output at `index`, which is empty for the outputs that are not ours.
+pub fn verify_output_scripts<I>(
+    psbt: &PartiallySignedTransaction,
+    mut derive_scripts: impl FnMut(usize, &Output) -> I,
+) -> Result<(), OutputScriptError>
+where
+    I: IntoIterator<Item = Script>,
+{
+    for (index, (out, psbt_out)) in psbt
+        .unsigned_tx
+        .output
+        .iter()
+        .zip(psbt.outputs.iter())
+        .enumerate()
+    {
+        if derive_scripts(index, psbt_out)
+            .into_iter()
+            .any(|derived| derived != out.script_pubkey)
+        {
+            return Err(OutputScriptError::Mismatch(index));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(all(test, not(feature = "stm32")))]
+mod tests {
+    use super::*;
+    use crate::test_utils::coinjoin_psbt;
+
+    #[test]
+    fn test_verify_output_scripts() {
+        use bitcoin::secp256k1::{Secp256k1, SecretKey};
+        use bitcoin::util::bip32::{DerivationPath, Fingerprint};
+        use bitcoin::util::psbt::Output;
+        use bitcoin::{PublicKey, Script};
+        use core::str::FromStr;
+        let secp = Secp256k1::new();
+        let key = |byte| {
+            PublicKey::new(
+                SecretKey::from_slice(&[byte; 32])
+                    .unwrap()
+                    .public_key(&secp),
+            )
+        };
+        let ours = Fingerprint::from(&[0xAA; 4][..]);
+
+        // Stands in for our wpkh descriptor: a script for each key with our fingerprint
+        let derive_scripts = |_, out: &Output| {
+            out.bip32_derivation
+                .iter()
+                .filter(|(_, (f, _))| *f == ours)
+                .map(|(key, _)| {
+                    Script::new_v0_p2wpkh(&PublicKey::new(*key).wpubkey_hash().unwrap())
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // A payment to someone else and our change
+        let (mut psbt, _) = coinjoin_psbt(&[5_000, 4_900]);
+        psbt.unsigned_tx.output[0].script_pubkey =
+            Script::new_v0_p2wpkh(&key(0x02).wpubkey_hash().unwrap());
+        psbt.unsigned_tx.output[1].script_pubkey =
+            Script::new_v0_p2wpkh(&key(0x01).wpubkey_hash().unwrap());
+        psbt.outputs[1].bip32_derivation.insert(
+            key(0x01).inner,
+            (ours, DerivationPath::from_str("m/84'/1'/0'/1/0").unwrap()),
+        );
+        assert_eq!(verify_output_scripts(&psbt, derive_scripts), Ok(()));
+
+        // The payment claims to be our change, which would hide it from the user
+        psbt.outputs[0].bip32_derivation.insert(
+            key(0x01).inner,
+            (ours, DerivationPath::from_str("m/84'/1'/0'/1/0").unwrap()),
+        );
+        assert_eq!(
+            verify_output_scripts(&psbt, derive_scripts),
+            Err(OutputScriptError::Mismatch(0))
+        );
+    }
+}
@@ -0,0 +1,162 @@
+// Portal Hardware Wallet firmware and supporting software libraries
+//
+// Copyright (C) 2024 Alekos Filini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Clock speeds of the I2C buses, used by the firmware to initialize the peripherals
+//!
+//! The display and the NFC IC are on separate buses. A full frame is 1 KiB, so the display bus
+//! runs in fast mode by default to flush it quicker, while the NFC bus stays in standard mode.
+//! The firmware validates its speeds with [`PeripheralConfig::new`] and passes them to
+//! `init_peripherals`.
+//!
+//! A display that fails to initialize doesn't stop the boot, the firmware reports its
+//! [`DisplayStatus`] and keeps answering over NFC.
+
+use core::fmt;
+
+/// Standard mode
+pub const I2C_STANDARD_MODE_HZ: u32 = 100_000;
+/// Fast mode
+pub const I2C_FAST_MODE_HZ: u32 = 400_000;
+/// Fastest speed supported by both the SSD1306 and the NT3H. The HAL would go up to 1 MHz.
+pub const I2C_MAX_HZ: u32 = I2C_FAST_MODE_HZ;
+/// With lower speeds the timings computed by the HAL overflow the prescaler
+pub const I2C_MIN_HZ: u32 = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum I2cBus {
+    Display,
+    Nfc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeripheralConfigError {
+    /// The speed of the bus is outside of [`I2C_MIN_HZ`]..=[`I2C_MAX_HZ`]
+    InvalidI2cSpeed { bus: I2cBus, hz: u32 },
+}
+
+impl fmt::Display for PeripheralConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeripheralConfig {
+    display_i2c_hz: u32,
+    nfc_i2c_hz: u32,
+}
+
+impl Default for PeripheralConfig {
+    fn default() -> Self {
+        PeripheralConfig {
+            display_i2c_hz: I2C_FAST_MODE_HZ,
+            nfc_i2c_hz: I2C_STANDARD_MODE_HZ,
+        }
+    }
+}
+
+impl PeripheralConfig {
+    pub fn new(display_i2c_hz: u32, nfc_i2c_hz: u32) -> Result<Self, PeripheralConfigError> {
+        for (bus, hz) in [(I2cBus::Display, display_i2c_hz), (I2cBus::Nfc, nfc_i2c_hz)] {
+            if !(I2C_MIN_HZ..=I2C_MAX_HZ).contains(&hz) {
+                return Err(PeripheralConfigError::InvalidI2cSpeed { bus, hz });
+            }
+        }
+
+        Ok(PeripheralConfig {
+            display_i2c_hz,
+            nfc_i2c_hz,
+        })
+    }
+
+    pub fn display_i2c_hz(&self) -> u32 {
+        self.display_i2c_hz
+    }
+
+    pub fn nfc_i2c_hz(&self) -> u32 {
+        self.nfc_i2c_hz
+    }
+}
+
+/// Outcome of the initialization of the display
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayStatus {
+    Ok,
+    /// Nothing can be shown, so nothing can be confirmed by the user either
+    Failed,
+}
+
+impl DisplayStatus {
+    pub fn from_init<E>(result: Result<(), E>) -> Self {
+        match result {
+            Ok(()) => DisplayStatus::Ok,
+            Err(_) => DisplayStatus::Failed,
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "stm32")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peripheral_config_default() {
+        let config = PeripheralConfig::default();
+        assert_eq!(config.display_i2c_hz(), I2C_FAST_MODE_HZ);
+        assert_eq!(config.nfc_i2c_hz(), I2C_STANDARD_MODE_HZ);
+        assert_eq!(
+            PeripheralConfig::new(I2C_FAST_MODE_HZ, I2C_STANDARD_MODE_HZ),
+            Ok(config)
+        );
+    }
+
+    #[test]
+    fn test_peripheral_config_limits() {
+        assert!(PeripheralConfig::new(I2C_MIN_HZ, I2C_MAX_HZ).is_ok());
+        assert_eq!(
+            PeripheralConfig::new(1_000_000, I2C_STANDARD_MODE_HZ),
+            Err(PeripheralConfigError::InvalidI2cSpeed {
+                bus: I2cBus::Display,
+                hz: 1_000_000
+            })
+        );
+        assert_eq!(
+            PeripheralConfig::new(I2C_FAST_MODE_HZ, I2C_MIN_HZ - 1),
+            Err(PeripheralConfigError::InvalidI2cSpeed {
+                bus: I2cBus::Nfc,
+                hz: I2C_MIN_HZ - 1
+            })
+        );
+        assert_eq!(
+            PeripheralConfig::new(0, 0),
+            Err(PeripheralConfigError::InvalidI2cSpeed {
+                bus: I2cBus::Display,
+                hz: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_display_status_from_init() {
+        assert_eq!(DisplayStatus::from_init::<()>(Ok(())), DisplayStatus::Ok);
+        // A display that doesn't ack the init commands
+        assert_eq!(
+            DisplayStatus::from_init(Err("BusWriteError")),
+            DisplayStatus::Failed
+        );
+    }
+}
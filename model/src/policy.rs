@@ -0,0 +1,531 @@
+// Portal Hardware Wallet firmware and supporting software libraries
+//
+// Copyright (C) 2024 Alekos Filini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Human-readable summary of the spending conditions of a script
+//!
+//! The script is a `witness_script` or a taproot leaf, as compiled from miniscript. Only scripts
+//! made of conditions that must all be satisfied are understood, for example
+//! `and_v(v:pk(A),older(144))` or `multi(2,A,B,C)`. Anything else, like branches or hash locks,
+//! is reported as [`Condition::Other`] so that the user knows the summary is incomplete.
+//!
+//! The timelocks set by the transaction itself, in `nLockTime` and in the `nSequence` of its
+//! inputs, are read with [`extract_timelocks`].
+
+use core::fmt;
+
+use alloc::vec::Vec;
+
+use bitcoin::blockdata::opcodes::{all::*, All};
+use bitcoin::blockdata::script::{read_scriptint, Instruction, Script};
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{LockTime, Sequence};
+
+/// Relative timelocks with this flag are in units of 512 seconds instead of blocks (BIP-68)
+const SEQUENCE_TYPE_FLAG: i64 = 1 << 22;
+/// Relative timelocks only use the lowest 16 bits
+const SEQUENCE_MASK: i64 = 0xFFFF;
+/// Absolute timelocks below this are block heights, otherwise unix timestamps
+const LOCKTIME_THRESHOLD: i64 = 500_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    /// Signatures from `threshold` of `keys` keys
+    Keys {
+        threshold: usize,
+        keys: usize,
+    },
+    /// Relative timelock, in blocks
+    OlderBlocks(u16),
+    /// Relative timelock, in seconds
+    OlderSeconds(u32),
+    AfterHeight(u32),
+    /// Absolute timelock, as a unix timestamp
+    AfterTime(u32),
+    /// The rest of the script couldn't be understood
+    Other,
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Condition::Keys {
+                threshold: 1,
+                keys: 1,
+            } => write!(f, "single key"),
+            Condition::Keys { threshold, keys } => write!(f, "{} of {}", threshold, keys),
+            Condition::OlderBlocks(blocks) => write!(f, "after {} blocks", blocks),
+            Condition::OlderSeconds(seconds) => write!(f, "after {} seconds", seconds),
+            Condition::AfterHeight(height) => write!(f, "after block {}", height),
+            Condition::AfterTime(time) => write!(f, "after time {}", time),
+            Condition::Other => write!(f, "complex script"),
+        }
+    }
+}
+
+/// Every condition that must be satisfied to spend, in the order they appear in the script
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyDescription {
+    pub conditions: Vec<Condition>,
+}
+
+impl PolicyDescription {
+    /// Whether the whole script was understood
+    pub fn is_complete(&self) -> bool {
+        !self.conditions.contains(&Condition::Other)
+    }
+}
+
+impl fmt::Display for PolicyDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, condition) in self.conditions.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", condition)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Condition {
+    fn relative(n: i64) -> Self {
+        match n & SEQUENCE_TYPE_FLAG {
+            0 => Condition::OlderBlocks((n & SEQUENCE_MASK) as u16),
+            _ => Condition::OlderSeconds((n & SEQUENCE_MASK) as u32 * 512),
+        }
+    }
+
+    fn absolute(n: i64) -> Self {
+        match n < LOCKTIME_THRESHOLD {
+            true => Condition::AfterHeight(n as u32),
+            false => Condition::AfterTime(n as u32),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    Num(i64),
+    Key,
+    Hash,
+    Op(All),
+    Invalid,
+}
+
+fn tokenize(script: &Script) -> Vec<Token> {
+    script
+        .instructions()
+        .map(|instruction| match instruction {
+            Ok(Instruction::PushBytes(data)) => match data.len() {
+                32 | 33 => Token::Key,
+                20 => Token::Hash,
+                _ => read_scriptint(data)
+                    .map(Token::Num)
+                    .unwrap_or(Token::Invalid),
+            },
+            Ok(Instruction::Op(op))
+                if (OP_PUSHNUM_1.to_u8()..=OP_PUSHNUM_16.to_u8()).contains(&op.to_u8()) =>
+            {
+                Token::Num((op.to_u8() - OP_PUSHNUM_1.to_u8() + 1) as i64)
+            }
+            Ok(Instruction::Op(op)) => Token::Op(op),
+            Err(_) => Token::Invalid,
+        })
+        .collect()
+}
+
+fn is_op(token: Option<&Token>, ops: &[All]) -> bool {
+    matches!(token, Some(Token::Op(op)) if ops.contains(op))
+}
+
+/// Parse the condition at the start of `tokens`, returning it with the number of tokens consumed
+fn parse_condition(tokens: &[Token]) -> Option<(Condition, usize)> {
+    match tokens {
+        // pk(A) and multi_a(k,A,B,...)
+        [Token::Key, Token::Op(op), ..] if [OP_CHECKSIG, OP_CHECKSIGVERIFY].contains(op) => {
+            let mut len = 2;
+            let mut keys = 1;
+            while let [Token::Key, Token::Op(OP_CHECKSIGADD), ..] = &tokens[len..] {
+                len += 2;
+                keys += 1;
+            }
+
+            match (keys, &tokens[len..]) {
+                (1, _) => Some((
+                    Condition::Keys {
+                        threshold: 1,
+                        keys: 1,
+                    },
+                    len,
+                )),
+                (_, [Token::Num(k), Token::Op(op), ..])
+                    if [OP_NUMEQUAL, OP_NUMEQUALVERIFY].contains(op) =>
+                {
+                    Some((
+                        Condition::Keys {
+                            threshold: *k as usize,
+                            keys,
+                        },
+                        len + 2,
+                    ))
+                }
+                _ => None,
+            }
+        }
+        // pkh(A)
+        [Token::Op(OP_DUP), Token::Op(OP_HASH160), Token::Hash, Token::Op(OP_EQUALVERIFY), Token::Op(op), ..]
+            if [OP_CHECKSIG, OP_CHECKSIGVERIFY].contains(op) =>
+        {
+            Some((
+                Condition::Keys {
+                    threshold: 1,
+                    keys: 1,
+                },
+                5,
+            ))
+        }
+        // multi(k,A,B,...)
+        [Token::Num(k), ..] if tokens.get(1) == Some(&Token::Key) => {
+            let keys = tokens[1..].iter().take_while(|t| **t == Token::Key).count();
+            match &tokens[1 + keys..] {
+                [Token::Num(n), Token::Op(op), ..]
+                    if *n as usize == keys
+                        && [OP_CHECKMULTISIG, OP_CHECKMULTISIGVERIFY].contains(op) =>
+                {
+                    Some((
+                        Condition::Keys {
+                            threshold: *k as usize,
+                            keys,
+                        },
+                        keys + 3,
+                    ))
+                }
+                _ => None,
+            }
+        }
+        // older(n) and after(n), followed by a DROP or VERIFY when not at the end
+        [Token::Num(n), Token::Op(op), rest @ ..] if [OP_CSV, OP_CLTV].contains(op) => {
+            let condition = match *op {
+                OP_CSV => Condition::relative(*n),
+                _ => Condition::absolute(*n),
+            };
+            let len = match is_op(rest.first(), &[OP_DROP, OP_VERIFY]) {
+                true => 3,
+                false => 2,
+            };
+            Some((condition, len))
+        }
+        _ => None,
+    }
+}
+
+/// Describe the conditions to spend with `script`
+pub fn describe_policy(script: &Script) -> PolicyDescription {
+    let tokens = tokenize(script);
+
+    let mut conditions = Vec::new();
+    let mut tokens = tokens.as_slice();
+    while !tokens.is_empty() {
+        match parse_condition(tokens) {
+            Some((condition, len)) => {
+                conditions.push(condition);
+                tokens = &tokens[len..];
+            }
+            None => {
+                conditions.push(Condition::Other);
+                break;
+            }
+        }
+    }
+
+    PolicyDescription { conditions }
+}
+
+/// Timelocks enforced by a transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timelocks {
+    pub absolute: Option<LockTime>,
+    /// Sequence of each input with a relative timelock
+    pub relative: Vec<Sequence>,
+}
+
+impl Timelocks {
+    pub fn is_empty(&self) -> bool {
+        self.absolute.is_none() && self.relative.is_empty()
+    }
+
+    /// The timelocks as [`Condition`]s, to be displayed like the ones from a script
+    pub fn conditions(&self) -> Vec<Condition> {
+        self.absolute
+            .iter()
+            .map(|lock| Condition::absolute(lock.to_consensus_u32() as i64))
+            .chain(
+                self.relative
+                    .iter()
+                    .map(|sequence| Condition::relative(sequence.0 as i64)),
+            )
+            .collect()
+    }
+}
+
+/// Timelocks set in the `nLockTime` and `nSequence` fields of the unsigned transaction
+///
+/// `nLockTime` is only enforced if at least one input has a non-final sequence, and relative
+/// timelocks only from version 2 transactions (BIP-68). Locks of zero are ignored, since they
+/// don't restrict anything.
+pub fn extract_timelocks(psbt: &PartiallySignedTransaction) -> Timelocks {
+    let tx = &psbt.unsigned_tx;
+
+    let absolute = match tx.is_lock_time_enabled() && tx.lock_time.0 != 0 {
+        true => Some(LockTime::from(tx.lock_time)),
+        false => None,
+    };
+    let relative = match tx.version >= 2 {
+        true => tx
+            .input
+            .iter()
+            .map(|input| input.sequence)
+            .filter(|sequence| {
+                sequence.is_relative_lock_time() && sequence.0 as i64 & SEQUENCE_MASK != 0
+            })
+            .collect(),
+        false => Vec::new(),
+    };
+
+    Timelocks { absolute, relative }
+}
+
+#[cfg(all(test, not(feature = "stm32")))]
+mod tests {
+    use super::*;
+
+    fn policy_keys(n: u8) -> Vec<[u8; 33]> {
+        (0..n).map(|i| [0x02 + (i % 2); 33]).collect()
+    }
+
+    #[test]
+    fn test_describe_policy() {
+        use bitcoin::blockdata::opcodes::all::*;
+        use bitcoin::blockdata::script::Builder;
+        let keys = policy_keys(3);
+
+        // pk(A)
+        let script = Builder::new()
+            .push_slice(&keys[0])
+            .push_opcode(OP_CHECKSIG)
+            .into_script();
+        assert_eq!(describe_policy(&script).to_string(), "single key");
+
+        // multi(2,A,B,C)
+        let script = Builder::new()
+            .push_int(2)
+            .push_slice(&keys[0])
+            .push_slice(&keys[1])
+            .push_slice(&keys[2])
+            .push_int(3)
+            .push_opcode(OP_CHECKMULTISIG)
+            .into_script();
+        let description = describe_policy(&script);
+        assert_eq!(
+            description.conditions,
+            vec![Condition::Keys {
+                threshold: 2,
+                keys: 3
+            }]
+        );
+        assert_eq!(description.to_string(), "2 of 3");
+        assert!(description.is_complete());
+
+        // multi_a(2,A,B,C)
+        let script = Builder::new()
+            .push_slice(&keys[0][1..])
+            .push_opcode(OP_CHECKSIG)
+            .push_slice(&keys[1][1..])
+            .push_opcode(OP_CHECKSIGADD)
+            .push_slice(&keys[2][1..])
+            .push_opcode(OP_CHECKSIGADD)
+            .push_int(2)
+            .push_opcode(OP_NUMEQUAL)
+            .into_script();
+        assert_eq!(describe_policy(&script).to_string(), "2 of 3");
+
+        // and_v(v:pk(A),older(144))
+        let script = Builder::new()
+            .push_slice(&keys[0])
+            .push_opcode(OP_CHECKSIGVERIFY)
+            .push_int(144)
+            .push_opcode(OP_CSV)
+            .into_script();
+        assert_eq!(
+            describe_policy(&script).to_string(),
+            "single key, after 144 blocks"
+        );
+
+        // and_v(v:multi(2,A,B,C),after(800000))
+        let script = Builder::new()
+            .push_int(2)
+            .push_slice(&keys[0])
+            .push_slice(&keys[1])
+            .push_slice(&keys[2])
+            .push_int(3)
+            .push_opcode(OP_CHECKMULTISIGVERIFY)
+            .push_int(800_000)
+            .push_opcode(OP_CLTV)
+            .into_script();
+        assert_eq!(
+            describe_policy(&script).to_string(),
+            "2 of 3, after block 800000"
+        );
+
+        // and_v(v:older(<512 seconds>),pk(A)), with the timelock first
+        let script = Builder::new()
+            .push_int((1 << 22) | 1)
+            .push_opcode(OP_CSV)
+            .push_opcode(OP_VERIFY)
+            .push_slice(&keys[0])
+            .push_opcode(OP_CHECKSIG)
+            .into_script();
+        assert_eq!(
+            describe_policy(&script).conditions,
+            vec![
+                Condition::OlderSeconds(512),
+                Condition::Keys {
+                    threshold: 1,
+                    keys: 1
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_describe_policy_other() {
+        use bitcoin::blockdata::opcodes::all::*;
+        use bitcoin::blockdata::script::Builder;
+        let keys = policy_keys(2);
+
+        // or_d(pk(A),and_v(v:pk(B),older(144)))
+        let script = Builder::new()
+            .push_slice(&keys[0])
+            .push_opcode(OP_CHECKSIG)
+            .push_opcode(OP_IFDUP)
+            .push_opcode(OP_NOTIF)
+            .push_slice(&keys[1])
+            .push_opcode(OP_CHECKSIGVERIFY)
+            .push_int(144)
+            .push_opcode(OP_CSV)
+            .push_opcode(OP_ENDIF)
+            .into_script();
+        let description = describe_policy(&script);
+        assert_eq!(
+            description.conditions,
+            vec![
+                Condition::Keys {
+                    threshold: 1,
+                    keys: 1
+                },
+                Condition::Other
+            ]
+        );
+        assert!(!description.is_complete());
+        assert_eq!(description.to_string(), "single key, complex script");
+
+        // Multisig with a wrong number of keys
+        let script = Builder::new()
+            .push_int(1)
+            .push_slice(&keys[0])
+            .push_slice(&keys[1])
+            .push_int(3)
+            .push_opcode(OP_CHECKMULTISIG)
+            .into_script();
+        assert_eq!(describe_policy(&script).conditions, vec![Condition::Other]);
+    }
+
+    fn timelocked_psbt(
+        version: i32,
+        lock_time: u32,
+        sequences: &[u32],
+    ) -> bitcoin::util::psbt::PartiallySignedTransaction {
+        use bitcoin::{PackedLockTime, Sequence, Transaction, TxIn};
+
+        let tx = Transaction {
+            version,
+            lock_time: PackedLockTime(lock_time),
+            input: sequences
+                .iter()
+                .map(|sequence| TxIn {
+                    sequence: Sequence(*sequence),
+                    ..Default::default()
+                })
+                .collect(),
+            output: vec![],
+        };
+        bitcoin::util::psbt::PartiallySignedTransaction::from_unsigned_tx(tx).unwrap()
+    }
+
+    #[test]
+    fn test_extract_timelocks() {
+        use bitcoin::{LockTime, Sequence};
+        // No timelocks
+        let timelocks = extract_timelocks(&timelocked_psbt(2, 0, &[0xFFFFFFFF, 0xFFFFFFFE]));
+        assert!(timelocks.is_empty());
+
+        // Height-based absolute lock
+        let timelocks = extract_timelocks(&timelocked_psbt(2, 800_000, &[0xFFFFFFFE]));
+        assert_eq!(
+            timelocks.absolute,
+            Some(LockTime::from_height(800_000).unwrap())
+        );
+        assert!(timelocks.relative.is_empty());
+        assert_eq!(
+            timelocks.conditions(),
+            vec![Condition::AfterHeight(800_000)]
+        );
+
+        // Time-based absolute lock
+        let timelocks = extract_timelocks(&timelocked_psbt(2, 1_700_000_000, &[0xFFFFFFFE]));
+        assert_eq!(
+            timelocks.absolute,
+            Some(LockTime::from_time(1_700_000_000).unwrap())
+        );
+        assert_eq!(
+            timelocks.conditions(),
+            vec![Condition::AfterTime(1_700_000_000)]
+        );
+
+        // nLockTime is not enforced when every input is final
+        let timelocks = extract_timelocks(&timelocked_psbt(2, 800_000, &[0xFFFFFFFF]));
+        assert!(timelocks.is_empty());
+
+        // Height and time-based relative locks, the RBF-only input is ignored
+        let timelocks =
+            extract_timelocks(&timelocked_psbt(2, 0, &[144, (1 << 22) | 2, 0xFFFFFFFD]));
+        assert_eq!(timelocks.absolute, None);
+        assert_eq!(
+            timelocks.relative,
+            vec![Sequence::from_height(144), Sequence((1 << 22) | 2)]
+        );
+        assert_eq!(
+            timelocks.conditions(),
+            vec![Condition::OlderBlocks(144), Condition::OlderSeconds(1024)]
+        );
+
+        // Relative locks are only enforced from version 2
+        let timelocks = extract_timelocks(&timelocked_psbt(1, 0, &[144]));
+        assert!(timelocks.is_empty());
+    }
+}
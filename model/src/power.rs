@@ -0,0 +1,106 @@
+// Portal Hardware Wallet firmware and supporting software libraries
+//
+// Copyright (C) 2024 Alekos Filini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Low-power idle while there's no RF field
+//!
+//! Without a field nobody is holding a phone to the device, so the TSC acquisitions are stopped
+//! and the display is dimmed until the field comes back.
+
+/// What the firmware has to do after a change of the RF field
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerTransition {
+    /// The field was lost: disable the TSC and dim the display
+    Idle,
+    /// The field is back: restore the brightness of the display, and re-enable the TSC if it
+    /// was enabled when the field was lost
+    Wake { enable_tsc: bool },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerPolicy {
+    field_present: bool,
+    tsc_was_enabled: bool,
+}
+
+impl Default for PowerPolicy {
+    fn default() -> Self {
+        PowerPolicy {
+            field_present: true,
+            tsc_was_enabled: false,
+        }
+    }
+}
+
+impl PowerPolicy {
+    pub fn is_low_power(&self) -> bool {
+        !self.field_present
+    }
+
+    /// Update the field status, `tsc_enabled` is whether the TSC is currently enabled
+    ///
+    /// Returns `None` if the field didn't change.
+    pub fn on_field_change(&mut self, present: bool, tsc_enabled: bool) -> Option<PowerTransition> {
+        if present == self.field_present {
+            return None;
+        }
+        self.field_present = present;
+
+        if present {
+            Some(PowerTransition::Wake {
+                enable_tsc: self.tsc_was_enabled,
+            })
+        } else {
+            self.tsc_was_enabled = tsc_enabled;
+            Some(PowerTransition::Idle)
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "stm32")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_power_policy_transitions() {
+        let mut policy = PowerPolicy::default();
+        assert!(!policy.is_low_power());
+        assert_eq!(policy.on_field_change(true, true), None);
+
+        // The TSC was enabled when the field was lost, so it's enabled again
+        assert_eq!(
+            policy.on_field_change(false, true),
+            Some(PowerTransition::Idle)
+        );
+        assert!(policy.is_low_power());
+        assert_eq!(policy.on_field_change(false, false), None);
+        assert_eq!(
+            policy.on_field_change(true, false),
+            Some(PowerTransition::Wake { enable_tsc: true })
+        );
+        assert!(!policy.is_low_power());
+
+        // Otherwise it stays disabled
+        assert_eq!(
+            policy.on_field_change(false, false),
+            Some(PowerTransition::Idle)
+        );
+        assert_eq!(
+            policy.on_field_change(true, false),
+            Some(PowerTransition::Wake { enable_tsc: false })
+        );
+    }
+}
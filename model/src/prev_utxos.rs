@@ -0,0 +1,132 @@
+// Portal Hardware Wallet firmware and supporting software libraries
+//
+// Copyright (C) 2024 Alekos Filini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Outputs spent by the inputs of a PSBT, used to compute the fee shown to the user
+//!
+//! For inputs other than taproot the amount in `witness_utxo` isn't signed by itself, so a
+//! coordinator could lie about it and make the fee look lower than it is. The full previous
+//! transaction in `non_witness_utxo` is checked against the txid being spent, so it's trusted
+//! instead, and a `witness_utxo` that disagrees with it is rejected.
+
+use core::fmt;
+
+use alloc::vec::Vec;
+
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::TxOut;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrevUtxoError {
+    /// The `non_witness_utxo` of the input at this index is not the transaction being spent, or
+    /// its output differs from the `witness_utxo`
+    InvalidNonWitnessUtxo(usize),
+    /// The input at this index only has a `witness_utxo`, which isn't allowed
+    MissingNonWitnessUtxo(usize),
+}
+
+impl fmt::Display for PrevUtxoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// The output spent by each input of `psbt`
+///
+/// The output is taken from the `non_witness_utxo`, which must be present unless
+/// `allow_witness_utxo` is set, as it is for taproot wallets.
+pub fn prev_utxos(
+    psbt: &PartiallySignedTransaction,
+    allow_witness_utxo: bool,
+) -> Result<Vec<&TxOut>, PrevUtxoError> {
+    psbt.unsigned_tx
+        .input
+        .iter()
+        .zip(psbt.inputs.iter())
+        .enumerate()
+        .map(|(index, (txin, input))| {
+            let vout = txin.previous_output.vout as usize;
+            match (&input.non_witness_utxo, &input.witness_utxo) {
+                (Some(prev_tx), _)
+                    if prev_tx.txid() != txin.previous_output.txid
+                        || prev_tx.output.len() <= vout =>
+                {
+                    Err(PrevUtxoError::InvalidNonWitnessUtxo(index))
+                }
+                (Some(prev_tx), Some(witness_utxo)) if &prev_tx.output[vout] != witness_utxo => {
+                    Err(PrevUtxoError::InvalidNonWitnessUtxo(index))
+                }
+                (Some(prev_tx), _) => Ok(&prev_tx.output[vout]),
+                (None, Some(witness_utxo)) if allow_witness_utxo => Ok(witness_utxo),
+                (None, _) => Err(PrevUtxoError::MissingNonWitnessUtxo(index)),
+            }
+        })
+        .collect()
+}
+
+#[cfg(all(test, not(feature = "stm32")))]
+mod tests {
+    use super::*;
+    use crate::test_utils::spending_psbt;
+
+    #[test]
+    fn test_prev_utxos_non_witness_utxo() {
+        let (mut psbt, prev_tx) = spending_psbt();
+        psbt.inputs[0].non_witness_utxo = Some(prev_tx.clone());
+        assert_eq!(prev_utxos(&psbt, false), Ok(vec![&prev_tx.output[0]]));
+
+        // A matching witness_utxo is fine
+        psbt.inputs[0].witness_utxo = Some(prev_tx.output[0].clone());
+        assert_eq!(prev_utxos(&psbt, false), Ok(vec![&prev_tx.output[0]]));
+
+        // Without the previous transaction only taproot wallets accept the witness_utxo
+        psbt.inputs[0].non_witness_utxo = None;
+        assert_eq!(
+            prev_utxos(&psbt, false),
+            Err(PrevUtxoError::MissingNonWitnessUtxo(0))
+        );
+        assert_eq!(prev_utxos(&psbt, true), Ok(vec![&prev_tx.output[0]]));
+    }
+
+    #[test]
+    fn test_prev_utxos_mismatched_witness_utxo() {
+        // The witness_utxo claims a lower amount, which would hide part of the fee
+        let (mut psbt, prev_tx) = spending_psbt();
+        psbt.inputs[0].non_witness_utxo = Some(prev_tx.clone());
+        psbt.inputs[0].witness_utxo = Some(bitcoin::TxOut {
+            value: 1_000,
+            ..prev_tx.output[0].clone()
+        });
+        assert_eq!(
+            prev_utxos(&psbt, false),
+            Err(PrevUtxoError::InvalidNonWitnessUtxo(0))
+        );
+        assert_eq!(
+            prev_utxos(&psbt, true),
+            Err(PrevUtxoError::InvalidNonWitnessUtxo(0))
+        );
+
+        // Same for a non_witness_utxo that isn't the transaction being spent
+        let mut other_tx = prev_tx.clone();
+        other_tx.lock_time = bitcoin::PackedLockTime(1);
+        psbt.inputs[0].non_witness_utxo = Some(other_tx);
+        psbt.inputs[0].witness_utxo = None;
+        assert_eq!(
+            prev_utxos(&psbt, false),
+            Err(PrevUtxoError::InvalidNonWitnessUtxo(0))
+        );
+    }
+}
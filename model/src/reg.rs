@@ -54,6 +54,27 @@ pub struct NC_REG {
     pub PTHRU_ON_OFF: bool,
     pub NFCS_I2C_RST_ON_OFF: bool,
 }
+impl NC_REG {
+    /// Pass-through mode with the host writing to the SRAM and the NFC side reading from it
+    pub fn passthrough_i2c_to_nfc() -> Self {
+        NC_REG::new()
+            .with_TRANSFER_DIR(TransferDir::HostToNfc)
+            .with_PTHRU_ON_OFF(true)
+    }
+
+    /// Pass-through mode with the NFC side writing to the SRAM and the host reading from it
+    pub fn passthrough_nfc_to_i2c() -> Self {
+        NC_REG::new()
+            .with_TRANSFER_DIR(TransferDir::NfcToHost)
+            .with_PTHRU_ON_OFF(true)
+    }
+
+    /// Mirror the SRAM into the user memory, with pass-through disabled
+    pub fn sram_mirror_enabled() -> Self {
+        NC_REG::new().with_SRAM_MIRROR_ON_OFF(true)
+    }
+}
+
 impl fmt::Debug for NC_REG {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("NC_REG")
@@ -67,6 +88,88 @@ impl fmt::Debug for NC_REG {
     }
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RegError {
+    /// SRAM mirroring only works while pass-through is disabled
+    PassthroughWithMirror,
+    /// Pass-through was enabled without explicitly choosing a transfer direction
+    PassthroughWithoutDirection,
+    /// `FD_OFF = HostDone` is only defined when `FD_ON = NfcDone`
+    HostDoneWithoutNfcDone,
+}
+
+/// Builder for [`NC_REG`] that rejects combinations the NT3H doesn't support
+///
+/// The rejected combinations are:
+/// - pass-through together with SRAM mirroring ([`RegError::PassthroughWithMirror`])
+/// - pass-through without a transfer direction ([`RegError::PassthroughWithoutDirection`])
+/// - [`FdOff::HostDone`] without [`FdOn::NfcDone`] ([`RegError::HostDoneWithoutNfcDone`])
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NcRegBuilder {
+    transfer_dir: Option<TransferDir>,
+    sram_mirror: bool,
+    fd_on: Option<FdOn>,
+    fd_off: Option<FdOff>,
+    passthrough: bool,
+    nfcs_i2c_rst: bool,
+}
+
+impl NcRegBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn transfer_dir(mut self, dir: TransferDir) -> Self {
+        self.transfer_dir = Some(dir);
+        self
+    }
+
+    pub fn sram_mirror(mut self, enabled: bool) -> Self {
+        self.sram_mirror = enabled;
+        self
+    }
+
+    pub fn fd_on(mut self, fd_on: FdOn) -> Self {
+        self.fd_on = Some(fd_on);
+        self
+    }
+
+    pub fn fd_off(mut self, fd_off: FdOff) -> Self {
+        self.fd_off = Some(fd_off);
+        self
+    }
+
+    pub fn passthrough(mut self, enabled: bool) -> Self {
+        self.passthrough = enabled;
+        self
+    }
+
+    pub fn nfcs_i2c_rst(mut self, enabled: bool) -> Self {
+        self.nfcs_i2c_rst = enabled;
+        self
+    }
+
+    pub fn build(self) -> Result<NC_REG, RegError> {
+        if self.passthrough && self.sram_mirror {
+            return Err(RegError::PassthroughWithMirror);
+        }
+        if self.passthrough && self.transfer_dir.is_none() {
+            return Err(RegError::PassthroughWithoutDirection);
+        }
+        if self.fd_off == Some(FdOff::HostDone) && self.fd_on != Some(FdOn::NfcDone) {
+            return Err(RegError::HostDoneWithoutNfcDone);
+        }
+
+        Ok(NC_REG::new()
+            .with_TRANSFER_DIR(self.transfer_dir.unwrap_or(TransferDir::HostToNfc))
+            .with_SRAM_MIRROR_ON_OFF(self.sram_mirror)
+            .with_FD_ON(self.fd_on.unwrap_or(FdOn::FieldOn))
+            .with_FD_OFF(self.fd_off.unwrap_or(FdOff::Nothing))
+            .with_PTHRU_ON_OFF(self.passthrough)
+            .with_NFCS_I2C_RST_ON_OFF(self.nfcs_i2c_rst))
+    }
+}
+
 #[allow(non_camel_case_types)]
 #[bitfield]
 pub struct I2C_CLOCK_STR {
@@ -104,6 +207,26 @@ pub struct NS_REG {
     pub I2C_LOCKED: bool,
     pub NDEF_DATA_READ: bool,
 }
+impl NS_REG {
+    pub fn is_rf_field_present(&self) -> bool {
+        self.RF_FIELD_PRESENT()
+    }
+
+    pub fn is_eeprom_busy(&self) -> bool {
+        self.EEPROM_WR_BUSY()
+    }
+
+    /// Data written by the host is still in the SRAM, waiting to be read by the NFC side
+    pub fn is_pending_rf_read(&self) -> bool {
+        self.SRAM_RF_READY()
+    }
+
+    /// Data written by the NFC side is in the SRAM, ready to be read by the host
+    pub fn is_ready_for_i2c_read(&self) -> bool {
+        self.SRAM_I2C_READY()
+    }
+}
+
 impl fmt::Debug for NS_REG {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("NS_REG")
@@ -119,6 +242,92 @@ impl fmt::Debug for NS_REG {
     }
 }
 
+/// Address of the first of the four 16-byte blocks of the SRAM
+pub const BLOCK_SRAM: u8 = 0xF8;
+
+/// Whether the host can move the next 64-byte block in pass-through mode
+///
+/// Before writing, the NFC side must have read the previous block (`SRAM_RF_READY` cleared).
+/// Before reading, the NFC side must have finished writing it (`SRAM_I2C_READY` set).
+pub fn sram_ready(dir: TransferDir, ns_reg: &NS_REG) -> bool {
+    match dir {
+        TransferDir::HostToNfc => !ns_reg.is_pending_rf_read(),
+        TransferDir::NfcToHost => ns_reg.is_ready_for_i2c_read(),
+    }
+}
+
+/// The I2C writes that move a 64-byte block to the SRAM, each one prefixed with the address of
+/// its 16-byte block
+///
+/// The NT3H sets `SRAM_RF_READY` once the last block is written.
+pub fn sram_block_writes(block: &[u8; 64]) -> [[u8; 17]; 4] {
+    let mut writes = [[0u8; 17]; 4];
+    for (i, (write, chunk)) in writes.iter_mut().zip(block.chunks(16)).enumerate() {
+        write[0] = BLOCK_SRAM + i as u8;
+        write[1..].copy_from_slice(chunk);
+    }
+
+    writes
+}
+
+/// Outcome of one read of `EEPROM_WR_BUSY` while waiting for an EEPROM write
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum EepromPoll {
+    Ready,
+    /// Still busy, read again after a short delay
+    Busy,
+    /// Still busy after every allowed read
+    TimedOut,
+}
+
+/// Bounded polling of `EEPROM_WR_BUSY`, giving up after `max_retries` busy reads
+#[derive(Debug, Clone, Copy)]
+pub struct EepromWritePoll {
+    retries_left: usize,
+}
+
+impl EepromWritePoll {
+    pub fn new(max_retries: usize) -> Self {
+        EepromWritePoll {
+            retries_left: max_retries,
+        }
+    }
+
+    pub fn poll(&mut self, ns_reg: &NS_REG) -> EepromPoll {
+        if !ns_reg.is_eeprom_busy() {
+            return EepromPoll::Ready;
+        }
+
+        match self.retries_left.checked_sub(1) {
+            Some(left) if left > 0 => {
+                self.retries_left = left;
+                EepromPoll::Busy
+            }
+            _ => {
+                self.retries_left = 0;
+                EepromPoll::TimedOut
+            }
+        }
+    }
+}
+
+/// Single read of `NS_REG` over I2C
+///
+/// Implemented by the firmware on top of the I2C peripheral, and by a mock in the tests.
+pub trait ReadNsReg {
+    type Error;
+
+    /// A NACK from the NT3H is returned as an error, without retrying
+    fn read_ns_reg(&mut self) -> Result<NS_REG, Self::Error>;
+}
+
+/// Whether an RF field is present, checked with a single read of `NS_REG`
+///
+/// A failed read is returned to the caller instead of being interpreted as "no field".
+pub fn field_present<R: ReadNsReg>(regs: &mut R) -> Result<bool, R::Error> {
+    Ok(regs.read_ns_reg()?.is_rf_field_present())
+}
+
 #[allow(non_camel_case_types)]
 #[bitfield]
 pub struct AUTH0 {
@@ -212,3 +421,177 @@ impl fmt::Debug for DYNAMIC_LOCK_BYTES {
             .finish()
     }
 }
+
+#[cfg(all(test, not(feature = "stm32")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nc_reg_helpers() {
+        // TRANSFER_DIR is bit 0, SRAM_MIRROR_ON_OFF bit 1 and PTHRU_ON_OFF bit 6
+        assert_eq!(NC_REG::passthrough_i2c_to_nfc().into_bytes(), [0b0100_0000]);
+        assert_eq!(NC_REG::passthrough_nfc_to_i2c().into_bytes(), [0b0100_0001]);
+        assert_eq!(NC_REG::sram_mirror_enabled().into_bytes(), [0b0000_0010]);
+    }
+
+    #[test]
+    fn test_nc_reg_builder() {
+        let nc_reg = NcRegBuilder::new()
+            .transfer_dir(TransferDir::NfcToHost)
+            .passthrough(true)
+            .build()
+            .unwrap();
+        assert_eq!(
+            nc_reg.into_bytes(),
+            NC_REG::passthrough_nfc_to_i2c().into_bytes()
+        );
+
+        let nc_reg = NcRegBuilder::new()
+            .fd_on(FdOn::NfcDone)
+            .fd_off(FdOff::HostDone)
+            .build()
+            .unwrap();
+        assert_eq!(nc_reg.into_bytes(), [0b0011_1100]);
+
+        assert_eq!(
+            NcRegBuilder::new()
+                .transfer_dir(TransferDir::HostToNfc)
+                .passthrough(true)
+                .sram_mirror(true)
+                .build()
+                .unwrap_err(),
+            RegError::PassthroughWithMirror
+        );
+        assert_eq!(
+            NcRegBuilder::new().passthrough(true).build().unwrap_err(),
+            RegError::PassthroughWithoutDirection
+        );
+        assert_eq!(
+            NcRegBuilder::new()
+                .fd_on(FdOn::TagSelected)
+                .fd_off(FdOff::HostDone)
+                .build()
+                .unwrap_err(),
+            RegError::HostDoneWithoutNfcDone
+        );
+    }
+
+    #[test]
+    fn test_ns_reg_helpers() {
+        assert!(NS_REG::from_bytes([0b0000_0001]).is_rf_field_present());
+        assert!(NS_REG::from_bytes([0b0000_0010]).is_eeprom_busy());
+        assert!(NS_REG::from_bytes([0b0000_1000]).is_pending_rf_read());
+        assert!(NS_REG::from_bytes([0b0001_0000]).is_ready_for_i2c_read());
+
+        let ns_reg = NS_REG::from_bytes([0b1110_0100]);
+        assert!(!ns_reg.is_rf_field_present());
+        assert!(!ns_reg.is_eeprom_busy());
+        assert!(!ns_reg.is_pending_rf_read());
+        assert!(!ns_reg.is_ready_for_i2c_read());
+    }
+
+    #[test]
+    fn test_field_present() {
+        /// Replies to each read with the next response, a NACK is `None`
+        struct MockI2c(alloc::vec::IntoIter<Option<u8>>);
+        impl ReadNsReg for MockI2c {
+            type Error = &'static str;
+
+            fn read_ns_reg(&mut self) -> Result<NS_REG, Self::Error> {
+                match self.0.next().expect("Unexpected read") {
+                    Some(byte) => Ok(NS_REG::from_bytes([byte])),
+                    None => Err("NACK"),
+                }
+            }
+        }
+
+        let mut i2c = MockI2c(alloc::vec![Some(0b0000_0001), Some(0b0001_1000), None].into_iter());
+        assert_eq!(field_present(&mut i2c), Ok(true));
+        assert_eq!(field_present(&mut i2c), Ok(false));
+        // A NACK isn't reported as "no field", and isn't retried
+        assert_eq!(field_present(&mut i2c), Err("NACK"));
+        assert!(i2c.0.next().is_none());
+    }
+
+    #[test]
+    fn test_sram_passthrough_handshake() {
+        /// NFC side of the SRAM, which takes a few polls to read each block
+        struct MockSram {
+            rf_ready: bool,
+            polls_left: usize,
+            received: alloc::vec::Vec<u8>,
+            last_block: alloc::vec::Vec<u8>,
+        }
+        impl MockSram {
+            fn poll(&mut self) -> NS_REG {
+                if self.rf_ready && self.polls_left == 0 {
+                    self.rf_ready = false;
+                    self.received.append(&mut self.last_block);
+                }
+                self.polls_left = self.polls_left.saturating_sub(1);
+
+                NS_REG::new().with_SRAM_RF_READY(self.rf_ready)
+            }
+
+            fn write(&mut self, write: &[u8; 17]) {
+                assert!(!self.rf_ready, "Overwrote a block not read yet");
+                assert_eq!(write[0], BLOCK_SRAM + (self.last_block.len() / 16) as u8);
+                self.last_block.extend_from_slice(&write[1..]);
+                if write[0] == BLOCK_SRAM + 3 {
+                    self.rf_ready = true;
+                    self.polls_left = 2;
+                }
+            }
+        }
+
+        let mut sram = MockSram {
+            rf_ready: false,
+            polls_left: 0,
+            received: alloc::vec![],
+            last_block: alloc::vec![],
+        };
+        let mut polls = 0;
+        for i in 0..3u8 {
+            while !sram_ready(TransferDir::HostToNfc, &sram.poll()) {
+                polls += 1;
+            }
+            for write in sram_block_writes(&[i; 64]) {
+                sram.write(&write);
+            }
+        }
+        while !sram_ready(TransferDir::HostToNfc, &sram.poll()) {}
+
+        assert_eq!(polls, 4);
+        assert_eq!(sram.received.len(), 3 * 64);
+        for (i, block) in sram.received.chunks(64).enumerate() {
+            assert!(block.iter().all(|b| *b == i as u8));
+        }
+
+        let ns_reg = NS_REG::new().with_SRAM_I2C_READY(true);
+        assert!(sram_ready(TransferDir::NfcToHost, &ns_reg));
+        assert!(!sram_ready(TransferDir::NfcToHost, &NS_REG::new()));
+    }
+
+    #[test]
+    fn test_eeprom_write_poll() {
+        /// Runs the polling against a mock EEPROM that is busy for the first `busy` reads
+        fn wait_ready(busy: usize, max_retries: usize) -> (EepromPoll, usize) {
+            let mut poll = EepromWritePoll::new(max_retries);
+            for read in 0.. {
+                let ns_reg = NS_REG::new().with_EEPROM_WR_BUSY(read < busy);
+                match poll.poll(&ns_reg) {
+                    EepromPoll::Busy => continue,
+                    result => return (result, read + 1),
+                }
+            }
+            unreachable!()
+        }
+
+        assert_eq!(wait_ready(0, 10), (EepromPoll::Ready, 1));
+        assert_eq!(wait_ready(3, 10), (EepromPoll::Ready, 4));
+        assert_eq!(wait_ready(9, 10), (EepromPoll::Ready, 10));
+        assert_eq!(wait_ready(10, 10), (EepromPoll::TimedOut, 10));
+        assert_eq!(wait_ready(usize::MAX, 1), (EepromPoll::TimedOut, 1));
+        assert_eq!(wait_ready(usize::MAX, 0), (EepromPoll::TimedOut, 1));
+    }
+}
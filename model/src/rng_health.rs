@@ -0,0 +1,136 @@
+// Portal Hardware Wallet firmware and supporting software libraries
+//
+// Copyright (C) 2024 Alekos Filini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Health tests of the raw bytes from the hardware RNG
+//!
+//! These are the continuous health tests of NIST SP 800-90B (section 4.4), run once at boot over
+//! the bytes used to seed the software RNG. They don't prove that the source is good, but they
+//! catch a source that is stuck or heavily biased.
+//!
+//! The cutoffs assume a min-entropy of [`ASSUMED_ENTROPY_BITS`] per byte with a false positive
+//! probability of 2^-20, which is conservative for the conditioned output of the STM32 RNG.
+
+use core::fmt;
+
+/// Min-entropy per byte assumed to compute the cutoffs
+pub const ASSUMED_ENTROPY_BITS: usize = 4;
+/// Number of raw bytes needed by [`rng_health_check`], one window of the adaptive proportion test
+pub const RNG_HEALTH_SAMPLES: usize = 512;
+/// Runs of this many identical bytes fail the repetition count test, `1 + ceil(20 / H)`
+const REPETITION_CUTOFF: usize = 1 + 20_usize.div_ceil(ASSUMED_ENTROPY_BITS);
+/// Windows where the first byte appears this many times fail the adaptive proportion test
+///
+/// `1 + CRITBINOM(512, 2^-H, 1 - 2^-20)`, from table 2 of SP 800-90B
+const ADAPTIVE_PROPORTION_CUTOFF: usize = 62;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RngError {
+    /// Less than [`RNG_HEALTH_SAMPLES`] bytes
+    NotEnoughSamples,
+    /// The source repeated the same byte too many times in a row
+    RepetitionCount,
+    /// A byte appears too often, the source is biased
+    AdaptiveProportion,
+    /// The hardware reported a fault of the source while sampling it
+    SourceError,
+}
+
+impl fmt::Display for RngError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+fn repetition_count_test(raw: &[u8]) -> Result<(), RngError> {
+    let mut run = 1;
+    for pair in raw.windows(2) {
+        run = match pair[0] == pair[1] {
+            true => run + 1,
+            false => 1,
+        };
+        if run >= REPETITION_CUTOFF {
+            return Err(RngError::RepetitionCount);
+        }
+    }
+
+    Ok(())
+}
+
+fn adaptive_proportion_test(raw: &[u8]) -> Result<(), RngError> {
+    for window in raw.chunks_exact(RNG_HEALTH_SAMPLES) {
+        let count = window.iter().filter(|b| **b == window[0]).count();
+        if count >= ADAPTIVE_PROPORTION_CUTOFF {
+            return Err(RngError::AdaptiveProportion);
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that `raw` bytes from the hardware RNG don't look degraded
+///
+/// At least [`RNG_HEALTH_SAMPLES`] bytes are needed.
+pub fn rng_health_check(raw: &[u8]) -> Result<(), RngError> {
+    if raw.len() < RNG_HEALTH_SAMPLES {
+        return Err(RngError::NotEnoughSamples);
+    }
+
+    repetition_count_test(raw)?;
+    adaptive_proportion_test(raw)
+}
+
+#[cfg(all(test, not(feature = "stm32")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rng_health_good_source() {
+        use rand_chacha::rand_core::{RngCore, SeedableRng};
+
+        let mut rng = rand_chacha::ChaCha20Rng::from_seed([0x42; 32]);
+        let mut raw = [0; RNG_HEALTH_SAMPLES * 2];
+        rng.fill_bytes(&mut raw);
+
+        assert_eq!(rng_health_check(&raw), Ok(()));
+    }
+
+    #[test]
+    fn test_rng_health_bad_source() {
+        assert_eq!(
+            rng_health_check(&[0x00; RNG_HEALTH_SAMPLES]),
+            Err(RngError::RepetitionCount)
+        );
+        // No byte is repeated twice in a row, but half of them are the same
+        let alternating = [0xAA, 0x55].repeat(RNG_HEALTH_SAMPLES / 2);
+        assert_eq!(
+            rng_health_check(&alternating),
+            Err(RngError::AdaptiveProportion)
+        );
+        // A short pattern repeating over and over
+        let counter = (0..RNG_HEALTH_SAMPLES)
+            .map(|i| (i % 8) as u8)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rng_health_check(&counter),
+            Err(RngError::AdaptiveProportion)
+        );
+        assert_eq!(
+            rng_health_check(&[0x42; 32]),
+            Err(RngError::NotEnoughSamples)
+        );
+    }
+}
@@ -0,0 +1,316 @@
+// Portal Hardware Wallet firmware and supporting software libraries
+//
+// Copyright (C) 2024 Alekos Filini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Shamir secret sharing of the seed
+//!
+//! Plain threshold sharing over GF(256) with the Rijndael polynomial: the secret is stored
+//! at x = 255 and a digest share at x = 254 detects wrong recombinations. This is not
+//! SLIP-39: shares are binary CBOR values, with no mnemonic encoding, checksum, groups or
+//! passphrase encryption, and they are not compatible with SLIP-39 wallets.
+
+use core::fmt;
+
+use alloc::vec::Vec;
+
+use minicbor::{Decode, Encode};
+
+use bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+
+pub const MAX_SHARE_COUNT: u8 = 16;
+pub const MIN_SECRET_LEN: usize = 16;
+
+const DIGEST_INDEX: u8 = 254;
+const SECRET_INDEX: u8 = 255;
+const DIGEST_LEN: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShamirError {
+    /// The threshold must be between 1 and the number of shares, which is at most
+    /// [`MAX_SHARE_COUNT`]
+    InvalidThreshold,
+    /// The seed must be at least [`MIN_SECRET_LEN`] bytes and have an even length
+    InvalidSecretLength,
+    NotEnoughShares,
+    /// The shares come from different splits or have inconsistent lengths
+    MismatchedShares,
+    DuplicateIndex,
+    /// The recovered secret doesn't match the digest, at least one share is wrong
+    InvalidDigest,
+}
+
+impl fmt::Display for ShamirError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct Share {
+    /// Random value shared by all the shares of the same split
+    #[cbor(n(0))]
+    pub identifier: u16,
+    #[cbor(n(1))]
+    pub threshold: u8,
+    #[cbor(n(2))]
+    pub index: u8,
+    #[cbor(n(3))]
+    pub value: Vec<u8>,
+}
+
+struct Tables {
+    exp: [u8; 255],
+    log: [u8; 256],
+}
+
+const TABLES: Tables = {
+    let mut exp = [0u8; 255];
+    let mut log = [0u8; 256];
+
+    // 3 is a generator of the multiplicative group
+    let mut x: u8 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x;
+        log[x as usize] = i as u8;
+
+        let double = (x << 1) ^ if x & 0x80 != 0 { 0x1B } else { 0x00 };
+        x ^= double;
+        i += 1;
+    }
+
+    Tables { exp, log }
+};
+
+/// Evaluate at `x` the polynomial passing through `points`, byte by byte
+fn interpolate(points: &[(u8, &[u8])], x: u8) -> Vec<u8> {
+    if let Some((_, value)) = points.iter().find(|(px, _)| *px == x) {
+        return value.to_vec();
+    }
+
+    let log = |v: u8| TABLES.log[v as usize] as usize;
+    let log_prod: usize = points.iter().map(|(px, _)| log(px ^ x)).sum();
+
+    let mut result = alloc::vec![0u8; points[0].1.len()];
+    for (px, value) in points {
+        let log_denominator: usize = points
+            .iter()
+            .filter(|(other, _)| other != px)
+            .map(|(other, _)| log(px ^ other))
+            .sum();
+        // Work modulo 255 while keeping the intermediate value positive
+        let log_basis = (log_prod + 255 * points.len() - log(px ^ x) - log_denominator % 255) % 255;
+
+        for (r, v) in result.iter_mut().zip(value.iter()) {
+            if *v != 0 {
+                *r ^= TABLES.exp[(log(*v) + log_basis) % 255];
+            }
+        }
+    }
+
+    result
+}
+
+fn create_digest(random: &[u8], secret: &[u8]) -> [u8; DIGEST_LEN] {
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(random);
+    engine.input(secret);
+    let hmac = hmac::Hmac::<sha256::Hash>::from_engine(engine);
+
+    hmac[..DIGEST_LEN].try_into().expect("Correct length")
+}
+
+/// Split `seed` into `count` shares, any `threshold` of which can recover it
+#[cfg(feature = "rng")]
+pub fn split_seed(
+    seed: &[u8],
+    threshold: u8,
+    count: u8,
+    rng: &mut impl rand_core::RngCore,
+) -> Result<Vec<Share>, ShamirError> {
+    if threshold == 0 || threshold > count || count > MAX_SHARE_COUNT {
+        return Err(ShamirError::InvalidThreshold);
+    }
+    if seed.len() < MIN_SECRET_LEN || seed.len() % 2 != 0 {
+        return Err(ShamirError::InvalidSecretLength);
+    }
+
+    let identifier = (rng.next_u32() & 0x7FFF) as u16;
+    let make_share = |index, value| Share {
+        identifier,
+        threshold,
+        index,
+        value,
+    };
+
+    if threshold == 1 {
+        return Ok((0..count).map(|i| make_share(i, seed.to_vec())).collect());
+    }
+
+    // The polynomial is fixed by `threshold` points: the secret, the digest and
+    // `threshold - 2` random shares
+    let random_shares = (0..threshold - 2)
+        .map(|i| {
+            let mut value = alloc::vec![0u8; seed.len()];
+            rng.fill_bytes(&mut value);
+            (i, value)
+        })
+        .collect::<Vec<_>>();
+
+    let mut digest_share = alloc::vec![0u8; seed.len()];
+    rng.fill_bytes(&mut digest_share[DIGEST_LEN..]);
+    let digest = create_digest(&digest_share[DIGEST_LEN..], seed);
+    digest_share[..DIGEST_LEN].copy_from_slice(&digest);
+
+    let points = random_shares
+        .iter()
+        .map(|(i, v)| (*i, v.as_slice()))
+        .chain([
+            (DIGEST_INDEX, digest_share.as_slice()),
+            (SECRET_INDEX, seed),
+        ])
+        .collect::<Vec<_>>();
+
+    let computed = (threshold - 2..count)
+        .map(|i| (i, interpolate(&points, i)))
+        .collect::<Vec<_>>();
+
+    Ok(random_shares
+        .into_iter()
+        .chain(computed)
+        .map(|(i, value)| make_share(i, value))
+        .collect())
+}
+
+/// Recombine the seed from at least `threshold` shares of the same split
+pub fn recover_seed(shares: &[Share]) -> Result<Vec<u8>, ShamirError> {
+    let first = shares.first().ok_or(ShamirError::NotEnoughShares)?;
+    if shares.iter().any(|s| {
+        s.identifier != first.identifier
+            || s.threshold != first.threshold
+            || s.value.len() != first.value.len()
+    }) {
+        return Err(ShamirError::MismatchedShares);
+    }
+    if first.value.len() < MIN_SECRET_LEN || first.value.len() % 2 != 0 {
+        return Err(ShamirError::InvalidSecretLength);
+    }
+    for (i, share) in shares.iter().enumerate() {
+        if shares[..i].iter().any(|s| s.index == share.index) {
+            return Err(ShamirError::DuplicateIndex);
+        }
+    }
+    if shares.len() < first.threshold as usize {
+        return Err(ShamirError::NotEnoughShares);
+    }
+
+    if first.threshold == 1 {
+        return Ok(first.value.clone());
+    }
+
+    let points = shares
+        .iter()
+        .take(first.threshold as usize)
+        .map(|s| (s.index, s.value.as_slice()))
+        .collect::<Vec<_>>();
+    let secret = interpolate(&points, SECRET_INDEX);
+    let digest_share = interpolate(&points, DIGEST_INDEX);
+
+    if digest_share[..DIGEST_LEN] != create_digest(&digest_share[DIGEST_LEN..], &secret) {
+        return Err(ShamirError::InvalidDigest);
+    }
+
+    Ok(secret)
+}
+
+#[cfg(all(test, feature = "rng", not(feature = "stm32")))]
+mod tests {
+    use super::*;
+
+    struct XorShiftRng(u64);
+
+    impl rand_core::RngCore for XorShiftRng {
+        fn next_u32(&mut self) -> u32 {
+            self.next_u64() as u32
+        }
+        fn next_u64(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            rand_core::impls::fill_bytes_via_next(self, dest)
+        }
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_shamir_roundtrip() {
+        let mut rng = XorShiftRng(0x5EED);
+        let seed = (0..32).collect::<Vec<u8>>();
+
+        let shares = split_seed(&seed, 3, 5, &mut rng).unwrap();
+        assert_eq!(shares.len(), 5);
+        assert!(shares.iter().all(|s| s.value != seed));
+
+        for subset in [[0, 1, 2], [4, 2, 0], [1, 3, 4], [2, 3, 4]] {
+            let selected = subset.map(|i| shares[i].clone());
+            assert_eq!(recover_seed(&selected).unwrap(), seed);
+        }
+        assert_eq!(recover_seed(&shares).unwrap(), seed);
+
+        let shares = split_seed(&seed[..16], 1, 3, &mut rng).unwrap();
+        assert_eq!(recover_seed(&shares[2..]).unwrap(), &seed[..16]);
+    }
+
+    #[test]
+    fn test_shamir_recover_errors() {
+        let mut rng = XorShiftRng(0x5EED);
+        let seed = [0x42; 16];
+
+        let shares = split_seed(&seed, 3, 5, &mut rng).unwrap();
+        assert_eq!(
+            recover_seed(&shares[..2]),
+            Err(ShamirError::NotEnoughShares)
+        );
+        assert_eq!(
+            recover_seed(&[shares[0].clone(), shares[1].clone(), shares[1].clone()]),
+            Err(ShamirError::DuplicateIndex)
+        );
+
+        let mut tampered = shares[..3].to_vec();
+        tampered[1].value[0] ^= 0x01;
+        assert_eq!(recover_seed(&tampered), Err(ShamirError::InvalidDigest));
+
+        let other = split_seed(&seed, 3, 5, &mut rng).unwrap();
+        assert_eq!(
+            recover_seed(&[shares[0].clone(), shares[1].clone(), other[2].clone()]),
+            Err(ShamirError::MismatchedShares)
+        );
+
+        assert_eq!(
+            split_seed(&seed, 4, 3, &mut rng),
+            Err(ShamirError::InvalidThreshold)
+        );
+        assert_eq!(
+            split_seed(&seed[..15], 2, 3, &mut rng),
+            Err(ShamirError::InvalidSecretLength)
+        );
+    }
+}
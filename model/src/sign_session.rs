@@ -0,0 +1,378 @@
+// Portal Hardware Wallet firmware and supporting software libraries
+//
+// Copyright (C) 2024 Alekos Filini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Resumable signing of the inputs of a transaction
+//!
+//! Signing a transaction with many inputs can block long enough for the NFC field to time out.
+//! A [`SignSession`] signs one input per [`step`](SignSession::step), so that the caller can
+//! service other tasks between inputs. The session is also an iterator over the progress of
+//! every step.
+//!
+//! In collaborative transactions some inputs belong to other parties. They are skipped and
+//! reported in the [`SignSummary`], so that the coordinator knows which signatures are missing.
+//!
+//! The device can also lose power halfway through. A [`SavedSignSession`] keeps the signatures
+//! produced so far, and [`SignSession::resume`] continues from the first input that wasn't
+//! signed yet.
+
+use alloc::vec::Vec;
+
+use minicbor::bytes::{ByteArray, ByteVec};
+use minicbor::{Decode, Encode};
+
+/// Something that signs a single input, called by [`SignSession`]
+pub trait InputSigner {
+    type Error;
+
+    fn sign_input(&mut self, index: usize) -> Result<(), Self::Error>;
+
+    /// Whether the input is ours, inputs of other parties are skipped by
+    /// [`SignSession::for_all_inputs`]
+    fn can_sign(&self, _index: usize) -> bool {
+        true
+    }
+}
+
+/// Inputs signed by a [`SignSession`] and inputs left to the other parties
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SignSummary {
+    pub signed: Vec<usize>,
+    pub skipped: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignProgress {
+    pub signed: usize,
+    pub total: usize,
+}
+
+impl SignProgress {
+    pub fn is_done(&self) -> bool {
+        self.signed == self.total
+    }
+}
+
+/// Progress of a [`SignSession`], saved so that it can be resumed after a reset
+///
+/// `digest` identifies the transaction being signed. `signatures` holds, in the same order as
+/// `signed`, whatever the caller needs to restore the signatures of each input.
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct SavedSignSession {
+    #[n(0)]
+    pub digest: ByteArray<32>,
+    #[n(1)]
+    pub signed: Vec<usize>,
+    #[n(2)]
+    pub signatures: Vec<ByteVec>,
+}
+
+impl SavedSignSession {
+    /// Whether this was saved while signing the transaction identified by `digest`
+    pub fn matches(&self, digest: &[u8; 32]) -> bool {
+        &*self.digest == digest && self.signed.len() == self.signatures.len()
+    }
+}
+
+pub struct SignSession<S> {
+    signer: S,
+    inputs: Vec<usize>,
+    skipped: Vec<usize>,
+    next: usize,
+}
+
+impl<S: InputSigner> SignSession<S> {
+    /// Sign `inputs` in order
+    pub fn new(signer: S, inputs: Vec<usize>) -> Self {
+        SignSession {
+            signer,
+            inputs,
+            skipped: Vec::new(),
+            next: 0,
+        }
+    }
+
+    /// Sign in order the inputs among the first `num_inputs` that the signer
+    /// [`can_sign`](InputSigner::can_sign), skipping the others
+    pub fn for_all_inputs(signer: S, num_inputs: usize) -> Self {
+        let (inputs, skipped) = (0..num_inputs).partition(|i| signer.can_sign(*i));
+        SignSession {
+            signer,
+            inputs,
+            skipped,
+            next: 0,
+        }
+    }
+
+    /// Like [`SignSession::for_all_inputs`], but skip the inputs already signed by a previous
+    /// session
+    ///
+    /// `signed` comes from the [`SavedSignSession`] of the previous session, the signatures of
+    /// those inputs must have been restored already. If the inputs don't match the ones this
+    /// session would sign first, every input is signed again.
+    pub fn resume(signer: S, num_inputs: usize, signed: &[usize]) -> Self {
+        let mut session = Self::for_all_inputs(signer, num_inputs);
+        if session.inputs.starts_with(signed) {
+            session.next = signed.len();
+        }
+
+        session
+    }
+
+    /// Save the progress, `signature` returns the data needed to restore the signatures of an
+    /// input
+    pub fn save(
+        &self,
+        digest: [u8; 32],
+        mut signature: impl FnMut(&S, usize) -> Vec<u8>,
+    ) -> SavedSignSession {
+        let signed = self.inputs[..self.next].to_vec();
+        let signatures = signed
+            .iter()
+            .map(|index| signature(&self.signer, *index).into())
+            .collect();
+
+        SavedSignSession {
+            digest: digest.into(),
+            signed,
+            signatures,
+        }
+    }
+
+    pub fn signer(&self) -> &S {
+        &self.signer
+    }
+
+    pub fn progress(&self) -> SignProgress {
+        SignProgress {
+            signed: self.next,
+            total: self.inputs.len(),
+        }
+    }
+
+    /// Sign the next input
+    ///
+    /// Once every input is signed this does nothing. An input that fails is retried at the next
+    /// call.
+    pub fn step(&mut self) -> Result<SignProgress, S::Error> {
+        if let Some(index) = self.inputs.get(self.next) {
+            self.signer.sign_input(*index)?;
+            self.next += 1;
+        }
+
+        Ok(self.progress())
+    }
+
+    /// Inputs signed so far and inputs skipped
+    pub fn summary(&self) -> SignSummary {
+        SignSummary {
+            signed: self.inputs[..self.next].to_vec(),
+            skipped: self.skipped.clone(),
+        }
+    }
+
+    pub fn into_signer(self) -> S {
+        self.signer
+    }
+}
+
+/// Callers should stop at the first error, since the failed input is tried again by the next
+/// iteration
+impl<S: InputSigner> Iterator for SignSession<S> {
+    type Item = Result<SignProgress, S::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.progress().is_done() {
+            None
+        } else {
+            Some(self.step())
+        }
+    }
+}
+
+/// Sign every input of a transaction with `num_inputs` inputs that `signer` can sign
+pub fn sign_all<S: InputSigner>(signer: S, num_inputs: usize) -> Result<SignSummary, S::Error> {
+    let mut session = SignSession::for_all_inputs(signer, num_inputs);
+    for progress in &mut session {
+        progress?;
+    }
+
+    Ok(session.summary())
+}
+
+#[cfg(all(test, not(feature = "stm32")))]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct RecordingSigner {
+        signed: Vec<usize>,
+        fail_once: Option<usize>,
+        foreign: Vec<usize>,
+    }
+
+    impl InputSigner for RecordingSigner {
+        type Error = usize;
+
+        fn sign_input(&mut self, index: usize) -> Result<(), usize> {
+            if self.fail_once == Some(index) {
+                self.fail_once = None;
+                return Err(index);
+            }
+            self.signed.push(index);
+            Ok(())
+        }
+
+        fn can_sign(&self, index: usize) -> bool {
+            !self.foreign.contains(&index)
+        }
+    }
+
+    #[test]
+    fn test_sign_session_steps() {
+        let mut session = SignSession::new(RecordingSigner::default(), vec![0, 2, 3]);
+        assert_eq!(
+            session.progress(),
+            SignProgress {
+                signed: 0,
+                total: 3
+            }
+        );
+
+        for signed in 1..=3 {
+            let progress = session.step().unwrap();
+            assert_eq!(progress, SignProgress { signed, total: 3 });
+            assert_eq!(progress.is_done(), signed == 3);
+        }
+        // Nothing left to sign
+        assert_eq!(session.step().unwrap().signed, 3);
+        assert_eq!(session.into_signer().signed, vec![0, 2, 3]);
+    }
+
+    #[test]
+    fn test_sign_session_iterator() {
+        let signer = RecordingSigner {
+            fail_once: Some(2),
+            ..Default::default()
+        };
+        let mut session = SignSession::new(signer, vec![0, 1, 2, 3]);
+
+        let results = session.by_ref().collect::<Vec<_>>();
+        assert_eq!(results.len(), 5);
+        // The failed input is retried
+        assert_eq!(results[2], Err(2));
+        assert_eq!(
+            results
+                .iter()
+                .filter_map(|r| r.as_ref().ok())
+                .map(|p| p.signed)
+                .collect::<Vec<_>>(),
+            vec![1, 2, 3, 4]
+        );
+        assert!(session.next().is_none());
+        assert_eq!(session.into_signer().signed, vec![0, 1, 2, 3]);
+
+        let mut empty = SignSession::new(RecordingSigner::default(), vec![]);
+        assert!(empty.next().is_none());
+        assert!(empty.progress().is_done());
+    }
+
+    #[test]
+    fn test_sign_all_skips_foreign_inputs() {
+        let signer = RecordingSigner {
+            foreign: vec![1, 3],
+            ..Default::default()
+        };
+        assert_eq!(
+            sign_all(signer, 5).unwrap(),
+            SignSummary {
+                signed: vec![0, 2, 4],
+                skipped: vec![1, 3],
+            }
+        );
+
+        // Only the inputs signed so far are reported
+        let signer = RecordingSigner {
+            foreign: vec![0],
+            ..Default::default()
+        };
+        let mut session = SignSession::for_all_inputs(signer, 3);
+        assert_eq!(session.progress().total, 2);
+        session.step().unwrap();
+        assert_eq!(
+            session.summary(),
+            SignSummary {
+                signed: vec![1],
+                skipped: vec![0],
+            }
+        );
+        assert_eq!(session.into_signer().signed, vec![1]);
+
+        let signer = RecordingSigner {
+            fail_once: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(sign_all(signer, 3), Err(2));
+    }
+
+    #[test]
+    fn test_sign_session_resume_after_reset() {
+        let digest = [0x42; 32];
+        let signer = RecordingSigner {
+            foreign: vec![1],
+            ..Default::default()
+        };
+        let mut session = SignSession::for_all_inputs(signer, 4);
+        session.step().unwrap();
+        let saved = session.save(digest, |signer, index| {
+            assert!(signer.signed.contains(&index));
+            vec![index as u8]
+        });
+        assert_eq!(saved.signed, vec![0]);
+
+        // Reset between the first and the second signature, only the saved session survives
+        drop(session);
+        let saved: SavedSignSession = minicbor::decode(&minicbor::to_vec(&saved).unwrap()).unwrap();
+        assert!(saved.matches(&digest));
+        assert!(!saved.matches(&[0x00; 32]));
+        assert_eq!(saved.signatures, vec![vec![0].into()]);
+
+        let signer = RecordingSigner {
+            foreign: vec![1],
+            ..Default::default()
+        };
+        let mut session = SignSession::resume(signer, 4, &saved.signed);
+        assert_eq!(session.progress().signed, 1);
+        for progress in &mut session {
+            progress.unwrap();
+        }
+        assert_eq!(
+            session.summary(),
+            SignSummary {
+                signed: vec![0, 2, 3],
+                skipped: vec![1],
+            }
+        );
+        // The first input is not signed again
+        assert_eq!(session.signer().signed, vec![2, 3]);
+        let saved = session.save(digest, |_, index| vec![index as u8]);
+        assert_eq!(saved.signed, vec![0, 2, 3]);
+
+        // Progress saved for different inputs is ignored
+        let session = SignSession::resume(RecordingSigner::default(), 4, &[1]);
+        assert_eq!(session.progress().signed, 0);
+    }
+}
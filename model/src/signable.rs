@@ -0,0 +1,122 @@
+// Portal Hardware Wallet firmware and supporting software libraries
+//
+// Copyright (C) 2024 Alekos Filini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Inputs of a PSBT that the wallet can sign
+//!
+//! An input can only be signed if one of its key origins has our fingerprint, and the script
+//! derived from our descriptors at that origin is the one it spends. The second check needs the
+//! descriptors, so it's left to the caller.
+
+use alloc::vec::Vec;
+
+use bitcoin::util::bip32::Fingerprint;
+use bitcoin::util::psbt::{Input, PartiallySignedTransaction};
+
+/// Whether one of the ECDSA or taproot key origins of `input` has `fingerprint`
+pub fn has_key_from(input: &Input, fingerprint: Fingerprint) -> bool {
+    input
+        .bip32_derivation
+        .values()
+        .any(|(f, _)| *f == fingerprint)
+        || input
+            .tap_key_origins
+            .values()
+            .any(|(_, (f, _))| *f == fingerprint)
+}
+
+/// Indices of the inputs that can be signed, without producing any signature
+///
+/// `derives_script` is only called for the inputs with a key from `fingerprint`, with their
+/// index, and tells whether our descriptors derive the script they spend.
+pub fn signable_inputs(
+    psbt: &PartiallySignedTransaction,
+    fingerprint: Fingerprint,
+    mut derives_script: impl FnMut(usize, &Input) -> bool,
+) -> Vec<usize> {
+    psbt.inputs
+        .iter()
+        .enumerate()
+        .filter(|(i, input)| has_key_from(input, fingerprint) && derives_script(*i, input))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+#[cfg(all(test, not(feature = "stm32")))]
+mod tests {
+    use super::*;
+    use crate::test_utils::coinjoin_psbt;
+
+    #[test]
+    fn test_signable_inputs() {
+        use bitcoin::secp256k1::{Secp256k1, SecretKey};
+        use bitcoin::util::bip32::{DerivationPath, Fingerprint};
+        use bitcoin::PublicKey;
+        use core::str::FromStr;
+        let secp = Secp256k1::new();
+        let key = PublicKey::new(
+            SecretKey::from_slice(&[0x01; 32])
+                .unwrap()
+                .public_key(&secp),
+        );
+        let ours = Fingerprint::from(&[0xAA; 4][..]);
+        let theirs = Fingerprint::from(&[0xBB; 4][..]);
+        let path = DerivationPath::from_str("m/84'/1'/0'/0/0").unwrap();
+
+        // Four inputs of the CoinJoin, built from two
+        let (mut psbt, _) = coinjoin_psbt(&[9_900]);
+        psbt.unsigned_tx
+            .input
+            .extend(psbt.unsigned_tx.input.clone());
+        psbt.inputs.extend(psbt.inputs.clone());
+
+        // Ours with an ECDSA key
+        psbt.inputs[0]
+            .bip32_derivation
+            .insert(key.inner, (ours, path.clone()));
+        // Someone else's
+        psbt.inputs[1]
+            .bip32_derivation
+            .insert(key.inner, (theirs, path.clone()));
+        // Ours with a taproot key, alongside a key of someone else
+        let (x_only, _) = key.inner.x_only_public_key();
+        psbt.inputs[2]
+            .tap_key_origins
+            .insert(x_only, (vec![], (ours, path.clone())));
+        psbt.inputs[2]
+            .bip32_derivation
+            .insert(key.inner, (theirs, path.clone()));
+        // Our key, but the script doesn't match our descriptors
+        psbt.inputs[3]
+            .bip32_derivation
+            .insert(key.inner, (ours, path));
+
+        let before = psbt.clone();
+        let mut checked = vec![];
+        let signable = signable_inputs(&psbt, ours, |i, _| {
+            checked.push(i);
+            i != 3
+        });
+        assert_eq!(signable, vec![0, 2]);
+        // The scripts are only derived for the inputs with our key
+        assert_eq!(checked, vec![0, 2, 3]);
+        assert_eq!(psbt, before);
+
+        assert!(has_key_from(&psbt.inputs[1], theirs));
+        assert!(!has_key_from(&psbt.inputs[0], theirs));
+        assert_eq!(signable_inputs(&psbt, theirs, |_, _| true), vec![1, 2]);
+    }
+}
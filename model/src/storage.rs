@@ -0,0 +1,1068 @@
+// Portal Hardware Wallet firmware and supporting software libraries
+//
+// Copyright (C) 2024 Alekos Filini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Format of the data stored in the flash pages of the device
+//!
+//! Every page starts with a header made of a format byte, the length of the payload and a CRC32
+//! of the payload, so that bit-rot is detected instead of being decoded as valid data. Pages
+//! written before the CRC was introduced only have the 2-byte big-endian length, they are still
+//! accepted when read.
+//!
+//! The logic built on top of the format only goes through the [`PageStorage`] trait, which the
+//! firmware implements on its flash.
+//!
+//! Frequently-updated records are spread over a [`FlashRing`] of pages to reduce wear, records
+//! that span multiple pages are written atomically with [`write_transaction`].
+
+use core::fmt;
+use core::ops::Range;
+
+use alloc::vec::Vec;
+
+pub const PAGE_SIZE: usize = 2048;
+
+/// First byte of a flash page written with a CRC
+///
+/// Pages in the legacy format start directly with the big-endian length, whose first
+/// byte is always smaller than `0x08`, so the two formats can't be confused.
+const FLASH_FORMAT_CRC: u8 = 0x81;
+/// Format byte, 2-byte length and 4-byte CRC of the payload
+pub const FLASH_HEADER_LEN: usize = 7;
+
+const SEQUENCE_LEN: usize = 4;
+const ERASED_SEQUENCE: u32 = 0xFFFF_FFFF;
+
+/// Pages of the spare bank used to stage the data of a transaction
+///
+/// Like [`TRANSACTION_COMMIT_PAGE`] they are part of
+/// [`RESERVED_PAGES`](crate::fw_manifest::RESERVED_PAGES), so a firmware update never writes
+/// over them.
+pub const TRANSACTION_STAGING_PAGES: Range<usize> = 251..254;
+/// Page of the spare bank that marks a staged transaction as committed
+pub const TRANSACTION_COMMIT_PAGE: usize = 254;
+/// Prefix of the commit marker, followed by the big-endian target page of each staged page
+const TRANSACTION_MAGIC: [u8; 4] = *b"TXN1";
+
+/// Pages in each flash bank
+pub const BANK_PAGES: usize = 256;
+
+/// Flash bank, relative to the one currently booted
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum BankStatus {
+    Active,
+    Spare,
+}
+
+/// Flash bank to target for a read/write/erase operation
+///
+/// **NOTE**: unfortunately the meaning of `Bank1` and `Bank2` is not always consistent
+/// in the code: specifically, when peforming an erase operation the `FlashBank` refers
+/// to the actual physical bank being erased, no matter what bank is booted at the moment.
+///
+/// When performing a read or write operation `Bank1` refers to the currently-booted bank,
+/// while `Bank2` refers to the spare bank. This is because the stm32l4xx-hal crate writes
+/// directly to the flash memory address, and when using dual bank boot the "current bank"
+/// is always mapped at 0x0000_0000 and 0x0800_0000, independently of which physical bank
+/// is backing it.
+///
+/// In short:
+///
+/// | API                       | `Bank1` means   |
+/// |---------------------------|-----------------|
+/// | [`physical_page_index`]   | physical bank 1 |
+/// | [`logical_page_index`]    | the booted bank |
+///
+/// To read a physical bank through the address space use [`mapped_page_index`], which accounts
+/// for the bank currently booted.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FlashBank {
+    Bank1,
+    Bank2,
+}
+
+impl FlashBank {
+    pub fn opposite(&self) -> Self {
+        match self {
+            FlashBank::Bank1 => FlashBank::Bank2,
+            FlashBank::Bank2 => FlashBank::Bank1,
+        }
+    }
+
+    /// The bank that is not currently booted
+    pub fn spare(fb_mode: bool) -> Self {
+        match fb_mode {
+            false => FlashBank::Bank2,
+            true => FlashBank::Bank1,
+        }
+    }
+
+    /// The physical bank that is `which`, given the `spare` physical bank
+    pub fn with_status(spare: FlashBank, which: BankStatus) -> Self {
+        match which {
+            BankStatus::Active => spare.opposite(),
+            BankStatus::Spare => spare,
+        }
+    }
+}
+
+/// Index of `page` of the physical `bank`, counting from the first page of `Bank1`
+pub fn physical_page_index(bank: FlashBank, page: usize) -> usize {
+    match bank {
+        FlashBank::Bank1 => page,
+        FlashBank::Bank2 => page + BANK_PAGES,
+    }
+}
+
+/// Index in the address space at which `page` of the physical `bank` is currently mapped
+pub fn mapped_page_index(fb_mode: bool, bank: FlashBank, page: usize) -> usize {
+    let mapped = match fb_mode {
+        false => bank,
+        true => bank.opposite(),
+    };
+    physical_page_index(mapped, page)
+}
+
+/// Index in the address space of `page` in the active or spare bank, which doesn't depend on the
+/// bank booted
+pub fn logical_page_index(which: BankStatus, page: usize) -> usize {
+    match which {
+        BankStatus::Active => page,
+        BankStatus::Spare => page + BANK_PAGES,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageError {
+    /// The page doesn't contain valid data
+    CorruptedData,
+    /// The data doesn't fit in the space available
+    TooBig,
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Raw access to the pages of both flash banks
+///
+/// Like on the real flash, a page must be erased before it's written again.
+pub trait PageStorage {
+    type Error: From<StorageError>;
+
+    fn read_page(
+        &mut self,
+        bank: BankStatus,
+        page: usize,
+        buf: &mut [u8; PAGE_SIZE],
+    ) -> Result<(), Self::Error>;
+
+    fn erase_page(&mut self, bank: BankStatus, page: usize) -> Result<(), Self::Error>;
+
+    fn write_page(
+        &mut self,
+        bank: BankStatus,
+        page: usize,
+        data: &[u8; PAGE_SIZE],
+    ) -> Result<(), Self::Error>;
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+
+    !crc
+}
+
+/// Prepend the header to the data that will be written to a flash page
+///
+/// The result is not padded to the page size.
+pub fn serialize_flash_page(serialized: &[u8]) -> Option<Vec<u8>> {
+    if serialized.len() > PAGE_SIZE - FLASH_HEADER_LEN {
+        return None;
+    }
+
+    let mut data = Vec::with_capacity(FLASH_HEADER_LEN + serialized.len());
+    data.push(FLASH_FORMAT_CRC);
+    data.extend_from_slice(&(serialized.len() as u16).to_be_bytes());
+    data.extend_from_slice(&crc32(serialized).to_be_bytes());
+    data.extend_from_slice(serialized);
+
+    Some(data)
+}
+
+/// Return the payload stored in a flash page, or `None` if the page is corrupted
+///
+/// Pages written before the introduction of the CRC are still accepted.
+pub fn parse_flash_page(page: &[u8]) -> Option<&[u8]> {
+    match *page.first()? {
+        FLASH_FORMAT_CRC => {
+            let len = u16::from_be_bytes(page.get(1..3)?.try_into().unwrap()) as usize;
+            let crc = u32::from_be_bytes(page.get(3..FLASH_HEADER_LEN)?.try_into().unwrap());
+            let payload = page.get(FLASH_HEADER_LEN..FLASH_HEADER_LEN + len)?;
+
+            if crc32(payload) == crc {
+                Some(payload)
+            } else {
+                None
+            }
+        }
+        _ => {
+            let len = u16::from_be_bytes(page.get(..2)?.try_into().unwrap()) as usize;
+            if len >= PAGE_SIZE - 2 {
+                return None;
+            }
+
+            page.get(2..2 + len)
+        }
+    }
+}
+
+/// Read the payload of `page`, or [`StorageError::CorruptedData`] if it's not valid
+pub fn read_payload<'b, S: PageStorage>(
+    storage: &mut S,
+    bank: BankStatus,
+    page: usize,
+    buf: &'b mut [u8; PAGE_SIZE],
+) -> Result<&'b [u8], S::Error> {
+    storage.read_page(bank, page, buf)?;
+    parse_flash_page(buf).ok_or_else(|| StorageError::CorruptedData.into())
+}
+
+/// Erase `page` and write `serialized` to it, padded with zeros
+pub fn write_payload<S: PageStorage>(
+    storage: &mut S,
+    bank: BankStatus,
+    page: usize,
+    serialized: &[u8],
+) -> Result<(), S::Error> {
+    let data = serialize_flash_page(serialized).ok_or(StorageError::TooBig)?;
+    let mut buf = [0x00; PAGE_SIZE];
+    buf[..data.len()].copy_from_slice(&data);
+
+    storage.erase_page(bank, page)?;
+    storage.write_page(bank, page, &buf)
+}
+
+/// Write a page only if its content differs from what is currently stored
+///
+/// The comparison is done on the parsed payload, so the length header, checksum and padding
+/// don't need to match byte for byte. A page that can't be parsed is always rewritten.
+///
+/// Returns whether the page was actually written.
+pub fn update_if_changed<S: PageStorage>(
+    storage: &mut S,
+    bank: BankStatus,
+    page: usize,
+    serialized: &[u8],
+) -> Result<bool, S::Error> {
+    let mut buf = [0u8; PAGE_SIZE];
+    if matches!(read_payload(storage, bank, page, &mut buf), Ok(current) if current == serialized) {
+        return Ok(false);
+    }
+
+    write_payload(storage, bank, page, serialized)?;
+    Ok(true)
+}
+
+/// A ring of pages of the active bank backing a single logical record
+///
+/// Every write goes to the page following the most recent one, so that frequently-updated
+/// data doesn't keep erasing the same page. Each page stores a big-endian sequence number
+/// at the start of the payload, the page with the highest sequence is the current one.
+///
+/// Pages that fail to parse (erased or with a bad CRC) or that are too short to contain a
+/// sequence number are ignored, which means that a write interrupted after the erase
+/// leaves the previous version readable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlashRing {
+    first_page: usize,
+    num_pages: usize,
+}
+
+impl FlashRing {
+    pub const fn new(first_page: usize, num_pages: usize) -> Self {
+        FlashRing {
+            first_page,
+            num_pages,
+        }
+    }
+
+    pub fn pages(&self) -> Range<usize> {
+        self.first_page..self.first_page + self.num_pages
+    }
+
+    fn next_page(&self, page: usize) -> usize {
+        self.first_page + (page - self.first_page + 1) % self.num_pages
+    }
+
+    fn newest<S: PageStorage>(
+        &self,
+        storage: &mut S,
+        buf: &mut [u8; PAGE_SIZE],
+    ) -> Option<(usize, u32)> {
+        self.pages()
+            .filter_map(|page| read_sequence(storage, page, buf).map(|seq| (page, seq)))
+            .max_by_key(|(_, seq)| *seq)
+    }
+
+    /// Read the newest version of the record
+    pub fn read<'b, S: PageStorage>(
+        &self,
+        storage: &mut S,
+        buf: &'b mut [u8; PAGE_SIZE],
+    ) -> Result<&'b [u8], S::Error> {
+        let (page, _) = self
+            .newest(storage, buf)
+            .ok_or(StorageError::CorruptedData)?;
+
+        let data = read_payload(storage, BankStatus::Active, page, buf)?;
+        Ok(&data[SEQUENCE_LEN..])
+    }
+
+    /// Write a new version of the record to the page after the newest one
+    pub fn write<S: PageStorage>(
+        &self,
+        storage: &mut S,
+        serialized: &[u8],
+    ) -> Result<(), S::Error> {
+        let mut buf = [0u8; PAGE_SIZE];
+        let (page, sequence) = match self.newest(storage, &mut buf) {
+            Some((page, seq)) => (self.next_page(page), seq + 1),
+            None => (self.first_page, 0),
+        };
+
+        let mut data = Vec::with_capacity(SEQUENCE_LEN + serialized.len());
+        data.extend_from_slice(&sequence.to_be_bytes());
+        data.extend_from_slice(serialized);
+
+        write_payload(storage, BankStatus::Active, page, &data)
+    }
+
+    /// Erase every page that still holds a version of the record
+    pub fn erase<S: PageStorage>(&self, storage: &mut S) -> Result<(), S::Error> {
+        let mut buf = [0u8; PAGE_SIZE];
+        for page in self.pages() {
+            if read_sequence(storage, page, &mut buf).is_some() {
+                storage.erase_page(BankStatus::Active, page)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reclaim the pages that only contain old versions of the record
+    ///
+    /// The newest version is first copied to the beginning of the ring with a higher sequence
+    /// number, then every other page still holding a version is erased. If this gets interrupted
+    /// the newest version is always readable from at least one page.
+    ///
+    /// Returns the number of pages freed.
+    pub fn compact<S: PageStorage>(&self, storage: &mut S) -> Result<usize, S::Error> {
+        let mut buf = [0u8; PAGE_SIZE];
+
+        let (newest, sequence) = match self.newest(storage, &mut buf) {
+            Some(v) => v,
+            None => return Ok(0),
+        };
+        if newest != self.first_page {
+            let mut data = read_payload(storage, BankStatus::Active, newest, &mut buf)?.to_vec();
+            data[..SEQUENCE_LEN].copy_from_slice(&(sequence + 1).to_be_bytes());
+            write_payload(storage, BankStatus::Active, self.first_page, &data)?;
+        }
+
+        let mut freed = 0;
+        for page in self.pages().skip(1) {
+            if read_sequence(storage, page, &mut buf).is_some() {
+                storage.erase_page(BankStatus::Active, page)?;
+                freed += 1;
+            }
+        }
+
+        Ok(freed)
+    }
+}
+
+fn read_sequence<S: PageStorage>(
+    storage: &mut S,
+    page: usize,
+    buf: &mut [u8; PAGE_SIZE],
+) -> Option<u32> {
+    let data = read_payload(storage, BankStatus::Active, page, buf).ok()?;
+    if data.len() < SEQUENCE_LEN {
+        return None;
+    }
+
+    match u32::from_be_bytes(data[..SEQUENCE_LEN].try_into().unwrap()) {
+        ERASED_SEQUENCE => None,
+        seq => Some(seq),
+    }
+}
+
+/// Atomically write multiple pages of the active bank
+///
+/// The data is first staged in the spare bank, then a commit marker listing the target pages
+/// is written. Only after that the pages are copied to their final location. If the device
+/// resets before the marker is written the old data is left untouched, otherwise
+/// [`recover_transaction`] will finish copying the staged pages at the next boot.
+pub fn write_transaction<S: PageStorage>(
+    storage: &mut S,
+    pages: &[(usize, &[u8])],
+) -> Result<(), S::Error> {
+    if pages.is_empty() {
+        return Ok(());
+    }
+    if pages.len() > TRANSACTION_STAGING_PAGES.len() {
+        return Err(StorageError::TooBig.into());
+    }
+
+    // A leftover marker must not point to the pages being staged
+    storage.erase_page(BankStatus::Spare, TRANSACTION_COMMIT_PAGE)?;
+    for ((_, data), staging_page) in pages.iter().zip(TRANSACTION_STAGING_PAGES) {
+        write_payload(storage, BankStatus::Spare, staging_page, data)?;
+    }
+
+    let marker = TRANSACTION_MAGIC
+        .into_iter()
+        .chain(
+            pages
+                .iter()
+                .flat_map(|(page, _)| (*page as u16).to_be_bytes()),
+        )
+        .collect::<Vec<_>>();
+    write_payload(storage, BankStatus::Spare, TRANSACTION_COMMIT_PAGE, &marker)?;
+
+    recover_transaction(storage)
+}
+
+/// Complete a transaction that was committed but not fully copied to its target pages
+///
+/// This is a no-op if there's no pending transaction and it's always safe to call again
+/// if it gets interrupted.
+pub fn recover_transaction<S: PageStorage>(storage: &mut S) -> Result<(), S::Error> {
+    let mut buf = [0u8; PAGE_SIZE];
+
+    let targets = match read_payload(
+        storage,
+        BankStatus::Spare,
+        TRANSACTION_COMMIT_PAGE,
+        &mut buf,
+    ) {
+        Ok(marker)
+            if marker.starts_with(&TRANSACTION_MAGIC)
+                && marker.len() > TRANSACTION_MAGIC.len()
+                && marker.len() % 2 == 0
+                && (marker.len() - TRANSACTION_MAGIC.len()) / 2
+                    <= TRANSACTION_STAGING_PAGES.len() =>
+        {
+            marker[TRANSACTION_MAGIC.len()..]
+                .chunks_exact(2)
+                .map(|v| u16::from_be_bytes(v.try_into().unwrap()) as usize)
+                .collect::<Vec<_>>()
+        }
+        _ => return Ok(()),
+    };
+
+    log::debug!("Completing flash transaction on pages {:?}", targets);
+
+    for (target, staging_page) in targets.iter().zip(TRANSACTION_STAGING_PAGES) {
+        let staged = read_payload(storage, BankStatus::Spare, staging_page, &mut buf)?.to_vec();
+        write_payload(storage, BankStatus::Active, *target, &staged)?;
+    }
+
+    let staging_pages = TRANSACTION_STAGING_PAGES.take(targets.len());
+    for page in core::iter::once(TRANSACTION_COMMIT_PAGE).chain(staging_pages) {
+        storage.erase_page(BankStatus::Spare, page)?;
+    }
+
+    Ok(())
+}
+
+/// Overwrite a page with random bytes and then erase it
+#[cfg(feature = "rng")]
+pub fn scrub_page<S: PageStorage>(
+    storage: &mut S,
+    bank: BankStatus,
+    page: usize,
+    rng: &mut impl rand_core::RngCore,
+) -> Result<(), S::Error> {
+    let mut noise = [0x00; PAGE_SIZE];
+    rng.fill_bytes(&mut noise);
+
+    storage.erase_page(bank, page)?;
+    storage.write_page(bank, page, &noise)?;
+    storage.erase_page(bank, page)
+}
+
+/// Discard any staged transaction, without completing it
+///
+/// The commit marker goes first, so that an interrupted discard can't be recovered either.
+#[cfg(feature = "rng")]
+pub fn scrub_transaction<S: PageStorage>(
+    storage: &mut S,
+    rng: &mut impl rand_core::RngCore,
+) -> Result<(), S::Error> {
+    for page in core::iter::once(TRANSACTION_COMMIT_PAGE).chain(TRANSACTION_STAGING_PAGES) {
+        scrub_page(storage, BankStatus::Spare, page, rng)?;
+    }
+
+    Ok(())
+}
+
+/// Remove every key from the flash: staged transactions, the config page of both banks and the
+/// pages of `aux_ring`
+///
+/// Staged transactions go first, since they may hold a copy of the config that would otherwise
+/// be restored at the next boot. The config is copied to the spare bank by firmware updates,
+/// so it's scrubbed in both banks. It's safe to call again if it gets interrupted.
+#[cfg(feature = "rng")]
+pub fn wipe_key_material<S: PageStorage>(
+    storage: &mut S,
+    config_page: usize,
+    aux_ring: &FlashRing,
+    rng: &mut impl rand_core::RngCore,
+) -> Result<(), S::Error> {
+    scrub_transaction(storage, rng)?;
+    scrub_page(storage, BankStatus::Active, config_page, rng)?;
+    scrub_page(storage, BankStatus::Spare, config_page, rng)?;
+    aux_ring.erase(storage)
+}
+
+#[cfg(all(test, not(feature = "stm32")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bank_to_flash_mapping() {
+        assert_eq!(FlashBank::spare(false), FlashBank::Bank2);
+        assert_eq!(FlashBank::spare(true), FlashBank::Bank1);
+
+        for fb_mode in [false, true] {
+            let spare = FlashBank::spare(fb_mode);
+            let booted = spare.opposite();
+            assert_eq!(FlashBank::with_status(spare, BankStatus::Active), booted);
+            assert_eq!(FlashBank::with_status(spare, BankStatus::Spare), spare);
+
+            for page in [0, 1, 250, BANK_PAGES - 1] {
+                let erased =
+                    |which| physical_page_index(FlashBank::with_status(spare, which), page);
+                let active = erased(BankStatus::Active);
+                let spare_page = erased(BankStatus::Spare);
+
+                // The page erased is the one that is then written through the address space
+                for which in [BankStatus::Active, BankStatus::Spare] {
+                    assert_eq!(
+                        mapped_page_index(fb_mode, FlashBank::with_status(spare, which), page),
+                        logical_page_index(which, page),
+                        "fb_mode {} {:?} page {}",
+                        fb_mode,
+                        which,
+                        page
+                    );
+                }
+
+                // Booted bank always mapped first
+                assert_eq!(mapped_page_index(fb_mode, booted, page), page);
+                assert_eq!(mapped_page_index(fb_mode, spare, page), page + BANK_PAGES);
+
+                // Never touch the booted bank when targeting the spare one
+                assert_ne!(active, spare_page);
+                assert_eq!(active % BANK_PAGES, page);
+                assert_eq!(spare_page % BANK_PAGES, page);
+                assert_eq!(spare_page < BANK_PAGES, spare == FlashBank::Bank1);
+                assert!(active < 2 * BANK_PAGES && spare_page < 2 * BANK_PAGES);
+            }
+        }
+    }
+
+    #[test]
+    fn test_flash_page_roundtrip() {
+        let mut page = serialize_flash_page(b"hello").unwrap();
+        assert_eq!(page.len(), FLASH_HEADER_LEN + 5);
+        page.resize(PAGE_SIZE, 0x00);
+        assert_eq!(parse_flash_page(&page), Some(b"hello".as_slice()));
+
+        let max = vec![0x42; PAGE_SIZE - FLASH_HEADER_LEN];
+        assert!(serialize_flash_page(&max).is_some());
+        assert!(serialize_flash_page(&[0x42; PAGE_SIZE - FLASH_HEADER_LEN + 1]).is_none());
+    }
+
+    #[test]
+    fn test_flash_page_legacy_format() {
+        let mut page = vec![0x00, 0x03, 0x01, 0x02, 0x03];
+        page.resize(PAGE_SIZE, 0x00);
+        assert_eq!(parse_flash_page(&page), Some([0x01, 0x02, 0x03].as_slice()));
+
+        // Erased page
+        assert_eq!(parse_flash_page(&[0xFF; PAGE_SIZE]), None);
+    }
+
+    #[test]
+    fn test_flash_page_byte_flip() {
+        let payload = (0..200u8).collect::<Vec<_>>();
+        let mut page = serialize_flash_page(&payload).unwrap();
+        let written = page.len();
+        page.resize(PAGE_SIZE, 0x00);
+
+        for i in 0..written {
+            let mut corrupted = page.clone();
+            corrupted[i] ^= 0xFF;
+            assert_eq!(parse_flash_page(&corrupted), None, "byte {} flipped", i);
+        }
+
+        // Every single-bit error after the format byte is caught by the CRC
+        for i in 1..written {
+            for bit in 0..8 {
+                let mut corrupted = page.clone();
+                corrupted[i] ^= 1 << bit;
+                assert_eq!(
+                    parse_flash_page(&corrupted),
+                    None,
+                    "bit {} of byte {} flipped",
+                    bit,
+                    i
+                );
+            }
+        }
+    }
+
+    /// Both banks of the flash in memory, optionally failing after a number of operations
+    struct MemFlash {
+        pages: std::collections::BTreeMap<(BankStatus, usize), [u8; PAGE_SIZE]>,
+        erases: usize,
+        writes: usize,
+        /// Erases and writes that succeed before the flash starts failing, like a power loss
+        ops_left: Option<usize>,
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum MemFlashError {
+        Storage(StorageError),
+        PowerLoss,
+    }
+
+    impl From<StorageError> for MemFlashError {
+        fn from(e: StorageError) -> Self {
+            MemFlashError::Storage(e)
+        }
+    }
+
+    impl MemFlash {
+        fn new() -> Self {
+            MemFlash {
+                pages: Default::default(),
+                erases: 0,
+                writes: 0,
+                ops_left: None,
+            }
+        }
+
+        fn take_op(&mut self) -> Result<(), MemFlashError> {
+            match &mut self.ops_left {
+                Some(0) => Err(MemFlashError::PowerLoss),
+                Some(left) => {
+                    *left -= 1;
+                    Ok(())
+                }
+                None => Ok(()),
+            }
+        }
+    }
+
+    impl PageStorage for MemFlash {
+        type Error = MemFlashError;
+
+        fn read_page(
+            &mut self,
+            bank: BankStatus,
+            page: usize,
+            buf: &mut [u8; PAGE_SIZE],
+        ) -> Result<(), MemFlashError> {
+            *buf = self
+                .pages
+                .get(&(bank, page))
+                .copied()
+                .unwrap_or([0xFF; PAGE_SIZE]);
+            Ok(())
+        }
+
+        fn erase_page(&mut self, bank: BankStatus, page: usize) -> Result<(), MemFlashError> {
+            self.take_op()?;
+            self.erases += 1;
+            self.pages.remove(&(bank, page));
+            Ok(())
+        }
+
+        fn write_page(
+            &mut self,
+            bank: BankStatus,
+            page: usize,
+            data: &[u8; PAGE_SIZE],
+        ) -> Result<(), MemFlashError> {
+            self.take_op()?;
+            self.writes += 1;
+            // Programming can only clear bits
+            let current = self.pages.entry((bank, page)).or_insert([0xFF; PAGE_SIZE]);
+            for (byte, new) in current.iter_mut().zip(data) {
+                *byte &= new;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_update_if_changed() {
+        let mut flash = MemFlash::new();
+        assert_eq!(
+            update_if_changed(&mut flash, BankStatus::Active, 250, b"settings"),
+            Ok(true)
+        );
+        assert_eq!((flash.erases, flash.writes), (1, 1));
+
+        // Identical data doesn't erase nor write the page
+        assert_eq!(
+            update_if_changed(&mut flash, BankStatus::Active, 250, b"settings"),
+            Ok(false)
+        );
+        assert_eq!((flash.erases, flash.writes), (1, 1));
+
+        // Same payload in the other bank is a different page
+        assert_eq!(
+            update_if_changed(&mut flash, BankStatus::Spare, 250, b"settings"),
+            Ok(true)
+        );
+        assert_eq!((flash.erases, flash.writes), (2, 2));
+
+        assert_eq!(
+            update_if_changed(&mut flash, BankStatus::Active, 250, b"changed"),
+            Ok(true)
+        );
+        assert_eq!((flash.erases, flash.writes), (3, 3));
+        let mut buf = [0u8; PAGE_SIZE];
+        assert_eq!(
+            read_payload(&mut flash, BankStatus::Active, 250, &mut buf),
+            Ok(b"changed".as_slice())
+        );
+
+        // A legacy page with the same payload is not rewritten either
+        let mut legacy = [0x00; PAGE_SIZE];
+        legacy[..4].copy_from_slice(&[0x00, 0x02, 0xAA, 0xBB]);
+        PageStorage::erase_page(&mut flash, BankStatus::Active, 251).unwrap();
+        PageStorage::write_page(&mut flash, BankStatus::Active, 251, &legacy).unwrap();
+        let (erases, writes) = (flash.erases, flash.writes);
+        assert_eq!(
+            update_if_changed(&mut flash, BankStatus::Active, 251, &[0xAA, 0xBB]),
+            Ok(false)
+        );
+        assert_eq!((flash.erases, flash.writes), (erases, writes));
+
+        // A corrupted page is always rewritten
+        write_payload(&mut flash, BankStatus::Active, 252, b"data").unwrap();
+        flash.pages.get_mut(&(BankStatus::Active, 252)).unwrap()[10] ^= 0x01;
+        assert_eq!(
+            update_if_changed(&mut flash, BankStatus::Active, 252, b"data"),
+            Ok(true)
+        );
+    }
+
+    #[test]
+    fn test_flash_ring_rotation() {
+        let ring = FlashRing::new(251, 4);
+        let mut flash = MemFlash::new();
+        let mut buf = [0u8; PAGE_SIZE];
+        assert!(ring.read(&mut flash, &mut buf).is_err());
+
+        for i in 0..10u8 {
+            ring.write(&mut flash, &[i]).unwrap();
+            assert_eq!(ring.read(&mut flash, &mut buf), Ok([i].as_slice()));
+        }
+        // Only the pages of the ring are used
+        assert_eq!(flash.erases, 10);
+        assert_eq!(flash.pages.len(), 4);
+        assert!(flash
+            .pages
+            .keys()
+            .all(|(bank, page)| *bank == BankStatus::Active && ring.pages().contains(page)));
+
+        // An erased page in the middle of the ring doesn't reset the sequence
+        flash.erase_page(BankStatus::Active, 252).unwrap();
+        ring.write(&mut flash, &[0xAA]).unwrap();
+        assert_eq!(ring.read(&mut flash, &mut buf), Ok([0xAA].as_slice()));
+    }
+
+    #[test]
+    fn test_flash_ring_power_loss() {
+        let ring = FlashRing::new(251, 4);
+        let mut buf = [0u8; PAGE_SIZE];
+
+        // Power lost after the erase, before the write
+        let mut flash = MemFlash::new();
+        ring.write(&mut flash, b"old").unwrap();
+        flash.ops_left = Some(1);
+        assert_eq!(
+            ring.write(&mut flash, b"new"),
+            Err(MemFlashError::PowerLoss)
+        );
+        assert_eq!(ring.read(&mut flash, &mut buf), Ok(b"old".as_slice()));
+
+        // Power lost in the middle of the write, leaving the header but only part of the payload
+        let mut flash = MemFlash::new();
+        ring.write(&mut flash, b"old").unwrap();
+        ring.write(&mut flash, b"new").unwrap();
+        let half_written = flash.pages.get_mut(&(BankStatus::Active, 252)).unwrap();
+        half_written[12..].fill(0xFF);
+        assert_eq!(ring.read(&mut flash, &mut buf), Ok(b"old".as_slice()));
+        // The next write goes after the valid version
+        ring.write(&mut flash, b"newer").unwrap();
+        assert_eq!(ring.read(&mut flash, &mut buf), Ok(b"newer".as_slice()));
+    }
+
+    #[test]
+    fn test_flash_ring_compact() {
+        let ring = FlashRing::new(251, 4);
+        let mut flash = MemFlash::new();
+        let mut buf = [0u8; PAGE_SIZE];
+        assert_eq!(ring.compact(&mut flash), Ok(0));
+
+        for i in 0..6u8 {
+            ring.write(&mut flash, &[i]).unwrap();
+        }
+        assert_eq!(ring.compact(&mut flash), Ok(3));
+        assert_eq!(ring.read(&mut flash, &mut buf), Ok([5].as_slice()));
+        assert_eq!(
+            flash.pages.keys().collect::<Vec<_>>(),
+            vec![&(BankStatus::Active, 251)]
+        );
+
+        // Nothing left to free, and the newest version is already at the start of the ring
+        let erases = flash.erases;
+        assert_eq!(ring.compact(&mut flash), Ok(0));
+        assert_eq!(flash.erases, erases);
+
+        // Writes keep going after compaction
+        ring.write(&mut flash, &[6]).unwrap();
+        assert_eq!(ring.read(&mut flash, &mut buf), Ok([6].as_slice()));
+    }
+
+    #[test]
+    fn test_flash_ring_compact_interrupted() {
+        let ring = FlashRing::new(251, 4);
+        let mut buf = [0u8; PAGE_SIZE];
+
+        for ops in 0.. {
+            let mut flash = MemFlash::new();
+            for i in 0..7u8 {
+                ring.write(&mut flash, &[i]).unwrap();
+            }
+
+            flash.ops_left = Some(ops);
+            let result = ring.compact(&mut flash);
+            flash.ops_left = None;
+            assert_eq!(
+                ring.read(&mut flash, &mut buf),
+                Ok([6].as_slice()),
+                "{} ops",
+                ops
+            );
+
+            // Compacting again after the reboot finishes the job
+            ring.compact(&mut flash).unwrap();
+            assert_eq!(flash.pages.len(), 1);
+            assert_eq!(ring.read(&mut flash, &mut buf), Ok([6].as_slice()));
+
+            if result.is_ok() {
+                break;
+            }
+            assert_eq!(result, Err(MemFlashError::PowerLoss));
+        }
+    }
+
+    #[test]
+    fn test_transaction_pages_reserved() {
+        use crate::fw_manifest::{validate_fw_size, RESERVED_PAGES};
+        // The biggest image accepted ends before the staging pages
+        let last_fw_page = (0..)
+            .take_while(|p| validate_fw_size(p + 1).is_ok())
+            .last()
+            .unwrap();
+        assert!(last_fw_page < TRANSACTION_STAGING_PAGES.start);
+        assert!(TRANSACTION_STAGING_PAGES
+            .clone()
+            .all(|p| RESERVED_PAGES.contains(&p)));
+        assert!(RESERVED_PAGES.contains(&TRANSACTION_COMMIT_PAGE));
+        assert!(!TRANSACTION_STAGING_PAGES.contains(&TRANSACTION_COMMIT_PAGE));
+    }
+
+    #[test]
+    fn test_transaction() {
+        let mut flash = MemFlash::new();
+        let mut buf = [0u8; PAGE_SIZE];
+        write_transaction(&mut flash, &[(10, b"first"), (11, b"second")]).unwrap();
+        assert_eq!(
+            read_payload(&mut flash, BankStatus::Active, 10, &mut buf),
+            Ok(b"first".as_slice())
+        );
+        assert_eq!(
+            read_payload(&mut flash, BankStatus::Active, 11, &mut buf),
+            Ok(b"second".as_slice())
+        );
+        // Nothing is left in the spare bank
+        assert!(flash
+            .pages
+            .keys()
+            .all(|(bank, _)| *bank == BankStatus::Active));
+
+        assert_eq!(write_transaction(&mut flash, &[]), Ok(()));
+        assert_eq!(
+            write_transaction(&mut flash, &[(1, b"a"), (2, b"b"), (3, b"c"), (4, b"d")]),
+            Err(MemFlashError::Storage(StorageError::TooBig))
+        );
+    }
+
+    #[test]
+    fn test_transaction_power_loss() {
+        let old: [(usize, &[u8]); 3] =
+            [(10, b"old config 1"), (11, b"old config 2"), (12, b"old 3")];
+        let new: [(usize, &[u8]); 3] =
+            [(10, b"new config 1"), (11, b"new 2"), (12, b"new config 3")];
+        let mut buf = [0u8; PAGE_SIZE];
+
+        let mut saw_old = false;
+        for ops in 0.. {
+            let mut flash = MemFlash::new();
+            for (page, data) in old {
+                write_payload(&mut flash, BankStatus::Active, page, data).unwrap();
+            }
+
+            flash.ops_left = Some(ops);
+            let result = write_transaction(&mut flash, &new);
+            flash.ops_left = None;
+
+            // Reboot
+            recover_transaction(&mut flash).unwrap();
+            let current = old
+                .iter()
+                .map(|(page, _)| {
+                    read_payload(&mut flash, BankStatus::Active, *page, &mut buf)
+                        .unwrap()
+                        .to_vec()
+                })
+                .collect::<Vec<_>>();
+            let is_state = |state: &[(usize, &[u8])]| {
+                current
+                    .iter()
+                    .zip(state)
+                    .all(|(c, (_, data))| c.as_slice() == *data)
+            };
+
+            match result {
+                Ok(()) => {
+                    assert!(is_state(&new));
+                    break;
+                }
+                Err(e) => {
+                    assert_eq!(e, MemFlashError::PowerLoss);
+                    assert!(
+                        is_state(&old) || is_state(&new),
+                        "mixed state after {} ops",
+                        ops
+                    );
+                    saw_old |= is_state(&old);
+                }
+            }
+        }
+        assert!(saw_old);
+    }
+
+    #[cfg(feature = "rng")]
+    #[test]
+    fn test_wipe_key_material() {
+        use rand_chacha::rand_core::SeedableRng;
+
+        const CONFIG_PAGE: usize = 255;
+        const SETTINGS_PAGE: usize = 250;
+        let ring = FlashRing::new(251, 4);
+
+        let populated = || {
+            let mut flash = MemFlash::new();
+            write_payload(&mut flash, BankStatus::Active, SETTINGS_PAGE, b"settings").unwrap();
+            write_payload(&mut flash, BankStatus::Active, CONFIG_PAGE, b"seed").unwrap();
+            write_payload(&mut flash, BankStatus::Spare, CONFIG_PAGE, b"seed").unwrap();
+            for _ in 0..3 {
+                ring.write(&mut flash, b"aux").unwrap();
+            }
+            // A transaction committed but not copied yet
+            flash.ops_left = Some(5);
+            assert!(write_transaction(&mut flash, &[(CONFIG_PAGE, b"new seed")]).is_err());
+            assert!(flash.pages.contains_key(&(BankStatus::Spare, 254)));
+            flash.ops_left = None;
+            flash
+        };
+        let wiped = [
+            (BankStatus::Active, CONFIG_PAGE),
+            (BankStatus::Spare, CONFIG_PAGE),
+            (BankStatus::Active, 251),
+            (BankStatus::Active, 252),
+            (BankStatus::Active, 253),
+            (BankStatus::Active, 254),
+            (BankStatus::Spare, 251),
+            (BankStatus::Spare, 252),
+            (BankStatus::Spare, 253),
+            (BankStatus::Spare, 254),
+        ];
+        let assert_empty = |flash: &mut MemFlash| {
+            let mut buf = [0u8; PAGE_SIZE];
+            for (bank, page) in wiped {
+                flash.read_page(bank, page, &mut buf).unwrap();
+                assert_eq!(buf, [0xFF; PAGE_SIZE], "{:?} page {}", bank, page);
+            }
+            assert_eq!(
+                read_payload(flash, BankStatus::Active, SETTINGS_PAGE, &mut buf),
+                Ok(b"settings".as_slice())
+            );
+        };
+
+        let mut rng = rand_chacha::ChaCha20Rng::seed_from_u64(0);
+        let mut flash = populated();
+        let writes = flash.writes;
+        wipe_key_material(&mut flash, CONFIG_PAGE, &ring, &mut rng).unwrap();
+        assert_empty(&mut flash);
+        // The config and the staged pages were overwritten with noise before being erased
+        assert_eq!(flash.writes - writes, 6);
+
+        // The wipe is completed at the next boot if it gets interrupted
+        for ops in 0..12 {
+            let mut flash = populated();
+            flash.ops_left = Some(ops);
+            assert_eq!(
+                wipe_key_material(&mut flash, CONFIG_PAGE, &ring, &mut rng),
+                Err(MemFlashError::PowerLoss)
+            );
+            flash.ops_left = None;
+
+            wipe_key_material(&mut flash, CONFIG_PAGE, &ring, &mut rng).unwrap();
+            assert_empty(&mut flash);
+        }
+    }
+}
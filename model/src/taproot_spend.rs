@@ -0,0 +1,145 @@
+// Portal Hardware Wallet firmware and supporting software libraries
+//
+// Copyright (C) 2024 Alekos Filini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Spending paths signed for taproot inputs
+//!
+//! When we control both the internal key and a script leaf of an input, signing both is
+//! wasteful: the key-path spend is always cheaper. The [`TaprootSpendPolicy`] picks which
+//! signatures are kept after an input is signed, signatures that were already in the PSBT
+//! before, like the ones of other parties, are never removed.
+
+use bitcoin::util::psbt::Input;
+
+/// Which spending paths to sign for taproot inputs where we control both the internal key and a
+/// script
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TaprootSpendPolicy {
+    /// Only sign with the internal key, the cheapest way to spend
+    KeyPathOnly,
+    /// Only sign the script leaves
+    ScriptPathOnly,
+    #[default]
+    Both,
+}
+
+impl TaprootSpendPolicy {
+    pub fn signs_key_path(self) -> bool {
+        !matches!(self, TaprootSpendPolicy::ScriptPathOnly)
+    }
+
+    pub fn signs_script_path(self) -> bool {
+        !matches!(self, TaprootSpendPolicy::KeyPathOnly)
+    }
+
+    /// Drop the signatures added to `signed` since `before` for the paths excluded by the policy
+    pub fn filter_new_signatures(self, before: &Input, signed: &mut Input) {
+        if !self.signs_key_path() {
+            signed.tap_key_sig = before.tap_key_sig;
+        }
+        if !self.signs_script_path() {
+            signed
+                .tap_script_sigs
+                .retain(|k, _| before.tap_script_sigs.contains_key(k));
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "stm32")))]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+
+    /// An input signed by a cosigner for `leaf`, then by us with both the internal key and `leaf`
+    fn taproot_signed_input() -> (
+        bitcoin::util::psbt::Input,
+        bitcoin::util::psbt::Input,
+        bitcoin::XOnlyPublicKey,
+        bitcoin::XOnlyPublicKey,
+    ) {
+        use bitcoin::secp256k1::{schnorr, Secp256k1, SecretKey};
+        use bitcoin::util::schnorr::SchnorrSig;
+        use bitcoin::util::taproot::TapLeafHash;
+        use bitcoin::{SchnorrSighashType, XOnlyPublicKey};
+
+        let secp = Secp256k1::new();
+        let key = |byte| {
+            XOnlyPublicKey::from_keypair(&bitcoin::KeyPair::from_secret_key(
+                &secp,
+                &SecretKey::from_slice(&[byte; 32]).unwrap(),
+            ))
+            .0
+        };
+        let sig = |byte| SchnorrSig {
+            sig: schnorr::Signature::from_slice(&[byte; 64]).unwrap(),
+            hash_ty: SchnorrSighashType::Default,
+        };
+        let leaf = TapLeafHash::from_inner([0x42; 32]);
+        let (ours, cosigner) = (key(0x01), key(0x02));
+
+        let mut before = bitcoin::util::psbt::Input::default();
+        before.tap_script_sigs.insert((cosigner, leaf), sig(0x02));
+
+        let mut signed = before.clone();
+        signed.tap_key_sig = Some(sig(0x01));
+        signed.tap_script_sigs.insert((ours, leaf), sig(0x03));
+
+        (before, signed, ours, cosigner)
+    }
+
+    #[test]
+    fn test_taproot_spend_policy_key_path_only() {
+        let (before, mut signed, ours, cosigner) = taproot_signed_input();
+        TaprootSpendPolicy::KeyPathOnly.filter_new_signatures(&before, &mut signed);
+
+        assert!(signed.tap_key_sig.is_some());
+        let keys = signed
+            .tap_script_sigs
+            .keys()
+            .map(|(k, _)| *k)
+            .collect::<Vec<_>>();
+        assert!(!keys.contains(&ours));
+        // Signatures of other parties are left alone
+        assert_eq!(keys, vec![cosigner]);
+    }
+
+    #[test]
+    fn test_taproot_spend_policy_script_path_only() {
+        let (before, mut signed, ours, cosigner) = taproot_signed_input();
+        TaprootSpendPolicy::ScriptPathOnly.filter_new_signatures(&before, &mut signed);
+
+        assert!(signed.tap_key_sig.is_none());
+        let mut keys = signed
+            .tap_script_sigs
+            .keys()
+            .map(|(k, _)| *k)
+            .collect::<Vec<_>>();
+        keys.sort();
+        let mut expected = vec![ours, cosigner];
+        expected.sort();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn test_taproot_spend_policy_both() {
+        assert_eq!(TaprootSpendPolicy::default(), TaprootSpendPolicy::Both);
+
+        let (before, mut signed, _, _) = taproot_signed_input();
+        let expected = signed.clone();
+        TaprootSpendPolicy::Both.filter_new_signatures(&before, &mut signed);
+        assert_eq!(signed, expected);
+    }
+}
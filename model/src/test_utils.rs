@@ -0,0 +1,81 @@
+// Portal Hardware Wallet firmware and supporting software libraries
+//
+// Copyright (C) 2024 Alekos Filini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Fixtures shared by the tests of several modules
+
+/// A PSBT spending the first output of a transaction with a single 10k sat output
+pub fn spending_psbt() -> (
+    bitcoin::util::psbt::PartiallySignedTransaction,
+    bitcoin::Transaction,
+) {
+    use bitcoin::{OutPoint, PackedLockTime, Script, Transaction, TxIn, TxOut};
+
+    let prev_tx = Transaction {
+        version: 2,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn::default()],
+        output: vec![TxOut {
+            value: 10_000,
+            script_pubkey: Script::from(vec![0x00, 0x14, 0xAA]),
+        }],
+    };
+    let tx = Transaction {
+        version: 2,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: OutPoint::new(prev_tx.txid(), 0),
+            ..Default::default()
+        }],
+        output: vec![],
+    };
+    let psbt = bitcoin::util::psbt::PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+
+    (psbt, prev_tx)
+}
+
+/// A CoinJoin with one input of ours and one of someone else, 10k sat each, paying `outputs`
+pub fn coinjoin_psbt(
+    outputs: &[u64],
+) -> (
+    bitcoin::util::psbt::PartiallySignedTransaction,
+    Vec<bitcoin::TxOut>,
+) {
+    use bitcoin::{PackedLockTime, Script, Transaction, TxIn, TxOut};
+
+    let tx = Transaction {
+        version: 2,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn::default(), TxIn::default()],
+        output: outputs
+            .iter()
+            .map(|value| TxOut {
+                value: *value,
+                script_pubkey: Script::new(),
+            })
+            .collect(),
+    };
+    let prev_utxos = vec![
+        TxOut {
+            value: 10_000,
+            script_pubkey: Script::new(),
+        };
+        2
+    ];
+    let psbt = bitcoin::util::psbt::PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+
+    (psbt, prev_utxos)
+}
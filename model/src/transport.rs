@@ -0,0 +1,117 @@
+// Portal Hardware Wallet firmware and supporting software libraries
+//
+// Copyright (C) 2024 Alekos Filini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Link between the request handlers and whatever carries the messages to the host
+//!
+//! On the device the requests arrive over NFC and are passed to the handlers through rtic
+//! channels. The handlers only see a [`Transport`], so the same logic can be driven over a
+//! [`MpscTransport`] on std.
+
+use crate::{Reply, Request};
+
+/// The other end of the transport is gone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Disconnected;
+
+/// Both methods take `&self`: the handlers keep waiting for the next request in their event
+/// stream while they send the replies
+// Transports are only used from single-threaded executors, so there's no need for the futures
+// to be `Send`
+#[allow(async_fn_in_trait)]
+pub trait Transport {
+    /// Wait for the next request from the host
+    async fn recv_request(&self) -> Result<Request, Disconnected>;
+    async fn send_reply(&self, reply: Reply) -> Result<(), Disconnected>;
+}
+
+/// Transport over async channels, for std
+#[cfg(not(feature = "stm32"))]
+pub struct MpscTransport {
+    requests: async_channel::Receiver<Request>,
+    replies: async_channel::Sender<Reply>,
+}
+
+#[cfg(not(feature = "stm32"))]
+impl MpscTransport {
+    pub fn new(
+        requests: async_channel::Receiver<Request>,
+        replies: async_channel::Sender<Reply>,
+    ) -> Self {
+        MpscTransport { requests, replies }
+    }
+}
+
+#[cfg(not(feature = "stm32"))]
+impl Transport for MpscTransport {
+    async fn recv_request(&self) -> Result<Request, Disconnected> {
+        self.requests.recv().await.map_err(|_| Disconnected)
+    }
+
+    async fn send_reply(&self, reply: Reply) -> Result<(), Disconnected> {
+        self.replies.send(reply).await.map_err(|_| Disconnected)
+    }
+}
+
+#[cfg(all(test, not(feature = "stm32")))]
+mod tests {
+    use super::*;
+
+    struct NoopWaker;
+    impl std::task::Wake for NoopWaker {
+        fn wake(self: std::sync::Arc<Self>) {}
+    }
+
+    fn block_on<F: core::future::Future>(future: F) -> F::Output {
+        let waker = std::sync::Arc::new(NoopWaker).into();
+        let mut cx = core::task::Context::from_waker(&waker);
+        let mut future = core::pin::pin!(future);
+        loop {
+            if let core::task::Poll::Ready(v) = future.as_mut().poll(&mut cx) {
+                return v;
+            }
+        }
+    }
+
+    #[test]
+    fn test_mpsc_transport() {
+        use core::future::Future;
+        let (request_sender, request_receiver) = async_channel::bounded(1);
+        let (reply_sender, reply_receiver) = async_channel::bounded(1);
+        let transport = MpscTransport::new(request_receiver, reply_sender);
+
+        // Like in the event stream of the handlers, the request is awaited while the replies
+        // are sent
+        let mut next_request = core::pin::pin!(transport.recv_request());
+        let waker = std::sync::Arc::new(NoopWaker).into();
+        let mut cx = core::task::Context::from_waker(&waker);
+        assert!(next_request.as_mut().poll(&mut cx).is_pending());
+
+        block_on(transport.send_reply(Reply::DelayedReply)).unwrap();
+        assert!(matches!(reply_receiver.try_recv(), Ok(Reply::DelayedReply)));
+
+        // The host is on another thread
+        let host = std::thread::spawn(move || {
+            block_on(request_sender.send(Request::Cancel)).unwrap();
+        });
+        assert!(matches!(block_on(next_request), Ok(Request::Cancel)));
+        host.join().unwrap();
+
+        assert_eq!(block_on(transport.recv_request()).err(), Some(Disconnected));
+        core::mem::drop(reply_receiver);
+        assert_eq!(block_on(transport.send_reply(Reply::Ok)), Err(Disconnected));
+    }
+}
@@ -0,0 +1,431 @@
+// Portal Hardware Wallet firmware and supporting software libraries
+//
+// Copyright (C) 2024 Alekos Filini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Touch sensing logic, independent from the hardware
+//!
+//! The TSC count drops when the sensor is touched. How much depends on the unit and on the
+//! environment, so instead of a fixed threshold the firmware samples the untouched count at
+//! boot and derives a [`Calibration`] from it.
+//!
+//! The touched/untouched samples are then turned into [`Gesture`]s by a [`GestureDetector`].
+//!
+//! Boards with several channels laid out as a slider can also compute the
+//! [`slider_position`] of the finger. The current Portal hardware has a single channel.
+
+use core::fmt;
+
+use minicbor::{Decode, Encode};
+
+/// Number of acquisitions sampled to calibrate the sensor
+pub const CALIBRATION_SAMPLES: usize = 32;
+/// Minimum drop from the baseline to register a touch, in percent of the baseline
+pub const MIN_TOUCH_DROP_PERCENT: u32 = 10;
+/// Calibration is refused if the samples spread more than this, in percent of the baseline,
+/// which usually means that the sensor was touched while sampling
+pub const MAX_BASELINE_SPREAD_PERCENT: u32 = 5;
+/// Margin kept above the noise, as a multiple of the spread of the baseline samples
+const NOISE_MARGIN: u32 = 3;
+
+/// Touches shorter than this are ignored as noise
+pub const DEBOUNCE_MS: u64 = 30;
+/// Touches held at least this long are long presses
+pub const LONG_PRESS_MS: u64 = 800;
+/// Maximum time between the release of a tap and the following touch to make a double tap
+pub const DOUBLE_TAP_WINDOW_MS: u64 = 300;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalibrationError {
+    NotEnoughSamples,
+    /// The samples are all zero, the acquisitions didn't complete
+    NoSignal,
+    /// The samples spread more than [`MAX_BASELINE_SPREAD_PERCENT`]
+    Unstable,
+}
+
+impl fmt::Display for CalibrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub struct Calibration {
+    /// Average count while untouched
+    #[cbor(n(0))]
+    pub baseline: u16,
+    /// Counts below this are touches
+    #[cbor(n(1))]
+    pub threshold: u16,
+}
+
+impl Calibration {
+    /// Compute the threshold from at least [`CALIBRATION_SAMPLES`] untouched counts
+    pub fn from_samples(samples: &[u16]) -> Result<Self, CalibrationError> {
+        if samples.len() < CALIBRATION_SAMPLES {
+            return Err(CalibrationError::NotEnoughSamples);
+        }
+
+        let sum: u32 = samples.iter().map(|s| *s as u32).sum();
+        let baseline = sum / samples.len() as u32;
+        if baseline == 0 {
+            return Err(CalibrationError::NoSignal);
+        }
+
+        let min = *samples.iter().min().expect("Not empty") as u32;
+        let max = *samples.iter().max().expect("Not empty") as u32;
+        let spread = max - min;
+        if spread * 100 > baseline * MAX_BASELINE_SPREAD_PERCENT {
+            return Err(CalibrationError::Unstable);
+        }
+
+        let drop = (baseline * MIN_TOUCH_DROP_PERCENT / 100).max(spread * NOISE_MARGIN);
+        Ok(Calibration {
+            baseline: baseline as u16,
+            threshold: (baseline - drop) as u16,
+        })
+    }
+
+    pub fn is_touched(&self, count: u16) -> bool {
+        count < self.threshold
+    }
+}
+
+/// Position of the finger along a slider, from 0 at the first channel to 255 at the last one
+///
+/// `counts` and `calibrations` are given in the order the channels are laid out. The position is
+/// the centroid of how far each count dropped below its baseline, so a finger between two pads
+/// gives an intermediate value. Returns `None` if no channel is touched or the slider has fewer
+/// than two channels.
+pub fn slider_position(counts: &[u16], calibrations: &[Calibration]) -> Option<u8> {
+    if counts.len() < 2 || counts.len() != calibrations.len() {
+        return None;
+    }
+    if !counts
+        .iter()
+        .zip(calibrations)
+        .any(|(count, calibration)| calibration.is_touched(*count))
+    {
+        return None;
+    }
+
+    let (weighted, total) = counts.iter().zip(calibrations).enumerate().fold(
+        (0u64, 0u64),
+        |(weighted, total), (i, (count, calibration))| {
+            let signal = calibration.baseline.saturating_sub(*count) as u64;
+            (weighted + i as u64 * signal, total + signal)
+        },
+    );
+
+    let last = (counts.len() - 1) as u64;
+    Some(((weighted * u8::MAX as u64 + total * last / 2) / (total * last)) as u8)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gesture {
+    Tap,
+    DoubleTap,
+    LongPress,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum GestureState {
+    #[default]
+    Idle,
+    Pressed {
+        since: u64,
+    },
+    /// A tap was released, waiting to see if a second one follows
+    Released {
+        at: u64,
+    },
+    SecondPress {
+        since: u64,
+        first_released_at: u64,
+    },
+    /// The long press was already reported, waiting for the release
+    LongPressed,
+}
+
+/// State machine recognizing gestures from timestamped touch samples
+///
+/// Samples are expected to arrive regularly, also while the sensor is not touched, since a tap
+/// is only reported once the double tap window has expired.
+#[derive(Debug, Clone, Default)]
+pub struct GestureDetector {
+    state: GestureState,
+}
+
+impl GestureDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn reset(&mut self) {
+        self.state = GestureState::Idle;
+    }
+
+    /// Feed a sample, `now` is a timestamp in milliseconds from any monotonic clock
+    pub fn push(&mut self, touched: bool, now: u64) -> Option<Gesture> {
+        let (state, gesture) = match (self.state, touched) {
+            (GestureState::Idle, false) => (GestureState::Idle, None),
+            (GestureState::Idle, true) => (GestureState::Pressed { since: now }, None),
+
+            (GestureState::Pressed { since }, true)
+                if now.saturating_sub(since) >= LONG_PRESS_MS =>
+            {
+                (GestureState::LongPressed, Some(Gesture::LongPress))
+            }
+            (GestureState::Pressed { .. }, true) => (self.state, None),
+            (GestureState::Pressed { since }, false) if now.saturating_sub(since) < DEBOUNCE_MS => {
+                (GestureState::Idle, None)
+            }
+            (GestureState::Pressed { .. }, false) => (GestureState::Released { at: now }, None),
+
+            (GestureState::Released { at }, _) if now.saturating_sub(at) > DOUBLE_TAP_WINDOW_MS => {
+                let state = match touched {
+                    true => GestureState::Pressed { since: now },
+                    false => GestureState::Idle,
+                };
+                (state, Some(Gesture::Tap))
+            }
+            (GestureState::Released { .. }, false) => (self.state, None),
+            (GestureState::Released { at }, true) => (
+                GestureState::SecondPress {
+                    since: now,
+                    first_released_at: at,
+                },
+                None,
+            ),
+
+            (GestureState::SecondPress { .. }, true) => (self.state, None),
+            (
+                GestureState::SecondPress {
+                    since,
+                    first_released_at,
+                },
+                false,
+            ) if now.saturating_sub(since) < DEBOUNCE_MS => (
+                GestureState::Released {
+                    at: first_released_at,
+                },
+                None,
+            ),
+            (GestureState::SecondPress { .. }, false) => {
+                (GestureState::Idle, Some(Gesture::DoubleTap))
+            }
+
+            (GestureState::LongPressed, true) => (GestureState::LongPressed, None),
+            (GestureState::LongPressed, false) => (GestureState::Idle, None),
+        };
+
+        self.state = state;
+        gesture
+    }
+}
+
+#[cfg(all(test, not(feature = "stm32")))]
+mod tests {
+    use super::*;
+
+    fn tsc_samples(base: u16, jitter: u16) -> Vec<u16> {
+        (0..CALIBRATION_SAMPLES)
+            .map(|i| if i % 2 == 0 { base } else { base + jitter })
+            .collect()
+    }
+
+    #[test]
+    fn test_tsc_calibration() {
+        let calibration = Calibration::from_samples(&tsc_samples(1400, 10)).unwrap();
+        assert!((1400..=1410).contains(&calibration.baseline));
+        // The minimum drop dominates over the small noise
+        assert_eq!(
+            calibration.threshold,
+            calibration.baseline - calibration.baseline / 10
+        );
+
+        // Untouched counts, including the noise, are never touches
+        for count in tsc_samples(1400, 10) {
+            assert!(!calibration.is_touched(count));
+        }
+        // A finger lowers the count well below the baseline
+        for count in [1000, 1100, 1200, 1250] {
+            assert!(calibration.is_touched(count));
+        }
+    }
+
+    #[test]
+    fn test_tsc_calibration_noise_margin() {
+        let calibration = Calibration::from_samples(&tsc_samples(2000, 90)).unwrap();
+        let spread = 90;
+        assert_eq!(calibration.threshold, calibration.baseline - spread * 3);
+        assert!(!calibration.is_touched(calibration.baseline - spread));
+        assert!(calibration.is_touched(calibration.threshold - 1));
+    }
+
+    #[test]
+    fn test_tsc_calibration_errors() {
+        assert_eq!(
+            Calibration::from_samples(&[1400; 4]),
+            Err(CalibrationError::NotEnoughSamples)
+        );
+        assert_eq!(
+            Calibration::from_samples(&[0; CALIBRATION_SAMPLES]),
+            Err(CalibrationError::NoSignal)
+        );
+
+        // Touched halfway through the calibration
+        let mut samples = tsc_samples(1400, 10);
+        samples[CALIBRATION_SAMPLES / 2..].fill(1000);
+        assert_eq!(
+            Calibration::from_samples(&samples),
+            Err(CalibrationError::Unstable)
+        );
+    }
+
+    #[test]
+    fn test_tsc_calibration_cbor() {
+        let calibration = Calibration::from_samples(&tsc_samples(1400, 10)).unwrap();
+        let encoded = minicbor::to_vec(calibration).unwrap();
+        assert_eq!(
+            minicbor::decode::<Calibration>(&encoded).unwrap(),
+            calibration
+        );
+    }
+
+    #[test]
+    fn test_tsc_slider_position() {
+        let calibrations = [Calibration {
+            baseline: 1000,
+            threshold: 900,
+        }; 3];
+
+        // Right on one of the pads
+        assert_eq!(slider_position(&[500, 1000, 1000], &calibrations), Some(0));
+        assert_eq!(
+            slider_position(&[1000, 500, 1000], &calibrations),
+            Some(128)
+        );
+        assert_eq!(
+            slider_position(&[1000, 1000, 500], &calibrations),
+            Some(255)
+        );
+        // Between two pads, closer to the one with the larger drop
+        assert_eq!(slider_position(&[1000, 600, 600], &calibrations), Some(191));
+        assert_eq!(slider_position(&[700, 400, 1000], &calibrations), Some(85));
+        // Counts above the baseline don't pull the position
+        assert_eq!(
+            slider_position(&[1100, 500, 1000], &calibrations),
+            Some(128)
+        );
+
+        // Not touched
+        assert_eq!(slider_position(&[950, 1000, 980], &calibrations), None);
+        // A single channel is not a slider
+        assert_eq!(slider_position(&[500], &calibrations[..1]), None);
+        assert_eq!(slider_position(&[500, 500], &calibrations), None);
+    }
+
+    fn run_gestures(samples: &[(bool, u64)]) -> Vec<Gesture> {
+        let mut detector = GestureDetector::new();
+        samples
+            .iter()
+            .filter_map(|(touched, now)| detector.push(*touched, *now))
+            .collect()
+    }
+
+    /// Sample every 10ms between `from` and `to`
+    fn touch_samples(touched: bool, from: u64, to: u64) -> Vec<(bool, u64)> {
+        (from..to).step_by(10).map(|t| (touched, t)).collect()
+    }
+
+    #[test]
+    fn test_gesture_tap() {
+        let samples = [
+            touch_samples(false, 0, 100),
+            touch_samples(true, 100, 250),
+            touch_samples(false, 250, 1000),
+        ]
+        .concat();
+        assert_eq!(run_gestures(&samples), vec![Gesture::Tap]);
+
+        // The tap is only reported once the double tap window expires
+        let samples = [touch_samples(true, 0, 150), touch_samples(false, 150, 400)].concat();
+        assert_eq!(run_gestures(&samples), vec![]);
+    }
+
+    #[test]
+    fn test_gesture_double_tap() {
+        let samples = [
+            touch_samples(true, 0, 150),
+            touch_samples(false, 150, 350),
+            touch_samples(true, 350, 500),
+            touch_samples(false, 500, 1500),
+        ]
+        .concat();
+        assert_eq!(run_gestures(&samples), vec![Gesture::DoubleTap]);
+
+        // Too far apart, two separate taps
+        let samples = [
+            touch_samples(true, 0, 150),
+            touch_samples(false, 150, 600),
+            touch_samples(true, 600, 750),
+            touch_samples(false, 750, 1500),
+        ]
+        .concat();
+        assert_eq!(run_gestures(&samples), vec![Gesture::Tap, Gesture::Tap]);
+    }
+
+    #[test]
+    fn test_gesture_long_press() {
+        let samples = [
+            touch_samples(true, 0, 2000),
+            touch_samples(false, 2000, 3000),
+        ]
+        .concat();
+        assert_eq!(run_gestures(&samples), vec![Gesture::LongPress]);
+
+        // Released just before the threshold
+        let samples = [
+            touch_samples(true, 0, LONG_PRESS_MS),
+            touch_samples(false, LONG_PRESS_MS, 2000),
+        ]
+        .concat();
+        assert_eq!(run_gestures(&samples), vec![Gesture::Tap]);
+    }
+
+    #[test]
+    fn test_gesture_debounce() {
+        // Glitches shorter than the debounce time are ignored
+        let samples = [
+            touch_samples(false, 0, 100),
+            touch_samples(true, 100, 110),
+            touch_samples(false, 110, 1000),
+        ]
+        .concat();
+        assert_eq!(run_gestures(&samples), vec![]);
+
+        // Also between the two taps of a double tap
+        let samples = [
+            touch_samples(true, 0, 150),
+            touch_samples(false, 150, 200),
+            touch_samples(true, 200, 210),
+            touch_samples(false, 210, 700),
+        ]
+        .concat();
+        assert_eq!(run_gestures(&samples), vec![Gesture::Tap]);
+    }
+}
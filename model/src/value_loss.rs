@@ -0,0 +1,144 @@
+// Portal Hardware Wallet firmware and supporting software libraries
+//
+// Copyright (C) 2024 Alekos Filini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Guard against value loss when co-signing a transaction built by someone else
+//!
+//! In a collaborative transaction, like a CoinJoin, we only contribute some of the inputs and
+//! expect to get the same value back in our outputs, minus our share of the fee. A coordinator
+//! could instead send part of our coins to itself, and since it also controls the other inputs
+//! the total fee would still look reasonable.
+
+use core::fmt;
+
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::TxOut;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueLossError {
+    /// Our outputs return less than our inputs minus `max_fee`
+    ValueLoss { lost: u64, max_fee: u64 },
+    /// The values of the inputs or outputs don't fit in a `u64`
+    Overflow,
+}
+
+impl fmt::Display for ValueLossError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+fn checked_sum(mut values: impl Iterator<Item = u64>) -> Result<u64, ValueLossError> {
+    values
+        .try_fold(0u64, |sum, value| sum.checked_add(value))
+        .ok_or(ValueLossError::Overflow)
+}
+
+/// Ensure that the outputs we control return the value of our inputs, minus at most `max_fee`
+///
+/// `prev_utxos` are the outputs spent by each input, `our_input` and `our_output` tell which
+/// inputs and outputs are ours by their index. Returns the value we lose, which is at most
+/// `max_fee`.
+pub fn verify_no_value_loss(
+    psbt: &PartiallySignedTransaction,
+    prev_utxos: &[&TxOut],
+    our_input: impl Fn(usize) -> bool,
+    our_output: impl Fn(usize) -> bool,
+    max_fee: u64,
+) -> Result<u64, ValueLossError> {
+    let our_inputs = checked_sum(
+        prev_utxos
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| our_input(*index))
+            .map(|(_, utxo)| utxo.value),
+    )?;
+    let our_outputs = checked_sum(
+        psbt.unsigned_tx
+            .output
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| our_output(*index))
+            .map(|(_, out)| out.value),
+    )?;
+
+    let lost = our_inputs.saturating_sub(our_outputs);
+    if lost > max_fee {
+        return Err(ValueLossError::ValueLoss { lost, max_fee });
+    }
+
+    Ok(lost)
+}
+
+#[cfg(all(test, not(feature = "stm32")))]
+mod tests {
+    use super::*;
+    use crate::test_utils::coinjoin_psbt;
+
+    #[test]
+    fn test_verify_no_value_loss() {
+        // Each party gets its coins back, minus 100 sat of fees
+        let (psbt, prev_utxos) = coinjoin_psbt(&[9_900, 9_900]);
+        let prev_utxos = prev_utxos.iter().collect::<Vec<_>>();
+        assert_eq!(
+            verify_no_value_loss(&psbt, &prev_utxos, |i| i == 0, |i| i == 0, 200),
+            Ok(100)
+        );
+        // The fee limit is inclusive
+        assert_eq!(
+            verify_no_value_loss(&psbt, &prev_utxos, |i| i == 0, |i| i == 0, 100),
+            Ok(100)
+        );
+        // Getting more than we put in is fine
+        assert_eq!(
+            verify_no_value_loss(&psbt, &prev_utxos, |i| i == 0, |_| true, 200),
+            Ok(0)
+        );
+    }
+
+    #[test]
+    fn test_verify_no_value_loss_draining() {
+        // The coordinator takes 8.9k sat of ours, while the total fee is still 200 sat
+        let (psbt, prev_utxos) = coinjoin_psbt(&[1_000, 18_800]);
+        let prev_utxos = prev_utxos.iter().collect::<Vec<_>>();
+        assert_eq!(
+            verify_no_value_loss(&psbt, &prev_utxos, |i| i == 0, |i| i == 0, 200),
+            Err(ValueLossError::ValueLoss {
+                lost: 9_000,
+                max_fee: 200
+            })
+        );
+
+        // An output that isn't ours is not counted, even if it has the right value
+        let (psbt, prev_utxos) = coinjoin_psbt(&[9_900, 9_900]);
+        let prev_utxos = prev_utxos.iter().collect::<Vec<_>>();
+        assert_eq!(
+            verify_no_value_loss(&psbt, &prev_utxos, |i| i == 0, |_| false, 200),
+            Err(ValueLossError::ValueLoss {
+                lost: 10_000,
+                max_fee: 200
+            })
+        );
+
+        // Values that overflow are rejected instead of wrapping
+        let (psbt, prev_utxos) = coinjoin_psbt(&[u64::MAX, 1]);
+        let prev_utxos = prev_utxos.iter().collect::<Vec<_>>();
+        assert_eq!(
+            verify_no_value_loss(&psbt, &prev_utxos, |_| true, |_| true, 200),
+            Err(ValueLossError::Overflow)
+        );
+    }
+}
@@ -17,6 +17,9 @@
 
 use crate::MessageFragment;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteBufferFull;
+
 pub struct WriteBuffer<const DATA_LEN: usize, const NUM_BUFS: usize, const PREFIX_LEN: usize> {
     _prefix: [u8; PREFIX_LEN],
     buffer: [[u8; DATA_LEN]; NUM_BUFS],
@@ -26,6 +29,9 @@ pub struct WriteBuffer<const DATA_LEN: usize, const NUM_BUFS: usize, const PREFI
 impl<const DATA_LEN: usize, const NUM_BUFS: usize, const PREFIX_LEN: usize>
     WriteBuffer<DATA_LEN, NUM_BUFS, PREFIX_LEN>
 {
+    /// Append a fragment to the buffer
+    ///
+    /// Bytes that don't fit are silently dropped, use [`WriteBuffer::try_append`] to detect that.
     pub fn append(&mut self, fragment: &MessageFragment) {
         let mut data_iter = fragment.get_filled_data().iter();
 
@@ -43,6 +49,53 @@ impl<const DATA_LEN: usize, const NUM_BUFS: usize, const PREFIX_LEN: usize>
         }
     }
 
+    /// Rewind the buffer so that it can be reused for the next message
+    ///
+    /// The address and prefix bytes at the beginning of each buffer are preserved, while the data
+    /// is zeroed so that nothing from the previous message is transmitted again.
+    pub fn reset(&mut self) {
+        for b in self.buffer.iter_mut() {
+            b[1 + PREFIX_LEN..].fill(0x00);
+        }
+        self.cursor = 1 + PREFIX_LEN;
+    }
+
+    /// Number of bytes that can still be appended, excluding the prefixes
+    pub fn remaining_capacity(&self) -> usize {
+        let current = self.cursor / DATA_LEN;
+        if current >= NUM_BUFS {
+            return 0;
+        }
+
+        DATA_LEN * (current + 1) - self.cursor
+            + (NUM_BUFS - current - 1) * (DATA_LEN - PREFIX_LEN - 1)
+    }
+
+    /// Append a fragment only if it fits entirely, leaving the buffer untouched otherwise
+    pub fn try_append(&mut self, fragment: &MessageFragment) -> Result<(), WriteBufferFull> {
+        if fragment.get_filled_data().len() > self.remaining_capacity() {
+            return Err(WriteBufferFull);
+        }
+
+        self.append(fragment);
+        Ok(())
+    }
+
+    /// The buffers that contain at least one byte of data
+    ///
+    /// Unlike [`WriteBuffer::get_data`] this doesn't include the terminator, which can be
+    /// obtained separately with [`WriteBuffer::terminator`].
+    pub fn get_filled_data(&self) -> impl Iterator<Item = &[u8; DATA_LEN]> {
+        let filled = (self.cursor - (PREFIX_LEN + 1)).div_ceil(DATA_LEN);
+
+        self.buffer.iter().take(filled)
+    }
+
+    /// The last buffer, which always needs to be written to complete the transaction
+    pub fn terminator(&self) -> &[u8; DATA_LEN] {
+        &self.buffer[NUM_BUFS - 1]
+    }
+
     pub fn get_data(&self) -> impl Iterator<Item = &[u8; DATA_LEN]> {
         // Take as many buffers as necessary plus the last one which is the terminator
         // and always needs to be written to complete the transaction
@@ -60,6 +113,7 @@ impl<const DATA_LEN: usize, const NUM_BUFS: usize, const PREFIX_LEN: usize>
 }
 
 pub trait WriteBufferInit<const DATA_LEN: usize, const NUM_BUFS: usize, const PREFIX_LEN: usize> {
+    #[allow(clippy::new_ret_no_self)]
     fn new() -> WriteBuffer<DATA_LEN, NUM_BUFS, PREFIX_LEN>;
 
     fn init_fields(
@@ -72,3 +126,96 @@ pub trait WriteBufferInit<const DATA_LEN: usize, const NUM_BUFS: usize, const PR
         }
     }
 }
+
+#[cfg(all(test, not(feature = "stm32")))]
+mod tests {
+    use super::*;
+
+    struct TestWriteBuffer;
+
+    impl WriteBufferInit<4, 3, 1> for TestWriteBuffer {
+        fn new() -> WriteBuffer<4, 3, 1> {
+            Self::init_fields([[0xA0, 0xF0, 0, 0], [0xA1, 0xF1, 0, 0], [0xA2, 0xF2, 0, 0]])
+        }
+    }
+
+    #[test]
+    fn test_write_buffer_exactly_full() {
+        let mut buffer = TestWriteBuffer::new();
+        assert_eq!(buffer.remaining_capacity(), 6);
+
+        let fragment = MessageFragment::new(&[0x01, 0x02, 0x03, 0x04], true);
+        assert!(buffer.try_append(&fragment).is_ok());
+        assert_eq!(buffer.remaining_capacity(), 0);
+
+        let data = buffer.get_data().collect::<Vec<_>>();
+        assert_eq!(
+            data,
+            vec![
+                &[0xA0, 0xF0, 0x01, 0x04],
+                &[0xA1, 0xF1, 0x01, 0x02],
+                &[0xA2, 0xF2, 0x03, 0x04]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_buffer_over_full() {
+        let mut buffer = TestWriteBuffer::new();
+        let fragment = MessageFragment::new(&[0x01, 0x02, 0x03, 0x04, 0x05], true);
+        assert_eq!(buffer.try_append(&fragment), Err(WriteBufferFull));
+        assert_eq!(buffer.remaining_capacity(), 6);
+
+        let fragment = MessageFragment::new(&[0x01], true);
+        assert!(buffer.try_append(&fragment).is_ok());
+        assert_eq!(buffer.remaining_capacity(), 3);
+        let fragment = MessageFragment::new(&[0x01, 0x02], true);
+        assert!(buffer.try_append(&fragment).is_err());
+    }
+
+    #[test]
+    fn test_write_buffer_reset() {
+        let mut buffer = TestWriteBuffer::new();
+        let fragment = MessageFragment::new(&[0x01, 0x02, 0x03, 0x04], true);
+        buffer.try_append(&fragment).unwrap();
+
+        buffer.reset();
+        assert_eq!(buffer.remaining_capacity(), 6);
+
+        let fragment = MessageFragment::new(&[0x05], true);
+        buffer.try_append(&fragment).unwrap();
+
+        let data = buffer.get_data().collect::<Vec<_>>();
+        assert_eq!(
+            data,
+            vec![
+                &[0xA0, 0xF0, 0x01, 0x01],
+                &[0xA1, 0xF1, 0x05, 0x00],
+                &[0xA2, 0xF2, 0x00, 0x00]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_buffer_filled_data() {
+        let mut buffer = TestWriteBuffer::new();
+        assert_eq!(buffer.get_filled_data().count(), 0);
+
+        buffer.try_append(&MessageFragment::new(&[], true)).unwrap();
+        assert_eq!(buffer.get_filled_data().count(), 1);
+        assert_eq!(buffer.get_data().count(), 3);
+
+        buffer.reset();
+        buffer
+            .try_append(&MessageFragment::new(&[0x01], true))
+            .unwrap();
+        assert_eq!(buffer.get_filled_data().count(), 2);
+
+        buffer.reset();
+        buffer
+            .try_append(&MessageFragment::new(&[0x01, 0x02, 0x03, 0x04], true))
+            .unwrap();
+        assert_eq!(buffer.get_filled_data().count(), 3);
+        assert_eq!(buffer.terminator(), &[0xA2, 0xF2, 0x03, 0x04]);
+    }
+}
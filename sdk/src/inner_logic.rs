@@ -15,14 +15,12 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::ops::DerefMut;
 use std::time::Duration;
 
 use async_std::channel;
 use futures::FutureExt;
 
 use model::encryption::CipherState;
-use rand::RngCore;
 
 use model::reg::*;
 use model::write_buffer::*;
@@ -159,6 +157,7 @@ pub(crate) async fn inner_future(
     async fn process_raw_message(
         nfc: &mut super::IndexedChannelPair,
         decrypt: &mut CipherState,
+        aad: &model::SessionAad,
         message: Message,
         replies: &channel::Sender<Result<Reply, FutureError>>,
         use_fast_ops: bool,
@@ -177,7 +176,7 @@ pub(crate) async fn inner_future(
 
         let msg = recv_message(nfc, use_fast_ops).await?;
         let mut decrypt_buf = Vec::new();
-        let reply: Reply = msg.deserialize(&mut decrypt_buf, decrypt)?;
+        let reply: Reply = msg.deserialize(&mut decrypt_buf, aad.reply(), decrypt)?;
 
         #[cfg(feature = "debug")]
         debug.send(super::DebugMessage::In(reply.clone())).await?;
@@ -193,6 +192,7 @@ pub(crate) async fn inner_future(
         nfc: &mut super::IndexedChannelPair,
         encrypt: &mut CipherState,
         decrypt: &mut CipherState,
+        aad: &model::SessionAad,
         request: Request,
         replies: &channel::Sender<Result<Reply, FutureError>>,
         use_fast_ops: bool,
@@ -204,10 +204,11 @@ pub(crate) async fn inner_future(
             .send(super::DebugMessage::Out(request.clone()))
             .await?;
 
-        let msg = Message::new_serialize(&request, encrypt)?;
+        let msg = Message::new_serialize(&request, aad.request(), encrypt)?;
         process_raw_message(
             nfc,
             decrypt,
+            aad,
             msg,
             replies,
             use_fast_ops,
@@ -224,6 +225,7 @@ pub(crate) async fn inner_future(
         nfc: &mut super::IndexedChannelPair,
         encrypt: &mut CipherState,
         decrypt: &mut CipherState,
+        aad: &model::SessionAad,
         raw_message: Vec<u8>,
         use_fast_ops: bool,
 
@@ -234,8 +236,8 @@ pub(crate) async fn inner_future(
             .await?;
 
         let (temp_s, temp_r) = channel::unbounded();
-        let msg = Message::from_slice_encrypt(&raw_message, encrypt)?;
-        process_raw_message(nfc, decrypt, msg, &temp_s, use_fast_ops, debug).await?;
+        let msg = Message::from_slice_encrypt(&raw_message, aad.request(), encrypt)?;
+        process_raw_message(nfc, decrypt, aad, msg, &temp_s, use_fast_ops, debug).await?;
 
         core::mem::drop(temp_r);
 
@@ -243,8 +245,7 @@ pub(crate) async fn inner_future(
     }
 
     // Perform noise handshake first
-    let mut ephemeral_key = model::encryption::wrap_sensitive([0; 32]);
-    (rand::thread_rng()).fill_bytes(ephemeral_key.deref_mut());
+    let ephemeral_key = model::encryption::SecpDH::genkey_from_rng(&mut rand::thread_rng());
     let mut handshake_state = model::encryption::handhake_state_initiator(ephemeral_key);
 
     let out_msg = handshake_state
@@ -256,15 +257,22 @@ pub(crate) async fn inner_future(
     wait_next(nfc, Some(TransferDir::HostToNfc)).await?;
 
     let in_msg = recv_message(nfc, use_fast_ops).await?;
-    match handshake_state.read_message_vec(in_msg.data()) {
-        Ok(_) => {
+    let session_aad = match handshake_state.read_message_vec(in_msg.data()) {
+        Ok(payload) => {
             log::debug!("Valid handshake!");
+            match model::SessionAad::from_handshake_payload(&payload) {
+                Some(aad) => aad,
+                None => {
+                    log::warn!("Invalid session ID in handshake: {:02X?}", payload);
+                    return Err(FutureError::Canceled);
+                }
+            }
         }
         Err(e) => {
             log::warn!("Invalid handshake: {:?}", e);
             return Err(FutureError::Canceled); // TODO: add specific error
         }
-    }
+    };
 
     assert!(handshake_state.completed());
     log::debug!("Completed Noise handshake");
@@ -278,14 +286,14 @@ pub(crate) async fn inner_future(
         let result = futures::select_biased! {
             r = requests.recv().fuse() => {
                 match r {
-                    Ok(r) => process_request(nfc, &mut encrypt, &mut decrypt, r, replies, use_fast_ops, #[cfg(feature = "debug")] debug_out).await,
+                    Ok(r) => process_request(nfc, &mut encrypt, &mut decrypt, &session_aad, r, replies, use_fast_ops, #[cfg(feature = "debug")] debug_out).await,
                     Err(e) => Err(e.into()),
                 }
             },
             _data = debug_in.recv().fuse() => {
                 #[cfg(feature = "debug")]
                 match _data {
-                    Ok(data) => process_send_debug_msg(nfc, &mut encrypt, &mut decrypt, data, use_fast_ops, #[cfg(feature = "debug")] debug_out).await,
+                    Ok(data) => process_send_debug_msg(nfc, &mut encrypt, &mut decrypt, &session_aad, data, use_fast_ops, #[cfg(feature = "debug")] debug_out).await,
                     Err(e) => Err(e.into()),
                 }
                 #[cfg(not(feature = "debug"))]
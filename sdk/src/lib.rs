@@ -30,6 +30,8 @@ use inner_logic::FutureError;
 
 use miniscript::TranslatePk;
 
+use unicode_normalization::UnicodeNormalization;
+
 use model::bitcoin::util::bip32;
 use model::{
     BsmsRound2, ExtendedKey, InitializationStatus, NumWordsMnemonic, Reply, Request, ScriptType,
@@ -52,7 +54,8 @@ const SRAM2_SIZE: u32 = 32 * 1024;
 const SRAM2_END: u32 = SRAM2_BASE + SRAM2_SIZE;
 
 const FLASH_BASE: u32 = 0x0800_0000;
-const FLASH_SIZE: u32 = 510 * 2048;
+/// Space available for the firmware, the pages after it are reserved
+const FLASH_SIZE: u32 = (model::fw_manifest::MAX_FW_PAGES * 2048) as u32;
 const FLASH_END: u32 = FLASH_BASE + FLASH_SIZE;
 
 #[cfg(feature = "bindings")]
@@ -83,6 +86,11 @@ pub enum DebugMessage {
 
 macro_rules! send_with_retry {
     ($channels:expr, $req:expr, $( $match:tt )*) => ({
+        // Replies carry no id, so only one call at a time can talk to the device
+        let _in_flight = $channels.in_flight.lock().await;
+        // A cancel that arrived after the previous call was done has nothing left to stop
+        while $channels.cancel.1.try_recv().is_ok() {}
+
         let mut i = 0;
         let mut send_ping = false;
 
@@ -90,7 +98,11 @@ macro_rules! send_with_retry {
             if i > MAX_RETRIES {
                 break Err(SdkError::CommunicationError)
             }
-            let req = if !send_ping {
+            let req = if $channels.cancel.1.try_recv().is_ok() {
+                // The reply to the cancel is ours, which ends this loop with `Canceled`
+                send_ping = false;
+                model::Request::Cancel
+            } else if !send_ping {
                 $req
             } else {
                 send_ping = false;
@@ -124,6 +136,12 @@ macro_rules! send_with_retry {
                 Ok(Reply::UnexpectedMessage) => {
                     break Err(SdkError::UnexpectedMessage)
                 }
+                Ok(Reply::Canceled) => {
+                    break Err(SdkError::Canceled)
+                }
+                Ok(Reply::UnsupportedProtocol(version)) => {
+                    break Err(SdkError::UnsupportedProtocol { version })
+                }
                 _ => {
                     i += 1; // Only increment when there's some kind of failure
                 },
@@ -234,13 +252,15 @@ impl PortalSdk {
         num_words: GenerateMnemonicWords,
         network: model::bitcoin::Network,
         password: Option<String>,
+        passphrase: Option<String>,
     ) -> Result<(), SdkError> {
         let num_words = match num_words {
             GenerateMnemonicWords::Words12 => NumWordsMnemonic::Words12,
             GenerateMnemonicWords::Words24 => NumWordsMnemonic::Words24,
         };
+        let passphrase = normalize_passphrase(passphrase);
 
-        send_with_retry!(self.requests, Request::GenerateMnemonic { num_words, network, password: password.clone() }, Ok(Reply::Ok) => break Ok(()))?;
+        send_with_retry!(self.requests, Request::GenerateMnemonic { num_words, network, password: password.clone(), passphrase: passphrase.clone() }, Ok(Reply::Ok) => break Ok(()))?;
         Ok(())
     }
 
@@ -249,8 +269,10 @@ impl PortalSdk {
         mnemonic: String,
         network: model::bitcoin::Network,
         password: Option<String>,
+        passphrase: Option<String>,
     ) -> Result<(), SdkError> {
-        send_with_retry!(self.requests, Request::SetMnemonic { mnemonic: mnemonic.clone(), network, password: password.clone() }, Ok(Reply::Ok) => break Ok(()))?;
+        let passphrase = normalize_passphrase(passphrase);
+        send_with_retry!(self.requests, Request::SetMnemonic { mnemonic: mnemonic.clone(), network, password: password.clone(), passphrase: passphrase.clone() }, Ok(Reply::Ok) => break Ok(()))?;
         Ok(())
     }
 
@@ -272,6 +294,23 @@ impl PortalSdk {
         Ok(address)
     }
 
+    pub async fn display_keychain_address(
+        &self,
+        keychain: KeychainKind,
+        index: u32,
+    ) -> Result<model::bitcoin::Address, SdkError> {
+        let request = Request::DisplayKeychainAddress {
+            keychain: keychain.into(),
+            index,
+        };
+        let address =
+            send_with_retry!(self.requests, request.clone(), Ok(Reply::Address(s)) => break Ok(s))?;
+        let address = address
+            .parse()
+            .map_err(|_| SdkError::DeserializationError)?;
+        Ok(address)
+    }
+
     pub async fn sign_psbt(&self, psbt: String) -> Result<String, SdkError> {
         use model::bitcoin::consensus::{deserialize, serialize};
 
@@ -283,23 +322,52 @@ impl PortalSdk {
 
         let psbt = send_with_retry!(self.requests, Request::SignPsbt(psbt.clone().into()), Ok(Reply::SignedPsbt(s)) => break Ok(s))?;
 
-        // We encode the signatures in a format that's almost psbt but incompatible in some cases,
-        // so we parse it manually here
-        let inputs =
-            psbt::PortalPsbt::parse(psbt.deref()).map_err(|_| SdkError::DeserializationError)?;
-        let mut psbt =
-            model::bitcoin::util::psbt::Psbt::from_unsigned_tx(original_psbt.unsigned_tx.clone())
-                .expect("Valid unsigned tx");
-        psbt.inputs = inputs.inputs;
-
-        original_psbt
-            .combine(psbt)
-            .map_err(|_| SdkError::DeserializationError)?;
+        combine_signatures(&mut original_psbt, &psbt)?;
         let original_psbt = serialize(&original_psbt);
 
         Ok(base64::encode(&original_psbt))
     }
 
+    /// Sign up to [`model::MAX_BATCH_PSBTS`] PSBTs, which are confirmed together on the device
+    pub async fn sign_psbt_batch(&self, psbts: Vec<String>) -> Result<Vec<String>, SdkError> {
+        use model::bitcoin::consensus::{deserialize, serialize};
+
+        let psbts = psbts
+            .iter()
+            .map(base64::decode)
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut original_psbts = psbts
+            .iter()
+            .map(|psbt| deserialize::<model::bitcoin::util::psbt::Psbt>(psbt))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| SdkError::DeserializationError)?;
+
+        send_with_retry!(self.requests, Request::BeginSignPsbt, Ok(Reply::Ok) => break Ok(()))?;
+
+        let request = Request::SignBatch(psbts.into_iter().map(Into::into).collect());
+        let signed = send_with_retry!(self.requests, request.clone(), Ok(Reply::SignedBatch(s)) => break Ok(s))?;
+        if signed.len() != original_psbts.len() {
+            return Err(SdkError::DeserializationError);
+        }
+
+        original_psbts
+            .iter_mut()
+            .zip(signed.iter())
+            .map(|(original_psbt, signatures)| {
+                combine_signatures(original_psbt, signatures)?;
+                Ok(base64::encode(serialize(original_psbt)))
+            })
+            .collect()
+    }
+
+    /// Raw network transaction of a PSBT with every input finalized, like the ones returned by
+    /// [`PortalSdk::sign_psbt`] when all the inputs belong to the device
+    pub async fn extract_tx(&self, psbt: String) -> Result<Vec<u8>, SdkError> {
+        let psbt = base64::decode(&psbt)?;
+        let tx = send_with_retry!(self.requests, Request::ExtractTx(psbt.clone().into()), Ok(Reply::RawTx(tx)) => break Ok(tx))?;
+        Ok(tx.to_vec())
+    }
+
     pub async fn get_xpub(&self, path: bip32::DerivationPath) -> Result<DeviceXpub, SdkError> {
         let (xpub, bsms) = send_with_retry!(self.requests, Request::GetXpub(path.clone().into()), Ok(Reply::Xpub { xpub, bsms }) => break Ok((xpub, bsms)))?;
 
@@ -405,7 +473,7 @@ impl PortalSdk {
                     cause: e.to_string(),
                 }
             })?;
-            let parsed = parsed.translate_pk(&mut BsmsTranslator)?;
+            let parsed = parsed.translate_pk(&mut BranchesTranslator::bsms())?;
             println!("{}", parsed);
 
             (
@@ -415,7 +483,7 @@ impl PortalSdk {
                 }),
             )
         } else {
-            (descriptor, None)
+            (translate_multipath(&descriptor)?, None)
         };
 
         let parsed = Descriptor::<DescriptorPublicKey>::from_str(&descriptor).map_err(|e| {
@@ -469,15 +537,86 @@ impl PortalSdk {
         Ok(())
     }
 
+    pub async fn get_fingerprint(&self) -> Result<bip32::Fingerprint, SdkError> {
+        let fingerprint = send_with_retry!(self.requests, Request::GetFingerprint, Ok(Reply::Fingerprint(bytes)) => break Ok(bytes))?;
+        Ok(bip32::Fingerprint::from(fingerprint.as_slice()))
+    }
+
+    /// Version of the message encoding used by the device, see [`model::PROTOCOL_VERSION`]
+    pub async fn get_protocol_version(&self) -> Result<u32, SdkError> {
+        let version = send_with_retry!(self.requests, Request::GetProtocolVersion, Ok(Reply::ProtocolVersion(version)) => break Ok(version))?;
+        Ok(version)
+    }
+
+    /// Version, hardware revision and serial number of the device, available in any state
+    pub async fn get_device_info(&self) -> Result<HardwareInfo, SdkError> {
+        let info = send_with_retry!(self.requests, Request::GetDeviceInfo, Ok(Reply::DeviceInfo { fw_version, hw_rev, serial }) => break Ok(HardwareInfo {
+            fw_version,
+            hw_rev,
+            serial: serial.iter().map(|b| format!("{:02X}", b)).collect(),
+        }))?;
+        Ok(info)
+    }
+
     pub async fn public_descriptors(&self) -> Result<Descriptors, SdkError> {
         let descriptor = send_with_retry!(self.requests, Request::PublicDescriptor, Ok(Reply::Descriptor{ external, internal }) => break Ok(Descriptors { external, internal }))?;
         Ok(descriptor)
     }
 
+    pub async fn export_descriptor(&self, keychain: KeychainKind) -> Result<String, SdkError> {
+        let keychain = keychain.into();
+        let descriptor = send_with_retry!(self.requests, Request::ExportDescriptor { keychain }, Ok(Reply::ExportedDescriptor(descriptor)) => break Ok(descriptor))?;
+        Ok(descriptor)
+    }
+
+    /// Set the display brightness, from `0` (dimmest) to `4` (brightest)
+    pub async fn set_brightness(&self, level: u8) -> Result<(), SdkError> {
+        send_with_retry!(self.requests, Request::SetBrightness(level), Ok(Reply::Ok) => break Ok(()))?;
+        Ok(())
+    }
+
+    pub async fn set_rotation(&self, rotation: Rotation) -> Result<(), SdkError> {
+        send_with_retry!(self.requests, Request::SetRotation(rotation.into()), Ok(Reply::Ok) => break Ok(()))?;
+        Ok(())
+    }
+
+    /// Phrase shown by the device when it's ready, confirmed on the device. An empty phrase
+    /// removes it.
+    pub async fn set_anti_phishing_phrase(&self, phrase: String) -> Result<(), SdkError> {
+        send_with_retry!(self.requests, Request::SetAntiPhishingPhrase(phrase.clone()), Ok(Reply::Ok) => break Ok(()))?;
+        Ok(())
+    }
+
+    /// Erase the wallet from the device, confirmed on the device. Also works on a locked device,
+    /// without the password.
+    pub async fn factory_reset(&self) -> Result<(), SdkError> {
+        send_with_retry!(self.requests, Request::FactoryReset, Ok(Reply::Ok) => break Ok(()))?;
+        Ok(())
+    }
+
+    /// Stop the operation in progress on the device, like the signing of a batch. The call that
+    /// started it returns [`SdkError::Canceled`] and the signatures produced so far are
+    /// discarded.
+    pub async fn cancel(&self) -> Result<(), SdkError> {
+        if self.requests.in_flight.try_lock().is_none() {
+            // Let the call in flight send the cancel in place of its next request, so that it's
+            // the one getting the `Canceled` reply. Then wait for it to return.
+            let _ = self.requests.cancel.0.try_send(());
+            let _ = self.requests.in_flight.lock().await;
+            return Ok(());
+        }
+
+        // The reply is `Canceled`, which `send_with_retry` turns into an error for every request
+        match send_with_retry!(self.requests, Request::Cancel, Ok(Reply::Ok) => break Ok(())) {
+            Ok(()) | Err(SdkError::Canceled) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
     pub async fn update_firmware(&self, binary: Vec<u8>) -> Result<(), SdkError> {
         // First 64 bytes are the signature, then there's the actual firmware.
         // We expect at least two pages (4K)
-        if binary.len() < 64 + 4096 || binary.len() > 64 + 510 * 2048 {
+        if binary.len() < 64 + 4096 || binary.len() > 64 + FLASH_SIZE as usize {
             return Err(SdkError::InvalidFirmware);
         }
 
@@ -549,20 +688,62 @@ impl PortalSdk {
     }
 }
 
-struct BsmsTranslator;
-impl miniscript::Translator<String, String, SdkError> for BsmsTranslator {
-    fn pk(&mut self, pk: &String) -> Result<String, SdkError> {
-        if pk.ends_with("/**") {
-            let mut pk = pk.clone();
-            pk.replace_range(pk.len() - 3.., "/*");
+/// Replaces a suffix that stands for both the `/0/*` and `/1/*` branches with `/*`, since the
+/// device appends the branch itself
+struct BranchesTranslator {
+    suffix: &'static str,
+    /// Error returned for keys that don't end with `suffix`
+    cause: &'static str,
+}
 
-            Ok(pk)
-        } else {
-            Err(SdkError::UnsupportedDescriptor {
-                cause:
-                    "When using BSMS all the keys must end with descriptor template syntax (`/**`)"
-                        .into(),
-            })
+impl BranchesTranslator {
+    fn bsms() -> Self {
+        BranchesTranslator {
+            suffix: "/**",
+            cause: "When using BSMS all the keys must end with descriptor template syntax (`/**`)",
+        }
+    }
+
+    fn multipath() -> Self {
+        BranchesTranslator {
+            suffix: "/<0;1>/*",
+            cause: "Multipath keys must all end with `/<0;1>/*`",
+        }
+    }
+}
+
+/// The device derives the seed from the passphrase as-is, so it must be normalized here
+fn normalize_passphrase(passphrase: Option<String>) -> Option<String> {
+    passphrase.map(|p| p.nfkd().collect())
+}
+
+/// Turn multipath keys (`/<0;1>/*`) into the form accepted by the device
+///
+/// Descriptors without multipath keys are returned unchanged.
+fn translate_multipath(descriptor: &str) -> Result<String, SdkError> {
+    use miniscript::descriptor::Descriptor;
+    use std::str::FromStr;
+
+    if !descriptor.contains('<') {
+        return Ok(descriptor.to_string());
+    }
+
+    let parsed =
+        Descriptor::<String>::from_str(descriptor).map_err(|e| SdkError::InvalidDescriptor {
+            cause: e.to_string(),
+        })?;
+    Ok(parsed
+        .translate_pk(&mut BranchesTranslator::multipath())?
+        .to_string())
+}
+
+impl miniscript::Translator<String, String, SdkError> for BranchesTranslator {
+    fn pk(&mut self, pk: &String) -> Result<String, SdkError> {
+        match pk.strip_suffix(self.suffix) {
+            Some(key) => Ok(format!("{}/*", key)),
+            None => Err(SdkError::UnsupportedDescriptor {
+                cause: self.cause.into(),
+            }),
         }
     }
 
@@ -598,6 +779,10 @@ impl miniscript::Translator<String, String, SdkError> for BsmsTranslator {
 struct RequestChannels {
     o: channel::Sender<Request>,
     i: channel::Receiver<Result<Reply, FutureError>>,
+    /// Held by the call waiting for a reply on `i`
+    in_flight: Mutex<()>,
+    /// Cancel for the call holding `in_flight`
+    cancel: (channel::Sender<()>, channel::Receiver<()>),
 }
 
 struct NfcChannels {
@@ -702,6 +887,8 @@ impl InnerManager {
         let req_channels = RequestChannels {
             o: requests_s,
             i: replies_r,
+            in_flight: Mutex::new(()),
+            cancel: channel::bounded(1),
         };
         let nfc_channels = NfcChannels {
             o: nfc_in_s,
@@ -740,6 +927,40 @@ impl InnerManager {
     }
 }
 
+/// Fingerprint of the static key of a device, formatted for the user to compare
+///
+/// The firmware doesn't have a static key yet, so for now there's nothing on the device to compare
+/// this with.
+#[cfg_attr(feature = "bindings", uniffi::export)]
+pub fn static_key_fingerprint(pubkey: Vec<u8>) -> Result<String, SdkError> {
+    let pubkey: [u8; 64] = pubkey
+        .try_into()
+        .map_err(|_| SdkError::DeserializationError)?;
+    let fingerprint = model::encryption::static_key_fingerprint(&pubkey);
+    Ok(model::encryption::format_static_key_fingerprint(
+        &fingerprint,
+    ))
+}
+
+/// Merge the signatures sent by the device into `original_psbt`
+///
+/// We encode the signatures in a format that's almost psbt but incompatible in some cases, so we
+/// parse it manually here
+fn combine_signatures(
+    original_psbt: &mut model::bitcoin::util::psbt::Psbt,
+    signatures: &[u8],
+) -> Result<(), SdkError> {
+    let inputs = psbt::PortalPsbt::parse(signatures).map_err(|_| SdkError::DeserializationError)?;
+    let mut psbt =
+        model::bitcoin::util::psbt::Psbt::from_unsigned_tx(original_psbt.unsigned_tx.clone())
+            .expect("Valid unsigned tx");
+    psbt.inputs = inputs.inputs;
+
+    original_psbt
+        .combine(psbt)
+        .map_err(|_| SdkError::DeserializationError)
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 #[cfg_attr(feature = "bindings", derive(uniffi::Record))]
 pub struct CardStatus {
@@ -754,6 +975,15 @@ pub struct CardStatus {
     pub fingerprint: Option<bip32::Fingerprint>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "bindings", derive(uniffi::Record))]
+pub struct HardwareInfo {
+    pub fw_version: String,
+    pub hw_rev: u8,
+    /// Hex-encoded unique ID of the device
+    pub serial: String,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 #[cfg_attr(feature = "bindings", derive(uniffi::Record))]
 pub struct Descriptors {
@@ -792,6 +1022,38 @@ pub enum GenerateMnemonicWords {
     Words24,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "bindings", derive(uniffi::Enum))]
+pub enum KeychainKind {
+    External,
+    Internal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "bindings", derive(uniffi::Enum))]
+pub enum Rotation {
+    Normal,
+    Flipped,
+}
+
+impl From<Rotation> for model::Rotation {
+    fn from(value: Rotation) -> Self {
+        match value {
+            Rotation::Normal => model::Rotation::Normal,
+            Rotation::Flipped => model::Rotation::Flipped,
+        }
+    }
+}
+
+impl From<KeychainKind> for model::KeychainKind {
+    fn from(value: KeychainKind) -> Self {
+        match value {
+            KeychainKind::External => model::KeychainKind::External,
+            KeychainKind::Internal => model::KeychainKind::Internal,
+        }
+    }
+}
+
 #[derive(Debug)]
 #[cfg_attr(feature = "bindings", derive(uniffi::Error))]
 #[cfg_attr(feature = "bindings", uniffi(flat_error))]
@@ -805,9 +1067,21 @@ pub enum SdkError {
     Base64,
     InvalidFirmware,
     Locked,
-    DeviceError { cause: String },
-    InvalidDescriptor { cause: String },
-    UnsupportedDescriptor { cause: String },
+    /// The operation was stopped with [`PortalSdk::cancel`]
+    Canceled,
+    /// The device uses a different version of the protocol
+    UnsupportedProtocol {
+        version: u32,
+    },
+    DeviceError {
+        cause: String,
+    },
+    InvalidDescriptor {
+        cause: String,
+    },
+    UnsupportedDescriptor {
+        cause: String,
+    },
 }
 
 impl core::fmt::Display for SdkError {
@@ -881,3 +1155,143 @@ mod ffi {
 
 #[cfg(feature = "bindings")]
 uniffi::setup_scaffolding!();
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use miniscript::descriptor::{Descriptor, DescriptorPublicKey};
+    use std::str::FromStr;
+
+    const KEY_A: &str = "[73c5da0a/48'/1'/0'/2']tpubDFH9dgzveyD8zTbPUFuLrGmCydNvxehyNdUXKJAQN8x4aZ4j6UZqGfnqFrD4NqyaTVGKbvEW54tsvPTK2UoSbCC1PJY8iCNiwTL3RWZEheQ";
+    const KEY_B: &str = "[3977ad96/48'/1'/0'/2']tpubDE2WqbYnigRFTi6h4Km571hyX5umkEUvgLUa8kuB7tWXeBD6ffvbXqM2adiWoX9cpwQC9EQakVhy82yeCvwy1RHJVzFaC1ffhNVmEphWuEk";
+
+    #[test]
+    fn test_translate_multipath() {
+        let translated = translate_multipath(&format!(
+            "wsh(sortedmulti(1,{}/<0;1>/*,{}/<0;1>/*))",
+            KEY_A, KEY_B
+        ))
+        .unwrap();
+        let expected = Descriptor::<DescriptorPublicKey>::from_str(&format!(
+            "wsh(sortedmulti(1,{}/*,{}/*))",
+            KEY_A, KEY_B
+        ))
+        .unwrap();
+        assert_eq!(
+            Descriptor::<DescriptorPublicKey>::from_str(&translated).unwrap(),
+            expected
+        );
+
+        // Left untouched without multipath keys
+        let plain = format!("wpkh({}/*)", KEY_A);
+        assert_eq!(translate_multipath(&plain).unwrap(), plain);
+
+        // Only the standard receive and change branches are supported
+        for descriptor in [
+            format!("wpkh({}/<1;0>/*)", KEY_A),
+            format!("wsh(sortedmulti(1,{}/<0;1>/*,{}/*))", KEY_A, KEY_B),
+        ] {
+            assert!(matches!(
+                translate_multipath(&descriptor),
+                Err(SdkError::UnsupportedDescriptor { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_normalize_passphrase() {
+        // Precomposed and decomposed forms must lead to the same wallet
+        assert_eq!(
+            normalize_passphrase(Some("caf\u{e9}".into())),
+            Some("cafe\u{301}".into())
+        );
+        assert_eq!(
+            normalize_passphrase(Some("cafe\u{301}".into())),
+            Some("cafe\u{301}".into())
+        );
+        assert_eq!(normalize_passphrase(None), None);
+    }
+
+    #[test]
+    fn test_cancel_in_flight() {
+        const PSBT: &str = "cHNidP8BAFICAAAAAaBa/zzN4DufvU55XxA5Atv6Ce8IBjwQDorNb9ozNj0jAAAAAAD9////AfETAAAAAAAAFgAUow0Bk6zYJpM8neIOWSVDUI/SMw/09SoAAAEBHxAnAAAAAAAAFgAUjZMlxw1pfsKhfCwghXBAZbPAh6ABAN4CAAAAAAEB5wbexMJPm5cAOIzEZEfaBja+X6j4PCEZMdH1FqlJET8AAAAAAP3///8CECcAAAAAAAAWABSNkyXHDWl+wqF8LCCFcEBls8CHoAAyAAAAAAAAFgAUDE+Hi6xSRoQyv20NbKaqOwhiuGECRzBEAiBsNI/BcueDMnAh1tFofo3HQlABy65FIIoTOqf2d0cMygIgIvZ4UESL+JcmUUOMtACOY578cYERCc1rsz/vHY+g4z8BIQOL3i/ypht9oqUxUQ6pDwd62GxnTuslqeZGeNFnMNxo6fT1KgAiBgMZy1Vcgedg0NSvlpCWyLHYOiAh9SIP2ne8XKMYLzv1wxhzxdoKVAAAgAEAAIAAAACAAAAAACoAAAAAAA==";
+
+        let (manager, requests, nfc, stop, _debug_channels) = InnerManager::new(false);
+        let sdk = Arc::new(PortalSdk {
+            requests,
+            nfc,
+            manager: Mutex::new(None),
+            stop,
+
+            #[cfg(feature = "debug")]
+            debug_channels: _debug_channels,
+        });
+
+        // Device that keeps signing until it gets a cancel
+        let (signing_s, signing_r) = channel::bounded(1);
+        let device = async_std::task::spawn(async move {
+            let mut received = vec![];
+            loop {
+                let request = manager.requests.recv().await.unwrap();
+                let reply = match &request {
+                    Request::BeginSignPsbt => Reply::Ok,
+                    Request::SignBatch(_) => {
+                        signing_s.send(()).await.unwrap();
+                        Reply::DelayedReply
+                    }
+                    Request::Ping => Reply::DelayedReply,
+                    Request::Cancel => Reply::Canceled,
+                    _ => Reply::UnexpectedMessage,
+                };
+                let done = matches!(request, Request::Cancel);
+                received.push(request);
+                manager.replies.send(Ok(reply)).await.unwrap();
+
+                if done {
+                    break received;
+                }
+            }
+        });
+
+        let test = async {
+            let sign = async_std::task::spawn({
+                let sdk = Arc::clone(&sdk);
+                async move { sdk.sign_psbt_batch(vec![PSBT.into(); 2]).await }
+            });
+
+            signing_r.recv().await.unwrap();
+            sdk.cancel().await.unwrap();
+
+            assert!(matches!(sign.await, Err(SdkError::Canceled)));
+
+            let received = device.await;
+            assert!(matches!(received.last(), Some(Request::Cancel)));
+            assert_eq!(
+                received
+                    .iter()
+                    .filter(|r| matches!(r, Request::Cancel))
+                    .count(),
+                1
+            );
+        };
+        async_std::task::block_on(async_std::future::timeout(Duration::from_secs(5), test))
+            .expect("The call in flight got the reply to the cancel");
+    }
+
+    #[test]
+    fn test_static_key_fingerprint() {
+        // Compressed generator point, padded to 64 bytes like the keys in the handshake
+        use model::bitcoin::hashes::hex::FromHex;
+
+        let mut pubkey = Vec::<u8>::from_hex(
+            "0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798",
+        )
+        .unwrap();
+        pubkey.resize(64, 0);
+
+        // First 4 bytes of its sha256
+        assert_eq!(static_key_fingerprint(pubkey.clone()).unwrap(), "0F71-5BAF");
+        assert!(static_key_fingerprint(pubkey[..33].to_vec()).is_err());
+    }
+}
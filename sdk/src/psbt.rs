@@ -77,6 +77,16 @@ impl RawMap {
 
                         state.partial_sigs.insert(pk, sig);
                     }
+                    // PSBT_IN_FINAL_SCRIPTSIG
+                    0x07 => {
+                        state.final_script_sig = Some(data.into());
+                    }
+                    // PSBT_IN_FINAL_SCRIPTWITNESS
+                    0x08 => {
+                        let witness = model::bitcoin::consensus::deserialize(&data)?;
+
+                        state.final_script_witness = Some(witness);
+                    }
                     // PSBT_IN_TAP_KEY_SIG
                     0x13 => {
                         let sig = model::bitcoin::SchnorrSig::from_slice(&data).map_err(map_err)?;
@@ -160,4 +170,22 @@ mod test {
             assert_eq!(input.partial_sigs.len(), 1);
         }
     }
+
+    #[test]
+    fn test_parse_finalized_input() {
+        use model::bitcoin::consensus::serialize;
+        use model::bitcoin::{Script, Witness};
+
+        let input = psbt::Input {
+            final_script_sig: Some(Script::from(vec![0x16, 0x00, 0x14])),
+            final_script_witness: Some(Witness::from_vec(vec![vec![0x30; 71], vec![0x02; 33]])),
+            ..Default::default()
+        };
+
+        let mut data = vec![0x70, 0x73, 0x62, 0x74, 0xFF, 0x00];
+        data.extend(serialize(&input));
+        let parsed = PortalPsbt::parse(&data).unwrap().inputs;
+
+        assert_eq!(parsed, vec![input]);
+    }
 }